@@ -0,0 +1,111 @@
+//! Implementing `Compact` by hand for a struct with dynamic fields,
+//! then compacting it into and out of a buffer with the unsafe entry points.
+
+extern crate compact;
+extern crate simple_allocator_trait;
+
+use compact::{CString, CVec, Compact, DynamicLayout};
+use simple_allocator_trait::{Allocator, DefaultHeap};
+
+#[derive(Clone)]
+struct ActorState {
+    id: u32,
+    name: CString,
+    inbox: CVec<u32>,
+}
+
+impl Compact for ActorState {
+    fn is_still_compact(&self) -> bool {
+        self.name.is_still_compact() && self.inbox.is_still_compact()
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        let mut layout = DynamicLayout::new();
+        layout.field(&self.name);
+        layout.field(&self.inbox);
+        layout.size()
+    }
+
+    fn dynamic_align() -> usize {
+        CString::dynamic_align().max(CVec::<u32>::dynamic_align())
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        // fields without a dynamic part are just copied
+        (*dest).id = (*source).id;
+        // fields with a dynamic part get consecutive slices of the new dynamic part,
+        // padded so that each is aligned
+        let mut layout = DynamicLayout::new();
+        let name_offset = layout.field(&(*source).name);
+        let inbox_offset = layout.field(&(*source).inbox);
+        Compact::compact(
+            &mut (*source).name,
+            &mut (*dest).name,
+            new_dynamic_part.add(name_offset),
+        );
+        Compact::compact(
+            &mut (*source).inbox,
+            &mut (*dest).inbox,
+            new_dynamic_part.add(inbox_offset),
+        );
+    }
+
+    unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+        // needed to stream values with `compact_to_writer`
+        self.name.shift_compact_pointers(shift);
+        self.inbox.shift_compact_pointers(shift);
+    }
+
+    unsafe fn decompact(source: *const Self) -> Self {
+        ActorState {
+            id: (*source).id,
+            name: Compact::decompact(&(*source).name),
+            inbox: Compact::decompact(&(*source).inbox),
+        }
+    }
+
+    fn repair_lossy(&mut self, path: &str, dropped: &mut Vec<String>) {
+        self.name.repair_lossy(&format!("{}.name", path), dropped);
+        self.inbox.repair_lossy(&format!("{}.inbox", path), dropped);
+    }
+}
+
+fn main() {
+    let mut state = ActorState {
+        id: 7,
+        name: String::from("worker").into(),
+        inbox: vec![1, 2, 3].into(),
+    };
+
+    // the buffer has to hold the static part followed by the dynamic part
+    // (allocated as `u64`s, so it is aligned for both)
+    let bytes = state.total_size_bytes();
+    let words = bytes.div_ceil(8);
+    let storage = DefaultHeap::allocate::<u64>(words) as *mut ActorState;
+
+    unsafe {
+        Compact::compact_behind(&mut state, storage);
+        // compacting semantically moved `state` into `storage`, it must not be dropped
+        ::std::mem::forget(state);
+
+        let compacted = &mut *storage;
+        assert!(compacted.is_still_compact());
+        assert!(compacted.name == "worker");
+
+        // mutation works transparently, growing spills onto the heap
+        compacted.inbox.push(4);
+        assert!(!compacted.is_still_compact());
+
+        // get an independent, freely stored value back before freeing the buffer
+        let decompacted = Compact::decompact(storage);
+        DefaultHeap::deallocate(storage as *mut u64, words);
+
+        assert_eq!(7, decompacted.id);
+        assert_eq!(&[1, 2, 3, 4], &*decompacted.inbox);
+    }
+}
+
+#[test]
+fn custom_compact() {
+    main();
+}
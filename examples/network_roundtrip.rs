@@ -0,0 +1,48 @@
+//! Sending a compact value over a boundary as bytes, reading a part of it in place
+//! on the hot path and recreating the full value where needed.
+
+extern crate compact;
+
+use compact::{CDict, CSlice, CVec, Compact};
+
+fn main() {
+    let mut positions: CVec<u32> = CVec::with_capacity(64);
+    positions.extend(vec![10, 20, 30]);
+
+    // unused capacity is trimmed, so the message stays small
+    let packet = positions.compact_to_bytes();
+    assert!(packet.len() < positions.total_size_bytes());
+
+    // hot path: look at the elements inside the received packet without allocating.
+    // the packet is copied into 8-byte aligned memory first, like a receive buffer would be
+    let mut received = vec![0u64; packet.len().div_ceil(8)];
+    unsafe {
+        ::std::ptr::copy_nonoverlapping(
+            packet.as_ptr(),
+            received.as_mut_ptr() as *mut u8,
+            packet.len(),
+        );
+        let bytes = ::std::slice::from_raw_parts(received.as_ptr() as *const u8, packet.len());
+        let view = CSlice::<u32>::from_bytes(bytes).expect("valid packet");
+        assert_eq!(60, view.iter().sum::<u32>());
+    }
+
+    // recreate values with nested dynamic parts safely
+    let mut routes: CDict<u32, CVec<u32>> = CDict::new();
+    routes.insert(1, vec![2, 3].into());
+    routes.insert(2, vec![1].into());
+    let packet = routes.compact_to_bytes();
+    // both ends run this same build, so the layouts match
+    let received =
+        unsafe { CDict::<u32, CVec<u32>>::from_compact_bytes(&packet) }.expect("valid packet");
+    assert_eq!(received, routes);
+
+    // truncated packets are rejected instead of being read out of bounds
+    let truncated = &packet[..packet.len() - 1];
+    assert!(unsafe { CDict::<u32, CVec<u32>>::from_compact_bytes(truncated) }.is_err());
+}
+
+#[test]
+fn network_roundtrip() {
+    main();
+}
@@ -0,0 +1,34 @@
+//! Persisting a compact value to a file and loading it again,
+//! repairing partially corrupt values instead of failing the whole save.
+
+extern crate compact;
+
+use compact::{CHashMap, CString, Compact};
+use std::fs;
+
+fn main() {
+    let mut inventory: CHashMap<u32, CString> = CHashMap::new();
+    inventory.insert(1, String::from("sword").into());
+    inventory.insert(2, String::from("shield").into());
+
+    let path =
+        ::std::env::temp_dir().join(format!("compact-persistence-{}.bin", ::std::process::id()));
+
+    // compact_lossy reports the paths of parts that had to be dropped
+    let (bytes, dropped) = inventory.compact_lossy();
+    assert!(dropped.is_empty());
+    fs::write(&path, &bytes).unwrap();
+
+    // the file was written by this same build just above
+    let stored = fs::read(&path).unwrap();
+    let loaded = unsafe { CHashMap::<u32, CString>::from_compact_bytes(&stored) }.unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert!(loaded == inventory);
+    assert!(*loaded.get(&2).unwrap() == "shield");
+}
+
+#[test]
+fn persistence() {
+    main();
+}
@@ -0,0 +1,72 @@
+//! Packing many values of different sizes back-to-back into one slab,
+//! for example to keep all actor states of one type close together in memory.
+
+extern crate compact;
+
+use compact::{CVec, Compact};
+
+type State = CVec<u64>;
+
+/// A slab of consecutive, compactly stored `State`s
+struct Slab {
+    words: Vec<u64>,
+    offsets: Vec<usize>,
+}
+
+impl Slab {
+    fn new() -> Slab {
+        Slab {
+            words: Vec::new(),
+            offsets: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, mut state: State) -> usize {
+        // keep every state 8-byte aligned by rounding its size up to whole words
+        let size_words = state.total_size_bytes().div_ceil(8);
+        let offset = self.words.len();
+        self.words.resize(offset + size_words, 0);
+        unsafe {
+            Compact::compact_behind(
+                &mut state,
+                self.words.as_mut_ptr().add(offset) as *mut State,
+            );
+        }
+        ::std::mem::forget(state);
+        self.offsets.push(offset);
+        self.offsets.len() - 1
+    }
+
+    fn get(&self, id: usize) -> &State {
+        // compact states only use relative pointers, so they don't care where the slab lives
+        unsafe { &*(self.words.as_ptr().add(self.offsets[id]) as *const State) }
+    }
+}
+
+impl Drop for Slab {
+    fn drop(&mut self) {
+        for &offset in &self.offsets {
+            // states might have spilled onto the heap, decompacting and dropping frees that
+            unsafe { Compact::decompact(self.words.as_ptr().add(offset) as *const State) };
+        }
+    }
+}
+
+fn main() {
+    let mut slab = Slab::new();
+    let small = slab.add(vec![1].into());
+    let large = slab.add((0..100).collect());
+    let empty = slab.add(CVec::new());
+
+    // moving the whole slab keeps all states valid
+    let slab = Box::new(slab);
+    assert_eq!(&[1], &**slab.get(small));
+    assert_eq!(99, slab.get(large)[99]);
+    assert!(slab.get(empty).is_empty());
+    assert!((0..3).all(|id| slab.get(id).is_still_compact()));
+}
+
+#[test]
+fn slab_packing() {
+    main();
+}
@@ -0,0 +1,218 @@
+use super::compact_vec::FromVecAllocator;
+use super::simple_allocator_trait::Allocator;
+use std::alloc::{self, Layout};
+use std::mem;
+use std::sync::Mutex;
+
+/// An `Allocator` that bump-allocates from a global arena of large chunks,
+/// so storage that spills out of compacted values still lands in a few contiguous regions.
+///
+/// Deallocation only reclaims memory if it was the most recent allocation
+/// (which makes a growing `CVec` reuse its old spot), everything else is
+/// reclaimed at once by `BumpAllocator::reset`.
+///
+/// ```
+/// # use compact::{BumpAllocator, CVec};
+/// BumpAllocator::reserve(1024);
+/// let mut list: CVec<u32, BumpAllocator> = CVec::new();
+/// list.extend(0..100);
+/// assert!(BumpAllocator::used_bytes() >= 400);
+/// drop(list);
+/// unsafe { BumpAllocator::reset() };
+/// assert_eq!(0, BumpAllocator::used_bytes());
+/// ```
+pub struct BumpAllocator {}
+
+const CHUNK_ALIGN: usize = 16;
+const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+struct Chunk {
+    ptr: *mut u8,
+    size: usize,
+}
+
+impl Chunk {
+    fn new(size: usize) -> Chunk {
+        let layout = Layout::from_size_align(size, CHUNK_ALIGN).expect("arena chunk too large");
+        let ptr = unsafe { alloc::alloc(layout) };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        Chunk { ptr, size }
+    }
+}
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        unsafe {
+            alloc::dealloc(
+                self.ptr,
+                Layout::from_size_align_unchecked(self.size, CHUNK_ALIGN),
+            )
+        }
+    }
+}
+
+struct Arena {
+    /// The last chunk is the one currently allocated from
+    chunks: Vec<Chunk>,
+    /// Bytes used in the current chunk
+    top: usize,
+    /// Bytes used in all previous chunks
+    used_before: usize,
+}
+
+// the chunks are only ever accessed while holding the lock
+unsafe impl Send for Arena {}
+
+static ARENA: Mutex<Arena> = Mutex::new(Arena {
+    chunks: Vec::new(),
+    top: 0,
+    used_before: 0,
+});
+
+impl Arena {
+    fn allocate(&mut self, bytes: usize, align: usize) -> *mut u8 {
+        if let Some(ptr) = self.bump(bytes, align) {
+            return ptr;
+        }
+        let previous_size = self.chunks.last().map_or(0, |chunk| chunk.size);
+        let size = (bytes + align)
+            .max(previous_size * 2)
+            .max(DEFAULT_CHUNK_SIZE);
+        self.used_before += self.top;
+        self.top = 0;
+        self.chunks.push(Chunk::new(size));
+        self.bump(bytes, align).expect("fresh chunk is large enough")
+    }
+
+    fn bump(&mut self, bytes: usize, align: usize) -> Option<*mut u8> {
+        let chunk = self.chunks.last()?;
+        let address = chunk.ptr as usize + self.top;
+        let padding = address.wrapping_neg() & (align - 1);
+        let new_top = self.top + padding + bytes;
+        if new_top > chunk.size {
+            return None;
+        }
+        self.top = new_top;
+        Some((address + padding) as *mut u8)
+    }
+
+    fn deallocate(&mut self, ptr: *mut u8, bytes: usize) {
+        if let Some(chunk) = self.chunks.last() {
+            // only the most recent allocation can be given back
+            if ptr as usize + bytes == chunk.ptr as usize + self.top && self.top >= bytes {
+                self.top -= bytes;
+            }
+        }
+    }
+
+    fn used_bytes(&self) -> usize {
+        self.used_before + self.top
+    }
+
+    fn capacity_bytes(&self) -> usize {
+        self.chunks.iter().map(|chunk| chunk.size).sum()
+    }
+}
+
+fn with_arena<R, F: FnOnce(&mut Arena) -> R>(f: F) -> R {
+    let mut arena = ARENA.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    f(&mut arena)
+}
+
+impl BumpAllocator {
+    /// Bytes handed out (including alignment padding) since the last `reset`
+    pub fn used_bytes() -> usize {
+        with_arena(|arena| arena.used_bytes())
+    }
+
+    /// Bytes of all chunks the arena currently owns
+    pub fn capacity_bytes() -> usize {
+        with_arena(|arena| arena.capacity_bytes())
+    }
+
+    /// Make sure the next `bytes` bytes can be allocated from one contiguous chunk
+    pub fn reserve(bytes: usize) {
+        with_arena(|arena| {
+            let free = arena.chunks.last().map_or(0, |chunk| chunk.size - arena.top);
+            if free < bytes {
+                arena.used_before += arena.top;
+                arena.top = 0;
+                arena.chunks.push(Chunk::new(bytes.max(DEFAULT_CHUNK_SIZE)));
+            }
+        })
+    }
+
+    /// Reclaim all storage allocated so far. If the arena grew into several chunks,
+    /// they are replaced by a single one of their total size,
+    /// so the next round of allocations is contiguous.
+    ///
+    /// # Safety
+    /// No value that still uses storage of the `BumpAllocator` may be accessed
+    /// or dropped afterwards.
+    pub unsafe fn reset() {
+        with_arena(|arena| {
+            if arena.chunks.len() > 1 {
+                let total = arena.capacity_bytes();
+                arena.chunks.clear();
+                arena.chunks.push(Chunk::new(total));
+            }
+            arena.top = 0;
+            arena.used_before = 0;
+        })
+    }
+}
+
+impl Allocator for BumpAllocator {
+    fn allocate<T>(capacity: usize) -> *mut T {
+        let bytes = capacity
+            .checked_mul(mem::size_of::<T>())
+            .expect("capacity overflow");
+        with_arena(|arena| arena.allocate(bytes, mem::align_of::<T>())) as *mut T
+    }
+
+    unsafe fn deallocate<T>(ptr: *mut T, capacity: usize) {
+        let bytes = capacity * mem::size_of::<T>();
+        with_arena(|arena| arena.deallocate(ptr as *mut u8, bytes))
+    }
+}
+
+impl FromVecAllocator for BumpAllocator {}
+
+#[test]
+fn bump_allocator() {
+    use super::compact_vec::CompactVec;
+    type List = CompactVec<u64, BumpAllocator>;
+
+    // other tests don't use the `BumpAllocator`, so nobody else resets it meanwhile
+    unsafe { BumpAllocator::reset() };
+    let mut a: List = CompactVec::with_capacity(2);
+    a.push(1);
+    let a_storage = a.as_ptr() as usize;
+    assert_eq!(0, a_storage % mem::align_of::<u64>());
+    let b: CompactVec<u8, BumpAllocator> = CompactVec::with_capacity(3);
+    let c: List = CompactVec::with_capacity(1);
+    assert_eq!(a_storage + 16, b.as_ptr() as usize);
+    // padded for alignment
+    assert_eq!(a_storage + 24, c.as_ptr() as usize);
+    assert_eq!(32, BumpAllocator::used_bytes());
+
+    // the spot of the most recent allocation is reused
+    drop(c);
+    assert_eq!(24, BumpAllocator::used_bytes());
+    a.extend(2..5);
+    assert_eq!(a_storage + 24, a.as_ptr() as usize);
+    drop(b);
+    assert_eq!(&[1, 2, 3, 4], &a[..]);
+
+    // spills into a second chunk, which gets merged on reset
+    let big: List = CompactVec::with_capacity(DEFAULT_CHUNK_SIZE / 8);
+    assert_eq!(2, with_arena(|arena| arena.chunks.len()));
+    drop(big);
+    drop(a);
+    unsafe { BumpAllocator::reset() };
+    assert_eq!(0, BumpAllocator::used_bytes());
+    assert_eq!(1, with_arena(|arena| arena.chunks.len()));
+    assert!(BumpAllocator::capacity_bytes() >= 2 * DEFAULT_CHUNK_SIZE);
+}
@@ -1,5 +1,13 @@
+use std::alloc::{self, Layout};
+use std::cell::Cell;
+use std::collections::hash_map::DefaultHasher;
+use std::error::Error;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Write};
 use std::mem;
 use std::ptr;
+use std::slice;
 
 /// A trait for objects with a statically-sized part and a potential dynamically-sized part
 /// that can be stored both compactly in consecutive memory or freely on the heap
@@ -13,27 +21,136 @@ pub trait Compact: Sized + Clone {
     /// Size of the dynamic part in bytes
     fn dynamic_size_bytes(&self) -> usize;
 
-    /// Total size of the object (static part + dynamic part)
+    /// Alignment (a power of two) that the dynamic part has to start at,
+    /// so that all storage within it is aligned for its elements.
+    ///
+    /// Types whose dynamic part consists of the dynamic parts of several fields should return
+    /// the largest alignment of their fields and place them using `DynamicLayout`.
+    fn dynamic_align() -> usize {
+        1
+    }
+
+    /// Total size of the object (static part, padding up to `dynamic_align`, dynamic part)
     fn total_size_bytes(&self) -> usize {
-        self.dynamic_size_bytes() + mem::size_of::<Self>()
+        padded_static_size::<Self>() + self.dynamic_size_bytes()
     }
 
     /// Copy the static part of `source` to `dest` and compactly store
     /// the dynamic part of `source` as the new dynamic part of `dest` at `new_dynamic_part`.
     /// This semantically moves source into dest.
+    ///
+    /// # Safety
+    ///
+    /// `source` and `dest` have to point to valid memory for `Self` and `new_dynamic_part` has to
+    /// point to at least `dynamic_size_bytes()` of writable memory. `source` must not be used
+    /// or dropped afterwards.
     unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8);
 
-    /// Get a pointer to behind the static part of `self` (commonly used place for the dynamic part)
+    /// Get a pointer to behind the static part of `self` (commonly used place for the dynamic part),
+    /// padded so that it is aligned to `dynamic_align` if `ptr` is.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` has to point into an allocation that extends at least one `Self`
+    /// and the padding past it.
     unsafe fn behind(ptr: *mut Self) -> *mut u8 {
-        ptr.offset(1) as *mut u8
+        (ptr as *mut u8).add(padded_static_size::<Self>())
     }
 
     /// Like `compact` with `new_dynamic_part` set to `dest.behind()`
+    ///
+    /// # Safety
+    ///
+    /// See `compact`, `dest` has to point to at least `total_size_bytes()` of writable memory.
+    /// For all storage within the dynamic part to be aligned, `dest` has to be aligned
+    /// to `dynamic_align` (as well as to `Self`).
     unsafe fn compact_behind(source: *mut Self, dest: *mut Self) {
         let behind_dest = Self::behind(dest);
         Self::compact(source, dest, behind_dest)
     }
 
+    /// Like `compact_behind`, but if `source` is still compact, the whole block of static
+    /// and dynamic part is moved with a single copy instead of being compacted field by field.
+    /// If `source` and `dest` are the same, nothing needs to be done at all.
+    ///
+    /// Returns whether this fast path was taken.
+    ///
+    /// # Safety
+    ///
+    /// See `compact_behind`. Additionally, if `source` is still compact, its dynamic part has
+    /// to be stored right behind it, as done by `compact_behind`. Otherwise, `source` and `dest`
+    /// must not be the same.
+    unsafe fn compact_move_if_unchanged(source: *mut Self, dest: *mut Self) -> bool {
+        if (*source).is_still_compact() {
+            if source != dest {
+                // compact pointers are relative, so they stay valid when moving the whole block
+                ptr::copy(
+                    source as *const u8,
+                    dest as *mut u8,
+                    (*source).total_size_bytes(),
+                );
+            }
+            true
+        } else {
+            Self::compact_behind(source, dest);
+            false
+        }
+    }
+
+    /// Compact the dynamic part of `self` (which might have partly spilled to the heap)
+    /// into `buffer`, leaving the static part of `self` where it is.
+    ///
+    /// `buffer` may be the one that currently holds the dynamic part of `self`,
+    /// since `self` is fully decompacted before anything is written.
+    /// Fails without changing anything if `buffer` is too small.
+    ///
+    /// Compact pointers are 32-bit offsets, so `buffer` has to be close to `self`,
+    /// typically in the same allocation, right behind it. See `CompactedBox::recompact`
+    /// for a convenience that allocates a new buffer of the right size instead.
+    ///
+    /// # Safety
+    ///
+    /// `self` must not be moved afterwards and `buffer` has to outlive any use of `self`,
+    /// since `self` now points into `buffer`. The old dynamic part of `self` is not freed.
+    unsafe fn recompact_into(&mut self, buffer: &mut [u8]) -> Result<(), NeedBytes> {
+        let padding = (buffer.as_ptr() as usize).wrapping_neg() & (Self::dynamic_align() - 1);
+        let needed = padding + self.dynamic_size_bytes();
+        if buffer.len() < needed {
+            return Err(NeedBytes {
+                needed,
+                available: buffer.len(),
+            });
+        }
+        let distance = buffer.as_ptr() as isize - self as *const Self as isize;
+        assert!(
+            distance.abs() + (needed as isize) < i32::MAX as isize,
+            "buffer is too far away from the value to be pointed to compactly"
+        );
+        let mut free = Self::decompact(self);
+        Self::compact(&mut free, self, buffer.as_mut_ptr().add(padding));
+        mem::forget(free);
+        Ok(())
+    }
+
+    /// Like `recompact_into`, but only recompacts the parts of `self` that spilled
+    /// to the heap (found with `is_still_compact`), leaving the still compact ones where they are.
+    ///
+    /// Each spilled part is recompacted into the front of `spare`, which is then advanced past it.
+    /// Containers recurse into their elements while their own storage is still compact,
+    /// the default recompacts all of `self` if any part of it spilled.
+    /// If `spare` runs out, the parts recompacted so far stay recompacted.
+    ///
+    /// # Safety
+    ///
+    /// See `recompact_into`, which applies to all of `spare`.
+    unsafe fn recompact_dirty_into(&mut self, spare: &mut &mut [u8]) -> Result<(), NeedBytes> {
+        if self.is_still_compact() {
+            Ok(())
+        } else {
+            recompact_whole_into(self, spare)
+        }
+    }
+
     /// Creates a clone of self with the dynamic part guaranteed to be stored freely.
     ///
     /// *Note:* if the dynamic part was already stored freely, the calling environment
@@ -41,10 +158,629 @@ pub trait Compact: Sized + Clone {
     ///
     /// This is mostly used internally to correctly implement
     /// `Compact` datastructures that contain `Compact` elements.
+    ///
+    /// # Safety
+    ///
+    /// `source` has to point to a valid `Self`, compact or free.
     unsafe fn decompact(source: *const Self) -> Self;
+
+    /// Replace parts of `self` that can't be compacted safely (like vectors with a corrupt
+    /// length) with empty defaults, pushing their paths (below `path`) onto `dropped`.
+    ///
+    /// Implementations for structs should repair each field at `"{path}.{field}"`.
+    fn repair_lossy(&mut self, _path: &str, _dropped: &mut Vec<String>) {}
+
+    /// Clear the dirty flags of all `CompactCell`s within `self`, after taking note of them.
+    ///
+    /// Containers clear those of their elements.
+    fn clear_dirty(&mut self) {}
+
+    /// Record how compactly the containers within `self` are stored in `stats`
+    /// (see `compact_stats`).
+    ///
+    /// Containers record their own storage below `path` (see `CompactStats::record_container`),
+    /// implementations for structs should collect the stats of each field at `"{path}.{field}"`.
+    fn collect_stats(&self, _path: &str, _stats: &mut CompactStats) {}
+
+    /// Report how compactly `self` is stored: how many bytes are dynamic
+    /// and which containers within it spilled to the heap.
+    fn compact_stats(&self) -> CompactStats {
+        let mut stats = CompactStats {
+            dynamic_bytes: self.dynamic_size_bytes(),
+            ..CompactStats::default()
+        };
+        self.collect_stats("", &mut stats);
+        stats
+    }
+
+    /// Like `compact_to_bytes`, but first repairs `self` (see `repair_lossy`)
+    /// instead of crashing on partially corrupt values.
+    /// Also returns the paths of all parts that were dropped.
+    fn compact_lossy(&mut self) -> (Vec<u8>, Vec<String>) {
+        let mut dropped = Vec::new();
+        self.repair_lossy("", &mut dropped);
+        (self.compact_to_bytes(), dropped)
+    }
+
+    /// Compact a copy of `self` into owned bytes, e.g. for persistence.
+    /// Unused vector capacity is trimmed (see `CapacityMode::Trim`).
+    fn compact_to_bytes(&self) -> Vec<u8> {
+        with_capacity_mode(CapacityMode::Trim, || {
+            let mut source = self.clone();
+            let size = source.total_size_bytes();
+            with_scratch::<Self, _, _>(size, |buffer| unsafe {
+                Self::compact_behind(&mut source, buffer as *mut Self);
+                mem::forget(source);
+                ::std::slice::from_raw_parts(buffer, size).to_vec()
+            })
+        })
+    }
+
+    /// Like `compact`, but only write the static part of a compacted copy of `self` to `dest`,
+    /// pointing to a dynamic part at `new_dynamic_part` that is written separately
+    /// (see `write_dynamic_part`).
+    ///
+    /// Only containers overriding this (and `write_dynamic_part`) actually stream,
+    /// setting their fields directly. The default compacts a whole copy of `self`
+    /// into scratch memory, keeps only its static part and moves its compact pointers
+    /// with `shift_compact_pointers`.
+    ///
+    /// # Safety
+    ///
+    /// `dest` has to point to zeroed memory for `Self`. `new_dynamic_part` is only used
+    /// to compute compact pointers, it doesn't have to point to accessible memory.
+    unsafe fn compact_static_part(&self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        let mut source = self.clone();
+        let size = source.total_size_bytes();
+        with_scratch::<Self, _, _>(size, |buffer| {
+            Self::compact_behind(&mut source, buffer as *mut Self);
+            mem::forget(source);
+            // the static part is moved from `buffer` to `dest`, away from its dynamic part
+            (*(buffer as *mut Self)).shift_compact_pointers(
+                (new_dynamic_part as isize - dest as isize) - padded_static_size::<Self>() as isize,
+            );
+            ptr::copy_nonoverlapping(buffer as *const Self, dest, 1);
+        })
+    }
+
+    /// Move the compact pointers in the static part of a compacted `self` by `shift` bytes,
+    /// as if its dynamic part was moved by `shift` bytes relative to it.
+    /// Used by the default `compact_static_part`.
+    ///
+    /// Implementations for structs should shift each field, containers only shift
+    /// their own pointer (the pointers of their elements are relative to the dynamic part).
+    /// The default only supports values without a dynamic part.
+    ///
+    /// # Safety
+    ///
+    /// `self` has to be compacted, and its compact pointers can only be followed again
+    /// once the static part is `shift` bytes closer to (or further from) its dynamic part.
+    unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+        assert!(
+            shift == 0 || self.dynamic_size_bytes() == 0,
+            "{} has a dynamic part but doesn't implement shift_compact_pointers",
+            ::std::any::type_name::<Self>()
+        );
+    }
+
+    /// Write the dynamic part of a compacted copy of `self` to `writer`,
+    /// exactly `dynamic_size_bytes()` bytes as `compact` would store them.
+    ///
+    /// Containers write their elements one by one, the default compacts a whole copy
+    /// of `self` into scratch memory first.
+    fn write_dynamic_part<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut source = self.clone();
+        let size = source.total_size_bytes();
+        with_scratch::<Self, _, _>(size, |buffer| unsafe {
+            Self::compact_behind(&mut source, buffer as *mut Self);
+            mem::forget(source);
+            let static_size = padded_static_size::<Self>();
+            writer.write_all(slice::from_raw_parts(
+                buffer.add(static_size),
+                size - static_size,
+            ))
+        })
+    }
+
+    /// Stream the same bytes as `compact_to_bytes` to `writer` (like a file or socket),
+    /// without compacting all of `self` into one buffer first:
+    /// containers overriding `compact_static_part` and `write_dynamic_part` write
+    /// the static parts and then the dynamic parts of their elements,
+    /// so only single elements are compacted in memory at a time
+    /// (values of other types are compacted whole into scratch memory).
+    fn compact_to_writer<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        with_capacity_mode(CapacityMode::Trim, || {
+            let static_size = padded_static_size::<Self>();
+            with_scratch::<Self, _, _>(static_size, |buffer| unsafe {
+                self.compact_static_part(buffer as *mut Self, buffer.add(static_size));
+                writer.write_all(slice::from_raw_parts(buffer, static_size))
+            })?;
+            self.write_dynamic_part(writer)
+        })
+    }
+
+    /// Recreate a value (with its dynamic part stored freely) from its compacted static part
+    /// at `source`, reading its dynamic part from `reader` (see `decompact_from_reader`).
+    ///
+    /// Containers read their elements one by one, checking lengths against the stream.
+    /// Implementations for structs should read each field with `CompactReader::read_field`,
+    /// in the same order as in `compact`. The default only supports types without
+    /// a dynamic part (which don't need to be dropped).
+    ///
+    /// # Safety
+    ///
+    /// `source` has to point to the static part of a compacted `Self` as read from a stream
+    /// that fulfills the contract of `decompact_from_reader`, its compact pointers aren't followed.
+    unsafe fn read_dynamic_part<R: Read>(
+        source: *const Self,
+        reader: &mut CompactReader<R>,
+    ) -> Result<Self, FromReaderError> {
+        let _ = reader;
+        if mem::needs_drop::<Self>() {
+            Err(FromReaderError::Unsupported(::std::any::type_name::<Self>()))
+        } else {
+            Ok(Self::decompact(source))
+        }
+    }
+
+    /// Recreate a value (with its dynamic part stored freely) from a stream
+    /// (like a file or socket) of the bytes produced by `compact_to_writer` for the same type,
+    /// without reading them all into one buffer first.
+    ///
+    /// Only the static parts of elements of a container are kept in memory before reading
+    /// their dynamic parts, so a stream that was cut off fails with an error when it ends
+    /// instead of a huge length being allocated up front.
+    /// Reading many small parts, this should be given a buffered reader.
+    ///
+    /// # Safety
+    ///
+    /// The stream has to be produced by `compact_to_writer` for the same type, by the same build
+    /// of the same program, like for `from_compact_bytes`: static parts are read back
+    /// without checking them, and a corrupt length can still be matched by a long enough stream.
+    unsafe fn decompact_from_reader<R: Read>(reader: &mut R) -> Result<Self, FromReaderError> {
+        let static_size = padded_static_size::<Self>();
+        with_scratch::<Self, _, _>(static_size, |buffer| {
+            let mut reader = CompactReader {
+                reader,
+                position: 0,
+                wanted: 0,
+            };
+            reader.read_exact(slice::from_raw_parts_mut(buffer, static_size))?;
+            reader.read_field(buffer as *const Self)
+        })
+    }
+
+    /// Recreate a value (with its dynamic part stored freely) from bytes
+    /// produced by `compact_to_bytes` for the same type.
+    ///
+    /// The dynamic parts are read like by `decompact_from_reader`, following the lengths
+    /// in the static parts instead of compact pointers, so nothing outside of `bytes` is read.
+    /// Catches bytes that were cut off and parts that `read_dynamic_part` checks,
+    /// like strings with invalid UTF-8. Types with a dynamic part have to implement
+    /// `read_dynamic_part` for this.
+    ///
+    /// # Safety
+    ///
+    /// `bytes` have to be produced by `compact_to_bytes` for the same type, by the same build
+    /// of the same program (the layout of static parts can change between builds).
+    /// Static parts are read back as they are: enum discriminants and values of types
+    /// like `bool` or `char` have to be valid, so authenticate bytes received
+    /// from untrusted sources before.
+    unsafe fn from_compact_bytes(bytes: &[u8]) -> Result<Self, FromBytesError> {
+        let static_size = padded_static_size::<Self>();
+        if bytes.len() < static_size {
+            return Err(FromBytesError::TooShort {
+                expected: static_size,
+                actual: bytes.len(),
+            });
+        }
+
+        let mut stream = bytes;
+        let mut reader = CompactReader {
+            reader: &mut stream,
+            position: 0,
+            wanted: 0,
+        };
+        let result = with_scratch::<Self, _, _>(static_size, |buffer| {
+            reader.read_exact(slice::from_raw_parts_mut(buffer, static_size))?;
+            reader.read_field(buffer as *const Self)
+        });
+        result.map_err(|error| match error {
+            FromReaderError::Io(_) => FromBytesError::TooShort {
+                expected: reader.wanted,
+                actual: bytes.len(),
+            },
+            FromReaderError::Invalid { .. } => FromBytesError::Invalid,
+            FromReaderError::Unsupported(name) => FromBytesError::Unsupported(name),
+        })
+    }
+}
+
+pub(crate) fn align_up(offset: usize, align: usize) -> usize {
+    (offset + align - 1) & !(align - 1)
+}
+
+/// Write `count` zero bytes, used for padding and unused capacity in streamed dynamic parts
+pub(crate) fn write_zeros<W: Write>(writer: &mut W, count: usize) -> io::Result<()> {
+    io::copy(&mut io::repeat(0).take(count as u64), writer).map(|_| ())
+}
+
+/// `Compact::recompact_into` the front of `spare` and advance `spare` past it
+pub(crate) unsafe fn recompact_whole_into<T: Compact>(
+    value: &mut T,
+    spare: &mut &mut [u8],
+) -> Result<(), NeedBytes> {
+    let padding = (spare.as_ptr() as usize).wrapping_neg() & (T::dynamic_align() - 1);
+    let used = padding + value.dynamic_size_bytes();
+    value.recompact_into(spare)?;
+    let rest = mem::take(spare);
+    *spare = &mut rest[used..];
+    Ok(())
+}
+
+/// Size of the static part of `T`, padded so that its dynamic part can follow it aligned
+pub(crate) fn padded_static_size<T: Compact>() -> usize {
+    align_up(mem::size_of::<T>(), T::dynamic_align())
+}
+
+/// Places the dynamic parts of several fields one after another within one dynamic part,
+/// padding each to its `Compact::dynamic_align`.
+///
+/// Make the same sequence of `field` calls in `dynamic_size_bytes` (to get the total `size`)
+/// and in `compact` (to get the offset of each field within the new dynamic part).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DynamicLayout {
+    size: usize,
+}
+
+impl DynamicLayout {
+    /// Start with an empty dynamic part
+    pub fn new() -> DynamicLayout {
+        DynamicLayout { size: 0 }
+    }
+
+    /// Start behind `offset` bytes that are already used, like the own storage of a container
+    pub fn after(offset: usize) -> DynamicLayout {
+        DynamicLayout { size: offset }
+    }
+
+    /// Place the dynamic part of `field` and return its offset
+    pub fn field<T: Compact>(&mut self, field: &T) -> usize {
+        let offset = align_up(self.size, T::dynamic_align());
+        self.size = offset + field.dynamic_size_bytes();
+        offset
+    }
+
+    /// Size of everything placed so far, including padding
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+/// Why `Compact::from_compact_bytes` couldn't recreate a value
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FromBytesError {
+    /// There are fewer bytes than the compacted value needs
+    TooShort {
+        /// Bytes needed by the compacted value, as far as it could be read
+        expected: usize,
+        /// Bytes given
+        actual: usize,
+    },
+    /// The bytes contain a part that isn't valid for its type,
+    /// like a vector pointing outside of them or a string with invalid UTF-8
+    Invalid,
+    /// The named type has a dynamic part, but doesn't implement `Compact::read_dynamic_part`
+    Unsupported(&'static str),
 }
 
-/// Trivial implementation for fixed-sized, `Copy` types (no dynamic part)
+impl fmt::Display for FromBytesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FromBytesError::TooShort { expected, actual } => write!(
+                f,
+                "compacted value needs {} bytes, but only {} were given",
+                expected, actual
+            ),
+            FromBytesError::Invalid => write!(f, "bytes contain an invalid compacted value"),
+            FromBytesError::Unsupported(name) => {
+                write!(f, "{} can't be read from bytes", name)
+            }
+        }
+    }
+}
+
+impl Error for FromBytesError {}
+
+/// Why `Compact::decompact_from_reader` couldn't recreate a value
+#[derive(Debug)]
+pub enum FromReaderError {
+    /// Reading failed, for example because the stream ended early
+    Io(io::Error),
+    /// The stream doesn't contain a compacted value
+    Invalid {
+        /// What is wrong
+        reason: &'static str,
+        /// Bytes read before noticing
+        position: usize,
+    },
+    /// The named type has a dynamic part, but doesn't implement `Compact::read_dynamic_part`
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for FromReaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FromReaderError::Io(ref error) => {
+                write!(f, "reading compacted value failed: {}", error)
+            }
+            FromReaderError::Invalid { reason, position } => write!(
+                f,
+                "stream doesn't contain a compacted value: {} (at byte {})",
+                reason, position
+            ),
+            FromReaderError::Unsupported(name) => {
+                write!(f, "{} can't be read from a stream", name)
+            }
+        }
+    }
+}
+
+impl Error for FromReaderError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            FromReaderError::Io(ref error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for FromReaderError {
+    fn from(error: io::Error) -> Self {
+        FromReaderError::Io(error)
+    }
+}
+
+/// A stream of compacted bytes, read by `Compact::decompact_from_reader`
+/// and `Compact::read_dynamic_part`
+pub struct CompactReader<'a, R: Read + 'a> {
+    reader: &'a mut R,
+    position: usize,
+    /// Where the last read or skip would have ended
+    wanted: usize,
+}
+
+impl<'a, R: Read + 'a> CompactReader<'a, R> {
+    /// Bytes read so far
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Fill `buffer` from the stream
+    pub fn read_exact(&mut self, buffer: &mut [u8]) -> Result<(), FromReaderError> {
+        self.wanted = self.position + buffer.len();
+        self.reader.read_exact(buffer)?;
+        self.position += buffer.len();
+        Ok(())
+    }
+
+    /// Skip `count` bytes, like unused capacity or padding
+    pub fn skip(&mut self, count: usize) -> Result<(), FromReaderError> {
+        self.wanted = self.position + count;
+        let skipped = io::copy(&mut (&mut *self.reader).take(count as u64), &mut io::sink())?;
+        self.position += skipped as usize;
+        if skipped < count as u64 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(())
+    }
+
+    /// Skip the padding in front of the dynamic part of a field and read it
+    /// (see `DynamicLayout::field`), recreating the field from its static part at `source`
+    ///
+    /// # Safety
+    ///
+    /// See `Compact::read_dynamic_part`
+    pub unsafe fn read_field<T: Compact>(
+        &mut self,
+        source: *const T,
+    ) -> Result<T, FromReaderError> {
+        let padding = self.position.wrapping_neg() & (T::dynamic_align() - 1);
+        self.skip(padding)?;
+        T::read_dynamic_part(source, self)
+    }
+
+    /// An error about the stream not containing a compacted value
+    pub fn invalid(&self, reason: &'static str) -> FromReaderError {
+        FromReaderError::Invalid {
+            reason,
+            position: self.position,
+        }
+    }
+}
+
+/// How compactly a value is stored, see `Compact::compact_stats`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CompactStats {
+    /// Total size of the dynamic part
+    pub dynamic_bytes: usize,
+    /// Containers whose own storage is compact
+    pub compact_containers: usize,
+    /// Containers whose own storage spilled to the heap
+    pub spilled_containers: usize,
+    /// Bytes of the own storage of all compact containers
+    pub compact_bytes: usize,
+    /// Bytes of the own storage of all spilled containers
+    pub spilled_bytes: usize,
+    /// Paths of all spilled containers, like `".entries[3].value"`
+    pub spilled_paths: Vec<String>,
+    /// All recorded containers, in the order they were recorded
+    pub containers: Vec<ContainerStats>,
+}
+
+/// Where a single container within a value stores its elements, see `CompactStats`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ContainerStats {
+    /// Path of the container within the value, like `".entries[3].value"`
+    pub path: String,
+    /// Address of the own storage of the container
+    pub address: usize,
+    /// Bytes of the own storage of the container
+    pub bytes: usize,
+    /// Is the storage compact (or spilled to the heap)?
+    pub compact: bool,
+}
+
+impl CompactStats {
+    /// Record a container at `path` with `own_bytes` of storage at `storage` (not counting
+    /// the storage of its elements). Containers without own storage shouldn't be recorded.
+    pub fn record_container(
+        &mut self,
+        path: &str,
+        compact: bool,
+        own_bytes: usize,
+        storage: *const u8,
+    ) {
+        self.containers.push(ContainerStats {
+            path: path.to_owned(),
+            address: storage as usize,
+            bytes: own_bytes,
+            compact,
+        });
+        if compact {
+            self.compact_containers += 1;
+            self.compact_bytes += own_bytes;
+        } else {
+            self.spilled_containers += 1;
+            self.spilled_bytes += own_bytes;
+            self.spilled_paths.push(path.to_owned());
+        }
+    }
+
+    /// Share of container storage bytes that spilled to the heap, between 0 and 1
+    pub fn fragmentation(&self) -> f64 {
+        let total = self.compact_bytes + self.spilled_bytes;
+        if total == 0 {
+            0.0
+        } else {
+            self.spilled_bytes as f64 / total as f64
+        }
+    }
+}
+
+/// Returned by `Compact::recompact_into` when the given buffer is too small
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NeedBytes {
+    /// Bytes needed for the dynamic part
+    pub needed: usize,
+    /// Bytes given
+    pub available: usize,
+}
+
+impl fmt::Display for NeedBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "dynamic part needs {} bytes, but only {} are available",
+            self.needed, self.available
+        )
+    }
+}
+
+impl Error for NeedBytes {}
+
+/// 16-byte aligned piece of scratch memory, only accessed through raw pointers
+#[derive(Clone, Copy)]
+#[repr(align(16))]
+#[allow(dead_code)]
+struct ScratchChunk([u8; 16]);
+
+thread_local! {
+    static SCRATCH: Cell<Vec<ScratchChunk>> = const { Cell::new(Vec::new()) };
+}
+
+/// Pre-size the scratch buffer of this thread, so that compacting values of up to `bytes`
+/// to or from bytes doesn't allocate temporary buffers
+pub fn reserve_scratch(bytes: usize) {
+    let mut chunks = SCRATCH.with(|scratch| scratch.take());
+    let needed = bytes.div_ceil(16);
+    if chunks.len() < needed {
+        chunks.resize(needed, ScratchChunk([0; 16]));
+    }
+    SCRATCH.with(|scratch| scratch.set(chunks));
+}
+
+/// Size of the scratch buffer of this thread in bytes
+pub fn scratch_size() -> usize {
+    SCRATCH.with(|scratch| {
+        let chunks = scratch.take();
+        let size = chunks.len() * 16;
+        scratch.set(chunks);
+        size
+    })
+}
+
+/// Free the scratch buffer of this thread
+pub fn release_scratch() {
+    SCRATCH.with(|scratch| scratch.set(Vec::new()));
+}
+
+/// Run `f` with `size` zeroed bytes aligned for `T`, reusing (and growing)
+/// the scratch buffer of this thread
+pub(crate) fn with_scratch<T: Compact, R, F: FnOnce(*mut u8) -> R>(size: usize, f: F) -> R {
+    if mem::align_of::<T>().max(T::dynamic_align()) > mem::align_of::<ScratchChunk>() {
+        let buffer = AlignedBuffer::new::<T>(size);
+        return f(buffer.ptr);
+    }
+
+    // taken out while in use, so nested calls just get their own buffer
+    let mut chunks = SCRATCH.with(|scratch| scratch.take());
+    let needed = size.div_ceil(16).max(1);
+    if chunks.len() < needed {
+        chunks.resize(needed, ScratchChunk([0; 16]));
+    }
+    for chunk in &mut chunks[..needed] {
+        *chunk = ScratchChunk([0; 16]);
+    }
+
+    let result = f(chunks.as_mut_ptr() as *mut u8);
+
+    SCRATCH.with(|scratch| {
+        let other = scratch.take();
+        scratch.set(if other.len() > chunks.len() { other } else { chunks });
+    });
+    result
+}
+
+/// Zeroed memory, aligned for compacting a `T` into it
+pub(crate) struct AlignedBuffer {
+    pub(crate) ptr: *mut u8,
+    layout: Layout,
+}
+
+impl AlignedBuffer {
+    pub(crate) fn new<T: Compact>(size: usize) -> AlignedBuffer {
+        let align = mem::align_of::<T>().max(T::dynamic_align()).max(8);
+        let layout = Layout::from_size_align(size.max(1), align)
+            .expect("compacted value too large");
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        AlignedBuffer { ptr, layout }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr, self.layout) }
+    }
+}
+
+/// Trivial implementation for fixed-sized, `Copy` types (no dynamic part).
+///
+/// This covers std value types like `char`, `Duration`, `IpAddr`, `SocketAddr`
+/// or `NonZeroU32` as well, so they need no wrappers.
 impl<T: Copy> Compact for T {
     fn is_still_compact(&self) -> bool {
         true
@@ -60,3 +796,425 @@ impl<T: Copy> Compact for T {
         ptr::read_unaligned(source)
     }
 }
+
+/// How much storage compacted vectors and maps reserve for their elements
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CapacityMode {
+    /// Keep the full capacity, so compacted vectors can still grow in place (the default)
+    Preserve,
+    /// Only store `len` elements and set the capacity to `len` in the destination,
+    /// to keep messages small. Maps are compacted as if `shrink_to_fit` was called on them.
+    Trim,
+}
+
+thread_local! {
+    static CAPACITY_MODE: Cell<CapacityMode> = const { Cell::new(CapacityMode::Preserve) };
+}
+
+/// The `CapacityMode` currently used for compaction on this thread
+pub fn capacity_mode() -> CapacityMode {
+    CAPACITY_MODE.with(|mode| mode.get())
+}
+
+/// Run `f` with compaction on this thread using `mode`.
+///
+/// *Note:* `total_size_bytes` and `compact` have to be called with the same mode.
+pub fn with_capacity_mode<R, F: FnOnce() -> R>(mode: CapacityMode, f: F) -> R {
+    struct Restore(CapacityMode);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            CAPACITY_MODE.with(|mode| mode.set(self.0));
+        }
+    }
+
+    let _restore = Restore(CAPACITY_MODE.with(|old| old.replace(mode)));
+    f()
+}
+
+/// Combine the hashes of key-value pairs independently of their order,
+/// used to hash maps consistently with their (order-independent) equality
+pub(crate) fn unordered_hash<'a, K: Hash + 'a, V: Hash + 'a, I>(pairs: I) -> u64
+where
+    I: Iterator<Item = (&'a K, &'a V)>,
+{
+    pairs
+        .map(|pair| {
+            let mut hasher = DefaultHasher::new();
+            pair.hash(&mut hasher);
+            hasher.finish()
+        })
+        .fold(0, u64::wrapping_add)
+}
+
+#[test]
+fn bytes_roundtrip() {
+    use super::compact_dict::CompactDict;
+    use super::compact_vec::CompactVec;
+
+    let mut dict: CompactDict<u32, CompactVec<u32>> = CompactDict::new();
+    let mut with_headroom = CompactVec::with_capacity(100);
+    with_headroom.push(1);
+    dict.insert(1, with_headroom);
+    dict.insert(2, vec![2, 3].into());
+
+    let bytes = dict.compact_to_bytes();
+    assert!(bytes.len() < dict.total_size_bytes());
+    let restored =
+        unsafe { CompactDict::<u32, CompactVec<u32>>::from_compact_bytes(&bytes) }.unwrap();
+    assert_eq!(restored, dict);
+    assert!(!restored.is_still_compact());
+
+    assert_eq!(
+        7u64,
+        unsafe { u64::from_compact_bytes(&7u64.compact_to_bytes()) }.unwrap()
+    );
+}
+
+#[test]
+fn std_value_types() {
+    use super::compact_vec::CompactVec;
+    use super::compacted_box::CompactedBox;
+    use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+    use std::num::NonZeroU32;
+    use std::time::Duration;
+    type Message = (char, Duration, IpAddr, SocketAddr, Option<NonZeroU32>);
+    let message: Message = (
+        'ß',
+        Duration::from_millis(1500),
+        IpAddr::V6(Ipv6Addr::LOCALHOST),
+        "10.0.0.1:8080".parse().unwrap(),
+        NonZeroU32::new(7),
+    );
+    let messages: CompactVec<Message> = vec![message; 3].into();
+    assert_eq!(messages, *CompactedBox::new(messages.clone()));
+    let decompacted =
+        unsafe { CompactVec::<Message>::from_compact_bytes(&messages.compact_to_bytes()) };
+    assert_eq!(messages, decompacted.unwrap());
+}
+
+#[test]
+fn bytes_reuse_scratch() {
+    use super::compact_vec::CompactVec;
+
+    release_scratch();
+    reserve_scratch(1000);
+    assert_eq!(1008, scratch_size());
+
+    let list: CompactVec<u32> = (0..10).collect();
+    let bytes = list.compact_to_bytes();
+    assert_eq!(
+        list,
+        unsafe { CompactVec::<u32>::from_compact_bytes(&bytes) }.unwrap()
+    );
+    assert_eq!(1008, scratch_size());
+
+    // grows if needed
+    let list: CompactVec<u32> = (0..1000).collect();
+    assert_eq!(
+        list,
+        unsafe { CompactVec::<u32>::from_compact_bytes(&list.compact_to_bytes()) }.unwrap()
+    );
+    assert!(scratch_size() >= 4000);
+
+    release_scratch();
+    assert_eq!(0, scratch_size());
+}
+
+#[test]
+fn bytes_errors() {
+    use super::compact_str::CompactString;
+    use super::compact_vec::CompactVec;
+
+    let list: CompactVec<u32> = vec![1, 2, 3].into();
+    let bytes = list.compact_to_bytes();
+    assert_eq!(
+        Err(FromBytesError::TooShort {
+            expected: bytes.len(),
+            actual: bytes.len() - 4
+        }),
+        unsafe { CompactVec::<u32>::from_compact_bytes(&bytes[..bytes.len() - 4]) }
+    );
+    assert_eq!(
+        Err(FromBytesError::TooShort {
+            expected: mem::size_of::<CompactVec<u32>>(),
+            actual: 3
+        }),
+        unsafe { CompactVec::<u32>::from_compact_bytes(&bytes[..3]) }
+    );
+
+    // cut off within nested parts, which are only read up to the end of the bytes
+    let names: CompactVec<CompactString> = vec!["Ada".into(), "Grace".into()].into();
+    let bytes = names.compact_to_bytes();
+    assert_eq!(
+        Err(FromBytesError::TooShort {
+            expected: bytes.len(),
+            actual: bytes.len() - 1
+        }),
+        unsafe { CompactVec::<CompactString>::from_compact_bytes(&bytes[..bytes.len() - 1]) }
+            .map(|_| ())
+    );
+    for len in mem::size_of::<CompactVec<CompactString>>()..bytes.len() {
+        let result = unsafe { CompactVec::<CompactString>::from_compact_bytes(&bytes[..len]) };
+        match result {
+            Err(FromBytesError::TooShort { expected, actual }) => {
+                assert!(expected > actual && actual == len)
+            }
+            _ => panic!("{} bytes were read", len),
+        }
+    }
+}
+
+#[test]
+fn compact_move_if_unchanged() {
+    use super::compact_vec::CompactVec;
+    use super::simple_allocator_trait::{Allocator, DefaultHeap};
+
+    type Lists = CompactVec<CompactVec<u32>>;
+    let mut lists: Lists = vec![vec![1].into(), vec![2, 3].into()].into();
+    let expected = lists.clone();
+    let bytes = lists.total_size_bytes();
+    let first = DefaultHeap::allocate(bytes);
+    let second = DefaultHeap::allocate(bytes);
+    unsafe {
+        // not compact yet, so it has to be compacted properly
+        assert!(!Compact::compact_move_if_unchanged(&mut lists, first));
+        mem::forget(lists);
+        assert!(Compact::compact_move_if_unchanged(first, first));
+        assert!(Compact::compact_move_if_unchanged(first, second));
+        assert!((*second).is_still_compact());
+        assert_eq!(expected, Compact::decompact(second));
+        DefaultHeap::deallocate(first, bytes);
+        DefaultHeap::deallocate(second, bytes);
+    }
+}
+
+#[test]
+fn recompact_into() {
+    use super::compact_vec::CompactVec;
+    type Lists = CompactVec<CompactVec<u32>>;
+    let mut lists: Lists = vec![vec![1].into(), vec![2, 3].into()].into();
+    // an actor-like slot: the static part, followed by room for the dynamic part
+    let mut slot = vec![0u64; 32];
+    let static_part = slot.as_mut_ptr() as *mut Lists;
+    let rest = unsafe {
+        ::std::slice::from_raw_parts_mut(
+            Lists::behind(static_part),
+            slot.len() * 8 - mem::size_of::<Lists>(),
+        )
+    };
+    unsafe {
+        Compact::compact_behind(&mut lists, static_part);
+        mem::forget(lists);
+        let lists = &mut *static_part;
+
+        // spill part of it, then recompact into the same buffer
+        lists[1].push(4);
+        assert!(!lists.is_still_compact());
+        lists.recompact_into(rest).unwrap();
+        assert!(lists.is_still_compact());
+        assert_eq!(vec![2, 3, 4], lists[1]);
+
+        lists.push(vec![5; 64].into());
+        let needed = lists.dynamic_size_bytes();
+        assert_eq!(
+            Err(NeedBytes {
+                needed,
+                available: rest.len()
+            }),
+            lists.recompact_into(rest)
+        );
+        assert!(!lists.is_still_compact());
+        ::std::ptr::drop_in_place(lists);
+    }
+}
+
+#[test]
+fn compact_stats() {
+    use super::compact_hash_map::OpenAddressingMap;
+    use super::compact_vec::CompactVec;
+    use super::compacted_box::CompactedBox;
+    type Map = OpenAddressingMap<u32, CompactVec<u32>>;
+
+    let map: Map = (0..4).map(|n| (n, vec![n; 2].into())).collect();
+    let stats = map.compact_stats();
+    assert_eq!(map.dynamic_size_bytes(), stats.dynamic_bytes);
+    assert_eq!(0, stats.compact_containers);
+    assert_eq!(5, stats.spilled_containers);
+    assert_eq!(1.0, stats.fragmentation());
+
+    let mut boxed = CompactedBox::new(map);
+    let stats = boxed.compact_stats();
+    assert_eq!(5, stats.compact_containers);
+    assert!(stats.spilled_paths.is_empty());
+    assert_eq!(0.0, stats.fragmentation());
+
+    boxed.with_mut(|map| map.get_mut(&2).unwrap().push(3));
+    let stats = boxed.compact_stats();
+    assert_eq!(4, stats.compact_containers);
+    assert_eq!(1, stats.spilled_containers);
+    assert_eq!(1, stats.spilled_paths.len());
+    assert!(stats.spilled_paths[0].starts_with(".entries["));
+    assert!(stats.spilled_paths[0].ends_with("].value"));
+    assert!(stats.fragmentation() > 0.0 && stats.fragmentation() < 1.0);
+}
+
+#[test]
+fn dynamic_parts_are_aligned() {
+    use super::compact_dict::CompactDict;
+    use super::compact_str::CompactString;
+    use super::compact_vec::CompactVec;
+    use super::compacted_box::CompactedBox;
+    type Dict = CompactDict<u8, CompactVec<u128>>;
+    type Named = CompactVec<CompactDict<u32, CompactString>>;
+    assert_eq!(16, Dict::dynamic_align());
+    assert_eq!(8, Named::dynamic_align());
+    assert_eq!(1, CompactString::dynamic_align());
+
+    let mut dict = Dict::new();
+    for n in 0..3u8 {
+        dict.push_at(n, u128::from(n));
+    }
+    let boxed = CompactedBox::new(dict.clone());
+    assert!(boxed.is_still_compact());
+    for n in 0..3 {
+        let values = boxed.get(n).unwrap();
+        assert_eq!(0, values.as_ptr() as usize % 16);
+        assert_eq!(&[u128::from(n)], &values[..]);
+    }
+    assert_eq!(
+        dict,
+        unsafe { Dict::from_compact_bytes(&dict.compact_to_bytes()) }.unwrap()
+    );
+
+    // odd-sized strings followed by more vectors
+    let named: Named = (0..3)
+        .map(|n| {
+            let mut dict = CompactDict::new();
+            dict.insert(n, CompactString::from("odd"));
+            dict
+        })
+        .collect();
+    let boxed = CompactedBox::new(named);
+    for dict in boxed.iter() {
+        assert_eq!(0, dict.keys().next().unwrap() as *const u32 as usize % 4);
+    }
+}
+
+#[test]
+fn dynamic_layout() {
+    use super::compact_str::CompactString;
+    use super::compact_vec::CompactVec;
+    let name = CompactString::from("abc");
+    let numbers: CompactVec<u64> = vec![1, 2].into();
+    let mut layout = DynamicLayout::new();
+    assert_eq!(0, layout.field(&name));
+    assert_eq!(8, layout.field(&numbers));
+    assert_eq!(24, layout.size());
+    let mut behind = DynamicLayout::after(5);
+    assert_eq!(5, behind.field(&name));
+    assert_eq!(8, behind.size());
+}
+
+#[test]
+fn compact_to_writer() {
+    use super::compact_dict::CompactDict;
+    use super::compact_hash_map::OpenAddressingMap;
+    use super::compact_option::CompactOption;
+    use super::compact_str::CompactString;
+    use super::compact_vec::CompactVec;
+    type Dict = CompactDict<u32, CompactVec<CompactString>>;
+    // `CompactOption` streams by compacting each element separately
+    type Options = CompactVec<CompactOption<CompactVec<u64>>>;
+    type Map = OpenAddressingMap<u32, CompactString>;
+    type Nested = OpenAddressingMap<u32, Options>;
+
+    let mut dict = Dict::new();
+    for n in 0..10 {
+        let names = (0..n)
+            .map(|i| CompactString::from("x".repeat(i as usize)))
+            .collect();
+        dict.insert(n, names);
+    }
+    let mut streamed = Vec::new();
+    dict.compact_to_writer(&mut streamed).unwrap();
+    // same layout (bytes only differ in padding)
+    assert_eq!(dict.compact_to_bytes().len(), streamed.len());
+    assert_eq!(
+        dict,
+        unsafe { Dict::from_compact_bytes(&streamed) }.unwrap()
+    );
+
+    let options: Options = (0..10)
+        .map(|n| CompactOption((n % 3 != 0).then(|| (0..n).collect())))
+        .collect();
+    let mut streamed = Vec::new();
+    options.compact_to_writer(&mut streamed).unwrap();
+    assert_eq!(options.compact_to_bytes().len(), streamed.len());
+    assert_eq!(
+        options,
+        unsafe { Options::from_compact_bytes(&streamed) }.unwrap()
+    );
+
+    let mut map = Map::new();
+    for n in 0..20 {
+        map.insert(n, CompactString::from(n.to_string()));
+    }
+    let mut streamed = Vec::new();
+    map.compact_to_writer(&mut streamed).unwrap();
+    assert_eq!(map, unsafe { Map::from_compact_bytes(&streamed) }.unwrap());
+
+    // map entries are streamed by compacting each of them separately
+    let nested: Nested = (0..10).map(|n| (n, options.clone())).collect();
+    let mut streamed = Vec::new();
+    nested.compact_to_writer(&mut streamed).unwrap();
+    assert_eq!(nested.compact_to_bytes().len(), streamed.len());
+    assert_eq!(
+        nested,
+        unsafe { Nested::from_compact_bytes(&streamed) }.unwrap()
+    );
+}
+
+#[test]
+fn decompact_from_reader() {
+    use super::compact_dict::CompactDict;
+    use super::compact_hash_map::OpenAddressingMap;
+    use super::compact_option::CompactOption;
+    use super::compact_str::CompactString;
+    use super::compact_vec::CompactVec;
+    type Dict = CompactDict<u32, CompactVec<CompactString>>;
+    type Map = OpenAddressingMap<u32, CompactVec<CompactOption<CompactString>>>;
+
+    let mut dict = Dict::new();
+    let mut map = Map::new();
+    for n in 0..10 {
+        let names: CompactVec<CompactString> = (0..n)
+            .map(|i| CompactString::from("x".repeat(i as usize)))
+            .collect();
+        dict.insert(n, names);
+        let options = (0..n)
+            .map(|i| CompactOption((i % 2 == 0).then(|| i.to_string().into())))
+            .collect();
+        map.insert(n, options);
+    }
+
+    let mut streamed = Vec::new();
+    dict.compact_to_writer(&mut streamed).unwrap();
+    assert_eq!(
+        dict,
+        unsafe { Dict::decompact_from_reader(&mut &streamed[..]) }.unwrap()
+    );
+    let mut streamed = Vec::new();
+    map.compact_to_writer(&mut streamed).unwrap();
+    assert_eq!(
+        map,
+        unsafe { Map::decompact_from_reader(&mut &streamed[..]) }.unwrap()
+    );
+
+    match unsafe { Map::decompact_from_reader(&mut &streamed[..streamed.len() - 1]) } {
+        Err(FromReaderError::Io(ref error)) => {
+            assert_eq!(io::ErrorKind::UnexpectedEof, error.kind())
+        }
+        other => panic!("unexpected {:?}", other.map(|_| ())),
+    }
+}
@@ -0,0 +1,310 @@
+use super::compact::{Compact, CompactReader, CompactStats, FromReaderError};
+use super::compact_vec::CompactVec;
+use std::io::{self, Read, Write};
+
+/// A compact storage for raw bytes (a `Vec<u8>`), which, unlike `CString`,
+/// don't need to be valid UTF-8.
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CompactBytes {
+    bytes: CompactVec<u8>,
+}
+
+impl CompactBytes {
+    /// Create empty `CBytes`
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Create empty `CBytes` with a given capacity
+    pub fn with_capacity(cap: usize) -> Self {
+        CompactBytes {
+            bytes: CompactVec::with_capacity(cap),
+        }
+    }
+
+    /// Append a single byte
+    pub fn push(&mut self, byte: u8) {
+        self.bytes.push(byte);
+    }
+
+    /// Append all bytes of `bytes`
+    pub fn extend_from_slice(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_copy_slice(bytes);
+    }
+
+    /// All bytes as a slice
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Split the bytes at `at`, keeping the bytes before it and returning the rest.
+    ///
+    /// Panics if `at` is larger than the length.
+    pub fn split_off(&mut self, at: usize) -> CompactBytes {
+        let rest = CompactBytes::from(&self.bytes[at..]);
+        self.bytes.truncate(at);
+        rest
+    }
+
+    /// Turn into an immutable view of the same bytes, without copying them
+    pub fn freeze(self) -> FrozenBytes {
+        FrozenBytes { bytes: self }
+    }
+
+    /// The underlying vector of bytes
+    pub fn into_vec(self) -> CompactVec<u8> {
+        self.bytes
+    }
+}
+
+impl ::std::ops::Deref for CompactBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl ::std::ops::DerefMut for CompactBytes {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes
+    }
+}
+
+impl From<CompactVec<u8>> for CompactBytes {
+    fn from(bytes: CompactVec<u8>) -> CompactBytes {
+        CompactBytes { bytes }
+    }
+}
+
+impl From<Vec<u8>> for CompactBytes {
+    fn from(bytes: Vec<u8>) -> CompactBytes {
+        CompactBytes {
+            bytes: bytes.into(),
+        }
+    }
+}
+
+impl<'a> From<&'a [u8]> for CompactBytes {
+    fn from(bytes: &'a [u8]) -> CompactBytes {
+        let mut compact_bytes = CompactBytes::with_capacity(bytes.len());
+        compact_bytes.extend_from_slice(bytes);
+        compact_bytes
+    }
+}
+
+/// Formats a byte as two hex digits
+struct HexByte(u8);
+
+impl ::std::fmt::Debug for HexByte {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "{:02x}", self.0)
+    }
+}
+
+/// Lists the bytes in hex, like `[ff, 00, 61]`
+impl ::std::fmt::Debug for CompactBytes {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_list()
+            .entries(self.iter().map(|&byte| HexByte(byte)))
+            .finish()
+    }
+}
+
+/// All bytes as one hex string, like `ff0061`
+impl ::std::fmt::LowerHex for CompactBytes {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        self.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+/// All bytes as one hex string, like `FF0061`
+impl ::std::fmt::UpperHex for CompactBytes {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        self.iter().try_for_each(|byte| write!(f, "{:02X}", byte))
+    }
+}
+
+impl Compact for CompactBytes {
+    fn is_still_compact(&self) -> bool {
+        self.bytes.is_still_compact()
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        self.bytes.dynamic_size_bytes()
+    }
+
+    fn dynamic_align() -> usize {
+        CompactVec::<u8>::dynamic_align()
+    }
+
+    fn repair_lossy(&mut self, path: &str, dropped: &mut Vec<String>) {
+        self.bytes.repair_lossy(path, dropped);
+    }
+
+    fn collect_stats(&self, path: &str, stats: &mut CompactStats) {
+        self.bytes.collect_stats(path, stats);
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        Compact::compact(&mut (*source).bytes, &mut (*dest).bytes, new_dynamic_part)
+    }
+
+    unsafe fn compact_static_part(&self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        self.bytes
+            .compact_static_part(&mut (*dest).bytes, new_dynamic_part)
+    }
+
+    fn write_dynamic_part<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.bytes.write_dynamic_part(writer)
+    }
+
+    unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+        self.bytes.shift_compact_pointers(shift);
+    }
+
+    unsafe fn decompact(source: *const Self) -> Self {
+        CompactBytes {
+            bytes: Compact::decompact(&(*source).bytes),
+        }
+    }
+
+    unsafe fn read_dynamic_part<R: Read>(
+        source: *const Self,
+        reader: &mut CompactReader<R>,
+    ) -> Result<Self, FromReaderError> {
+        Ok(CompactBytes {
+            bytes: reader.read_field(&(*source).bytes)?,
+        })
+    }
+}
+
+/// An immutable view of `CBytes`, see `CompactBytes::freeze`
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FrozenBytes {
+    bytes: CompactBytes,
+}
+
+impl FrozenBytes {
+    /// All bytes as a slice
+    pub fn as_slice(&self) -> &[u8] {
+        self.bytes.as_slice()
+    }
+
+    /// Make the bytes mutable again, without copying them
+    pub fn thaw(self) -> CompactBytes {
+        self.bytes
+    }
+}
+
+impl ::std::ops::Deref for FrozenBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl ::std::fmt::Debug for FrozenBytes {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        self.bytes.fmt(f)
+    }
+}
+
+impl Compact for FrozenBytes {
+    fn is_still_compact(&self) -> bool {
+        self.bytes.is_still_compact()
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        self.bytes.dynamic_size_bytes()
+    }
+
+    fn dynamic_align() -> usize {
+        CompactBytes::dynamic_align()
+    }
+
+    fn repair_lossy(&mut self, path: &str, dropped: &mut Vec<String>) {
+        self.bytes.repair_lossy(path, dropped);
+    }
+
+    fn collect_stats(&self, path: &str, stats: &mut CompactStats) {
+        self.bytes.collect_stats(path, stats);
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        Compact::compact(&mut (*source).bytes, &mut (*dest).bytes, new_dynamic_part)
+    }
+
+    unsafe fn compact_static_part(&self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        self.bytes
+            .compact_static_part(&mut (*dest).bytes, new_dynamic_part)
+    }
+
+    fn write_dynamic_part<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.bytes.write_dynamic_part(writer)
+    }
+
+    unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+        self.bytes.shift_compact_pointers(shift);
+    }
+
+    unsafe fn decompact(source: *const Self) -> Self {
+        FrozenBytes {
+            bytes: Compact::decompact(&(*source).bytes),
+        }
+    }
+
+    unsafe fn read_dynamic_part<R: Read>(
+        source: *const Self,
+        reader: &mut CompactReader<R>,
+    ) -> Result<Self, FromReaderError> {
+        Ok(FrozenBytes {
+            bytes: reader.read_field(&(*source).bytes)?,
+        })
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> ::arbitrary::Arbitrary<'a> for CompactBytes {
+    fn arbitrary(u: &mut ::arbitrary::Unstructured<'a>) -> ::arbitrary::Result<Self> {
+        <&[u8] as ::arbitrary::Arbitrary>::arbitrary(u).map(CompactBytes::from)
+    }
+
+    fn arbitrary_take_rest(u: ::arbitrary::Unstructured<'a>) -> ::arbitrary::Result<Self> {
+        <&[u8] as ::arbitrary::Arbitrary>::arbitrary_take_rest(u).map(CompactBytes::from)
+    }
+
+    fn size_hint(depth: usize) -> (usize, Option<usize>) {
+        <&[u8] as ::arbitrary::Arbitrary>::size_hint(depth)
+    }
+}
+
+#[test]
+fn compact_bytes() {
+    let mut bytes: CompactBytes = vec![0xff, 0xfe].into();
+    bytes.push(0);
+    bytes.extend_from_slice(b"abc");
+    assert_eq!(&[0xff, 0xfe, 0, b'a', b'b', b'c'], &*bytes);
+
+    let roundtripped =
+        unsafe { CompactBytes::from_compact_bytes(&bytes.compact_to_bytes()) }.unwrap();
+    assert_eq!(bytes, roundtripped);
+}
+
+#[test]
+fn byte_buffer() {
+    use super::compacted_box::CompactedBox;
+    let mut bytes = CompactBytes::from(&b"header:body"[..]);
+    let body = bytes.split_off(7);
+    assert_eq!(b"header:", bytes.as_slice());
+    assert_eq!(b"body", body.as_slice());
+    assert_eq!("[62, 6f, 64, 79]", format!("{:?}", body));
+    assert_eq!("626f6479", format!("{:x}", body));
+
+    let frozen = CompactBytes::from(vec![0xab, 0x00, 0xcd]).freeze();
+    assert_eq!("AB00CD", format!("{:X}", frozen.clone().thaw()));
+    let boxed = CompactedBox::new(frozen.clone());
+    assert_eq!(frozen, *boxed);
+    assert_eq!(&[0xab, 0x00, 0xcd], &boxed[..]);
+}
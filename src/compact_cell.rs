@@ -0,0 +1,198 @@
+use super::compact::{Compact, CompactReader, CompactStats, FromReaderError};
+use std::io::{self, Read, Write};
+
+/// Wraps a field of compacted state to track whether it was mutated,
+/// like for incremental snapshots that only write changed fields.
+///
+/// Mutable access sets its dirty flag, which stays set until `clear_dirty`.
+/// Since nested cells can only be mutated through the cells around them,
+/// a clean cell contains only clean cells.
+pub struct CompactCell<T> {
+    value: T,
+    dirty: bool,
+}
+
+impl<T: Compact> CompactCell<T> {
+    /// Wrap `value` in a clean cell
+    pub fn new(value: T) -> Self {
+        CompactCell {
+            value,
+            dirty: false,
+        }
+    }
+
+    /// Was the value mutated since the dirty flag was last cleared?
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Unwrap the value
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+impl<T> ::std::ops::Deref for CompactCell<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> ::std::ops::DerefMut for CompactCell<T> {
+    /// Access the value mutably, setting the dirty flag
+    fn deref_mut(&mut self) -> &mut T {
+        self.dirty = true;
+        &mut self.value
+    }
+}
+
+impl<T: Compact> Compact for CompactCell<T> {
+    fn is_still_compact(&self) -> bool {
+        self.value.is_still_compact()
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        self.value.dynamic_size_bytes()
+    }
+
+    fn dynamic_align() -> usize {
+        T::dynamic_align()
+    }
+
+    fn collect_stats(&self, path: &str, stats: &mut CompactStats) {
+        self.value.collect_stats(path, stats);
+    }
+
+    fn repair_lossy(&mut self, path: &str, dropped: &mut Vec<String>) {
+        self.value.repair_lossy(path, dropped);
+    }
+
+    /// Clear the dirty flag of this cell and all cells within it
+    fn clear_dirty(&mut self) {
+        self.dirty = false;
+        self.value.clear_dirty();
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        (*dest).dirty = (*source).dirty;
+        Compact::compact(&mut (*source).value, &mut (*dest).value, new_dynamic_part)
+    }
+
+    unsafe fn compact_static_part(&self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        (*dest).dirty = self.dirty;
+        self.value
+            .compact_static_part(&mut (*dest).value, new_dynamic_part)
+    }
+
+    fn write_dynamic_part<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.value.write_dynamic_part(writer)
+    }
+
+    unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+        self.value.shift_compact_pointers(shift);
+    }
+
+    unsafe fn decompact(source: *const Self) -> Self {
+        CompactCell {
+            value: Compact::decompact(&(*source).value),
+            dirty: (*source).dirty,
+        }
+    }
+
+    unsafe fn read_dynamic_part<R: Read>(
+        source: *const Self,
+        reader: &mut CompactReader<R>,
+    ) -> Result<Self, FromReaderError> {
+        Ok(CompactCell {
+            value: reader.read_field(&(*source).value)?,
+            // the byte read from the stream might not be a valid `bool`
+            dirty: *(::std::ptr::addr_of!((*source).dirty) as *const u8) != 0,
+        })
+    }
+}
+
+impl<T: Compact> Clone for CompactCell<T> {
+    fn clone(&self) -> Self {
+        CompactCell {
+            value: self.value.clone(),
+            dirty: self.dirty,
+        }
+    }
+}
+
+impl<T: Compact + Default> Default for CompactCell<T> {
+    fn default() -> Self {
+        CompactCell::new(T::default())
+    }
+}
+
+impl<T: ::std::fmt::Debug> ::std::fmt::Debug for CompactCell<T> {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        self.value.fmt(f)
+    }
+}
+
+impl<T: PartialEq> PartialEq for CompactCell<T> {
+    /// Compares only the values, not whether they are dirty
+    fn eq(&self, other: &Self) -> bool {
+        self.value == other.value
+    }
+}
+
+#[test]
+fn tracks_mutation() {
+    use super::compact_hash_map::OpenAddressingMap;
+    use super::compact_vec::CompactVec;
+    type Inventory = CompactCell<OpenAddressingMap<u32, CompactCell<CompactVec<u32>>>>;
+    let mut inventory: Inventory = CompactCell::new(
+        (0..10)
+            .map(|n| (n, CompactCell::new(vec![n].into())))
+            .collect(),
+    );
+    assert!(!inventory.is_dirty());
+    assert_eq!(Some(&vec![3].into()), inventory.get(&3).map(|cell| &**cell));
+    assert!(!inventory.is_dirty());
+
+    inventory.get_mut(&3).unwrap().push(30);
+    assert!(inventory.is_dirty());
+    let dirty: Vec<u32> = inventory
+        .pairs()
+        .filter(|(_, cell)| cell.is_dirty())
+        .map(|(key, _)| *key)
+        .collect();
+    assert_eq!(vec![3], dirty);
+
+    inventory.clear_dirty();
+    assert!(!inventory.is_dirty());
+    assert!(inventory.values().all(|cell| !cell.is_dirty()));
+}
+
+#[test]
+fn compact_cell() {
+    use super::compact_str::CompactString;
+    use super::compact_vec::CompactVec;
+    use super::compacted_box::CompactedBox;
+    let mut names: CompactVec<CompactCell<CompactString>> = vec![
+        CompactCell::new("Ada".into()),
+        CompactCell::new("Grace".into()),
+    ]
+    .into();
+    names[1].push_str(" Hopper");
+    let mut boxed = CompactedBox::new(names.clone());
+    assert!(boxed.is_still_compact());
+    assert_eq!(
+        vec![false, true],
+        boxed.iter().map(|cell| cell.is_dirty()).collect::<Vec<_>>()
+    );
+    boxed.with_mut(|names| names.clear_dirty());
+    assert!(boxed.iter().all(|cell| !cell.is_dirty()));
+
+    let mut stream = Vec::new();
+    names.compact_to_writer(&mut stream).unwrap();
+    let read: CompactVec<CompactCell<CompactString>> =
+        unsafe { Compact::decompact_from_reader(&mut &stream[..]) }.unwrap();
+    assert_eq!(names, read);
+    assert!(read[1].is_dirty());
+}
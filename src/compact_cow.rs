@@ -0,0 +1,432 @@
+use super::compact::{Compact, CompactReader, CompactStats, DynamicLayout, FromReaderError};
+use super::compact_str::CompactString;
+use super::compact_vec::CompactVec;
+use std::io::Read;
+use std::ptr;
+
+/// Either a borrowed slice of external data or an owned `CVec`, like `std::borrow::Cow`.
+///
+/// Borrowed data is never compact: compacting a `CCow` stores an owned copy
+/// of the borrowed elements in the new dynamic part.
+/// The dynamic size of borrowed elements is assumed to not grow when they're cloned.
+pub enum CompactCow<'a, T: Compact + Clone + 'a> {
+    /// Borrowed external data
+    Borrowed(&'a [T]),
+    /// Owned, possibly compactly stored data
+    Owned(CompactVec<T>),
+}
+
+/// Either a borrowed `&str` or an owned `CString`, like `std::borrow::Cow<str>`.
+/// See `CCow`.
+#[derive(Clone)]
+pub enum CompactCowStr<'a> {
+    /// Borrowed external string
+    Borrowed(&'a str),
+    /// Owned, possibly compactly stored string
+    Owned(CompactString),
+}
+
+impl<'a, T: Compact + Clone + 'a> CompactCow<'a, T> {
+    /// Is the data still borrowed?
+    pub fn is_borrowed(&self) -> bool {
+        match *self {
+            CompactCow::Borrowed(_) => true,
+            CompactCow::Owned(_) => false,
+        }
+    }
+
+    /// Get mutable access to owned data, copying borrowed data first
+    pub fn to_mut(&mut self) -> &mut CompactVec<T> {
+        if let CompactCow::Borrowed(borrowed) = *self {
+            *self = CompactCow::Owned(borrowed.to_vec().into());
+        }
+        match *self {
+            CompactCow::Owned(ref mut owned) => owned,
+            CompactCow::Borrowed(_) => unreachable!(),
+        }
+    }
+
+    /// Convert into owned data, copying borrowed data
+    pub fn into_owned(self) -> CompactVec<T> {
+        match self {
+            CompactCow::Borrowed(borrowed) => borrowed.to_vec().into(),
+            CompactCow::Owned(owned) => owned,
+        }
+    }
+}
+
+impl<'a> CompactCowStr<'a> {
+    /// Is the string still borrowed?
+    pub fn is_borrowed(&self) -> bool {
+        match *self {
+            CompactCowStr::Borrowed(_) => true,
+            CompactCowStr::Owned(_) => false,
+        }
+    }
+
+    /// Get mutable access to an owned string, copying a borrowed string first
+    pub fn to_mut(&mut self) -> &mut CompactString {
+        if let CompactCowStr::Borrowed(borrowed) = *self {
+            *self = CompactCowStr::Owned(borrowed.into());
+        }
+        match *self {
+            CompactCowStr::Owned(ref mut owned) => owned,
+            CompactCowStr::Borrowed(_) => unreachable!(),
+        }
+    }
+
+    /// Convert into an owned string, copying a borrowed string
+    pub fn into_owned(self) -> CompactString {
+        match self {
+            CompactCowStr::Borrowed(borrowed) => borrowed.into(),
+            CompactCowStr::Owned(owned) => owned,
+        }
+    }
+}
+
+impl<'a, T: Compact + Clone + 'a> Clone for CompactCow<'a, T> {
+    fn clone(&self) -> Self {
+        match *self {
+            CompactCow::Borrowed(borrowed) => CompactCow::Borrowed(borrowed),
+            CompactCow::Owned(ref owned) => CompactCow::Owned(owned.clone()),
+        }
+    }
+}
+
+impl<'a, T: Compact + Clone + 'a> ::std::ops::Deref for CompactCow<'a, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match *self {
+            CompactCow::Borrowed(borrowed) => borrowed,
+            CompactCow::Owned(ref owned) => owned,
+        }
+    }
+}
+
+impl<'a> ::std::ops::Deref for CompactCowStr<'a> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        match *self {
+            CompactCowStr::Borrowed(borrowed) => borrowed,
+            CompactCowStr::Owned(ref owned) => owned,
+        }
+    }
+}
+
+impl<'a, T: Compact + Clone + 'a> From<&'a [T]> for CompactCow<'a, T> {
+    fn from(borrowed: &'a [T]) -> Self {
+        CompactCow::Borrowed(borrowed)
+    }
+}
+
+impl<'a, T: Compact + Clone + 'a> From<CompactVec<T>> for CompactCow<'a, T> {
+    fn from(owned: CompactVec<T>) -> Self {
+        CompactCow::Owned(owned)
+    }
+}
+
+impl<'a> From<&'a str> for CompactCowStr<'a> {
+    fn from(borrowed: &'a str) -> Self {
+        CompactCowStr::Borrowed(borrowed)
+    }
+}
+
+impl<'a> From<CompactString> for CompactCowStr<'a> {
+    fn from(owned: CompactString) -> Self {
+        CompactCowStr::Owned(owned)
+    }
+}
+
+impl<'a, 'b, T, U> PartialEq<CompactCow<'b, U>> for CompactCow<'a, T>
+where
+    T: Compact + Clone + PartialEq<U> + 'a,
+    U: Compact + Clone + 'b,
+{
+    /// Compares the contents, no matter if borrowed or owned
+    fn eq(&self, other: &CompactCow<'b, U>) -> bool {
+        **self == **other
+    }
+}
+
+impl<'a, 'b> PartialEq<CompactCowStr<'b>> for CompactCowStr<'a> {
+    /// Compares the contents, no matter if borrowed or owned
+    fn eq(&self, other: &CompactCowStr<'b>) -> bool {
+        **self == **other
+    }
+}
+
+impl<'a, T: Compact + Clone + ::std::fmt::Debug + 'a> ::std::fmt::Debug for CompactCow<'a, T> {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<'a> ::std::fmt::Debug for CompactCowStr<'a> {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<'a, T: Compact + Clone + 'a> Compact for CompactCow<'a, T> {
+    fn is_still_compact(&self) -> bool {
+        match *self {
+            CompactCow::Borrowed(_) => false,
+            CompactCow::Owned(ref owned) => owned.is_still_compact(),
+        }
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        match *self {
+            CompactCow::Borrowed(borrowed) => {
+                let base_size = ::std::mem::size_of_val(borrowed);
+                if ::std::mem::needs_drop::<T>() {
+                    let mut layout = DynamicLayout::after(base_size);
+                    for elem in borrowed {
+                        layout.field(elem);
+                    }
+                    layout.size()
+                } else {
+                    base_size
+                }
+            }
+            CompactCow::Owned(ref owned) => owned.dynamic_size_bytes(),
+        }
+    }
+
+    fn dynamic_align() -> usize {
+        CompactVec::<T>::dynamic_align()
+    }
+
+    fn repair_lossy(&mut self, path: &str, dropped: &mut Vec<String>) {
+        if let CompactCow::Owned(ref mut owned) = *self {
+            owned.repair_lossy(path, dropped);
+        }
+    }
+
+    fn collect_stats(&self, path: &str, stats: &mut CompactStats) {
+        match *self {
+            // borrowed data counts as spilled, since it isn't stored compactly
+            CompactCow::Borrowed(borrowed) => {
+                if !borrowed.is_empty() {
+                    stats.record_container(
+                        path,
+                        false,
+                        ::std::mem::size_of_val(borrowed),
+                        borrowed.as_ptr() as *const u8,
+                    );
+                }
+                for (i, item) in borrowed.iter().enumerate() {
+                    item.collect_stats(&format!("{}[{}]", path, i), stats);
+                }
+            }
+            CompactCow::Owned(ref owned) => owned.collect_stats(path, stats),
+        }
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        ptr::write(dest, CompactCow::Owned(CompactVec::new()));
+        let dest_owned = match *dest {
+            CompactCow::Owned(ref mut dest_owned) => dest_owned,
+            CompactCow::Borrowed(_) => unreachable!(),
+        };
+        match *source {
+            CompactCow::Borrowed(borrowed) => {
+                let mut owned = CompactVec::from(borrowed.to_vec());
+                debug_assert!(owned.dynamic_size_bytes() <= (*source).dynamic_size_bytes());
+                Compact::compact(&mut owned, dest_owned, new_dynamic_part);
+                // the elements are now owned by dest
+                ::std::mem::forget(owned);
+            }
+            CompactCow::Owned(ref mut owned) => {
+                Compact::compact(owned, dest_owned, new_dynamic_part)
+            }
+        }
+    }
+
+    unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+        // compacted cows are always owned
+        if let CompactCow::Owned(ref mut owned) = *self {
+            owned.shift_compact_pointers(shift);
+        }
+    }
+
+    unsafe fn decompact(source: *const Self) -> Self {
+        match *source {
+            CompactCow::Borrowed(borrowed) => CompactCow::Borrowed(borrowed),
+            CompactCow::Owned(ref owned) => CompactCow::Owned(Compact::decompact(owned)),
+        }
+    }
+
+    unsafe fn read_dynamic_part<R: Read>(
+        source: *const Self,
+        reader: &mut CompactReader<R>,
+    ) -> Result<Self, FromReaderError> {
+        match *source {
+            // always compacted as owned
+            CompactCow::Borrowed(_) => Err(reader.invalid("compacted CCow is borrowed")),
+            CompactCow::Owned(ref owned) => Ok(CompactCow::Owned(reader.read_field(owned)?)),
+        }
+    }
+}
+
+impl<'a> Compact for CompactCowStr<'a> {
+    fn is_still_compact(&self) -> bool {
+        match *self {
+            CompactCowStr::Borrowed(_) => false,
+            CompactCowStr::Owned(ref owned) => owned.is_still_compact(),
+        }
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        match *self {
+            CompactCowStr::Borrowed(borrowed) => borrowed.len(),
+            CompactCowStr::Owned(ref owned) => owned.dynamic_size_bytes(),
+        }
+    }
+
+    fn dynamic_align() -> usize {
+        CompactString::dynamic_align()
+    }
+
+    fn repair_lossy(&mut self, path: &str, dropped: &mut Vec<String>) {
+        if let CompactCowStr::Owned(ref mut owned) = *self {
+            owned.repair_lossy(path, dropped);
+        }
+    }
+
+    fn collect_stats(&self, path: &str, stats: &mut CompactStats) {
+        match *self {
+            CompactCowStr::Borrowed(borrowed) => {
+                if !borrowed.is_empty() {
+                    stats.record_container(path, false, borrowed.len(), borrowed.as_ptr());
+                }
+            }
+            CompactCowStr::Owned(ref owned) => owned.collect_stats(path, stats),
+        }
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        ptr::write(dest, CompactCowStr::Owned(CompactString::new()));
+        let dest_owned = match *dest {
+            CompactCowStr::Owned(ref mut dest_owned) => dest_owned,
+            CompactCowStr::Borrowed(_) => unreachable!(),
+        };
+        match *source {
+            CompactCowStr::Borrowed(borrowed) => {
+                let mut owned = CompactString::from(borrowed);
+                Compact::compact(&mut owned, dest_owned, new_dynamic_part);
+                // the bytes are now owned by dest
+                ::std::mem::forget(owned);
+            }
+            CompactCowStr::Owned(ref mut owned) => {
+                Compact::compact(owned, dest_owned, new_dynamic_part)
+            }
+        }
+    }
+
+    unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+        // compacted cows are always owned
+        if let CompactCowStr::Owned(ref mut owned) = *self {
+            owned.shift_compact_pointers(shift);
+        }
+    }
+
+    unsafe fn decompact(source: *const Self) -> Self {
+        match *source {
+            CompactCowStr::Borrowed(borrowed) => CompactCowStr::Borrowed(borrowed),
+            CompactCowStr::Owned(ref owned) => CompactCowStr::Owned(Compact::decompact(owned)),
+        }
+    }
+
+    unsafe fn read_dynamic_part<R: Read>(
+        source: *const Self,
+        reader: &mut CompactReader<R>,
+    ) -> Result<Self, FromReaderError> {
+        match *source {
+            CompactCowStr::Borrowed(_) => Err(reader.invalid("compacted CCowStr is borrowed")),
+            CompactCowStr::Owned(ref owned) => Ok(CompactCowStr::Owned(reader.read_field(owned)?)),
+        }
+    }
+}
+
+/// Always owned, since only `&[u8]` can be borrowed from fuzzer input
+#[cfg(feature = "arbitrary")]
+impl<'a, T: Compact + Clone + ::arbitrary::Arbitrary<'a>> ::arbitrary::Arbitrary<'a>
+    for CompactCow<'a, T>
+{
+    fn arbitrary(u: &mut ::arbitrary::Unstructured<'a>) -> ::arbitrary::Result<Self> {
+        Ok(CompactCow::Owned(::arbitrary::Arbitrary::arbitrary(u)?))
+    }
+
+    fn size_hint(depth: usize) -> (usize, Option<usize>) {
+        <CompactVec<T> as ::arbitrary::Arbitrary>::size_hint(depth)
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> ::arbitrary::Arbitrary<'a> for CompactCowStr<'a> {
+    fn arbitrary(u: &mut ::arbitrary::Unstructured<'a>) -> ::arbitrary::Result<Self> {
+        Ok(if u.arbitrary()? {
+            CompactCowStr::Borrowed(u.arbitrary()?)
+        } else {
+            CompactCowStr::Owned(u.arbitrary()?)
+        })
+    }
+
+    fn size_hint(depth: usize) -> (usize, Option<usize>) {
+        ::arbitrary::size_hint::and(
+            <bool as ::arbitrary::Arbitrary>::size_hint(depth),
+            <&str as ::arbitrary::Arbitrary>::size_hint(depth),
+        )
+    }
+}
+
+#[test]
+fn copy_on_write() {
+    let state: Vec<CompactVec<u32>> = vec![vec![1].into(), vec![2, 3].into()];
+    let mut cow: CompactCow<CompactVec<u32>> = state[..].into();
+    assert!(cow.is_borrowed());
+    assert_eq!(2, cow.len());
+    assert_eq!(cow, CompactCow::from(CompactVec::from(state.clone())));
+
+    cow.to_mut().push(vec![4].into());
+    assert!(!cow.is_borrowed());
+    assert_eq!(3, cow.len());
+    assert_eq!(2, state.len());
+
+    let mut string: CompactCowStr = "Hello".into();
+    string.to_mut().push_str(" World");
+    assert_eq!("Hello World", &*string.into_owned());
+}
+
+#[test]
+fn compacting_borrowed_copies() {
+    use super::simple_allocator_trait::{Allocator, DefaultHeap};
+    fn compact_and_decompact<T: Compact>(mut value: T) -> T {
+        let bytes = value.total_size_bytes();
+        let storage: *mut T = DefaultHeap::allocate(bytes);
+        unsafe {
+            Compact::compact_behind(&mut value, storage);
+            ::std::mem::forget(value);
+            assert!((*storage).is_still_compact());
+            let decompacted = Compact::decompact(storage);
+            DefaultHeap::deallocate(storage, bytes);
+            decompacted
+        }
+    }
+
+    let state: Vec<CompactVec<u32>> = vec![vec![1].into(), vec![2, 3].into()];
+    let cow = compact_and_decompact(CompactCow::from(&state[1..]));
+    assert!(!cow.is_borrowed());
+    assert_eq!(cow, CompactCow::from(&state[1..]));
+
+    let owned = compact_and_decompact(CompactCow::from(CompactVec::from(state.clone())));
+    assert_eq!(owned, CompactCow::from(&state[..]));
+
+    let name = String::from("echo");
+    let string = compact_and_decompact(CompactCowStr::from(&name[..]));
+    assert!(!string.is_borrowed());
+    assert_eq!("echo", &*string);
+}
@@ -0,0 +1,287 @@
+use super::compact::{Compact, CompactReader, CompactStats, DynamicLayout, FromReaderError};
+use super::compact_hash_map::OpenAddressingMap;
+use super::compact_vec::{CompactVec, Drain};
+use super::simple_allocator_trait::{Allocator, DefaultHeap};
+use std::hash::Hash;
+use std::io::Read;
+
+/// A queue of events that drops events whose key was already seen
+/// among the keys of the last `window` accepted events.
+///
+/// Memory is bounded by the window: only that many keys are remembered.
+/// Keys stay remembered after their events have been drained, so duplicates
+/// arriving shortly after are still dropped.
+pub struct CompactDedupQueue<K, E, A: Allocator = DefaultHeap> {
+    window: u32,
+    /// Index of the oldest remembered key, once `recent_keys` is full
+    oldest: u32,
+    /// Ring buffer of the most recently accepted keys
+    recent_keys: CompactVec<K, A>,
+    seen: OpenAddressingMap<K, (), A>,
+    events: CompactVec<E, A>,
+}
+
+impl<K: Compact + Eq + Hash, E: Compact, A: Allocator> CompactDedupQueue<K, E, A> {
+    /// Create a new, empty queue remembering the keys of the last `window` accepted events
+    pub fn new(window: usize) -> Self {
+        CompactDedupQueue {
+            window: window as u32,
+            oldest: 0,
+            recent_keys: CompactVec::new(),
+            seen: OpenAddressingMap::new(),
+            events: CompactVec::new(),
+        }
+    }
+
+    /// The amount of recently accepted keys that are remembered
+    pub fn window(&self) -> usize {
+        self.window as usize
+    }
+
+    /// Change the amount of remembered keys, forgetting the oldest ones if it shrinks
+    pub fn set_window(&mut self, window: usize) {
+        // bring the remembered keys into oldest-first order
+        let oldest = self.oldest as usize;
+        let mut keys: Vec<K> = self.recent_keys.drain(..).collect();
+        keys.rotate_left(oldest);
+        let forget = keys.len().saturating_sub(window);
+        for key in keys.drain(..forget) {
+            self.seen.remove(&key);
+        }
+        self.recent_keys = keys.into_iter().collect();
+        self.oldest = 0;
+        self.window = window as u32;
+    }
+
+    /// Amount of events waiting to be drained
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Are there no events waiting to be drained?
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Was `key` among the keys of the last `window` accepted events?
+    pub fn is_duplicate(&self, key: K) -> bool {
+        self.seen.contains_key(&key)
+    }
+
+    /// Enqueue `event`, unless its `key` is a duplicate within the window.
+    /// Returns whether the event was accepted.
+    pub fn push(&mut self, key: K, event: E) -> bool {
+        if self.is_duplicate(key.clone()) {
+            return false;
+        }
+
+        if self.window > 0 {
+            if self.recent_keys.len() < self.window as usize {
+                self.recent_keys.push(key.clone());
+            } else {
+                let slot: *mut K = &mut self.recent_keys[self.oldest as usize];
+                // decompact, since the key might be stored compactly with its dynamic part
+                let forgotten = unsafe {
+                    let forgotten = Compact::decompact(slot);
+                    ::std::ptr::write(slot, key.clone());
+                    forgotten
+                };
+                self.seen.remove(&forgotten);
+                self.oldest = (self.oldest + 1) % self.window;
+            }
+            self.seen.insert(key, ());
+        }
+
+        self.events.push(event);
+        true
+    }
+
+    /// Remove and iterate over all accepted events, in the order they were pushed
+    pub fn drain_ready(&mut self) -> Drain<E, A> {
+        self.events.drain(..)
+    }
+
+    /// Drop all waiting events and forget all remembered keys
+    pub fn clear(&mut self) {
+        self.recent_keys.clear();
+        self.seen = OpenAddressingMap::new();
+        self.events.clear();
+        self.oldest = 0;
+    }
+}
+
+impl<K: Compact + Eq + Hash, E: Compact, A: Allocator> Compact for CompactDedupQueue<K, E, A> {
+    fn is_still_compact(&self) -> bool {
+        self.recent_keys.is_still_compact()
+            && self.seen.is_still_compact()
+            && self.events.is_still_compact()
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        let mut layout = DynamicLayout::new();
+        layout.field(&self.recent_keys);
+        layout.field(&self.seen);
+        layout.field(&self.events);
+        layout.size()
+    }
+
+    fn dynamic_align() -> usize {
+        CompactVec::<K, A>::dynamic_align()
+            .max(OpenAddressingMap::<K, (), A>::dynamic_align())
+            .max(CompactVec::<E, A>::dynamic_align())
+    }
+
+    fn collect_stats(&self, path: &str, stats: &mut CompactStats) {
+        self.recent_keys
+            .collect_stats(&format!("{}.recent_keys", path), stats);
+        self.seen.collect_stats(&format!("{}.seen", path), stats);
+        self.events.collect_stats(&format!("{}.events", path), stats);
+    }
+
+    fn repair_lossy(&mut self, path: &str, dropped: &mut Vec<String>) {
+        self.recent_keys.repair_lossy(&format!("{}.recent_keys", path), dropped);
+        self.seen.repair_lossy(&format!("{}.seen", path), dropped);
+        self.events.repair_lossy(&format!("{}.events", path), dropped);
+        if self.oldest as usize >= self.recent_keys.len() {
+            self.oldest = 0;
+        }
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        let mut layout = DynamicLayout::new();
+        let recent_keys_offset = layout.field(&(*source).recent_keys);
+        let seen_offset = layout.field(&(*source).seen);
+        let events_offset = layout.field(&(*source).events);
+        (*dest).window = (*source).window;
+        (*dest).oldest = (*source).oldest;
+        Compact::compact(
+            &mut (*source).recent_keys,
+            &mut (*dest).recent_keys,
+            new_dynamic_part.add(recent_keys_offset),
+        );
+        Compact::compact(
+            &mut (*source).seen,
+            &mut (*dest).seen,
+            new_dynamic_part.add(seen_offset),
+        );
+        Compact::compact(
+            &mut (*source).events,
+            &mut (*dest).events,
+            new_dynamic_part.add(events_offset),
+        );
+    }
+
+    unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+        self.recent_keys.shift_compact_pointers(shift);
+        self.seen.shift_compact_pointers(shift);
+        self.events.shift_compact_pointers(shift);
+    }
+
+    unsafe fn decompact(source: *const Self) -> Self {
+        CompactDedupQueue {
+            window: (*source).window,
+            oldest: (*source).oldest,
+            recent_keys: Compact::decompact(&(*source).recent_keys),
+            seen: Compact::decompact(&(*source).seen),
+            events: Compact::decompact(&(*source).events),
+        }
+    }
+
+    unsafe fn read_dynamic_part<R: Read>(
+        source: *const Self,
+        reader: &mut CompactReader<R>,
+    ) -> Result<Self, FromReaderError> {
+        Ok(CompactDedupQueue {
+            window: (*source).window,
+            oldest: (*source).oldest,
+            recent_keys: reader.read_field(&(*source).recent_keys)?,
+            seen: reader.read_field(&(*source).seen)?,
+            events: reader.read_field(&(*source).events)?,
+        })
+    }
+}
+
+impl<K: Compact + Eq + Hash, E: Compact, A: Allocator> Clone for CompactDedupQueue<K, E, A> {
+    fn clone(&self) -> Self {
+        CompactDedupQueue {
+            window: self.window,
+            oldest: self.oldest,
+            recent_keys: self.recent_keys.clone(),
+            seen: self.seen.clone(),
+            events: self.events.clone(),
+        }
+    }
+}
+
+/// Pushes arbitrary events, so duplicates within the window are dropped as usual
+#[cfg(feature = "arbitrary")]
+impl<'a, K, E, A> ::arbitrary::Arbitrary<'a> for CompactDedupQueue<K, E, A>
+where
+    K: Compact + Eq + Hash + ::arbitrary::Arbitrary<'a>,
+    E: Compact + ::arbitrary::Arbitrary<'a>,
+    A: Allocator + 'a,
+{
+    fn arbitrary(u: &mut ::arbitrary::Unstructured<'a>) -> ::arbitrary::Result<Self> {
+        let mut queue = Self::new(u.arbitrary::<u16>()? as usize);
+        for event in u.arbitrary_iter::<(K, E)>()? {
+            let (key, event) = event?;
+            queue.push(key, event);
+        }
+        Ok(queue)
+    }
+}
+
+#[test]
+fn drops_duplicates_within_window() {
+    let mut queue: CompactDedupQueue<usize, CompactVec<u32>> = CompactDedupQueue::new(3);
+    assert!(queue.push(1, vec![1].into()));
+    assert!(!queue.push(1, vec![2].into()));
+    assert!(queue.push(2, vec![3].into()));
+    assert!(queue.push(3, vec![4].into()));
+    assert_eq!(3, queue.len());
+
+    let drained: Vec<_> = queue.drain_ready().collect();
+    assert_eq!(drained, vec![vec![1], vec![3], vec![4]]);
+    assert!(queue.is_empty());
+
+    // still remembered after draining
+    assert!(!queue.push(2, vec![5].into()));
+    // forgets 1 because the window is full
+    assert!(queue.push(4, vec![6].into()));
+    assert!(queue.push(1, vec![7].into()));
+    assert!(queue.is_duplicate(4));
+    assert!(!queue.is_duplicate(2));
+
+    queue.set_window(1);
+    assert!(queue.is_duplicate(1));
+    assert!(!queue.is_duplicate(4));
+    assert!(queue.push(4, vec![8].into()));
+    assert!(!queue.is_duplicate(1));
+
+    queue.clear();
+    assert!(queue.is_empty());
+    assert!(queue.push(4, vec![9].into()));
+}
+
+#[test]
+fn compact_dedup_queue() {
+    type Queue = CompactDedupQueue<usize, CompactVec<u32>>;
+    let mut queue: Queue = CompactDedupQueue::new(20);
+    for n in 0..30 {
+        queue.push(n % 15, vec![n as u32; 3].into());
+    }
+    assert_eq!(15, queue.len());
+
+    let bytes = queue.total_size_bytes();
+    let storage = DefaultHeap::allocate(bytes);
+    unsafe {
+        Compact::compact_behind(&mut queue, storage as *mut Queue);
+        ::std::mem::forget(queue);
+        assert!((*(storage as *mut Queue)).is_still_compact());
+        assert!((*(storage as *mut Queue)).is_duplicate(29 % 15));
+        let mut decompacted = Compact::decompact(storage as *mut Queue);
+        assert!(!decompacted.push(28 % 15, CompactVec::new()));
+        assert_eq!(decompacted.drain_ready().last(), Some(vec![14; 3].into()));
+        DefaultHeap::deallocate(storage, bytes);
+    }
+}
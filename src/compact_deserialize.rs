@@ -0,0 +1,262 @@
+use super::compact::{align_up, padded_static_size, AlignedBuffer, Compact};
+use super::compacted_box::CompactedBox;
+use serde::de::{Deserialize, DeserializeSeed, Deserializer, Error};
+use std::marker::PhantomData;
+use std::ptr;
+
+/// A dynamic part found while planning
+#[derive(Copy, Clone)]
+struct Part {
+    len: usize,
+    bytes: usize,
+    align: usize,
+}
+
+/// Records the dynamic parts a value needs while planning its deserialization
+pub struct Planner {
+    parts: Vec<Part>,
+}
+
+impl Planner {
+    /// Start a dynamic part before planning the values inside of it,
+    /// returns the handle to `end` it with
+    pub fn begin(&mut self) -> usize {
+        self.parts.push(Part {
+            len: 0,
+            bytes: 0,
+            align: 1,
+        });
+        self.parts.len() - 1
+    }
+
+    /// Set the amount of elements, size and alignment of a dynamic part started with `begin`
+    pub fn end(&mut self, part: usize, len: usize, bytes: usize, align: usize) {
+        self.parts[part] = Part { len, bytes, align };
+    }
+
+    /// A seed to plan a `T` with, for example as an element of a sequence
+    pub fn seed<T>(&mut self) -> PlanSeed<'_, T> {
+        PlanSeed {
+            planner: self,
+            marker: PhantomData,
+        }
+    }
+}
+
+/// Plans a `T` with `T::plan`
+pub struct PlanSeed<'a, T> {
+    planner: &'a mut Planner,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<'a, 'de, T: CompactDeserialize<'de>> DeserializeSeed<'de> for PlanSeed<'a, T> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        T::plan(deserializer, self.planner)
+    }
+}
+
+/// Hands out the planned dynamic parts of a value, one after another in its buffer
+pub struct Placer {
+    parts: ::std::vec::IntoIter<Part>,
+    next: *mut u8,
+}
+
+impl Placer {
+    /// The amount of elements of the next planned dynamic part and where to place them,
+    /// or `None` if all planned parts were handed out already
+    pub fn take(&mut self) -> Option<(usize, *mut u8)> {
+        let part = self.parts.next()?;
+        // the buffer is aligned for all dynamic parts, so aligning addresses is like aligning offsets
+        let start = align_up(self.next as usize, part.align) - self.next as usize;
+        unsafe {
+            let place = self.next.add(start);
+            self.next = place.add(part.bytes);
+            Some((part.len, place))
+        }
+    }
+
+    /// A seed to place a `T` at `dest` with, for example as an element of a sequence
+    pub fn seed<T>(&mut self, dest: *mut T) -> PlaceSeed<'_, T> {
+        PlaceSeed { placer: self, dest }
+    }
+}
+
+/// Places a `T` with `T::place`
+pub struct PlaceSeed<'a, T> {
+    placer: &'a mut Placer,
+    dest: *mut T,
+}
+
+impl<'a, 'de, T: CompactDeserialize<'de>> DeserializeSeed<'de> for PlaceSeed<'a, T> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        unsafe { T::place(deserializer, self.dest, self.placer) }
+    }
+}
+
+/// The error for input that doesn't match what was planned
+pub(crate) fn input_changed<E: Error>() -> E {
+    E::custom("input changed between planning and placing its deserialization")
+}
+
+/// A value that can be deserialized straight into compacted storage, see `DeserializePlan`
+pub trait CompactDeserialize<'de>: Compact + Sized {
+    /// Go through the serialized value and record each dynamic part it needs with `planner`,
+    /// in the order that `place` takes them
+    fn plan<D: Deserializer<'de>>(deserializer: D, planner: &mut Planner) -> Result<(), D::Error>;
+
+    /// Deserialize the value into `dest`, taking its dynamic parts from `placer`.
+    /// Fails if the input doesn't match the planned dynamic parts.
+    ///
+    /// # Safety
+    ///
+    /// `dest` has to point to writable memory for the static part,
+    /// which isn't dropped if placing fails.
+    unsafe fn place<D: Deserializer<'de>>(
+        deserializer: D,
+        dest: *mut Self,
+        placer: &mut Placer,
+    ) -> Result<(), D::Error>;
+}
+
+/// Trivial implementation for fixed-sized, `Copy` types (no dynamic part)
+impl<'de, T: Copy + Deserialize<'de>> CompactDeserialize<'de> for T {
+    fn plan<D: Deserializer<'de>>(deserializer: D, _: &mut Planner) -> Result<(), D::Error> {
+        T::deserialize(deserializer).map(|_| ())
+    }
+
+    unsafe fn place<D: Deserializer<'de>>(
+        deserializer: D,
+        dest: *mut Self,
+        _: &mut Placer,
+    ) -> Result<(), D::Error> {
+        ptr::write(dest, T::deserialize(deserializer)?);
+        Ok(())
+    }
+}
+
+/// Deserializes a value straight into a `CompactedBox`, without building it on the heap first,
+/// so peak memory stays at the size of the compacted value.
+///
+/// This takes two passes over the same input: `new` only records the size of each
+/// dynamic part, then `place` allocates one buffer of exactly the planned size
+/// and deserializes the value into it.
+///
+/// ```
+/// # extern crate compact;
+/// # extern crate serde_json;
+/// # use compact::{CString, CVec, DeserializePlan};
+/// # fn main() {
+/// let json = r#"["Baker Street", "Main Avenue"]"#;
+/// let plan: DeserializePlan<CVec<CString>> =
+///     DeserializePlan::new(&mut serde_json::Deserializer::from_str(json)).unwrap();
+/// let streets = plan
+///     .place(&mut serde_json::Deserializer::from_str(json))
+///     .unwrap();
+/// assert_eq!("Main Avenue", streets[1].as_str());
+/// # }
+/// ```
+pub struct DeserializePlan<T> {
+    parts: Vec<Part>,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T: Compact> DeserializePlan<T> {
+    /// Go through the serialized value once, to plan its deserialization
+    pub fn new<'de, D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        T: CompactDeserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        let mut planner = Planner { parts: Vec::new() };
+        T::plan(deserializer, &mut planner)?;
+        Ok(DeserializePlan {
+            parts: planner.parts,
+            marker: PhantomData,
+        })
+    }
+
+    /// Size of the buffer that the value will be deserialized into
+    pub fn total_size_bytes(&self) -> usize {
+        let dynamic_size = self
+            .parts
+            .iter()
+            .fold(0, |offset, part| align_up(offset, part.align) + part.bytes);
+        padded_static_size::<T>() + dynamic_size
+    }
+
+    /// Deserialize the value from the same input again, into a buffer of the planned size
+    pub fn place<'de, D>(self, deserializer: D) -> Result<CompactedBox<T>, D::Error>
+    where
+        T: CompactDeserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        let size = self.total_size_bytes();
+        let buffer = AlignedBuffer::new::<T>(size);
+        let dest = buffer.ptr as *mut T;
+        let mut placer = Placer {
+            parts: self.parts.into_iter(),
+            next: unsafe { T::behind(dest) },
+        };
+        // if placing fails, everything placed so far only lives in the buffer,
+        // which is freed without dropping the value
+        unsafe { T::place(deserializer, dest, &mut placer)? };
+        if placer.take().is_some() {
+            return Err(input_changed());
+        }
+        Ok(unsafe { CompactedBox::from_buffer(buffer, size) })
+    }
+}
+
+#[test]
+fn deserialize_into_compacted_box() {
+    use super::compact_str::CompactString;
+    use super::compact_vec::CompactVec;
+    let json = r#"[[1, 2, 3], [], [4]]"#;
+    let plan: DeserializePlan<CompactVec<CompactVec<u16>>> =
+        DeserializePlan::new(&mut ::serde_json::Deserializer::from_str(json)).unwrap();
+    let expected: CompactVec<CompactVec<u16>> = ::serde_json::from_str(json).unwrap();
+    assert_eq!(expected.compact_to_bytes().len(), plan.total_size_bytes());
+    let boxed = plan
+        .place(&mut ::serde_json::Deserializer::from_str(json))
+        .unwrap();
+    assert!(boxed.is_still_compact());
+    assert_eq!(expected, *boxed);
+
+    let json = r#"["tree", "", "bush"]"#;
+    let plan: DeserializePlan<CompactVec<CompactString>> =
+        DeserializePlan::new(&mut ::serde_json::Deserializer::from_str(json)).unwrap();
+    let mut boxed = plan
+        .place(&mut ::serde_json::Deserializer::from_str(json))
+        .unwrap();
+    assert!(boxed.is_still_compact());
+    assert_eq!(
+        vec!["tree", "", "bush"],
+        boxed.iter().map(|s| s.as_str()).collect::<Vec<_>>()
+    );
+    boxed.with_mut(|names| names[1].push_str("moss"));
+    assert_eq!("moss", boxed[1].as_str());
+}
+
+#[test]
+fn input_changed_between_passes() {
+    use super::compact_str::CompactString;
+    use super::compact_vec::CompactVec;
+    for changed in &[
+        r#"["tree"]"#,
+        r#"["tree", "bush", "rock"]"#,
+        r#"["tree", "bus"]"#,
+    ] {
+        let plan: DeserializePlan<CompactVec<CompactString>> = DeserializePlan::new(
+            &mut ::serde_json::Deserializer::from_str(r#"["tree", "bush"]"#),
+        )
+        .unwrap();
+        let error = plan
+            .place(&mut ::serde_json::Deserializer::from_str(changed))
+            .unwrap_err();
+        assert!(error.to_string().starts_with("input changed"));
+    }
+}
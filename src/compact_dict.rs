@@ -1,12 +1,22 @@
 use super::simple_allocator_trait::{Allocator, DefaultHeap};
-use super::compact::Compact;
-use super::compact_vec::CompactVec;
+use super::compact::{
+    unordered_hash, write_zeros, Compact, CompactReader, CompactStats, DynamicLayout,
+    FromReaderError,
+};
+use super::compact_vec::{self, CompactVec};
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::io::{self, Read, Write};
+use std::iter::{FusedIterator, Zip};
+use std::slice;
 
 /// A simple linear-search key-value dictionary,
 /// implemented using two `CompactVec`'s, one for keys, one for values.
 ///
 /// The API loosely follows that of `std::collections::HashMap`.
 /// Spilling behaviour using `Allocator` is equivalent to `CompactVec`.
+///
+/// For more than a few hundred entries, prefer `CompactSortedDict`, which uses binary search.
 pub struct CompactDict<K: Copy, V: Compact + Clone, A: Allocator = DefaultHeap> {
     keys: CompactVec<K, A>,
     values: CompactVec<V, A>,
@@ -111,12 +121,14 @@ impl<K: Eq + Copy, V: Compact + Clone, A: Allocator> CompactDict<K, V, A> {
 
     /// Remove value at key `query` and return it, if it existed
     pub fn remove(&mut self, query: K) -> Option<V> {
+        self.remove_entry(query).map(|(_, value)| value)
+    }
+
+    /// Remove the entry for key `query` and return its key and value, if it existed
+    pub fn remove_entry(&mut self, query: K) -> Option<(K, V)> {
         for i in 0..self.keys.len() {
             if self.keys[i] == query {
-                let old_val = self.values[i].clone();
-                self.keys.remove(i);
-                self.values.remove(i);
-                return Some(old_val);
+                return Some((self.keys.remove(i), self.values.remove(i)));
             }
         }
         None
@@ -138,8 +150,62 @@ impl<K: Eq + Copy, V: Compact + Clone, A: Allocator> CompactDict<K, V, A> {
     }
 
     /// Iterator over all key-value pairs in the dictionary
-    pub fn pairs<'a>(&'a self) -> impl Iterator<Item = (&'a K, &'a V)> + Clone + 'a {
-        self.keys().zip(self.values())
+    pub fn pairs(&self) -> Iter<'_, K, V> {
+        self.iter()
+    }
+
+    /// Iterator over all key-value pairs in the dictionary, like `pairs`
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            pairs: self.keys.iter().zip(self.values.iter()),
+        }
+    }
+
+    /// Iterator over all key-value pairs in the dictionary,
+    /// with the value as a mutable reference
+    pub fn iter_mut(&mut self) -> IterMut<'_, K, V> {
+        IterMut {
+            pairs: self.keys.iter().zip(self.values.iter_mut()),
+        }
+    }
+
+    /// Iterator over all key-value pairs in the dictionary,
+    /// with the value as a mutable reference, like `iter_mut`
+    pub fn pairs_mut(&mut self) -> IterMut<'_, K, V> {
+        self.iter_mut()
+    }
+
+    /// Find the first key-value pair for which `predicate` returns true
+    pub fn find<F: FnMut(&K, &V) -> bool>(&self, mut predicate: F) -> Option<(&K, &V)> {
+        self.pairs().find(|&(key, value)| predicate(key, value))
+    }
+
+    /// Create a new dictionary with the same keys and all values transformed by `f`
+    pub fn map_values<U: Compact + Clone, F: FnMut(&V) -> U>(
+        &self,
+        mut f: F,
+    ) -> CompactDict<K, U, A> {
+        let mut new_values = CompactVec::with_capacity(self.len());
+        for value in self.values() {
+            new_values.push(f(value));
+        }
+        CompactDict {
+            keys: self.keys.clone(),
+            values: new_values,
+        }
+    }
+
+    /// Create a new dictionary mapping values to keys.
+    /// If several keys have the same value, the last of them ends up in the result.
+    pub fn invert(&self) -> CompactDict<V, K, A>
+    where
+        V: Copy + Eq,
+    {
+        let mut inverted = CompactDict::with_capacity(self.len());
+        for (key, value) in self.pairs() {
+            inverted.insert(*value, *key);
+        }
+        inverted
     }
 }
 
@@ -179,17 +245,80 @@ impl<K: Copy, V: Compact + Clone, A: Allocator> Compact for CompactDict<K, V, A>
     }
 
     fn dynamic_size_bytes(&self) -> usize {
-        self.keys.dynamic_size_bytes() + self.values.dynamic_size_bytes()
+        let mut layout = DynamicLayout::new();
+        layout.field(&self.keys);
+        layout.field(&self.values);
+        layout.size()
+    }
+
+    fn dynamic_align() -> usize {
+        CompactVec::<K, A>::dynamic_align().max(CompactVec::<V, A>::dynamic_align())
+    }
+
+    fn collect_stats(&self, path: &str, stats: &mut CompactStats) {
+        self.keys.collect_stats(&format!("{}.keys", path), stats);
+        self.values.collect_stats(&format!("{}.values", path), stats);
+    }
+
+    fn repair_lossy(&mut self, path: &str, dropped: &mut Vec<String>) {
+        self.keys.repair_lossy(&format!("{}.keys", path), dropped);
+        self.values.repair_lossy(&format!("{}.values", path), dropped);
+        // only keep pairs that still have both a key and a value
+        let len = self.keys.len().min(self.values.len());
+        self.keys.truncate(len);
+        self.values.truncate(len);
+    }
+
+    fn clear_dirty(&mut self) {
+        self.values.clear_dirty();
     }
 
     unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
-        let values_offset = (*source).keys.dynamic_size_bytes() as isize;
-        Compact::compact(&mut (*source).keys, &mut (*dest).keys, new_dynamic_part);
+        let mut layout = DynamicLayout::new();
+        let keys_offset = layout.field(&(*source).keys);
+        let values_offset = layout.field(&(*source).values);
+        Compact::compact(
+            &mut (*source).keys,
+            &mut (*dest).keys,
+            new_dynamic_part.add(keys_offset),
+        );
         Compact::compact(
             &mut (*source).values,
             &mut (*dest).values,
-            new_dynamic_part.offset(values_offset),
+            new_dynamic_part.add(values_offset),
+        );
+    }
+
+    unsafe fn compact_static_part(&self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        let mut layout = DynamicLayout::new();
+        let keys_offset = layout.field(&self.keys);
+        let values_offset = layout.field(&self.values);
+        self.keys.compact_static_part(
+            &mut (*dest).keys,
+            new_dynamic_part.wrapping_add(keys_offset),
         );
+        self.values.compact_static_part(
+            &mut (*dest).values,
+            new_dynamic_part.wrapping_add(values_offset),
+        );
+    }
+
+    fn write_dynamic_part<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut layout = DynamicLayout::new();
+        let keys_offset = layout.field(&self.keys);
+        let values_offset = layout.field(&self.values);
+        write_zeros(writer, keys_offset)?;
+        self.keys.write_dynamic_part(writer)?;
+        write_zeros(
+            writer,
+            values_offset - keys_offset - self.keys.dynamic_size_bytes(),
+        )?;
+        self.values.write_dynamic_part(writer)
+    }
+
+    unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+        self.keys.shift_compact_pointers(shift);
+        self.values.shift_compact_pointers(shift);
     }
 
     unsafe fn decompact(source: *const Self) -> CompactDict<K, V, A> {
@@ -198,6 +327,16 @@ impl<K: Copy, V: Compact + Clone, A: Allocator> Compact for CompactDict<K, V, A>
             values: Compact::decompact(&(*source).values),
         }
     }
+
+    unsafe fn read_dynamic_part<R: Read>(
+        source: *const Self,
+        reader: &mut CompactReader<R>,
+    ) -> Result<Self, FromReaderError> {
+        Ok(CompactDict {
+            keys: reader.read_field(&(*source).keys)?,
+            values: reader.read_field(&(*source).values)?,
+        })
+    }
 }
 
 impl<K: Copy, V: Compact + Clone, A: Allocator> Clone for CompactDict<K, V, A> {
@@ -220,7 +359,8 @@ impl<K: Copy + Eq, V: Compact + Clone, A: Allocator> ::std::iter::FromIterator<(
 {
     /// Construct a compact dictionary from an interator over key-value pairs
     fn from_iter<T: IntoIterator<Item = (K, V)>>(iter: T) -> Self {
-        let mut dict = Self::new();
+        let iter = iter.into_iter();
+        let mut dict = Self::with_capacity(iter.size_hint().0);
         for (key, value) in iter {
             dict.insert(key, value);
         }
@@ -239,6 +379,121 @@ impl<K: Copy + Eq, V: Compact + Clone, A: Allocator> ::std::iter::Extend<(K, V)>
     }
 }
 
+impl<K: Copy + Eq, V: Compact + Clone, A: Allocator> From<Vec<(K, V)>> for CompactDict<K, V, A> {
+    /// Construct a compact dictionary from key-value pairs, later duplicate keys win
+    fn from(pairs: Vec<(K, V)>) -> Self {
+        pairs.into_iter().collect()
+    }
+}
+
+impl<K: Copy + Eq, V: Compact + Clone, A: Allocator> ::std::ops::Index<&K>
+    for CompactDict<K, V, A>
+{
+    type Output = V;
+
+    /// The value for key `query`, panics if it doesn't exist
+    fn index(&self, query: &K) -> &V {
+        self.get(*query).expect("key not found")
+    }
+}
+
+impl<K: Copy + Eq, V: Compact + Clone, A: Allocator> ::std::ops::IndexMut<&K>
+    for CompactDict<K, V, A>
+{
+    /// The value for key `query` mutably, panics if it doesn't exist
+    fn index_mut(&mut self, query: &K) -> &mut V {
+        self.get_mut(*query).expect("key not found")
+    }
+}
+
+/// Iterator over the key-value pairs of a `CompactDict`, see `CompactDict::pairs`
+pub struct Iter<'a, K: 'a, V: 'a> {
+    pairs: Zip<slice::Iter<'a, K>, slice::Iter<'a, V>>,
+}
+
+/// Iterator over the key-value pairs of a `CompactDict` with mutable values,
+/// see `CompactDict::iter_mut`
+pub struct IterMut<'a, K: 'a, V: 'a> {
+    pairs: Zip<slice::Iter<'a, K>, slice::IterMut<'a, V>>,
+}
+
+/// Iterator moving the key-value pairs out of a `CompactDict`
+pub struct IntoIter<K, V, A: Allocator = DefaultHeap> {
+    pairs: Zip<compact_vec::IntoIter<K, A>, compact_vec::IntoIter<V, A>>,
+}
+
+/// Implement the iterator traits for an iterator over zipped keys and values
+macro_rules! zipped_pairs_iterator {
+    ([$($generics:tt)*] $name:ty, $item:ty, |$pair:ident| $value:expr) => {
+        impl<$($generics)*> Iterator for $name {
+            type Item = $item;
+
+            fn next(&mut self) -> Option<$item> {
+                let $pair = self.pairs.next()?;
+                Some($value)
+            }
+
+            fn size_hint(&self) -> (usize, Option<usize>) {
+                self.pairs.size_hint()
+            }
+        }
+
+        impl<$($generics)*> DoubleEndedIterator for $name {
+            fn next_back(&mut self) -> Option<$item> {
+                let $pair = self.pairs.next_back()?;
+                Some($value)
+            }
+        }
+
+        impl<$($generics)*> ExactSizeIterator for $name {}
+
+        impl<$($generics)*> FusedIterator for $name {}
+    };
+}
+
+zipped_pairs_iterator!(['a, K, V] Iter<'a, K, V>, (&'a K, &'a V), |pair| pair);
+zipped_pairs_iterator!(['a, K, V] IterMut<'a, K, V>, (&'a K, &'a mut V), |pair| pair);
+zipped_pairs_iterator!([K, V, A: Allocator] IntoIter<K, V, A>, (K, V), |pair| pair);
+
+impl<'a, K, V> Clone for Iter<'a, K, V> {
+    fn clone(&self) -> Self {
+        Iter {
+            pairs: self.pairs.clone(),
+        }
+    }
+}
+
+impl<K: Copy, V: Compact + Clone, A: Allocator> IntoIterator for CompactDict<K, V, A> {
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V, A>;
+
+    fn into_iter(self) -> IntoIter<K, V, A> {
+        IntoIter {
+            pairs: self.keys.into_iter().zip(self.values),
+        }
+    }
+}
+
+impl<'a, K: Copy + Eq, V: Compact + Clone, A: Allocator> IntoIterator for &'a CompactDict<K, V, A> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
+
+impl<'a, K: Copy + Eq, V: Compact + Clone, A: Allocator> IntoIterator
+    for &'a mut CompactDict<K, V, A>
+{
+    type Item = (&'a K, &'a mut V);
+    type IntoIter = IterMut<'a, K, V>;
+
+    fn into_iter(self) -> IterMut<'a, K, V> {
+        self.iter_mut()
+    }
+}
+
 impl<K, V, A> ::std::fmt::Debug for CompactDict<K, V, A>
 where
     K: Copy + Eq + ::std::fmt::Debug,
@@ -250,6 +505,59 @@ where
     }
 }
 
+impl<K, V, A, B> PartialEq<CompactDict<K, V, B>> for CompactDict<K, V, A>
+where
+    K: Copy + Eq,
+    V: Compact + PartialEq,
+    A: Allocator,
+    B: Allocator,
+{
+    /// Dictionaries are equal if they contain the same key-value pairs, in any order
+    fn eq(&self, other: &CompactDict<K, V, B>) -> bool {
+        self.len() == other.len()
+            && self
+                .pairs()
+                .all(|(key, value)| other.get(*key) == Some(value))
+    }
+}
+
+impl<K: Copy + Eq, V: Compact + Eq, A: Allocator> Eq for CompactDict<K, V, A> {}
+
+impl<K, V, A, S> PartialEq<HashMap<K, V, S>> for CompactDict<K, V, A>
+where
+    K: Copy + Eq + Hash,
+    V: Compact + PartialEq,
+    A: Allocator,
+    S: BuildHasher,
+{
+    fn eq(&self, other: &HashMap<K, V, S>) -> bool {
+        self.len() == other.len()
+            && self
+                .pairs()
+                .all(|(key, value)| other.get(key) == Some(value))
+    }
+}
+
+impl<K, V, A, S> PartialEq<CompactDict<K, V, A>> for HashMap<K, V, S>
+where
+    K: Copy + Eq + Hash,
+    V: Compact + PartialEq,
+    A: Allocator,
+    S: BuildHasher,
+{
+    fn eq(&self, other: &CompactDict<K, V, A>) -> bool {
+        other == self
+    }
+}
+
+impl<K: Copy + Eq + Hash, V: Compact + Hash, A: Allocator> Hash for CompactDict<K, V, A> {
+    /// Hashes independently of the order of entries, so equal dictionaries hash equally
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_usize(self.len());
+        state.write_u64(unordered_hash(self.pairs()));
+    }
+}
+
 #[cfg(feature = "serde-serialization")]
 use serde::ser::SerializeMap;
 #[cfg(feature = "serde-serialization")]
@@ -275,6 +583,7 @@ where
 }
 
 #[cfg(feature = "serde-serialization")]
+#[allow(clippy::type_complexity)]
 struct CompactDictVisitor<K: Copy, V: Compact, A: Allocator> {
     marker: PhantomData<fn() -> CompactDict<K, V, A>>,
 }
@@ -330,6 +639,26 @@ where
     }
 }
 
+#[cfg(feature = "arbitrary")]
+impl<'a, K, V, A> ::arbitrary::Arbitrary<'a> for CompactDict<K, V, A>
+where
+    K: Copy + Eq + ::arbitrary::Arbitrary<'a>,
+    V: Compact + Clone + ::arbitrary::Arbitrary<'a>,
+    A: Allocator + 'a,
+{
+    fn arbitrary(u: &mut ::arbitrary::Unstructured<'a>) -> ::arbitrary::Result<Self> {
+        u.arbitrary_iter()?.collect()
+    }
+
+    fn arbitrary_take_rest(u: ::arbitrary::Unstructured<'a>) -> ::arbitrary::Result<Self> {
+        u.arbitrary_take_rest_iter()?.collect()
+    }
+
+    fn size_hint(depth: usize) -> (usize, Option<usize>) {
+        <Vec<(K, V)> as ::arbitrary::Arbitrary>::size_hint(depth)
+    }
+}
+
 #[cfg(test)]
 fn elem(n: usize) -> usize {
     (n * n) as usize
@@ -428,6 +757,46 @@ fn pairs() {
     }
 }
 
+#[test]
+fn from_pairs() {
+    use super::compact_hash_map::OpenAddressingMap;
+    fn squares<M: ::std::iter::FromIterator<(u32, u32)> + Extend<(u32, u32)>>() -> M {
+        let mut map: M = (0..5).map(|n| (n, n * n)).collect();
+        map.extend((5..10).map(|n| (n, n * n)));
+        map
+    }
+    let dict: CompactDict<u32, u32> = squares();
+    let hash_map: OpenAddressingMap<u32, u32> = squares();
+    assert_eq!(10, dict.len());
+    assert!((0..10).all(|n| dict.get(n) == hash_map.get(&n)));
+
+    let dict: CompactDict<u32, u32> = vec![(1, 10), (2, 20), (1, 11)].into();
+    assert_eq!(2, dict.len());
+    assert_eq!(Some(&11), dict.get(1));
+}
+
+#[test]
+fn pairs_mut_and_into_iter() {
+    let mut map: CompactDict<usize, CompactVec<usize>> =
+        (0..10).map(|n| (n, vec![n].into())).collect();
+    for (key, value) in &mut map {
+        value.push(key * 10);
+    }
+    map.pairs_mut().next_back().unwrap().1.push(900);
+    assert_eq!(10, map.pairs().len());
+    assert_eq!(Some((&9, &vec![9, 90, 900].into())), map.iter().next_back());
+    let mut total = 0;
+    for (key, value) in &map {
+        total += key + value[1];
+    }
+    assert_eq!(495, total);
+
+    let mut pairs = map.into_iter();
+    assert_eq!(Some((0, vec![0, 0].into())), pairs.next());
+    assert_eq!(9, pairs.len());
+    // the rest is dropped with the iterator
+}
+
 #[test]
 fn push_at() {
     let mut map: CompactDict<usize, CompactVec<usize>> = CompactDict::new();
@@ -456,3 +825,113 @@ fn remove_iter() {
     assert!(iter.find(|i| *i == elem(50)).is_some());
     assert!(iter.find(|i| *i == elem(50) + 1).is_some());
 }
+
+#[test]
+fn remove_entry() {
+    let mut map: CompactDict<usize, CompactVec<usize>> = CompactDict::new();
+    for n in 0..10 {
+        map.push_at(n, elem(n));
+    }
+    assert_eq!(Some((4, vec![elem(4)].into())), map.remove_entry(4));
+    assert_eq!(None, map.remove_entry(4));
+    assert_eq!(Some(vec![elem(5)].into()), map.remove(5));
+    assert_eq!(8, map.len());
+}
+
+#[test]
+fn index() {
+    let mut map: CompactDict<usize, CompactVec<usize>> = CompactDict::new();
+    for n in 0..10 {
+        map.push_at(n, elem(n));
+    }
+    map[&2].push(elem(20));
+    assert_eq!(vec![elem(2), elem(20)], map[&2]);
+    assert_eq!(vec![elem(9)], map[&9]);
+}
+
+#[test]
+fn find() {
+    let mut map: CompactDict<usize, usize> = CompactDict::new();
+    for n in 0..100 {
+        map.insert(n, elem(n));
+    }
+    assert_eq!(Some((&11, &121)), map.find(|_, v| *v > 100));
+    assert_eq!(Some((&50, &2500)), map.find(|k, _| *k == 50));
+    assert!(map.find(|k, v| k == v && *k > 1).is_none());
+}
+
+#[test]
+fn map_values_and_invert() {
+    let mut map: CompactDict<usize, usize> = CompactDict::new();
+    for n in 0..100 {
+        map.insert(n, elem(n));
+    }
+
+    let strings = map.map_values(|v| CompactVec::<usize>::from(vec![*v; 2]));
+    assert_eq!(map.len(), strings.len());
+    assert_eq!(&[2500, 2500], &**strings.get(50).unwrap());
+
+    let inverted = map.invert();
+    assert_eq!(map.len(), inverted.len());
+    for n in 0..100 {
+        assert_eq!(Some(&n), inverted.get(elem(n)));
+    }
+
+    let mut duplicates: CompactDict<usize, usize> = CompactDict::new();
+    duplicates.insert(1, 7);
+    duplicates.insert(2, 7);
+    let inverted = duplicates.invert();
+    assert_eq!(1, inverted.len());
+    assert_eq!(Some(&2), inverted.get(7));
+}
+
+#[test]
+fn equality() {
+    let mut map: CompactDict<usize, CompactVec<usize>> = CompactDict::new();
+    let mut other: CompactDict<usize, CompactVec<usize>> = CompactDict::new();
+    let mut std_map = HashMap::new();
+    for n in 0..10 {
+        map.push_at(n, elem(n));
+        // different insertion order
+        other.push_at(9 - n, elem(9 - n));
+        std_map.insert(n, CompactVec::from(vec![elem(n)]));
+    }
+    assert_eq!(map, other);
+    assert_eq!(map, std_map);
+    assert_eq!(std_map, map);
+    other.push_at(3, 0);
+    assert_ne!(map, other);
+    other.remove(3);
+    assert_ne!(map, other);
+}
+
+#[test]
+fn hash_is_order_independent() {
+    use std::collections::hash_map::DefaultHasher;
+    let hash_of = |map: &CompactDict<usize, usize>| {
+        let mut hasher = DefaultHasher::new();
+        map.hash(&mut hasher);
+        hasher.finish()
+    };
+    let mut map: CompactDict<usize, usize> = CompactDict::new();
+    let mut other: CompactDict<usize, usize> = CompactDict::new();
+    for n in 0..10 {
+        map.insert(n, elem(n));
+        other.insert(9 - n, elem(9 - n));
+    }
+    assert_eq!(hash_of(&map), hash_of(&other));
+    other.insert(3, 0);
+    assert!(hash_of(&map) != hash_of(&other));
+}
+
+#[cfg(feature = "serde-serialization")]
+#[test]
+fn serde_roundtrip() {
+    let mut dict: CompactDict<u32, CompactVec<u32>> = CompactDict::new();
+    dict.insert(1, vec![1, 2].into());
+    dict.insert(3, vec![3].into());
+    let json = ::serde_json::to_string(&dict).unwrap();
+    assert_eq!(json, "{\"1\":[1,2],\"3\":[3]}");
+    let deserialized: CompactDict<u32, CompactVec<u32>> = ::serde_json::from_str(&json).unwrap();
+    assert_eq!(deserialized, dict);
+}
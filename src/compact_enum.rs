@@ -0,0 +1,405 @@
+use super::compact::{Compact, DynamicLayout};
+use std::mem;
+use std::ptr;
+
+/// Compacts the fields of the active variant of an enum, for implementing
+/// `Compact::compact` of enums by hand without matching on `dest` as well:
+///
+/// ```
+/// # use compact::{
+/// #     CString, CVec, Compact, CompactReader, DynamicLayout, FromReaderError, VariantCompactor,
+/// # };
+/// #[derive(Clone)]
+/// enum Message {
+///     Ping,
+///     Text(CString),
+///     Move { from: CVec<u32>, to: u32 },
+/// }
+///
+/// impl Compact for Message {
+///     fn is_still_compact(&self) -> bool {
+///         match *self {
+///             Message::Ping => true,
+///             Message::Text(ref text) => text.is_still_compact(),
+///             Message::Move { ref from, .. } => from.is_still_compact(),
+///         }
+///     }
+///
+///     fn dynamic_size_bytes(&self) -> usize {
+///         let mut layout = DynamicLayout::new();
+///         match *self {
+///             Message::Ping => {}
+///             Message::Text(ref text) => {
+///                 layout.field(text);
+///             }
+///             Message::Move { ref from, ref to } => {
+///                 layout.field(from);
+///                 layout.field(to);
+///             }
+///         }
+///         layout.size()
+///     }
+///
+///     fn dynamic_align() -> usize {
+///         CString::dynamic_align().max(CVec::<u32>::dynamic_align())
+///     }
+///
+///     unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+///         let mut variant = VariantCompactor::new(source, dest, new_dynamic_part);
+///         // same fields in the same order as in `dynamic_size_bytes`
+///         match *source {
+///             Message::Ping => {}
+///             Message::Text(ref mut text) => variant.field(text),
+///             Message::Move { ref mut from, ref mut to } => {
+///                 variant.field(from);
+///                 variant.field(to);
+///             }
+///         }
+///     }
+///
+///     unsafe fn decompact(source: *const Self) -> Self {
+///         match *source {
+///             Message::Ping => Message::Ping,
+///             Message::Text(ref text) => Message::Text(Compact::decompact(text)),
+///             Message::Move { ref from, to } => Message::Move {
+///                 from: Compact::decompact(from),
+///                 to,
+///             },
+///         }
+///     }
+///
+///     unsafe fn read_dynamic_part<R: std::io::Read>(
+///         source: *const Self,
+///         reader: &mut CompactReader<R>,
+///     ) -> Result<Self, FromReaderError> {
+///         Ok(match *source {
+///             Message::Ping => Message::Ping,
+///             Message::Text(ref text) => Message::Text(reader.read_field(text)?),
+///             Message::Move { ref from, ref to } => Message::Move {
+///                 from: reader.read_field(from)?,
+///                 to: reader.read_field(to)?,
+///             },
+///         })
+///     }
+/// }
+///
+/// let message = Message::Text("hello".into());
+/// let bytes = message.compact_to_bytes();
+/// // the bytes come from this very build
+/// match unsafe { Message::from_compact_bytes(&bytes) }.unwrap() {
+///     Message::Text(text) => assert_eq!("hello", text.as_str()),
+///     _ => unreachable!(),
+/// }
+/// ```
+///
+/// `compact_enum!` generates such impls.
+pub struct VariantCompactor<E> {
+    source: *mut E,
+    dest: *mut E,
+    new_dynamic_part: *mut u8,
+    layout: DynamicLayout,
+}
+
+impl<E> VariantCompactor<E> {
+    /// Start compacting `source` into `dest` by copying its static part,
+    /// which includes the discriminant and all fields without a dynamic part
+    ///
+    /// # Safety
+    ///
+    /// See `Compact::compact`
+    pub unsafe fn new(source: *mut E, dest: *mut E, new_dynamic_part: *mut u8) -> Self {
+        ptr::copy_nonoverlapping(source as *const E, dest, 1);
+        VariantCompactor {
+            source,
+            dest,
+            new_dynamic_part,
+            layout: DynamicLayout::new(),
+        }
+    }
+
+    /// Compact `field`, a field of the active variant of `source`, into the same field of `dest`,
+    /// placing its dynamic part behind the ones of previous fields (see `DynamicLayout`)
+    pub fn field<F: Compact>(&mut self, field: &mut F) {
+        let field_offset = (field as *mut F as usize).wrapping_sub(self.source as usize);
+        assert!(
+            field_offset
+                .checked_add(mem::size_of::<F>())
+                .is_some_and(|end| end <= mem::size_of::<E>()),
+            "field is not part of the compacted enum"
+        );
+        let offset = self.layout.field(field);
+        unsafe {
+            let dest_field = (self.dest as *mut u8).add(field_offset) as *mut F;
+            Compact::compact(field, dest_field, self.new_dynamic_part.add(offset));
+        }
+    }
+}
+
+/// Define an enum and implement `Compact` for it, sizing and compacting only the
+/// active variant, with the dynamic parts of its fields placed one after another.
+///
+/// Fields of tuple variants need names (only used within the impl). Generic enums aren't supported,
+/// the enum has to implement `Clone` (all field types have to implement `Compact`).
+///
+/// ```
+/// # #[macro_use] extern crate compact;
+/// # use compact::{CString, CVec, Compact};
+/// compact_enum! {
+///     #[derive(Clone, Debug, PartialEq)]
+///     pub enum Message {
+///         Ping,
+///         Text(text: CString),
+///         Move { from: CVec<u32>, to: u32 },
+///     }
+/// }
+///
+/// # fn main() {
+/// let message = Message::Move { from: vec![1, 2].into(), to: 3 };
+/// let bytes = message.compact_to_bytes();
+/// assert_eq!(message, unsafe { Message::from_compact_bytes(&bytes) }.unwrap());
+/// # }
+/// ```
+#[macro_export]
+macro_rules! compact_enum {
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident {
+            $(
+                $(#[$variant_meta:meta])*
+                $variant:ident
+                $(( $($tuple_field:ident : $tuple_type:ty),* $(,)? ))?
+                $({ $($struct_field:ident : $struct_type:ty),* $(,)? })?
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis enum $name {
+            $(
+                $(#[$variant_meta])*
+                $variant
+                $(( $($tuple_type),* ))?
+                $({ $($struct_field: $struct_type),* })?
+            ),*
+        }
+
+        impl $crate::Compact for $name {
+            fn is_still_compact(&self) -> bool {
+                match *self {
+                    $(
+                        $name::$variant
+                        $(( $(ref $tuple_field),* ))?
+                        $({ $(ref $struct_field),* })?
+                        => true
+                            $($( && $crate::Compact::is_still_compact($tuple_field) )*)?
+                            $($( && $crate::Compact::is_still_compact($struct_field) )*)?
+                    ),*
+                }
+            }
+
+            fn dynamic_size_bytes(&self) -> usize {
+                #[allow(unused_mut)]
+                let mut layout = $crate::DynamicLayout::new();
+                match *self {
+                    $(
+                        $name::$variant
+                        $(( $(ref $tuple_field),* ))?
+                        $({ $(ref $struct_field),* })?
+                        => {
+                            $($( layout.field($tuple_field); )*)?
+                            $($( layout.field($struct_field); )*)?
+                        }
+                    ),*
+                }
+                layout.size()
+            }
+
+            fn dynamic_align() -> usize {
+                #[allow(unused_mut)]
+                let mut align = 1;
+                $(
+                    $($( align = align.max(<$tuple_type as $crate::Compact>::dynamic_align()); )*)?
+                    $($( align = align.max(<$struct_type as $crate::Compact>::dynamic_align()); )*)?
+                )*
+                align
+            }
+
+            fn collect_stats(&self, path: &str, stats: &mut $crate::CompactStats) {
+                match *self {
+                    $(
+                        $name::$variant
+                        $(( $(ref $tuple_field),* ))?
+                        $({ $(ref $struct_field),* })?
+                        => {
+                            $($( $crate::Compact::collect_stats(
+                                $tuple_field,
+                                &format!("{}.{}.{}", path, stringify!($variant), stringify!($tuple_field)),
+                                stats,
+                            ); )*)?
+                            $($( $crate::Compact::collect_stats(
+                                $struct_field,
+                                &format!("{}.{}.{}", path, stringify!($variant), stringify!($struct_field)),
+                                stats,
+                            ); )*)?
+                            let _ = (path, stats);
+                        }
+                    ),*
+                }
+            }
+
+            fn repair_lossy(&mut self, path: &str, dropped: &mut Vec<String>) {
+                match *self {
+                    $(
+                        $name::$variant
+                        $(( $(ref mut $tuple_field),* ))?
+                        $({ $(ref mut $struct_field),* })?
+                        => {
+                            $($( $crate::Compact::repair_lossy(
+                                $tuple_field,
+                                &format!("{}.{}.{}", path, stringify!($variant), stringify!($tuple_field)),
+                                dropped,
+                            ); )*)?
+                            $($( $crate::Compact::repair_lossy(
+                                $struct_field,
+                                &format!("{}.{}.{}", path, stringify!($variant), stringify!($struct_field)),
+                                dropped,
+                            ); )*)?
+                            let _ = (path, dropped);
+                        }
+                    ),*
+                }
+            }
+
+            unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+                #[allow(unused_mut, unused_variables)]
+                let mut variant = $crate::VariantCompactor::new(source, dest, new_dynamic_part);
+                match *source {
+                    $(
+                        $name::$variant
+                        $(( $(ref mut $tuple_field),* ))?
+                        $({ $(ref mut $struct_field),* })?
+                        => {
+                            $($( variant.field($tuple_field); )*)?
+                            $($( variant.field($struct_field); )*)?
+                        }
+                    ),*
+                }
+            }
+
+            unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+                match *self {
+                    $(
+                        $name::$variant
+                        $(( $(ref mut $tuple_field),* ))?
+                        $({ $(ref mut $struct_field),* })?
+                        => {
+                            $($( $crate::Compact::shift_compact_pointers($tuple_field, shift); )*)?
+                            $($( $crate::Compact::shift_compact_pointers($struct_field, shift); )*)?
+                            let _ = shift;
+                        }
+                    ),*
+                }
+            }
+
+            unsafe fn decompact(source: *const Self) -> Self {
+                match *source {
+                    $(
+                        $name::$variant
+                        $(( $(ref $tuple_field),* ))?
+                        $({ $(ref $struct_field),* })?
+                        => $name::$variant
+                            $(( $($crate::Compact::decompact($tuple_field)),* ))?
+                            $({ $($struct_field: $crate::Compact::decompact($struct_field)),* })?
+                    ),*
+                }
+            }
+
+            unsafe fn read_dynamic_part<R: ::std::io::Read>(
+                source: *const Self,
+                reader: &mut $crate::CompactReader<R>,
+            ) -> Result<Self, $crate::FromReaderError> {
+                let _ = &reader;
+                Ok(match *source {
+                    $(
+                        $name::$variant
+                        $(( $(ref $tuple_field),* ))?
+                        $({ $(ref $struct_field),* })?
+                        => $name::$variant
+                            $(( $(reader.read_field($tuple_field)?),* ))?
+                            $({ $($struct_field: reader.read_field($struct_field)?),* })?
+                    ),*
+                })
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+compact_enum! {
+    #[derive(Clone, Debug, PartialEq)]
+    enum Message {
+        Ping,
+        Text(text: ::compact_str::CompactString),
+        /// Moves things around
+        Move { from: ::compact_vec::CompactVec<u64>, to: u32, label: ::compact_str::CompactString },
+    }
+}
+
+#[test]
+fn compact_enum() {
+    use super::compacted_box::CompactedBox;
+    assert_eq!(8, Message::dynamic_align());
+
+    let messages = vec![
+        Message::Ping,
+        Message::Text("hello".into()),
+        Message::Move {
+            from: vec![1, 2, 3].into(),
+            to: 4,
+            label: "odd".into(),
+        },
+    ];
+    for message in messages {
+        let bytes = message.compact_to_bytes();
+        assert_eq!(
+            message,
+            unsafe { Message::from_compact_bytes(&bytes) }.unwrap()
+        );
+        let mut streamed = Vec::new();
+        message.compact_to_writer(&mut streamed).unwrap();
+        assert_eq!(
+            message,
+            unsafe { Message::decompact_from_reader(&mut &streamed[..]) }.unwrap()
+        );
+    }
+
+    let mut boxed = CompactedBox::new(Message::Move {
+        from: vec![1, 2].into(),
+        to: 3,
+        label: "abc".into(),
+    });
+    assert!(boxed.is_still_compact());
+    assert_eq!(16 + 3, boxed.dynamic_size_bytes());
+    boxed.with_mut(|message| {
+        if let Message::Move { ref mut from, .. } = *message {
+            assert_eq!(0, from.as_ptr() as usize % 8);
+            from.push(7);
+        }
+    });
+    assert_eq!(
+        vec![".Move.from".to_owned()],
+        boxed.compact_stats().spilled_paths
+    );
+    assert!(boxed.recompact());
+}
+
+#[test]
+#[should_panic(expected = "field is not part of the compacted enum")]
+fn variant_compactor_checks_fields() {
+    let mut source = 1u64;
+    let mut dest = 0u64;
+    let mut other = 2u64;
+    unsafe {
+        let mut variant = VariantCompactor::new(&mut source, &mut dest, ptr::null_mut());
+        variant.field(&mut other);
+    }
+}
@@ -0,0 +1,290 @@
+use super::compact::{Compact, CompactReader, CompactStats, FromReaderError};
+use super::compact_vec::CompactVec;
+use super::simple_allocator_trait::{Allocator, DefaultHeap};
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::ops::{Index, IndexMut};
+
+/// A fieldless enum whose variants can be numbered `0..LENGTH`, to key a `CompactEnumMap`.
+///
+/// `enum_array!` declares enums implementing it.
+pub trait EnumArray: Copy {
+    /// Amount of variants
+    const LENGTH: usize;
+
+    /// The variant numbered `index`, panics if there is none
+    fn from_index(index: usize) -> Self;
+
+    /// The number of this variant
+    fn to_index(self) -> usize;
+}
+
+/// Declares a fieldless enum implementing `EnumArray`, numbering its variants in order:
+///
+/// ```
+/// # #[macro_use] extern crate compact;
+/// # use compact::{CEnumMap, CVec};
+/// enum_array! {
+///     #[derive(Clone, Copy, Debug, PartialEq)]
+///     pub enum Resource { Wood, Stone, Food }
+/// }
+///
+/// # fn main() {
+/// let mut stock: CEnumMap<Resource, CVec<u32>> = CEnumMap::new();
+/// stock[Resource::Food].push(3);
+/// assert_eq!(Some((Resource::Food, &vec![3].into())), stock.iter().last());
+/// # }
+/// ```
+///
+/// The enum has to derive `Clone` and `Copy`.
+#[macro_export]
+macro_rules! enum_array {
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident {
+            $(
+                $(#[$variant_meta:meta])*
+                $variant:ident
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis enum $name {
+            $(
+                $(#[$variant_meta])*
+                $variant
+            ),*
+        }
+
+        impl $crate::EnumArray for $name {
+            const LENGTH: usize = [$($name::$variant),*].len();
+
+            fn from_index(index: usize) -> Self {
+                [$($name::$variant),*][index]
+            }
+
+            fn to_index(self) -> usize {
+                self as usize
+            }
+        }
+    };
+}
+
+/// A map with one value for each variant of a fieldless enum,
+/// stored as a `CompactVec` of values in the order of the variants, without hashing.
+pub struct CompactEnumMap<E: EnumArray, V, A: Allocator = DefaultHeap> {
+    values: CompactVec<V, A>,
+    _keys: PhantomData<E>,
+}
+
+impl<E: EnumArray, V: Compact + Clone, A: Allocator> CompactEnumMap<E, V, A> {
+    /// Create a map with the default value for each variant
+    pub fn new() -> Self
+    where
+        V: Default,
+    {
+        Self::from_fn(|_| V::default())
+    }
+
+    /// Create a map with the value `f(variant)` for each variant
+    pub fn from_fn<F: FnMut(E) -> V>(mut f: F) -> Self {
+        let mut values = CompactVec::with_capacity(E::LENGTH);
+        for index in 0..E::LENGTH {
+            values.push(f(E::from_index(index)));
+        }
+        CompactEnumMap {
+            values,
+            _keys: PhantomData,
+        }
+    }
+
+    /// Amount of values, one for each variant
+    pub fn len(&self) -> usize {
+        E::LENGTH
+    }
+
+    /// Does the enum have no variants?
+    pub fn is_empty(&self) -> bool {
+        E::LENGTH == 0
+    }
+
+    /// Iterator over all variants and their values, in the order of the variants
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (E, &V)> + ExactSizeIterator {
+        self.values
+            .iter()
+            .enumerate()
+            .map(|(index, value)| (E::from_index(index), value))
+    }
+
+    /// Iterator over all variants and mutable references to their values,
+    /// in the order of the variants
+    pub fn iter_mut(&mut self) -> impl DoubleEndedIterator<Item = (E, &mut V)> + ExactSizeIterator {
+        self.values
+            .iter_mut()
+            .enumerate()
+            .map(|(index, value)| (E::from_index(index), value))
+    }
+
+    /// Iterator over all values, in the order of their variants
+    pub fn values(&self) -> ::std::slice::Iter<'_, V> {
+        self.values.iter()
+    }
+
+    /// Iterator over mutable references to all values, in the order of their variants
+    pub fn values_mut(&mut self) -> ::std::slice::IterMut<'_, V> {
+        self.values.iter_mut()
+    }
+}
+
+impl<E: EnumArray, V, A: Allocator> Index<E> for CompactEnumMap<E, V, A> {
+    type Output = V;
+
+    fn index(&self, key: E) -> &V {
+        &self.values[key.to_index()]
+    }
+}
+
+impl<E: EnumArray, V, A: Allocator> IndexMut<E> for CompactEnumMap<E, V, A> {
+    fn index_mut(&mut self, key: E) -> &mut V {
+        &mut self.values[key.to_index()]
+    }
+}
+
+impl<E: EnumArray, V: Compact + Clone, A: Allocator> Compact for CompactEnumMap<E, V, A> {
+    fn is_still_compact(&self) -> bool {
+        self.values.is_still_compact()
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        self.values.dynamic_size_bytes()
+    }
+
+    fn dynamic_align() -> usize {
+        CompactVec::<V, A>::dynamic_align()
+    }
+
+    fn collect_stats(&self, path: &str, stats: &mut CompactStats) {
+        self.values.collect_stats(path, stats);
+    }
+
+    fn repair_lossy(&mut self, path: &str, dropped: &mut Vec<String>) {
+        self.values.repair_lossy(path, dropped);
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        Compact::compact(&mut (*source).values, &mut (*dest).values, new_dynamic_part)
+    }
+
+    unsafe fn compact_static_part(&self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        self.values
+            .compact_static_part(&mut (*dest).values, new_dynamic_part)
+    }
+
+    fn write_dynamic_part<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.values.write_dynamic_part(writer)
+    }
+
+    unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+        self.values.shift_compact_pointers(shift);
+    }
+
+    unsafe fn decompact(source: *const Self) -> Self {
+        CompactEnumMap {
+            values: Compact::decompact(&(*source).values),
+            _keys: PhantomData,
+        }
+    }
+
+    unsafe fn read_dynamic_part<R: Read>(
+        source: *const Self,
+        reader: &mut CompactReader<R>,
+    ) -> Result<Self, FromReaderError> {
+        let values: CompactVec<V, A> = reader.read_field(&(*source).values)?;
+        if values.len() != E::LENGTH {
+            return Err(reader.invalid("enum map doesn't have one value for each variant"));
+        }
+        Ok(CompactEnumMap {
+            values,
+            _keys: PhantomData,
+        })
+    }
+}
+
+impl<E: EnumArray, V: Compact + Clone, A: Allocator> Clone for CompactEnumMap<E, V, A> {
+    fn clone(&self) -> Self {
+        CompactEnumMap {
+            values: self.values.clone(),
+            _keys: PhantomData,
+        }
+    }
+}
+
+impl<E: EnumArray, V: Compact + Clone + Default, A: Allocator> Default for CompactEnumMap<E, V, A> {
+    fn default() -> Self {
+        CompactEnumMap::new()
+    }
+}
+
+impl<E, V, A> ::std::fmt::Debug for CompactEnumMap<E, V, A>
+where
+    E: EnumArray + ::std::fmt::Debug,
+    V: Compact + Clone + ::std::fmt::Debug,
+    A: Allocator,
+{
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<E: EnumArray, V: PartialEq, A: Allocator> PartialEq for CompactEnumMap<E, V, A> {
+    fn eq(&self, other: &Self) -> bool {
+        self.values == other.values
+    }
+}
+
+#[cfg(test)]
+enum_array! {
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum Zone { Residential, Commercial, Industrial }
+}
+
+#[test]
+fn index_and_iterate() {
+    let mut demand: CompactEnumMap<Zone, i32> = CompactEnumMap::new();
+    demand[Zone::Commercial] += 5;
+    demand[Zone::Industrial] -= 2;
+    assert_eq!(3, demand.len());
+    assert_eq!(
+        vec![
+            (Zone::Residential, 0),
+            (Zone::Commercial, 5),
+            (Zone::Industrial, -2)
+        ],
+        demand
+            .iter()
+            .map(|(zone, v)| (zone, *v))
+            .collect::<Vec<_>>()
+    );
+    for (zone, value) in demand.iter_mut() {
+        *value += zone.to_index() as i32;
+    }
+    assert_eq!(vec![0, 6, 0], demand.values().cloned().collect::<Vec<_>>());
+}
+
+#[test]
+fn compact_enum_map() {
+    use super::compact_str::CompactString;
+    use super::compacted_box::CompactedBox;
+    let names: CompactEnumMap<Zone, CompactString> =
+        CompactEnumMap::from_fn(|zone| format!("{:?} zone", zone).into());
+    let boxed = CompactedBox::new(names.clone());
+    assert!(boxed.is_still_compact());
+    assert_eq!(names, *boxed);
+    assert_eq!("Industrial zone", boxed[Zone::Industrial].as_str());
+
+    let mut stream = Vec::new();
+    names.compact_to_writer(&mut stream).unwrap();
+    let read: CompactEnumMap<Zone, CompactString> =
+        unsafe { Compact::decompact_from_reader(&mut &stream[..]) }.unwrap();
+    assert_eq!(names, read);
+}
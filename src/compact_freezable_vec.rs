@@ -0,0 +1,294 @@
+use super::compact::{Compact, CompactReader, CompactStats, FromReaderError};
+use super::compact_vec::CompactVec;
+use super::lz;
+use super::simple_allocator_trait::{Allocator, DefaultHeap};
+use std::cell::OnceCell;
+use std::io::Read;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+
+/// A vector of plain `Copy` elements that can be frozen into compressed storage
+/// while it's rarely touched (like old history buffers), trading CPU for memory.
+///
+/// Accessing a frozen vector transparently decompresses it again.
+pub struct CompactFreezableVec<T: Copy, A: Allocator = DefaultHeap> {
+    /// Compressed bytes of the elements, only used while frozen
+    compressed: CompactVec<u8, A>,
+    /// Amount of elements while frozen
+    frozen_len: u32,
+    frozen: bool,
+    /// The elements, decompressed on first access while frozen
+    elements: OnceCell<CompactVec<T, A>>,
+}
+
+impl<T: Copy, A: Allocator> CompactFreezableVec<T, A> {
+    /// Create a new, empty (and not frozen) vector
+    pub fn new() -> Self {
+        CompactVec::new().into()
+    }
+
+    /// Is the vector currently stored compressed?
+    pub fn is_frozen(&self) -> bool {
+        self.frozen
+    }
+
+    /// Compress the elements, freeing their uncompressed storage until the next access
+    pub fn freeze_compressed(&mut self) {
+        if self.frozen {
+            // forget a cached decompression
+            self.elements = OnceCell::new();
+            return;
+        }
+
+        let elements = self.elements.take().expect("not frozen");
+        let bytes = unsafe {
+            ::std::slice::from_raw_parts(
+                elements.as_ptr() as *const u8,
+                elements.len() * mem::size_of::<T>(),
+            )
+        };
+        let compressed = lz::compress(bytes);
+        self.compressed = CompactVec::with_capacity(compressed.len());
+        self.compressed.extend_from_copy_slice(&compressed);
+        self.frozen_len = elements.len() as u32;
+        self.frozen = true;
+    }
+
+    /// Decompress the elements (if frozen) and unfreeze the vector
+    pub fn thaw(&mut self) -> &mut CompactVec<T, A> {
+        if self.frozen {
+            if self.elements.get().is_none() {
+                self.elements = OnceCell::from(self.decompress());
+            }
+            self.compressed = CompactVec::new();
+            self.frozen = false;
+        }
+        self.elements.get_mut().expect("not frozen")
+    }
+
+    fn decompress(&self) -> CompactVec<T, A> {
+        let len = self.frozen_len as usize;
+        let bytes = lz::decompress(&self.compressed, len * mem::size_of::<T>());
+        let mut elements = CompactVec::with_capacity(len);
+        unsafe {
+            ptr::copy_nonoverlapping(
+                bytes.as_ptr(),
+                elements.as_mut_ptr() as *mut u8,
+                bytes.len(),
+            );
+            elements.set_len(len);
+        }
+        elements
+    }
+}
+
+impl<T: Copy, A: Allocator> From<CompactVec<T, A>> for CompactFreezableVec<T, A> {
+    fn from(elements: CompactVec<T, A>) -> Self {
+        CompactFreezableVec {
+            compressed: CompactVec::new(),
+            frozen_len: 0,
+            frozen: false,
+            elements: OnceCell::from(elements),
+        }
+    }
+}
+
+impl<T: Copy, A: Allocator> Default for CompactFreezableVec<T, A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Copy, A: Allocator> Deref for CompactFreezableVec<T, A> {
+    type Target = CompactVec<T, A>;
+
+    fn deref(&self) -> &CompactVec<T, A> {
+        self.elements.get_or_init(|| self.decompress())
+    }
+}
+
+impl<T: Copy, A: Allocator> DerefMut for CompactFreezableVec<T, A> {
+    fn deref_mut(&mut self) -> &mut CompactVec<T, A> {
+        self.thaw()
+    }
+}
+
+impl<T: Copy, A: Allocator> Clone for CompactFreezableVec<T, A> {
+    fn clone(&self) -> Self {
+        CompactFreezableVec {
+            compressed: self.compressed.clone(),
+            frozen_len: self.frozen_len,
+            frozen: self.frozen,
+            elements: self.elements.clone(),
+        }
+    }
+}
+
+/// Only the compressed elements are stored while frozen, a cached decompression is dropped
+impl<T: Copy, A: Allocator> Compact for CompactFreezableVec<T, A> {
+    fn is_still_compact(&self) -> bool {
+        match self.elements.get() {
+            Some(elements) => !self.frozen && elements.is_still_compact(),
+            None => self.compressed.is_still_compact(),
+        }
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        if self.frozen {
+            self.compressed.dynamic_size_bytes()
+        } else {
+            self.elements
+                .get()
+                .expect("not frozen")
+                .dynamic_size_bytes()
+        }
+    }
+
+    fn dynamic_align() -> usize {
+        CompactVec::<u8, A>::dynamic_align().max(CompactVec::<T, A>::dynamic_align())
+    }
+
+    fn collect_stats(&self, path: &str, stats: &mut CompactStats) {
+        if self.frozen {
+            self.compressed.collect_stats(path, stats);
+        } else {
+            self.elements
+                .get()
+                .expect("not frozen")
+                .collect_stats(path, stats);
+        }
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        (*dest).frozen_len = (*source).frozen_len;
+        (*dest).frozen = (*source).frozen;
+        if (*source).frozen {
+            ptr::drop_in_place(&mut (*source).elements);
+            ptr::write(&mut (*dest).elements, OnceCell::new());
+            Compact::compact(
+                &mut (*source).compressed,
+                &mut (*dest).compressed,
+                new_dynamic_part,
+            );
+        } else {
+            ptr::drop_in_place(&mut (*source).compressed);
+            ptr::write(&mut (*dest).compressed, CompactVec::new());
+            ptr::write(&mut (*dest).elements, OnceCell::from(CompactVec::new()));
+            Compact::compact(
+                (*source).elements.get_mut().expect("not frozen"),
+                (*dest).elements.get_mut().expect("not frozen"),
+                new_dynamic_part,
+            );
+        }
+    }
+
+    unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+        if self.frozen {
+            self.compressed.shift_compact_pointers(shift);
+        } else {
+            self.elements
+                .get_mut()
+                .expect("not frozen")
+                .shift_compact_pointers(shift);
+        }
+    }
+
+    unsafe fn decompact(source: *const Self) -> Self {
+        if (*source).frozen {
+            CompactFreezableVec {
+                compressed: Compact::decompact(&(*source).compressed),
+                frozen_len: (*source).frozen_len,
+                frozen: true,
+                elements: OnceCell::new(),
+            }
+        } else {
+            Compact::decompact((*source).elements.get().expect("not frozen")).into()
+        }
+    }
+
+    unsafe fn read_dynamic_part<R: Read>(
+        source: *const Self,
+        reader: &mut CompactReader<R>,
+    ) -> Result<Self, FromReaderError> {
+        if (*source).frozen {
+            Ok(CompactFreezableVec {
+                compressed: reader.read_field(&(*source).compressed)?,
+                frozen_len: (*source).frozen_len,
+                frozen: true,
+                elements: OnceCell::new(),
+            })
+        } else {
+            match (*source).elements.get() {
+                Some(elements) => Ok(reader.read_field(elements)?.into()),
+                None => Err(reader.invalid("CFreezableVec is neither frozen nor has elements")),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a, T, A> ::arbitrary::Arbitrary<'a> for CompactFreezableVec<T, A>
+where
+    T: Copy + ::arbitrary::Arbitrary<'a>,
+    A: Allocator + 'a,
+{
+    fn arbitrary(u: &mut ::arbitrary::Unstructured<'a>) -> ::arbitrary::Result<Self> {
+        let mut vec = Self::from(CompactVec::arbitrary(u)?);
+        if u.arbitrary()? {
+            vec.freeze_compressed();
+        }
+        Ok(vec)
+    }
+
+    fn size_hint(depth: usize) -> (usize, Option<usize>) {
+        ::arbitrary::size_hint::and(
+            <CompactVec<T, A> as ::arbitrary::Arbitrary>::size_hint(depth),
+            <bool as ::arbitrary::Arbitrary>::size_hint(depth),
+        )
+    }
+}
+
+#[test]
+fn freeze_and_thaw() {
+    let history: CompactVec<u32> = (0..10_000).map(|n| n % 16).collect();
+    let mut frozen: CompactFreezableVec<u32> = history.clone().into();
+    let thawed_size = frozen.dynamic_size_bytes();
+
+    frozen.freeze_compressed();
+    assert!(frozen.is_frozen());
+    assert!(frozen.dynamic_size_bytes() < thawed_size / 10);
+
+    // reading decompresses transparently, but stays frozen
+    assert_eq!(frozen[9_999], 15);
+    assert!(frozen.is_frozen());
+    frozen.freeze_compressed();
+
+    // writing unfreezes
+    frozen.push(16);
+    assert!(!frozen.is_frozen());
+    assert_eq!(frozen.len(), 10_001);
+    assert_eq!(frozen[..10_000], history[..]);
+}
+
+#[test]
+fn compact_frozen() {
+    type History = CompactFreezableVec<u64>;
+    let mut history: History = CompactVec::from(vec![7u64; 1000]).into();
+    history.freeze_compressed();
+    // cache a decompression, which shouldn't be compacted
+    assert_eq!(history.len(), 1000);
+
+    let bytes = history.total_size_bytes();
+    assert!(bytes < 1000 * 8 / 10);
+    let storage = DefaultHeap::allocate(bytes);
+    unsafe {
+        Compact::compact_behind(&mut history, storage as *mut History);
+        ::std::mem::forget(history);
+        assert!((*(storage as *mut History)).is_still_compact());
+        let decompacted = Compact::decompact(storage as *mut History);
+        DefaultHeap::deallocate(storage, bytes);
+        assert!(decompacted.is_frozen());
+        assert_eq!(&decompacted[..], &[7u64; 1000][..]);
+    }
+}
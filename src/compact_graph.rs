@@ -0,0 +1,555 @@
+use super::compact::{Compact, CompactReader, CompactStats, DynamicLayout, FromReaderError};
+use super::compact_vec::CompactVec;
+use super::simple_allocator_trait::{Allocator, DefaultHeap};
+use std::io::Read;
+
+/// Marks the end of a list of edges
+const END: u32 = u32::MAX;
+
+/// Index of a node in a `CompactGraph`
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeIndex(u32);
+
+impl NodeIndex {
+    /// Create a node index from a position in the graph's nodes
+    pub fn new(index: usize) -> Self {
+        NodeIndex(index as u32)
+    }
+
+    /// The position of the node in the graph's nodes
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// Index of an edge in a `CompactGraph`
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EdgeIndex(u32);
+
+impl EdgeIndex {
+    /// Create an edge index from a position in the graph's edges
+    pub fn new(index: usize) -> Self {
+        EdgeIndex(index as u32)
+    }
+
+    /// The position of the edge in the graph's edges
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// Which edges of a node to visit
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Edges starting at the node
+    Outgoing = 0,
+    /// Edges ending at the node
+    Incoming = 1,
+}
+
+/// First outgoing and incoming edge of a node
+#[derive(Copy, Clone, Debug)]
+struct NodeLinks {
+    first: [u32; 2],
+}
+
+/// Source and target node of an edge, and the next edges leaving the source
+/// and entering the target
+#[derive(Copy, Clone, Debug)]
+struct EdgeLinks {
+    nodes: [u32; 2],
+    next: [u32; 2],
+}
+
+/// A directed graph with node weights `N` and edge weights `E`, stored in dense `CompactVec`s,
+/// so a whole graph (like a road network) can live in one compact buffer.
+///
+/// The outgoing and incoming edges of each node form linked lists through the edges.
+/// Like `swap_remove`, removing a node or edge moves the last one into its index.
+pub struct CompactGraph<N, E, A: Allocator = DefaultHeap> {
+    node_weights: CompactVec<N, A>,
+    nodes: CompactVec<NodeLinks, A>,
+    edge_weights: CompactVec<E, A>,
+    edges: CompactVec<EdgeLinks, A>,
+}
+
+impl<N: Compact + Clone, E: Compact + Clone, A: Allocator> CompactGraph<N, E, A> {
+    /// Create a new, empty graph
+    pub fn new() -> Self {
+        Self::with_capacity(0, 0)
+    }
+
+    /// Create a new, empty graph with room for `nodes` nodes and `edges` edges
+    pub fn with_capacity(nodes: usize, edges: usize) -> Self {
+        CompactGraph {
+            node_weights: CompactVec::with_capacity(nodes),
+            nodes: CompactVec::with_capacity(nodes),
+            edge_weights: CompactVec::with_capacity(edges),
+            edges: CompactVec::with_capacity(edges),
+        }
+    }
+
+    /// Amount of nodes in the graph
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Amount of edges in the graph
+    pub fn edge_count(&self) -> usize {
+        self.edges.len()
+    }
+
+    /// Add a node with weight `weight` and return its index
+    pub fn add_node(&mut self, weight: N) -> NodeIndex {
+        let index = NodeIndex::new(self.nodes.len());
+        self.node_weights.push(weight);
+        self.nodes.push(NodeLinks { first: [END; 2] });
+        index
+    }
+
+    /// Add an edge from `source` to `target` with weight `weight` and return its index.
+    /// Multiple edges between the same nodes are allowed.
+    ///
+    /// Panics if either node doesn't exist.
+    pub fn add_edge(&mut self, source: NodeIndex, target: NodeIndex, weight: E) -> EdgeIndex {
+        assert!(
+            source.index() < self.nodes.len() && target.index() < self.nodes.len(),
+            "edge between nodes that don't exist"
+        );
+        let index = self.edges.len() as u32;
+        let next = [
+            self.nodes[source.index()].first[0],
+            self.nodes[target.index()].first[1],
+        ];
+        self.nodes[source.index()].first[0] = index;
+        self.nodes[target.index()].first[1] = index;
+        self.edge_weights.push(weight);
+        self.edges.push(EdgeLinks {
+            nodes: [source.0, target.0],
+            next,
+        });
+        EdgeIndex(index)
+    }
+
+    /// The weight of node `node`, if it exists
+    pub fn node_weight(&self, node: NodeIndex) -> Option<&N> {
+        self.node_weights.get(node.index())
+    }
+
+    /// The weight of node `node` mutably, if it exists
+    pub fn node_weight_mut(&mut self, node: NodeIndex) -> Option<&mut N> {
+        self.node_weights.get_mut(node.index())
+    }
+
+    /// The weight of edge `edge`, if it exists
+    pub fn edge_weight(&self, edge: EdgeIndex) -> Option<&E> {
+        self.edge_weights.get(edge.index())
+    }
+
+    /// The weight of edge `edge` mutably, if it exists
+    pub fn edge_weight_mut(&mut self, edge: EdgeIndex) -> Option<&mut E> {
+        self.edge_weights.get_mut(edge.index())
+    }
+
+    /// Source and target node of edge `edge`, if it exists
+    pub fn edge_endpoints(&self, edge: EdgeIndex) -> Option<(NodeIndex, NodeIndex)> {
+        self.edges
+            .get(edge.index())
+            .map(|links| (NodeIndex(links.nodes[0]), NodeIndex(links.nodes[1])))
+    }
+
+    /// Some edge from `source` to `target`, if there is one
+    pub fn find_edge(&self, source: NodeIndex, target: NodeIndex) -> Option<EdgeIndex> {
+        self.edges(source)
+            .find(|&(_, other, _)| other == target)
+            .map(|(edge, _, _)| edge)
+    }
+
+    /// Iterator over the indices of all nodes
+    pub fn node_indices(&self) -> impl Iterator<Item = NodeIndex> {
+        (0..self.nodes.len()).map(NodeIndex::new)
+    }
+
+    /// Iterator over the indices of all edges
+    pub fn edge_indices(&self) -> impl Iterator<Item = EdgeIndex> {
+        (0..self.edges.len()).map(EdgeIndex::new)
+    }
+
+    /// Iterator over the targets of the edges leaving `node`,
+    /// most recently added edge first (neighbors can repeat)
+    pub fn neighbors(&self, node: NodeIndex) -> Neighbors<'_> {
+        self.neighbors_directed(node, Direction::Outgoing)
+    }
+
+    /// Iterator over the other ends of the edges leaving or entering `node`
+    pub fn neighbors_directed(&self, node: NodeIndex, direction: Direction) -> Neighbors<'_> {
+        Neighbors {
+            edges: &self.edges,
+            next: self.first_edge(node, direction),
+            direction: direction as usize,
+        }
+    }
+
+    /// Iterator over the edges leaving `node` as index, target node and weight
+    pub fn edges(&self, node: NodeIndex) -> impl Iterator<Item = (EdgeIndex, NodeIndex, &E)> {
+        let mut next = self.first_edge(node, Direction::Outgoing);
+        ::std::iter::from_fn(move || {
+            if next == END {
+                return None;
+            }
+            let edge = next;
+            let links = &self.edges[edge as usize];
+            next = links.next[0];
+            Some((
+                EdgeIndex(edge),
+                NodeIndex(links.nodes[1]),
+                &self.edge_weights[edge as usize],
+            ))
+        })
+    }
+
+    fn first_edge(&self, node: NodeIndex, direction: Direction) -> u32 {
+        self.nodes
+            .get(node.index())
+            .map_or(END, |links| links.first[direction as usize])
+    }
+
+    /// In the list of edges in `direction` of `node`, let the link pointing to `old` point to `new`
+    fn relink(&mut self, node: u32, direction: usize, old: u32, new: u32) {
+        let first = &mut self.nodes[node as usize].first[direction];
+        if *first == old {
+            *first = new;
+            return;
+        }
+        let mut edge = *first;
+        while edge != END {
+            let next = &mut self.edges[edge as usize].next[direction];
+            if *next == old {
+                *next = new;
+                return;
+            }
+            edge = *next;
+        }
+    }
+
+    /// Remove edge `edge` and return its weight, if it exists.
+    /// The last edge takes over its index.
+    pub fn remove_edge(&mut self, edge: EdgeIndex) -> Option<E> {
+        let removed = *self.edges.get(edge.index())?;
+        for direction in 0..2 {
+            self.relink(
+                removed.nodes[direction],
+                direction,
+                edge.0,
+                removed.next[direction],
+            );
+        }
+
+        let last = self.edges.len() as u32 - 1;
+        self.edges.swap_remove(edge.index());
+        let weight = self.edge_weights.swap_remove(edge.index());
+        if edge.0 != last {
+            let moved = self.edges[edge.index()];
+            for direction in 0..2 {
+                self.relink(moved.nodes[direction], direction, last, edge.0);
+            }
+        }
+        Some(weight)
+    }
+
+    /// Remove node `node` with all its edges and return its weight, if it exists.
+    /// The last node takes over its index.
+    pub fn remove_node(&mut self, node: NodeIndex) -> Option<N> {
+        if node.index() >= self.nodes.len() {
+            return None;
+        }
+        for &direction in &[Direction::Outgoing, Direction::Incoming] {
+            loop {
+                let edge = self.first_edge(node, direction);
+                if edge == END {
+                    break;
+                }
+                self.remove_edge(EdgeIndex(edge));
+            }
+        }
+
+        let last = self.nodes.len() as u32 - 1;
+        self.nodes.swap_remove(node.index());
+        let weight = self.node_weights.swap_remove(node.index());
+        if node.0 != last {
+            for direction in 0..2 {
+                let mut edge = self.nodes[node.index()].first[direction];
+                while edge != END {
+                    let links = &mut self.edges[edge as usize];
+                    links.nodes[direction] = node.0;
+                    edge = links.next[direction];
+                }
+            }
+        }
+        Some(weight)
+    }
+
+    /// Remove all nodes and edges
+    pub fn clear(&mut self) {
+        self.node_weights.clear();
+        self.nodes.clear();
+        self.edge_weights.clear();
+        self.edges.clear();
+    }
+}
+
+/// Iterator over the neighbors of a node, see `CompactGraph::neighbors`
+#[derive(Clone)]
+pub struct Neighbors<'a> {
+    edges: &'a [EdgeLinks],
+    next: u32,
+    direction: usize,
+}
+
+impl<'a> Iterator for Neighbors<'a> {
+    type Item = NodeIndex;
+
+    fn next(&mut self) -> Option<NodeIndex> {
+        if self.next == END {
+            return None;
+        }
+        let links = &self.edges[self.next as usize];
+        self.next = links.next[self.direction];
+        Some(NodeIndex(links.nodes[1 - self.direction]))
+    }
+}
+
+impl<N: Compact + Clone, E: Compact + Clone, A: Allocator> Default for CompactGraph<N, E, A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<N: Compact + Clone, E: Compact + Clone, A: Allocator> Clone for CompactGraph<N, E, A> {
+    fn clone(&self) -> Self {
+        CompactGraph {
+            node_weights: self.node_weights.clone(),
+            nodes: self.nodes.clone(),
+            edge_weights: self.edge_weights.clone(),
+            edges: self.edges.clone(),
+        }
+    }
+}
+
+impl<N: Compact + Clone, E: Compact + Clone, A: Allocator> Compact for CompactGraph<N, E, A> {
+    fn is_still_compact(&self) -> bool {
+        self.node_weights.is_still_compact()
+            && self.nodes.is_still_compact()
+            && self.edge_weights.is_still_compact()
+            && self.edges.is_still_compact()
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        let mut layout = DynamicLayout::new();
+        layout.field(&self.node_weights);
+        layout.field(&self.nodes);
+        layout.field(&self.edge_weights);
+        layout.field(&self.edges);
+        layout.size()
+    }
+
+    fn dynamic_align() -> usize {
+        CompactVec::<N, A>::dynamic_align()
+            .max(CompactVec::<NodeLinks, A>::dynamic_align())
+            .max(CompactVec::<E, A>::dynamic_align())
+            .max(CompactVec::<EdgeLinks, A>::dynamic_align())
+    }
+
+    fn collect_stats(&self, path: &str, stats: &mut CompactStats) {
+        self.node_weights
+            .collect_stats(&format!("{}.node_weights", path), stats);
+        self.nodes.collect_stats(&format!("{}.nodes", path), stats);
+        self.edge_weights
+            .collect_stats(&format!("{}.edge_weights", path), stats);
+        self.edges.collect_stats(&format!("{}.edges", path), stats);
+    }
+
+    fn repair_lossy(&mut self, path: &str, dropped: &mut Vec<String>) {
+        self.node_weights
+            .repair_lossy(&format!("{}.node_weights", path), dropped);
+        self.edge_weights
+            .repair_lossy(&format!("{}.edge_weights", path), dropped);
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        let mut layout = DynamicLayout::new();
+        let node_weights_offset = layout.field(&(*source).node_weights);
+        let nodes_offset = layout.field(&(*source).nodes);
+        let edge_weights_offset = layout.field(&(*source).edge_weights);
+        let edges_offset = layout.field(&(*source).edges);
+        Compact::compact(
+            &mut (*source).node_weights,
+            &mut (*dest).node_weights,
+            new_dynamic_part.add(node_weights_offset),
+        );
+        Compact::compact(
+            &mut (*source).nodes,
+            &mut (*dest).nodes,
+            new_dynamic_part.add(nodes_offset),
+        );
+        Compact::compact(
+            &mut (*source).edge_weights,
+            &mut (*dest).edge_weights,
+            new_dynamic_part.add(edge_weights_offset),
+        );
+        Compact::compact(
+            &mut (*source).edges,
+            &mut (*dest).edges,
+            new_dynamic_part.add(edges_offset),
+        );
+    }
+
+    unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+        self.node_weights.shift_compact_pointers(shift);
+        self.nodes.shift_compact_pointers(shift);
+        self.edge_weights.shift_compact_pointers(shift);
+        self.edges.shift_compact_pointers(shift);
+    }
+
+    unsafe fn decompact(source: *const Self) -> Self {
+        CompactGraph {
+            node_weights: Compact::decompact(&(*source).node_weights),
+            nodes: Compact::decompact(&(*source).nodes),
+            edge_weights: Compact::decompact(&(*source).edge_weights),
+            edges: Compact::decompact(&(*source).edges),
+        }
+    }
+
+    unsafe fn read_dynamic_part<R: Read>(
+        source: *const Self,
+        reader: &mut CompactReader<R>,
+    ) -> Result<Self, FromReaderError> {
+        let graph = CompactGraph {
+            node_weights: reader.read_field(&(*source).node_weights)?,
+            nodes: reader.read_field(&(*source).nodes)?,
+            edge_weights: reader.read_field(&(*source).edge_weights)?,
+            edges: reader.read_field(&(*source).edges)?,
+        };
+        let (node_count, edge_count) = (graph.nodes.len() as u32, graph.edges.len() as u32);
+        let valid = graph.node_weights.len() == graph.nodes.len()
+            && graph.edge_weights.len() == graph.edges.len()
+            && graph
+                .nodes
+                .iter()
+                .all(|links| links.first.iter().all(|&e| e == END || e < edge_count))
+            && graph.edges.iter().all(|links| {
+                links.nodes.iter().all(|&n| n < node_count)
+                    && links.next.iter().all(|&e| e == END || e < edge_count)
+            });
+        if valid {
+            Ok(graph)
+        } else {
+            Err(reader.invalid("graph links point to nodes or edges that don't exist"))
+        }
+    }
+}
+
+impl<N, E, A> ::std::fmt::Debug for CompactGraph<N, E, A>
+where
+    N: Compact + Clone + ::std::fmt::Debug,
+    E: Compact + Clone + ::std::fmt::Debug,
+    A: Allocator,
+{
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("CompactGraph")
+            .field("nodes", &&self.node_weights[..])
+            .field(
+                "edges",
+                &self
+                    .edge_indices()
+                    .map(|edge| {
+                        let (source, target) = self.edge_endpoints(edge).unwrap();
+                        (
+                            source.index(),
+                            target.index(),
+                            &self.edge_weights[edge.index()],
+                        )
+                    })
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+#[test]
+fn add_and_remove() {
+    use super::compact_str::CompactString;
+    let mut graph: CompactGraph<CompactString, u32> = CompactGraph::new();
+    let a = graph.add_node("a".into());
+    let b = graph.add_node("b".into());
+    let c = graph.add_node("c".into());
+    let ab = graph.add_edge(a, b, 1);
+    graph.add_edge(b, c, 2);
+    graph.add_edge(c, a, 3);
+    graph.add_edge(a, c, 4);
+    graph.add_edge(c, c, 5);
+
+    let mut neighbors: Vec<_> = graph.neighbors(a).collect();
+    neighbors.sort();
+    assert_eq!(vec![b, c], neighbors);
+    let mut incoming: Vec<_> = graph.neighbors_directed(c, Direction::Incoming).collect();
+    incoming.sort();
+    assert_eq!(vec![a, b, c], incoming);
+    assert_eq!(
+        Some(&4),
+        graph.find_edge(a, c).and_then(|e| graph.edge_weight(e))
+    );
+
+    // the last edge (c -> c) moves into the index of a -> b
+    assert_eq!(Some(1), graph.remove_edge(ab));
+    assert_eq!(Some((c, c)), graph.edge_endpoints(ab));
+    assert_eq!(None, graph.find_edge(a, b));
+
+    // c moves into the index of a
+    assert_eq!("a", graph.remove_node(a).unwrap().as_str());
+    assert_eq!(2, graph.node_count());
+    assert_eq!(Some("c"), graph.node_weight(a).map(|w| w.as_str()));
+    let mut weights: Vec<_> = graph.edges(a).map(|(_, target, &w)| (target, w)).collect();
+    weights.sort();
+    assert_eq!(vec![(a, 5)], weights);
+    assert_eq!(vec![a, b], {
+        let mut incoming: Vec<_> = graph.neighbors_directed(a, Direction::Incoming).collect();
+        incoming.sort();
+        incoming
+    });
+    assert_eq!(2, graph.edge_count());
+}
+
+#[test]
+fn compact_graph() {
+    use super::compact_str::CompactString;
+    use super::compacted_box::CompactedBox;
+    let mut graph: CompactGraph<CompactString, CompactVec<u32>> = CompactGraph::new();
+    let nodes: Vec<_> = (0..10)
+        .map(|n| graph.add_node(format!("node {}", n).into()))
+        .collect();
+    for (i, &node) in nodes.iter().enumerate() {
+        graph.add_edge(node, nodes[(i + 1) % 10], vec![i as u32; i].into());
+    }
+    graph.remove_node(nodes[3]);
+
+    let mut boxed = CompactedBox::new(graph.clone());
+    assert!(boxed.is_still_compact());
+    assert_eq!(9, boxed.node_count());
+    assert_eq!(
+        graph.neighbors(nodes[5]).collect::<Vec<_>>(),
+        boxed.neighbors(nodes[5]).collect::<Vec<_>>()
+    );
+    let edge = boxed.find_edge(nodes[5], nodes[6]).unwrap();
+    assert_eq!(&[5; 5], &**boxed.edge_weight(edge).unwrap());
+    boxed.with_mut(|graph| graph.edge_weight_mut(edge).unwrap().push(5));
+    assert!(boxed.recompact());
+
+    let mut streamed = Vec::new();
+    boxed.compact_to_writer(&mut streamed).unwrap();
+    let read = unsafe {
+        CompactGraph::<CompactString, CompactVec<u32>>::decompact_from_reader(&mut &streamed[..])
+    }
+    .unwrap();
+    assert_eq!(format!("{:?}", *boxed), format!("{:?}", read));
+}
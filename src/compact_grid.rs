@@ -0,0 +1,281 @@
+use super::compact::{Compact, CompactReader, CompactStats, FromReaderError};
+use super::compact_vec::CompactVec;
+use super::simple_allocator_trait::{Allocator, DefaultHeap};
+use std::io::{self, Read, Write};
+use std::ops::{Index, IndexMut};
+
+/// A dense 2D array (like terrain or zoning of a city), stored row by row in one `CompactVec`
+pub struct CompactGrid<T, A: Allocator = DefaultHeap> {
+    cells: CompactVec<T, A>,
+    width: u32,
+    height: u32,
+}
+
+impl<T: Compact + Clone, A: Allocator> CompactGrid<T, A> {
+    /// Create a grid of `width` times `height` cells, all set to `value`
+    pub fn new(width: usize, height: usize, value: T) -> Self {
+        let mut cells = CompactVec::with_capacity(width * height);
+        for _ in 0..width * height {
+            cells.push(value.clone());
+        }
+        CompactGrid {
+            cells,
+            width: width as u32,
+            height: height as u32,
+        }
+    }
+
+    /// Amount of cells in each row
+    pub fn width(&self) -> usize {
+        self.width as usize
+    }
+
+    /// Amount of rows
+    pub fn height(&self) -> usize {
+        self.height as usize
+    }
+
+    fn index_of(&self, x: usize, y: usize) -> Option<usize> {
+        if x < self.width() && y < self.height() {
+            Some(y * self.width() + x)
+        } else {
+            None
+        }
+    }
+
+    /// The cell in column `x` of row `y`, if it is inside the grid
+    pub fn get(&self, x: usize, y: usize) -> Option<&T> {
+        let index = self.index_of(x, y)?;
+        Some(&self.cells[index])
+    }
+
+    /// The cell in column `x` of row `y` mutably, if it is inside the grid
+    pub fn get_mut(&mut self, x: usize, y: usize) -> Option<&mut T> {
+        let index = self.index_of(x, y)?;
+        Some(&mut self.cells[index])
+    }
+
+    /// Row `y` as a slice, if it is inside the grid
+    pub fn row(&self, y: usize) -> Option<&[T]> {
+        let start = self.index_of(0, y)?;
+        Some(&self.cells[start..start + self.width()])
+    }
+
+    /// Row `y` as a mutable slice, if it is inside the grid
+    pub fn row_mut(&mut self, y: usize) -> Option<&mut [T]> {
+        let start = self.index_of(0, y)?;
+        let width = self.width();
+        Some(&mut self.cells[start..start + width])
+    }
+
+    /// Iterator over all rows, from the top
+    pub fn rows(&self) -> ::std::slice::ChunksExact<'_, T> {
+        self.cells.chunks_exact(self.width().max(1))
+    }
+
+    /// Iterator over all rows as mutable slices, from the top
+    pub fn rows_mut(&mut self) -> ::std::slice::ChunksExactMut<'_, T> {
+        let width = self.width().max(1);
+        self.cells.chunks_exact_mut(width)
+    }
+
+    /// All cells, row by row
+    pub fn as_slice(&self) -> &[T] {
+        &self.cells
+    }
+
+    /// Set all cells to `value`
+    pub fn fill(&mut self, value: T) {
+        for cell in self.cells.iter_mut() {
+            *cell = value.clone();
+        }
+    }
+
+    /// Change the size of the grid, keeping the cells that are inside both sizes
+    /// at their coordinates and setting new ones to `value`
+    pub fn resize(&mut self, width: usize, height: usize, value: T) {
+        let old = ::std::mem::take(self);
+        let mut cells = CompactVec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                cells.push(old.get(x, y).unwrap_or(&value).clone());
+            }
+        }
+        *self = CompactGrid {
+            cells,
+            width: width as u32,
+            height: height as u32,
+        };
+    }
+}
+
+impl<T: Compact + Clone, A: Allocator> Index<(usize, usize)> for CompactGrid<T, A> {
+    type Output = T;
+
+    /// The cell at `(x, y)`, panics if it is outside the grid
+    fn index(&self, (x, y): (usize, usize)) -> &T {
+        match self.get(x, y) {
+            Some(cell) => cell,
+            None => panic!(
+                "({}, {}) is outside of the {}x{} grid",
+                x, y, self.width, self.height
+            ),
+        }
+    }
+}
+
+impl<T: Compact + Clone, A: Allocator> IndexMut<(usize, usize)> for CompactGrid<T, A> {
+    /// The cell at `(x, y)` mutably, panics if it is outside the grid
+    fn index_mut(&mut self, (x, y): (usize, usize)) -> &mut T {
+        let (width, height) = (self.width, self.height);
+        match self.get_mut(x, y) {
+            Some(cell) => cell,
+            None => panic!("({}, {}) is outside of the {}x{} grid", x, y, width, height),
+        }
+    }
+}
+
+impl<T: Compact + Clone, A: Allocator> Compact for CompactGrid<T, A> {
+    fn is_still_compact(&self) -> bool {
+        self.cells.is_still_compact()
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        self.cells.dynamic_size_bytes()
+    }
+
+    fn dynamic_align() -> usize {
+        CompactVec::<T, A>::dynamic_align()
+    }
+
+    fn collect_stats(&self, path: &str, stats: &mut CompactStats) {
+        self.cells.collect_stats(path, stats);
+    }
+
+    fn repair_lossy(&mut self, path: &str, dropped: &mut Vec<String>) {
+        self.cells.repair_lossy(path, dropped);
+        if self.cells.len() != self.width() * self.height() {
+            self.cells = CompactVec::new();
+            self.width = 0;
+            self.height = 0;
+            dropped.push(path.to_owned());
+        }
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        (*dest).width = (*source).width;
+        (*dest).height = (*source).height;
+        Compact::compact(&mut (*source).cells, &mut (*dest).cells, new_dynamic_part)
+    }
+
+    unsafe fn compact_static_part(&self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        (*dest).width = self.width;
+        (*dest).height = self.height;
+        self.cells
+            .compact_static_part(&mut (*dest).cells, new_dynamic_part)
+    }
+
+    fn write_dynamic_part<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.cells.write_dynamic_part(writer)
+    }
+
+    unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+        self.cells.shift_compact_pointers(shift);
+    }
+
+    unsafe fn decompact(source: *const Self) -> Self {
+        CompactGrid {
+            cells: Compact::decompact(&(*source).cells),
+            width: (*source).width,
+            height: (*source).height,
+        }
+    }
+
+    unsafe fn read_dynamic_part<R: Read>(
+        source: *const Self,
+        reader: &mut CompactReader<R>,
+    ) -> Result<Self, FromReaderError> {
+        let grid = CompactGrid {
+            cells: reader.read_field(&(*source).cells)?,
+            width: (*source).width,
+            height: (*source).height,
+        };
+        if grid.cells.len() == grid.width() * grid.height() {
+            Ok(grid)
+        } else {
+            Err(reader.invalid("grid has a different amount of cells than its size"))
+        }
+    }
+}
+
+impl<T: Compact + Clone, A: Allocator> Clone for CompactGrid<T, A> {
+    fn clone(&self) -> Self {
+        CompactGrid {
+            cells: self.cells.clone(),
+            width: self.width,
+            height: self.height,
+        }
+    }
+}
+
+impl<T: Compact + Clone, A: Allocator> Default for CompactGrid<T, A> {
+    fn default() -> Self {
+        CompactGrid {
+            cells: CompactVec::new(),
+            width: 0,
+            height: 0,
+        }
+    }
+}
+
+impl<T: Compact + Clone + ::std::fmt::Debug, A: Allocator> ::std::fmt::Debug for CompactGrid<T, A> {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_list().entries(self.rows()).finish()
+    }
+}
+
+impl<T: Compact + Clone + PartialEq, A: Allocator> PartialEq for CompactGrid<T, A> {
+    fn eq(&self, other: &Self) -> bool {
+        self.width == other.width && self.height == other.height && self.cells == other.cells
+    }
+}
+
+#[test]
+fn get_rows_and_resize() {
+    let mut grid: CompactGrid<u8> = CompactGrid::new(3, 2, 0);
+    grid[(2, 1)] = 5;
+    *grid.get_mut(0, 0).unwrap() = 1;
+    assert_eq!(Some(&5), grid.get(2, 1));
+    assert_eq!(None, grid.get(3, 0));
+    assert_eq!(None, grid.get(0, 2));
+    assert_eq!(
+        vec![&[1, 0, 0][..], &[0, 0, 5][..]],
+        grid.rows().collect::<Vec<_>>()
+    );
+
+    grid.resize(2, 3, 9);
+    assert_eq!(&[1, 0, 0, 0, 9, 9], grid.as_slice());
+    assert_eq!(Some(&[9, 9][..]), grid.row(2));
+    grid.fill(4);
+    assert!(grid.rows().all(|row| row == [4, 4]));
+    grid.resize(0, 0, 0);
+    assert_eq!(0, grid.rows().count());
+}
+
+#[test]
+fn compact_grid() {
+    use super::compact_str::CompactString;
+    use super::compacted_box::CompactedBox;
+    let mut zoning: CompactGrid<CompactString> = CompactGrid::new(4, 4, "empty".into());
+    zoning[(1, 2)] = "residential".into();
+    let boxed = CompactedBox::new(zoning.clone());
+    assert!(boxed.is_still_compact());
+    assert_eq!(zoning, *boxed);
+    assert_eq!("residential", boxed[(1, 2)].as_str());
+
+    let mut stream = Vec::new();
+    zoning.compact_to_writer(&mut stream).unwrap();
+    let read: CompactGrid<CompactString> =
+        unsafe { Compact::decompact_from_reader(&mut &stream[..]) }.unwrap();
+    assert_eq!(zoning, read);
+}
@@ -1,50 +1,118 @@
-extern crate primal;
-
-use super::compact::Compact;
+use super::compact::{
+    align_up, capacity_mode, unordered_hash, CapacityMode, Compact, CompactReader, CompactStats,
+    DynamicLayout, FromReaderError, NeedBytes,
+};
 use super::compact_vec::CompactVec;
 use super::simple_allocator_trait::{Allocator, DefaultHeap};
-use std::collections::hash_map::DefaultHasher;
-#[cfg(test)]
+use super::fx_hash::FxBuildHasher;
+use std::borrow::Borrow;
 use std::collections::HashMap;
 use std::hash::Hash;
-use std::hash::Hasher;
-use std::iter::Iterator;
+use std::hash::{BuildHasher, Hasher};
+use std::iter::{FusedIterator, Iterator};
+use std::slice;
 
 use std;
-use std::fmt::Write;
 
 #[derive(Clone)]
 struct Entry<K, V> {
-    hash: u32,
-    tombstoned: bool,
+    hash: u64,
     inner: Option<(K, V)>,
 }
 
-struct QuadraticProbingIterator<'a, K: 'a, V: 'a, A: 'a + Allocator = DefaultHeap> {
+/// Visits the entries in probing order, up to and including the first free one
+struct LinearProbingIterator<'a, K: 'a, V: 'a, A: 'a + Allocator, H: 'a> {
     i: usize,
-    number_used: usize,
-    hash: u32,
-    map: &'a OpenAddressingMap<K, V, A>,
+    capacity: usize,
+    hash: u64,
+    map: &'a OpenAddressingMap<K, V, A, H>,
 }
 
-struct QuadraticProbingMutIterator<'a, K: 'a, V: 'a, A: 'a + Allocator = DefaultHeap> {
+/// Visits the entries in probing order, up to and including the first free one
+struct LinearProbingMutIterator<'a, K: 'a, V: 'a, A: 'a + Allocator, H: 'a> {
     i: usize,
-    number_used: usize,
-    hash: u32,
-    map: &'a mut OpenAddressingMap<K, V, A>,
+    capacity: usize,
+    hash: u64,
+    map: &'a mut OpenAddressingMap<K, V, A, H>,
 }
 
-/// A dynamically-sized open adressing quadratic probing hashmap
+/// A dynamically-sized open adressing linear probing hashmap
 /// that can be stored in compact sequential storage and
 /// automatically spills over into free heap storage using `Allocator`.
-pub struct OpenAddressingMap<K, V, A: Allocator = DefaultHeap> {
+///
+/// Both keys and values can be `Compact` types with a dynamic part.
+///
+/// Keys are hashed with hashers built by the `H` the map was created with
+/// (see `with_hasher`), and their hashes are stored in the entries.
+/// The `H` is stored bitwise along with the map when compacted,
+/// so compacting requires it to be `Copy`, like `SeededHashBuilder` or `FxBuildHasher`.
+pub struct OpenAddressingMap<K, V, A: Allocator = DefaultHeap, H = DefaultHashBuilder> {
     number_alive: u32,
-    number_used: u32,
     entries: CompactVec<Entry<K, V>, A>,
+    hasher: H,
+}
+
+/// Builds the hashers used by `OpenAddressingMap` unless specified otherwise:
+/// `DefaultHasher`, or `FxHasher` with the `fast-hash` feature
+///
+/// Maps store the hashes of their keys, so compacted maps can only be read
+/// by builds using the same hasher.
+#[cfg(not(feature = "fast-hash"))]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct DefaultHashBuilder;
+/// Builds the hashers used by `OpenAddressingMap` unless specified otherwise:
+/// `DefaultHasher`, or `FxHasher` with the `fast-hash` feature
+///
+/// Maps store the hashes of their keys, so compacted maps can only be read
+/// by builds using the same hasher.
+#[cfg(feature = "fast-hash")]
+pub type DefaultHashBuilder = FxBuildHasher;
+
+#[cfg(not(feature = "fast-hash"))]
+impl BuildHasher for DefaultHashBuilder {
+    type Hasher = ::std::collections::hash_map::DefaultHasher;
+
+    fn build_hasher(&self) -> Self::Hasher {
+        ::std::collections::hash_map::DefaultHasher::new()
+    }
+}
+
+/// Builds `DefaultHasher`s that start from a seed, so that maps can use
+/// a fixed seed in tests and a random one (see `random`) in production.
+///
+/// Unlike `RandomState` it is `Copy`, so maps using it can be compacted.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct SeededHashBuilder {
+    seed: u64,
+}
+
+impl SeededHashBuilder {
+    /// Build hashers starting from `seed`
+    pub fn new(seed: u64) -> Self {
+        SeededHashBuilder { seed }
+    }
+
+    /// Build hashers starting from a random seed, against maliciously chosen keys
+    pub fn random() -> Self {
+        SeededHashBuilder::new(::std::collections::hash_map::RandomState::new().hash_one(0u64))
+    }
+}
+
+impl BuildHasher for SeededHashBuilder {
+    type Hasher = ::std::collections::hash_map::DefaultHasher;
+
+    fn build_hasher(&self) -> Self::Hasher {
+        let mut hasher = ::std::collections::hash_map::DefaultHasher::new();
+        hasher.write_u64(self.seed);
+        hasher
+    }
 }
 
+/// An `OpenAddressingMap` using the fast, but not DoS-resistant `FxHasher`
+pub type FastOpenAddressingMap<K, V, A = DefaultHeap> = OpenAddressingMap<K, V, A, FxBuildHasher>;
+
 impl<K: Eq, V: Clone> Entry<K, V> {
-    fn make_used(&mut self, hash: u32, key: K, value: V) {
+    fn make_used(&mut self, hash: u64, key: K, value: V) {
         self.hash = hash;
         self.inner = Some((key, value));
     }
@@ -61,15 +129,23 @@ impl<K: Eq, V: Clone> Entry<K, V> {
         }
     }
 
-    fn remove(&mut self) -> Option<V> {
-        let old_val = self.value_option().cloned();
-        self.inner = None;
-        self.tombstoned = true;
-        old_val
+    fn remove(&mut self) -> Option<(K, V)>
+    where
+        K: Compact,
+        V: Compact,
+    {
+        // decompact, since key and value might be stored compactly with their dynamic parts
+        let old = self
+            .inner
+            .as_ref()
+            .map(|(key, value)| unsafe { (Compact::decompact(key), Compact::decompact(value)) });
+        // both were moved out by decompacting
+        ::std::mem::forget(self.inner.take());
+        old
     }
 
     fn used(&self) -> bool {
-        self.tombstoned || self.inner.is_some()
+        self.inner.is_some()
     }
 
     fn alive(&self) -> bool {
@@ -77,7 +153,7 @@ impl<K: Eq, V: Clone> Entry<K, V> {
     }
 
     fn free(&self) -> bool {
-        self.inner.is_none() && (!self.tombstoned)
+        self.inner.is_none()
     }
 
     fn key(&self) -> &K {
@@ -100,8 +176,13 @@ impl<K: Eq, V: Clone> Entry<K, V> {
         self.inner.as_mut().map(|kv| &mut kv.1)
     }
 
-    fn is_this(&self, key: &K) -> bool {
-        self.inner.as_ref().map_or(false, |kv| &kv.0 == key)
+    /// Whether this entry holds `key`, which hashes to `hash`.
+    /// Only compares the keys if the stored hash matches.
+    fn is_this<Q: Eq + ?Sized>(&self, hash: u64, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+    {
+        self.hash == hash && self.inner.as_ref().is_some_and(|kv| kv.0.borrow() == key)
     }
 
     fn into_tuple(self) -> (K, V) {
@@ -111,6 +192,22 @@ impl<K: Eq, V: Clone> Entry<K, V> {
     }
 }
 
+impl<K: Compact + Eq, V: Compact> Entry<K, V> {
+    /// Replace the value of a live entry with `f(key, old_value)`
+    fn update_value<F: FnOnce(&K, V) -> V>(&mut self, f: F) {
+        debug_assert!(self.alive());
+        // decompact, since key and value might be stored compactly with their dynamic parts
+        let (key, old_value) = unsafe {
+            let taken = Compact::decompact(self as *const Self);
+            ::std::ptr::write(&mut self.inner, None);
+            taken.into_tuple()
+        };
+        // if `f` panics, the entry is left empty
+        let new_value = f(&key, old_value);
+        self.inner = Some((key, new_value));
+    }
+}
+
 impl<K, V> std::fmt::Debug for Entry<K, V> {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "Entry {:?}, {:?}", self.hash, self.inner.is_some())
@@ -121,152 +218,271 @@ impl<K, V> Default for Entry<K, V> {
     fn default() -> Self {
         Entry {
             hash: 0,
-            tombstoned: false,
             inner: None,
         }
     }
 }
 
-impl<K: Copy, V: Compact> Compact for Entry<K, V> {
+impl<K: Compact, V: Compact> Compact for Entry<K, V> {
     fn is_still_compact(&self) -> bool {
-        if std::mem::needs_drop::<V>() {
-            if self.tombstoned {
-                true
-            } else {
-                self.inner
-                    .as_ref()
-                    .map_or(true, |kv_tuple| kv_tuple.1.is_still_compact())
-            }
+        if std::mem::needs_drop::<K>() || std::mem::needs_drop::<V>() {
+            self.inner.as_ref().is_none_or(|kv_tuple| {
+                kv_tuple.0.is_still_compact() && kv_tuple.1.is_still_compact()
+            })
         } else {
             true
         }
     }
 
     fn dynamic_size_bytes(&self) -> usize {
-        if std::mem::needs_drop::<V>() {
-            if self.tombstoned {
-                0
-            } else {
-                self.inner
-                    .as_ref()
-                    .map_or(0, |kv_tuple| kv_tuple.1.dynamic_size_bytes())
-            }
+        if std::mem::needs_drop::<K>() || std::mem::needs_drop::<V>() {
+            self.inner.as_ref().map_or(0, |kv_tuple| {
+                let mut layout = DynamicLayout::new();
+                layout.field(&kv_tuple.0);
+                layout.field(&kv_tuple.1);
+                layout.size()
+            })
         } else {
             0
         }
     }
 
+    fn dynamic_align() -> usize {
+        K::dynamic_align().max(V::dynamic_align())
+    }
+
     unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
         (*dest).hash = (*source).hash;
-        (*dest).tombstoned = (*source).tombstoned;
 
-        if std::mem::needs_drop::<V>() {
+        if std::mem::needs_drop::<K>() || std::mem::needs_drop::<V>() {
             ::std::ptr::copy_nonoverlapping(&(*source).inner, &mut (*dest).inner, 1);
             if (*dest).inner.is_some() {
+                let source_kv = (*source).inner.as_mut().unwrap();
+                let dest_kv = (*dest).inner.as_mut().unwrap();
+                // the dynamic part of the value follows the one of the key
+                let mut layout = DynamicLayout::new();
+                let key_offset = layout.field(&source_kv.0);
+                let value_offset = layout.field(&source_kv.1);
                 Compact::compact(
-                    &mut (*source).inner.as_mut().unwrap().1,
-                    &mut (*dest).inner.as_mut().unwrap().1,
-                    new_dynamic_part,
-                )
+                    &mut source_kv.0,
+                    &mut dest_kv.0,
+                    new_dynamic_part.add(key_offset),
+                );
+                Compact::compact(
+                    &mut source_kv.1,
+                    &mut dest_kv.1,
+                    new_dynamic_part.add(value_offset),
+                );
             }
         } else {
             (*dest).inner = std::ptr::read(&(*source).inner);
         }
     }
 
+    fn collect_stats(&self, path: &str, stats: &mut CompactStats) {
+        if let Some((ref key, ref value)) = self.inner {
+            key.collect_stats(&format!("{}.key", path), stats);
+            value.collect_stats(&format!("{}.value", path), stats);
+        }
+    }
+
+    fn repair_lossy(&mut self, path: &str, dropped: &mut Vec<String>) {
+        if let Some((ref mut key, ref mut value)) = self.inner {
+            key.repair_lossy(&format!("{}.key", path), dropped);
+            value.repair_lossy(&format!("{}.value", path), dropped);
+        }
+    }
+
+    fn clear_dirty(&mut self) {
+        if let Some((_, ref mut value)) = self.inner {
+            value.clear_dirty();
+        }
+    }
+
+    unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+        if let Some((ref mut key, ref mut value)) = self.inner {
+            key.shift_compact_pointers(shift);
+            value.shift_compact_pointers(shift);
+        }
+    }
+
     unsafe fn decompact(source: *const Self) -> Entry<K, V> {
         if (*source).inner.is_none() {
             Entry {
                 hash: (*source).hash,
-                tombstoned: (*source).tombstoned,
                 inner: None,
             }
-        } else if std::mem::needs_drop::<V>() {
+        } else if std::mem::needs_drop::<K>() || std::mem::needs_drop::<V>() {
             let insides = (*source).inner.as_ref().unwrap();
             Entry {
                 hash: (*source).hash,
-                tombstoned: (*source).tombstoned,
-                inner: Some((insides.0, (Compact::decompact(&insides.1)))),
+                inner: Some((
+                    Compact::decompact(&insides.0),
+                    Compact::decompact(&insides.1),
+                )),
             }
         } else {
             Entry {
                 hash: (*source).hash,
-                tombstoned: (*source).tombstoned,
                 inner: std::ptr::read(&(*source).inner),
             }
         }
     }
+
+    unsafe fn read_dynamic_part<R: ::std::io::Read>(
+        source: *const Self,
+        reader: &mut CompactReader<R>,
+    ) -> Result<Entry<K, V>, FromReaderError> {
+        if (*source).inner.is_some() && (std::mem::needs_drop::<K>() || std::mem::needs_drop::<V>())
+        {
+            let insides = (*source).inner.as_ref().unwrap();
+            Ok(Entry {
+                hash: (*source).hash,
+                inner: Some((
+                    reader.read_field(&insides.0)?,
+                    reader.read_field(&insides.1)?,
+                )),
+            })
+        } else {
+            Ok(Compact::decompact(source))
+        }
+    }
 }
 
-lazy_static! {
-    static ref PRIME_SIEVE: primal::Sieve = primal::Sieve::new(1_000_000);
+/// The entry where probing for `hash` starts, in entries of a power-of-two `capacity`.
+///
+/// Uses Fibonacci hashing, so that all bits of the hash affect the entry,
+/// not only the lowest ones picked by the mask.
+fn home_slot(hash: u64, capacity: usize) -> usize {
+    debug_assert!(capacity.is_power_of_two());
+    let bits = capacity.trailing_zeros();
+    if bits == 0 {
+        0
+    } else {
+        (hash.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (64 - bits)) as usize
+    }
 }
 
-impl<'a, K: Copy, V: Compact, A: Allocator> QuadraticProbingIterator<'a, K, V, A> {
+/// The first entry from the home slot of `hash` on that isn't `used`,
+/// in entries of a power-of-two `capacity` that aren't all used
+fn first_free_slot<F: Fn(usize) -> bool>(hash: u64, capacity: usize, used: F) -> usize {
+    let mut slot = home_slot(hash, capacity);
+    while used(slot) {
+        slot = (slot + 1) & (capacity - 1);
+    }
+    slot
+}
+
+/// Count a probe sequence that visited `length` entries, with the `metrics` feature
+#[inline]
+fn record_probe(length: usize) {
+    #[cfg(feature = "metrics")]
+    super::metrics::MAP.record_probe(length);
+    #[cfg(not(feature = "metrics"))]
+    let _ = length;
+}
+
+impl<'a, K: Compact, V: Compact, A: Allocator, H> LinearProbingIterator<'a, K, V, A, H> {
     fn for_map(
-        map: &'a OpenAddressingMap<K, V, A>,
-        hash: u32,
-    ) -> QuadraticProbingIterator<'a, K, V, A> {
-        QuadraticProbingIterator {
+        map: &'a OpenAddressingMap<K, V, A, H>,
+        hash: u64,
+    ) -> LinearProbingIterator<'a, K, V, A, H> {
+        LinearProbingIterator {
             i: 0,
-            number_used: map.entries.capacity(),
+            capacity: map.entries.capacity(),
             hash,
             map,
         }
     }
 }
 
-impl<'a, K: Copy, V: Compact, A: Allocator> QuadraticProbingMutIterator<'a, K, V, A> {
+impl<'a, K: Compact, V: Compact, A: Allocator, H> LinearProbingMutIterator<'a, K, V, A, H> {
     fn for_map(
-        map: &'a mut OpenAddressingMap<K, V, A>,
-        hash: u32,
-    ) -> QuadraticProbingMutIterator<'a, K, V, A> {
-        QuadraticProbingMutIterator {
+        map: &'a mut OpenAddressingMap<K, V, A, H>,
+        hash: u64,
+    ) -> LinearProbingMutIterator<'a, K, V, A, H> {
+        LinearProbingMutIterator {
             i: 0,
-            number_used: map.entries.capacity(),
+            capacity: map.entries.capacity(),
             hash,
             map,
         }
     }
 }
 
-impl<'a, K, V, A: Allocator> Iterator for QuadraticProbingIterator<'a, K, V, A> {
+impl<'a, K, V, A: Allocator, H> Iterator for LinearProbingIterator<'a, K, V, A, H> {
     type Item = &'a Entry<K, V>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.i >= self.number_used {
+        if self.i >= self.capacity {
             return None;
         }
-        let index = (self.hash as usize + self.i * self.i) % self.number_used;
-        self.i += 1;
-        Some(&self.map.entries[index])
+        let index = (home_slot(self.hash, self.capacity) + self.i) & (self.capacity - 1);
+        let entry = &self.map.entries[index];
+        // no entry of this probing sequence can come after a free one
+        self.i = if entry.inner.is_none() { self.capacity } else { self.i + 1 };
+        Some(entry)
     }
 }
 
-impl<'a, K, V, A: Allocator> Iterator for QuadraticProbingMutIterator<'a, K, V, A> {
+impl<'a, K, V, A: Allocator, H> Iterator for LinearProbingMutIterator<'a, K, V, A, H> {
     type Item = &'a mut Entry<K, V>;
     fn next(&mut self) -> Option<&'a mut Entry<K, V>> {
-        if self.i >= self.number_used {
+        if self.i >= self.capacity {
             return None;
         }
-        let index = (self.hash as usize + self.i * self.i) % self.number_used;
-        self.i += 1;
-        Some(unsafe { &mut *(&mut self.map.entries[index] as *mut Entry<K, V>) })
+        let index = (home_slot(self.hash, self.capacity) + self.i) & (self.capacity - 1);
+        let entry = unsafe { &mut *(&mut self.map.entries[index] as *mut Entry<K, V>) };
+        // no entry of this probing sequence can come after a free one
+        self.i = if entry.inner.is_none() { self.capacity } else { self.i + 1 };
+        Some(entry)
+    }
+}
+
+impl<K: Compact + Eq + Hash, V: Compact, A: Allocator, H: BuildHasher>
+    OpenAddressingMap<K, V, A, H>
+{
+    /// Create a map hashing keys with hashers built by `hasher`,
+    /// like a fixed-seed one in tests and a randomized one in production
+    pub fn with_hasher(hasher: H) -> Self {
+        Self::with_capacity_and_hasher(4, hasher)
+    }
+
+    /// Create a map with room for `l` entries, hashing keys with hashers built by `hasher`
+    pub fn with_capacity_and_hasher(l: usize, hasher: H) -> Self {
+        OpenAddressingMap {
+            entries: vec![Entry::default(); Self::round_capacity(l)]
+                .into_iter()
+                .collect(),
+            number_alive: 0,
+            hasher,
+        }
+    }
+
+    /// The builder of the hashers used for keys
+    pub fn hasher(&self) -> &H {
+        &self.hasher
+    }
+
+    fn round_capacity(n: usize) -> usize {
+        n.max(1).next_power_of_two()
     }
 }
 
-impl<K: Copy + Eq + Hash, V: Compact, A: Allocator> OpenAddressingMap<K, V, A> {
+impl<K: Compact + Eq + Hash, V: Compact, A: Allocator, H: BuildHasher + Default>
+    OpenAddressingMap<K, V, A, H>
+{
+    /// The highest load factor `shrink_to` rehashes into. Some entries always stay free,
+    /// since lookups of missing keys only stop at a free entry.
+    pub const MAX_LOAD_FACTOR: f32 = 0.875;
+
     /// constructor
     pub fn new() -> Self {
         Self::with_capacity(4)
     }
     /// constructor
     pub fn with_capacity(l: usize) -> Self {
-        OpenAddressingMap {
-            entries: vec![Entry::default(); Self::find_next_prime(l)].into(),
-            number_alive: 0,
-            number_used: 0,
-        }
+        Self::with_capacity_and_hasher(l, H::default())
     }
 
     /// Amount of entries in the dictionary
@@ -274,90 +490,356 @@ impl<K: Copy + Eq + Hash, V: Compact, A: Allocator> OpenAddressingMap<K, V, A> {
         self.number_alive as usize
     }
 
-    /// Amount of used entries in the dictionary
-    #[cfg(test)]
-    pub fn len_used(&self) -> usize {
-        self.number_used as usize
-    }
-
     /// Capacity of the dictionary
     #[cfg(test)]
     pub fn capacity(&self) -> usize {
         self.entries.capacity()
     }
 
+    /// Amount of entries visited to find the entry that is furthest from its home slot.
+    /// Bounds how many entries a lookup visits, since lookups of missing keys
+    /// stop at the first free entry (there are no tombstones to probe past).
+    pub fn max_probe_length(&self) -> usize {
+        let capacity = self.entries.capacity();
+        self.entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.used())
+            .map(|(index, entry)| {
+                (index.wrapping_sub(home_slot(entry.hash, capacity)) & (capacity - 1)) + 1
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
     /// Is the dictionary empty?
     pub fn is_empty(&self) -> bool {
         self.number_alive == 0
     }
 
-    /// Look up the value for key `query`, if it exists
-    pub fn get(&self, query: K) -> Option<&V> {
+    /// Look up the value for key `query`, if it exists.
+    /// `query` can be any borrowed form of the key type, like `&str` for `CString` keys.
+    pub fn get<Q: Hash + Eq + ?Sized>(&self, query: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+    {
         self.find_used(query).and_then(|e| e.value_option())
     }
 
     /// get mutable
-    pub fn get_mut(&mut self, query: K) -> Option<&mut V> {
+    pub fn get_mut<Q: Hash + Eq + ?Sized>(&mut self, query: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+    {
         self.find_used_mut(query).and_then(|e| e.mut_value_option())
     }
 
+    /// Look up the values at all `queries` mutably at once, like to move something between them.
+    /// Returns `None` if a query doesn't exist or if two queries are the same key.
+    pub fn get_many_mut<Q: Hash + Eq + ?Sized, const N: usize>(
+        &mut self,
+        queries: [&Q; N],
+    ) -> Option<[&mut V; N]>
+    where
+        K: Borrow<Q>,
+    {
+        let mut indices = [0; N];
+        for (i, &query) in queries.iter().enumerate() {
+            let index = match self.find_slot(self.hash_key(query), query) {
+                Some((index, true)) => index,
+                _ => return None,
+            };
+            if indices[..i].contains(&index) {
+                return None;
+            }
+            indices[i] = index;
+        }
+        let entries = self.entries.as_mut_ptr();
+        // the entries are distinct, so the references don't alias
+        Some(indices.map(|index| unsafe { (*entries.add(index)).mut_value() }))
+    }
+
+    /// Look up entries by hashes computed elsewhere (see `RawEntryBuilder::from_hash`)
+    pub fn raw_entry(&self) -> RawEntryBuilder<'_, K, V, A, H> {
+        RawEntryBuilder { map: self }
+    }
+
+    /// Look up, insert or remove entries by hashes computed elsewhere
+    /// (see `RawEntryBuilderMut::from_hash`)
+    pub fn raw_entry_mut(&mut self) -> RawEntryBuilderMut<'_, K, V, A, H> {
+        RawEntryBuilderMut { map: self }
+    }
+
     /// Does the dictionary contain a value for `query`?
-    pub fn contains_key(&self, query: K) -> bool {
-        self.get(query).map_or(false, |_| true)
+    pub fn contains_key<Q: Hash + Eq + ?Sized>(&self, query: &Q) -> bool
+    where
+        K: Borrow<Q>,
+    {
+        self.get(query).is_some()
     }
 
-    /// Insert new value at key `query` and return the previous value at that key, if any existed
+    /// Insert new value at key `query` and return the previous value at that key, if any existed.
+    ///
+    /// Never panics because of a lack of capacity: if probing finds no free entry,
+    /// the map grows and the insert is retried.
     pub fn insert(&mut self, query: K, value: V) -> Option<V> {
         self.insert_inner_growing(query, value)
     }
 
+    /// Insert all `pairs`, replacing the values of existing keys.
+    /// Makes room for all of them at once (counting them first if their iterator
+    /// doesn't know its exact length), so inserting them doesn't rehash again.
+    pub fn insert_many<I: IntoIterator<Item = (K, V)>>(&mut self, pairs: I) {
+        let pairs = pairs.into_iter();
+        match pairs.size_hint() {
+            (lower, Some(upper)) if lower == upper => self.insert_many_exact(lower, pairs),
+            _ => {
+                let pairs: Vec<(K, V)> = pairs.collect();
+                self.insert_many_exact(pairs.len(), pairs.into_iter())
+            }
+        }
+    }
+
+    fn insert_many_exact<I: Iterator<Item = (K, V)>>(&mut self, count: usize, pairs: I) {
+        self.reserve(count);
+        for (key, value) in pairs {
+            self.insert_inner(key, value);
+        }
+    }
+
+    /// Look up the value for key `query` mutably, inserting `default()` first
+    /// if it doesn't exist yet. Only probes the map once.
+    pub fn get_or_insert_with<F: FnOnce() -> V>(&mut self, query: K, default: F) -> &mut V {
+        self.ensure_capacity();
+        let hash = self.hash_key(&query);
+        loop {
+            match self.find_slot(hash, &query) {
+                Some((index, true)) => return self.entries[index].mut_value(),
+                Some((index, false)) => {
+                    self.entries[index].make_used(hash, query, default());
+                    self.number_alive += 1;
+                    return self.entries[index].mut_value();
+                }
+                None => {
+                    // probing didn't reach a free entry, grow and retry
+                    let new_capacity = self.entries.capacity() * 2;
+                    self.rehash(new_capacity);
+                }
+            }
+        }
+    }
+
+    /// Look up the value for key `query` mutably, inserting `V::default()` first
+    /// if it doesn't exist yet. Only probes the map once.
+    pub fn get_or_insert_default(&mut self, query: K) -> &mut V
+    where
+        V: Default,
+    {
+        self.get_or_insert_with(query, V::default)
+    }
+
+    /// Rehash into the smallest (power-of-two) capacity that keeps the amount of entries
+    /// at or below `load_factor` of the capacity.
+    ///
+    /// Higher load factors save memory, but make lookups slower.
+    /// Load factors above 1/2 only last until the next insert grows the map again,
+    /// and ones above `MAX_LOAD_FACTOR` are lowered to it.
+    pub fn shrink_to(&mut self, load_factor: f32) {
+        assert!(
+            load_factor > 0.0 && load_factor <= 1.0,
+            "load factor has to be in (0, 1]"
+        );
+        let load_factor = load_factor.min(Self::MAX_LOAD_FACTOR);
+        let new_capacity = (self.number_alive as f32 / load_factor).ceil() as usize;
+        self.rehash(new_capacity.max(1));
+    }
+
+    /// Rehash into the smallest (power-of-two) capacity that still keeps the map at most half full.
+    /// Never grows the map.
+    pub fn shrink_to_fit(&mut self) {
+        if self.trims_in_shrink_to_fit() {
+            self.rehash((self.number_alive as usize * 2).max(1));
+        }
+    }
+
+    /// Make room for at least `additional` more entries, so that inserting them
+    /// will at most rehash once, right now, into a (power-of-two) capacity at most half full
+    pub fn reserve(&mut self, additional: usize) {
+        if self.number_alive as usize + additional > self.entries.capacity() / 2 {
+            let needed = (self.number_alive as usize + additional) * 2;
+            let new_capacity = needed.max(self.entries.capacity() * 2);
+            self.rehash(new_capacity);
+        }
+    }
+
     /// Remove value at key `query` and return it, if it existed
-    pub fn remove(&mut self, query: K) -> Option<V> {
+    pub fn remove<Q: Hash + Eq + ?Sized>(&mut self, query: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+    {
+        self.remove_inner(query).map(|(_, value)| value)
+    }
+
+    /// Remove the entry for key `query` and return its key and value, if it existed
+    pub fn remove_entry<Q: Hash + Eq + ?Sized>(&mut self, query: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+    {
         self.remove_inner(query)
     }
 
     /// Iterator over all keys in the dictionary
-    pub fn keys<'a>(&'a self) -> impl Iterator<Item = &'a K> + 'a {
-        self.entries.iter().filter(|e| e.alive()).map(|e| e.key())
+    pub fn keys(&self) -> Keys<'_, K, V> {
+        Keys {
+            entries: self.entries.iter(),
+            remaining: self.len(),
+        }
+    }
+
+    /// A set view of all keys in the dictionary, for set algebra with the keys of other maps
+    pub fn keys_set(&self) -> KeySet<'_, K, V, A, H> {
+        KeySet { map: self }
     }
 
     /// Iterator over all values in the dictionary
-    pub fn values<'a>(&'a self) -> impl Iterator<Item = &'a V> + 'a {
-        self.entries.iter().filter(|e| e.alive()).map(|e| e.value())
+    pub fn values(&self) -> Values<'_, K, V> {
+        Values {
+            entries: self.entries.iter(),
+            remaining: self.len(),
+        }
     }
 
     /// Iterator over mutable references to all values in the dictionary
-    pub fn values_mut<'a>(&'a mut self) -> impl Iterator<Item = &'a mut V> + 'a {
-        self.entries
-            .iter_mut()
-            .filter(|e| e.alive())
-            .map(|e| e.mut_value())
+    pub fn values_mut(&mut self) -> ValuesMut<'_, K, V> {
+        ValuesMut {
+            remaining: self.len(),
+            entries: self.entries.iter_mut(),
+        }
     }
 
     /// Iterator over all key-value pairs in the dictionary
-    pub fn pairs<'a>(&'a self) -> impl Iterator<Item = (&'a K, &'a V)> + 'a {
-        self.entries
-            .iter()
-            .filter(|e| e.alive())
-            .map(|e| (e.key(), e.value()))
+    pub fn pairs(&self) -> Iter<'_, K, V> {
+        self.iter()
+    }
+
+    /// Iterator over all key-value pairs in the dictionary, like `pairs`
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            entries: self.entries.iter(),
+            remaining: self.len(),
+        }
+    }
+
+    /// Iterator over all key-value pairs in the dictionary,
+    /// with the value as a mutable reference
+    pub fn iter_mut(&mut self) -> IterMut<'_, K, V> {
+        IterMut {
+            remaining: self.len(),
+            entries: self.entries.iter_mut(),
+        }
     }
 
     /// Iterator over all key-value pairs in the dictionary,
     /// with the value as a mutable reference
-    pub fn pairs_mut<'a>(&'a mut self) -> impl Iterator<Item = (K, &'a mut V)> + 'a
+    pub fn pairs_mut(&mut self) -> PairsMut<'_, K, V>
     where
         K: Copy,
     {
-        self.entries
-            .iter_mut()
-            .filter(|e| e.alive())
-            .map(|e| (*e.key(), e.mut_value()))
+        PairsMut {
+            pairs: self.iter_mut(),
+        }
     }
 
-    fn hash(key: K) -> u32 {
-        let mut hasher = DefaultHasher::new();
-        key.hash(&mut hasher);
-        hasher.finish() as u32
+    /// Move all entries of `other` into this map. For keys present in both maps,
+    /// the value becomes `resolve(key, value_in_self, value_in_other)`.
+    ///
+    /// Room for all entries of `other` is reserved up front.
+    pub fn merge<F: FnMut(&K, V, V) -> V>(&mut self, mut other: Self, mut resolve: F) {
+        self.reserve(other.len());
+        let shares_hashes = self.shares_hashes(&other);
+        for entry in other.entries.drain(..).filter(|e| e.alive()) {
+            let hash = if shares_hashes {
+                entry.hash
+            } else {
+                self.hash_key(entry.key())
+            };
+            let (key, value) = entry.into_tuple();
+            match self.find_used_hashed_mut(hash, &key) {
+                Some(existing) => existing.update_value(|key, old| resolve(key, old, value)),
+                None => {
+                    self.insert_inner(key, value);
+                }
+            }
+        }
+    }
+
+    /// Create a new map with the entries of both maps. For keys present in both,
+    /// the value becomes `resolve(key, value_in_self, value_in_other)`.
+    pub fn union_with<B: Allocator, F: FnMut(&K, &V, &V) -> V>(
+        &self,
+        other: &OpenAddressingMap<K, V, B, H>,
+        mut resolve: F,
+    ) -> Self
+    where
+        H: Clone,
+    {
+        let mut union =
+            Self::with_capacity_and_hasher((self.len() + other.len()) * 2, self.hasher.clone());
+        for entry in self.entries.iter().filter(|e| e.alive()) {
+            let value = match other.find_used_stored(entry, self) {
+                Some(other_entry) => resolve(entry.key(), entry.value(), other_entry.value()),
+                None => entry.value().clone(),
+            };
+            union.insert_inner(entry.key().clone(), value);
+        }
+        for entry in other.entries.iter().filter(|e| e.alive()) {
+            if self.find_used_stored(entry, other).is_none() {
+                union.insert_inner(entry.key().clone(), entry.value().clone());
+            }
+        }
+        union
+    }
+
+    /// Compare this map (the old state) to `other` (the new state), collecting
+    /// the keys that were added, removed or whose value changed.
+    ///
+    /// Lookups reuse the hashes stored in the entries if the maps share their hashes,
+    /// so no key is hashed again.
+    pub fn diff<B: Allocator>(&self, other: &OpenAddressingMap<K, V, B, H>) -> MapDiff<K>
+    where
+        K: Clone,
+        V: PartialEq,
+    {
+        let mut diff = MapDiff {
+            added: CompactVec::new(),
+            removed: CompactVec::new(),
+            changed: CompactVec::new(),
+        };
+        for entry in self.entries.iter().filter(|e| e.alive()) {
+            match other.find_used_stored(entry, self) {
+                None => diff.removed.push(entry.key().clone()),
+                Some(other_entry) if other_entry.value() != entry.value() => {
+                    diff.changed.push(entry.key().clone())
+                }
+                Some(_) => {}
+            }
+        }
+        for entry in other.entries.iter().filter(|e| e.alive()) {
+            if self.find_used_stored(entry, other).is_none() {
+                diff.added.push(entry.key().clone());
+            }
+        }
+        diff
+    }
+
+    fn hash_key<Q: Hash + ?Sized>(&self, key: &Q) -> u64 {
+        self.hasher.hash_one(key)
+    }
+
+    /// Can hashes stored in the entries of `other` be used to look up keys in this map?
+    /// Only known for stateless hash builders, which hash equally every time.
+    fn shares_hashes<W, B: Allocator>(&self, _other: &OpenAddressingMap<K, W, B, H>) -> bool {
+        ::std::mem::size_of::<H>() == 0
     }
 
     fn insert_inner_growing(&mut self, query: K, value: V) -> Option<V> {
@@ -366,102 +848,220 @@ impl<K: Copy + Eq + Hash, V: Compact, A: Allocator> OpenAddressingMap<K, V, A> {
     }
 
     fn insert_inner(&mut self, query: K, value: V) -> Option<V> {
-        let res = self.insert_inner_inner(query, value);
-        if res.is_none() {
-            self.number_alive += 1;
-            self.number_used += 1;
+        let mut pair = (query, value);
+        loop {
+            match self.insert_inner_inner(pair.0, pair.1) {
+                Ok(res) => {
+                    if res.is_none() {
+                        self.number_alive += 1;
+                    }
+                    return res;
+                }
+                Err(rejected) => {
+                    // probing didn't reach a free entry, grow and retry
+                    let new_capacity = self.entries.capacity() * 2;
+                    self.rehash(new_capacity);
+                    pair = rejected;
+                }
+            }
         }
-        res
     }
 
-    fn insert_inner_inner(&mut self, query: K, value: V) -> Option<V> {
-        let hash = Self::hash(query);
-        for entry in self.quadratic_iterator_mut(hash) {
+    /// Returns the pair back if probing didn't find a place for it
+    fn insert_inner_inner(&mut self, query: K, value: V) -> Result<Option<V>, (K, V)> {
+        let hash = self.hash_key(&query);
+        for (probed, entry) in self.probing_iterator_mut(hash).enumerate() {
             if entry.free() {
+                record_probe(probed + 1);
                 entry.make_used(hash, query, value);
-                return None;
-            } else if entry.is_this(&query) {
-                return entry.replace_value(value);
+                return Ok(None);
+            } else if entry.is_this(hash, &query) {
+                record_probe(probed + 1);
+                return Ok(entry.replace_value(value));
             }
         }
-        panic!("should have place")
+        Err((query, value))
     }
 
-    fn remove_inner(&mut self, query: K) -> Option<V> {
-        // remove inner does not alter the size because of tombstones
-        let old = self.remove_inner_inner(query);
-        if old.is_some() {
-            self.number_alive -= 1;
+    fn remove_inner<Q: Hash + Eq + ?Sized>(&mut self, query: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+    {
+        let hash = self.hash_key(query);
+        match self.find_slot(hash, query) {
+            Some((index, true)) => Some(self.remove_at(index)),
+            _ => None,
         }
-        old
     }
 
-    fn remove_inner_inner(&mut self, query: K) -> Option<V> {
-        let hash = Self::hash(query);
-        for entry in self.quadratic_iterator_mut(hash) {
-            if entry.is_this(&query) {
-                return entry.remove();
+    /// Removes the live entry at `hole` using backward-shift deletion:
+    /// later entries of the same probing run move back into the hole,
+    /// so that no tombstones are needed and probing runs don't grow from removals
+    fn remove_at(&mut self, mut hole: usize) -> (K, V) {
+        let old = self.entries[hole].remove().expect("entry should be alive");
+        self.number_alive -= 1;
+
+        let capacity = self.entries.capacity();
+        let mask = capacity - 1;
+        let mut index = hole;
+        loop {
+            index = (index + 1) & mask;
+            if self.entries[index].free() {
+                break;
+            }
+            let home = home_slot(self.entries[index].hash, capacity);
+            // only move the entry back if the hole lies between its home and where it is now
+            if (hole.wrapping_sub(home) & mask) < (index.wrapping_sub(home) & mask) {
+                unsafe {
+                    // entries should be decompacted, else internal relative pointers get messed up!
+                    let moved = Compact::decompact(&self.entries[index]);
+                    ::std::ptr::write(&mut self.entries[hole], moved);
+                    ::std::ptr::write(&mut self.entries[index], Entry::default());
+                }
+                hole = index;
             }
         }
-        None
+        old
     }
 
     fn ensure_capacity(&mut self) {
-        if self.number_used as usize > self.entries.capacity() / 2 {
-            let mut new_capacity = self.entries.capacity() * 2;
-
-            // if there are lots of dead entries we do not need to double
-            // we are going to just garbage collect them
-            let number_dead = self.entries.capacity() - self.number_alive as usize;
-            if number_dead > self.entries.capacity() / 2 {
-                new_capacity = self.entries.capacity();
-            }
+        if self.number_alive as usize > self.entries.capacity() / 2 {
+            let new_capacity = self.entries.capacity() * 2;
+            self.rehash(new_capacity);
+        }
+    }
 
-            let mut new_hash_map = Self::with_capacity(new_capacity);
+    /// Move all live entries into new entries of (at least) `new_capacity`,
+    /// placing them using their stored hashes, without hashing or comparing keys again.
+    /// Allocates only the new entries.
+    fn rehash(&mut self, new_capacity: usize) {
+        #[cfg(feature = "metrics")]
+        super::metrics::MAP.record_rehash();
+        #[cfg(feature = "log")]
+        ::log::trace!(
+            "CHashMap<{}, {}> rehashes {} entries from capacity {} to at least {}",
+            ::std::any::type_name::<K>(),
+            ::std::any::type_name::<V>(),
+            self.number_alive,
+            self.entries.capacity(),
+            Self::round_capacity(new_capacity)
+        );
+        let capacity = Self::round_capacity(new_capacity.max(self.number_alive as usize));
+        let mut entries: CompactVec<Entry<K, V>, A> = CompactVec::with_capacity(capacity);
+        entries.resize_with(capacity, Entry::default);
+
+        // in the order of their old slots, probing like inserts do
+        for entry in self.entries.iter().filter(|e| e.alive()) {
+            let slot = first_free_slot(entry.hash, capacity, |i| entries[i].used());
+            // decompact, since the entry might be stored compactly with its dynamic parts
+            entries[slot] = unsafe { Compact::decompact(entry) };
+        }
+        // all live entries were moved out by decompacting, free ones don't own anything
+        unsafe { self.entries.set_len(0) };
+        self.entries = entries;
+    }
+
+    /// Index of the live entry with `hash` whose key matches `is_match`, if any
+    fn find_index_matching<F: FnMut(&K) -> bool>(
+        &self,
+        hash: u64,
+        mut is_match: F,
+    ) -> Option<usize> {
+        let capacity = self.entries.capacity();
+        (0..capacity)
+            .map(|i| (home_slot(hash, capacity) + i) & (capacity - 1))
+            .take_while(|&index| !self.entries[index].free())
+            .find(|&index| {
+                let entry = &self.entries[index];
+                entry.hash == hash && entry.alive() && is_match(entry.key())
+            })
+    }
 
-            for entry in self.entries.drain() {
-                if entry.alive() {
-                    let tuple = entry.into_tuple();
-                    new_hash_map.insert(tuple.0, tuple.1);
-                }
-            }
+    /// Index of the first free entry probing for `hash` reaches, if any
+    fn find_free_slot(&self, hash: u64) -> Option<usize> {
+        let capacity = self.entries.capacity();
+        let slot = |i| (home_slot(hash, capacity) + i) & (capacity - 1);
+        let probed = (0..capacity).find(|&i| self.entries[slot(i)].free())?;
+        record_probe(probed + 1);
+        Some(slot(probed))
+    }
 
-            *self = new_hash_map;
-        }
+    fn find_used<Q: Hash + Eq + ?Sized>(&self, query: &Q) -> Option<&Entry<K, V>>
+    where
+        K: Borrow<Q>,
+    {
+        self.find_used_hashed(self.hash_key(query), query)
     }
 
-    fn find_used(&self, query: K) -> Option<&Entry<K, V>> {
-        for entry in self.quadratic_iterator(query) {
-            if entry.is_this(&query) {
-                return Some(entry);
-            }
+    /// Like `find_used` for the key of an `entry` of `owner`, reusing its stored hash if possible
+    fn find_used_stored<W: Compact, B: Allocator>(
+        &self,
+        entry: &Entry<K, W>,
+        owner: &OpenAddressingMap<K, W, B, H>,
+    ) -> Option<&Entry<K, V>> {
+        if self.shares_hashes(owner) {
+            self.find_used_hashed(entry.hash, entry.key())
+        } else {
+            self.find_used(entry.key())
         }
-        None
     }
 
-    fn find_used_mut(&mut self, query: K) -> Option<&mut Entry<K, V>> {
-        let h = Self::hash(query);
-        for entry in self.quadratic_iterator_mut(h) {
-            if entry.is_this(&query) {
-                return Some(entry);
-            }
-        }
-        None
+    /// Like `find_used`, but reuses an already known hash of `query`
+    fn find_used_hashed<Q: Eq + ?Sized>(&self, hash: u64, query: &Q) -> Option<&Entry<K, V>>
+    where
+        K: Borrow<Q>,
+    {
+        LinearProbingIterator::for_map(self, hash).find(|entry| entry.is_this(hash, query))
     }
 
-    fn quadratic_iterator(&self, query: K) -> QuadraticProbingIterator<'_, K, V, A> {
-        QuadraticProbingIterator::for_map(self, Self::hash(query))
+    /// Index of the entry for `query` (and `true`) if it exists, otherwise
+    /// of the first free entry (and `false`), if probing reaches one
+    fn find_slot<Q: Eq + ?Sized>(&self, hash: u64, query: &Q) -> Option<(usize, bool)>
+    where
+        K: Borrow<Q>,
+    {
+        let capacity = self.entries.capacity();
+        let slot = |i| (home_slot(hash, capacity) + i) & (capacity - 1);
+        let probed = (0..capacity).find(|&i| {
+            let entry = &self.entries[slot(i)];
+            entry.free() || entry.is_this(hash, query)
+        })?;
+        record_probe(probed + 1);
+        let index = slot(probed);
+        Some((index, !self.entries[index].free()))
+    }
+
+    fn find_used_mut<Q: Hash + Eq + ?Sized>(&mut self, query: &Q) -> Option<&mut Entry<K, V>>
+    where
+        K: Borrow<Q>,
+    {
+        let h = self.hash_key(query);
+        self.find_used_hashed_mut(h, query)
     }
 
-    fn quadratic_iterator_mut(&mut self, hash: u32) -> QuadraticProbingMutIterator<'_, K, V, A> {
-        QuadraticProbingMutIterator::for_map(self, hash)
+    fn find_used_hashed_mut<Q: Eq + ?Sized>(
+        &mut self,
+        hash: u64,
+        query: &Q,
+    ) -> Option<&mut Entry<K, V>>
+    where
+        K: Borrow<Q>,
+    {
+        self.probing_iterator_mut(hash)
+            .find(|entry| entry.is_this(hash, query))
+    }
+
+    fn probing_iterator_mut(&mut self, hash: u64) -> LinearProbingMutIterator<'_, K, V, A, H> {
+        LinearProbingMutIterator::for_map(self, hash)
     }
 
-    fn find_next_prime(n: usize) -> usize {
-        PRIME_SIEVE.primes_from(n).find(|&i| i >= n).unwrap()
+    fn trims_in_shrink_to_fit(&self) -> bool {
+        Self::round_capacity((self.number_alive as usize * 2).max(1)) < self.entries.capacity()
     }
 
+    #[cfg(test)]
     fn display(&self) -> String {
+        use std::fmt::Write;
         let mut res = String::new();
         writeln!(&mut res, "size: {:?}", self.number_alive).unwrap();
         let mut size_left: isize = self.number_alive as isize;
@@ -476,18 +1076,585 @@ impl<K: Copy + Eq + Hash, V: Compact, A: Allocator> OpenAddressingMap<K, V, A> {
     }
 }
 
-impl<K: Copy + Eq + Hash, V: Compact, A: Allocator> Compact for OpenAddressingMap<K, V, A> {
+/// The keys that differ between two maps, see `OpenAddressingMap::diff`
+pub struct MapDiff<K, A: Allocator = DefaultHeap> {
+    /// Keys only present in the new map
+    pub added: CompactVec<K, A>,
+    /// Keys only present in the old map
+    pub removed: CompactVec<K, A>,
+    /// Keys present in both maps, with different values
+    pub changed: CompactVec<K, A>,
+}
+
+impl<K: Compact, A: Allocator> MapDiff<K, A> {
+    /// Are the two maps equal?
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl<K: Compact, A: Allocator> Clone for MapDiff<K, A> {
+    fn clone(&self) -> Self {
+        MapDiff {
+            added: self.added.clone(),
+            removed: self.removed.clone(),
+            changed: self.changed.clone(),
+        }
+    }
+}
+
+impl<K: Compact + ::std::fmt::Debug, A: Allocator> ::std::fmt::Debug for MapDiff<K, A> {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("MapDiff")
+            .field("added", &self.added)
+            .field("removed", &self.removed)
+            .field("changed", &self.changed)
+            .finish()
+    }
+}
+
+impl<K: Compact, A: Allocator> Compact for MapDiff<K, A> {
+    fn is_still_compact(&self) -> bool {
+        self.added.is_still_compact()
+            && self.removed.is_still_compact()
+            && self.changed.is_still_compact()
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        let mut layout = DynamicLayout::new();
+        layout.field(&self.added);
+        layout.field(&self.removed);
+        layout.field(&self.changed);
+        layout.size()
+    }
+
+    fn dynamic_align() -> usize {
+        CompactVec::<K, A>::dynamic_align()
+    }
+
+    fn collect_stats(&self, path: &str, stats: &mut CompactStats) {
+        self.added.collect_stats(&format!("{}.added", path), stats);
+        self.removed.collect_stats(&format!("{}.removed", path), stats);
+        self.changed.collect_stats(&format!("{}.changed", path), stats);
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        let mut layout = DynamicLayout::new();
+        let added_offset = layout.field(&(*source).added);
+        let removed_offset = layout.field(&(*source).removed);
+        let changed_offset = layout.field(&(*source).changed);
+        Compact::compact(
+            &mut (*source).added,
+            &mut (*dest).added,
+            new_dynamic_part.add(added_offset),
+        );
+        Compact::compact(
+            &mut (*source).removed,
+            &mut (*dest).removed,
+            new_dynamic_part.add(removed_offset),
+        );
+        Compact::compact(
+            &mut (*source).changed,
+            &mut (*dest).changed,
+            new_dynamic_part.add(changed_offset),
+        );
+    }
+
+    unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+        self.added.shift_compact_pointers(shift);
+        self.removed.shift_compact_pointers(shift);
+        self.changed.shift_compact_pointers(shift);
+    }
+
+    unsafe fn decompact(source: *const Self) -> Self {
+        MapDiff {
+            added: Compact::decompact(&(*source).added),
+            removed: Compact::decompact(&(*source).removed),
+            changed: Compact::decompact(&(*source).changed),
+        }
+    }
+
+    unsafe fn read_dynamic_part<R: ::std::io::Read>(
+        source: *const Self,
+        reader: &mut CompactReader<R>,
+    ) -> Result<Self, FromReaderError> {
+        Ok(MapDiff {
+            added: reader.read_field(&(*source).added)?,
+            removed: reader.read_field(&(*source).removed)?,
+            changed: reader.read_field(&(*source).changed)?,
+        })
+    }
+}
+
+/// A set view of the keys of an `OpenAddressingMap`, see `OpenAddressingMap::keys_set`.
+///
+/// Set operations look keys up in the other map directly, without building intermediate sets.
+pub struct KeySet<'a, K: 'a, V: 'a, A: 'a + Allocator = DefaultHeap, H: 'a = DefaultHashBuilder> {
+    map: &'a OpenAddressingMap<K, V, A, H>,
+}
+
+impl<'a, K: 'a, V: 'a, A: 'a + Allocator, H: 'a> Clone for KeySet<'a, K, V, A, H> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, K: 'a, V: 'a, A: 'a + Allocator, H: 'a> Copy for KeySet<'a, K, V, A, H> {}
+
+impl<'a, K, V, A, H> KeySet<'a, K, V, A, H>
+where
+    K: Compact + Eq + Hash + 'a,
+    V: Compact + 'a,
+    A: 'a + Allocator,
+    H: 'a + BuildHasher + Default,
+{
+    /// Amount of keys in the set
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Is the set empty?
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Is `key` in the set?
+    pub fn contains(&self, key: &K) -> bool {
+        self.map.find_used(key).is_some()
+    }
+
+    /// Iterator over all keys in the set
+    pub fn iter(&self) -> Keys<'a, K, V> {
+        self.map.keys()
+    }
+
+    /// Iterator over the keys that are in both sets
+    pub fn intersection<W: Compact + 'a, B: 'a + Allocator, G: 'a + BuildHasher + Default>(
+        &self,
+        other: KeySet<'a, K, W, B, G>,
+    ) -> impl Iterator<Item = &'a K> + 'a {
+        self.iter().filter(move |key| other.contains(key))
+    }
+
+    /// Iterator over the keys that are in this set, but not in `other`
+    pub fn difference<W: Compact + 'a, B: 'a + Allocator, G: 'a + BuildHasher + Default>(
+        &self,
+        other: KeySet<'a, K, W, B, G>,
+    ) -> impl Iterator<Item = &'a K> + 'a {
+        self.iter().filter(move |key| !other.contains(key))
+    }
+
+    /// Iterator over the keys that are in exactly one of the two sets
+    pub fn symmetric_difference<W: Compact + 'a, B: 'a + Allocator, G: 'a + BuildHasher + Default>(
+        &self,
+        other: KeySet<'a, K, W, B, G>,
+    ) -> impl Iterator<Item = &'a K> + 'a {
+        self.difference(other).chain(other.difference(*self))
+    }
+}
+
+/// Looks up entries by hashes computed elsewhere, see `OpenAddressingMap::raw_entry`
+pub struct RawEntryBuilder<'a, K: 'a, V: 'a, A: 'a + Allocator, H: 'a> {
+    map: &'a OpenAddressingMap<K, V, A, H>,
+}
+
+impl<'a, K, V, A, H> RawEntryBuilder<'a, K, V, A, H>
+where
+    K: Compact + Eq + Hash,
+    V: Compact,
+    A: Allocator,
+    H: BuildHasher + Default,
+{
+    /// The key-value pair with `hash` whose key matches `is_match`, if any.
+    /// `hash` has to be the one the map's hasher computes for the key
+    /// (like `map.hasher().hash_one(key)`), or the entry won't be found.
+    pub fn from_hash<F: FnMut(&K) -> bool>(self, hash: u64, is_match: F) -> Option<(&'a K, &'a V)> {
+        let map = self.map;
+        map.find_index_matching(hash, is_match).map(|index| {
+            let entry = &map.entries[index];
+            (entry.key(), entry.value())
+        })
+    }
+}
+
+/// Looks up, inserts or removes entries by hashes computed elsewhere,
+/// see `OpenAddressingMap::raw_entry_mut`
+pub struct RawEntryBuilderMut<'a, K: 'a, V: 'a, A: 'a + Allocator, H: 'a> {
+    map: &'a mut OpenAddressingMap<K, V, A, H>,
+}
+
+impl<'a, K, V, A, H> RawEntryBuilderMut<'a, K, V, A, H>
+where
+    K: Compact + Eq + Hash,
+    V: Compact,
+    A: Allocator,
+    H: BuildHasher + Default,
+{
+    /// The entry with `hash` whose key matches `is_match`, occupied or vacant.
+    /// `hash` has to be the one the map's hasher computes for the key
+    /// (like `map.hasher().hash_one(key)`), or the entry won't be found.
+    pub fn from_hash<F: FnMut(&K) -> bool>(
+        self,
+        hash: u64,
+        is_match: F,
+    ) -> RawEntryMut<'a, K, V, A, H> {
+        match self.map.find_index_matching(hash, is_match) {
+            Some(index) => RawEntryMut::Occupied(RawOccupiedEntryMut {
+                map: self.map,
+                index,
+            }),
+            None => RawEntryMut::Vacant(RawVacantEntryMut {
+                map: self.map,
+                hash,
+            }),
+        }
+    }
+}
+
+/// An entry found by `RawEntryBuilderMut::from_hash`
+pub enum RawEntryMut<'a, K: 'a, V: 'a, A: 'a + Allocator, H: 'a> {
+    /// There is a matching key-value pair
+    Occupied(RawOccupiedEntryMut<'a, K, V, A, H>),
+    /// There is no matching key-value pair yet
+    Vacant(RawVacantEntryMut<'a, K, V, A, H>),
+}
+
+/// A key-value pair found by `RawEntryBuilderMut::from_hash`
+pub struct RawOccupiedEntryMut<'a, K: 'a, V: 'a, A: 'a + Allocator, H: 'a> {
+    map: &'a mut OpenAddressingMap<K, V, A, H>,
+    index: usize,
+}
+
+/// Where `RawEntryBuilderMut::from_hash` didn't find a key-value pair
+pub struct RawVacantEntryMut<'a, K: 'a, V: 'a, A: 'a + Allocator, H: 'a> {
+    map: &'a mut OpenAddressingMap<K, V, A, H>,
+    hash: u64,
+}
+
+impl<'a, K, V, A, H> RawOccupiedEntryMut<'a, K, V, A, H>
+where
+    K: Compact + Eq + Hash,
+    V: Compact,
+    A: Allocator,
+    H: BuildHasher + Default,
+{
+    /// The key
+    pub fn key(&self) -> &K {
+        self.map.entries[self.index].key()
+    }
+
+    /// The value
+    pub fn get(&self) -> &V {
+        self.map.entries[self.index].value()
+    }
+
+    /// The value, mutably
+    pub fn get_mut(&mut self) -> &mut V {
+        self.map.entries[self.index].mut_value()
+    }
+
+    /// The value, mutably for as long as the map was borrowed
+    pub fn into_mut(self) -> &'a mut V {
+        self.map.entries[self.index].mut_value()
+    }
+
+    /// Replace the value, returning the old one
+    pub fn insert(&mut self, value: V) -> V {
+        self.map.entries[self.index]
+            .replace_value(value)
+            .expect("entry should be alive")
+    }
+
+    /// Remove the key-value pair from the map
+    pub fn remove_entry(self) -> (K, V) {
+        self.map.remove_at(self.index)
+    }
+}
+
+impl<'a, K, V, A, H> RawVacantEntryMut<'a, K, V, A, H>
+where
+    K: Compact + Eq + Hash,
+    V: Compact,
+    A: Allocator,
+    H: BuildHasher + Default,
+{
+    /// Insert `key` and `value` with the hash given to `from_hash`,
+    /// which has to be the one the map's hasher computes for `key`
+    pub fn insert(self, key: K, value: V) -> (&'a mut K, &'a mut V) {
+        let map = self.map;
+        map.ensure_capacity();
+        let index = loop {
+            match map.find_free_slot(self.hash) {
+                Some(index) => break index,
+                None => {
+                    // probing didn't reach a free entry, grow and retry
+                    let new_capacity = map.entries.capacity() * 2;
+                    map.rehash(new_capacity);
+                }
+            }
+        };
+        map.entries[index].make_used(self.hash, key, value);
+        map.number_alive += 1;
+        let pair = map.entries[index]
+            .inner
+            .as_mut()
+            .expect("entry was just made used");
+        (&mut pair.0, &mut pair.1)
+    }
+}
+
+/// Iterator over the keys of an `OpenAddressingMap`, see `OpenAddressingMap::keys`
+pub struct Keys<'a, K: 'a, V: 'a> {
+    entries: slice::Iter<'a, Entry<K, V>>,
+    remaining: usize,
+}
+
+/// Iterator over the values of an `OpenAddressingMap`, see `OpenAddressingMap::values`
+pub struct Values<'a, K: 'a, V: 'a> {
+    entries: slice::Iter<'a, Entry<K, V>>,
+    remaining: usize,
+}
+
+/// Iterator over mutable references to the values of an `OpenAddressingMap`,
+/// see `OpenAddressingMap::values_mut`
+pub struct ValuesMut<'a, K: 'a, V: 'a> {
+    entries: slice::IterMut<'a, Entry<K, V>>,
+    remaining: usize,
+}
+
+/// Iterator over the key-value pairs of an `OpenAddressingMap`, see `OpenAddressingMap::iter`
+pub struct Iter<'a, K: 'a, V: 'a> {
+    entries: slice::Iter<'a, Entry<K, V>>,
+    remaining: usize,
+}
+
+/// Iterator over the key-value pairs of an `OpenAddressingMap` with mutable values,
+/// see `OpenAddressingMap::iter_mut`
+pub struct IterMut<'a, K: 'a, V: 'a> {
+    entries: slice::IterMut<'a, Entry<K, V>>,
+    remaining: usize,
+}
+
+/// Iterator over the key-value pairs of an `OpenAddressingMap` with copied keys
+/// and mutable values, see `OpenAddressingMap::pairs_mut`
+pub struct PairsMut<'a, K: 'a, V: 'a> {
+    pairs: IterMut<'a, K, V>,
+}
+
+/// Implement the iterator traits for an iterator over the alive entries,
+/// which knows how many of them are left
+macro_rules! alive_entries_iterator {
+    ($name:ident<$a:lifetime>, $item:ty, |$entry:ident| $value:expr) => {
+        impl<$a, K, V> Iterator for $name<$a, K, V> {
+            type Item = $item;
+
+            fn next(&mut self) -> Option<$item> {
+                let $entry = self.entries.find(|entry| entry.inner.is_some())?;
+                self.remaining -= 1;
+                Some($value)
+            }
+
+            fn size_hint(&self) -> (usize, Option<usize>) {
+                (self.remaining, Some(self.remaining))
+            }
+        }
+
+        impl<$a, K, V> DoubleEndedIterator for $name<$a, K, V> {
+            fn next_back(&mut self) -> Option<$item> {
+                let $entry = self.entries.rfind(|entry| entry.inner.is_some())?;
+                self.remaining -= 1;
+                Some($value)
+            }
+        }
+
+        impl<$a, K, V> ExactSizeIterator for $name<$a, K, V> {}
+
+        impl<$a, K, V> FusedIterator for $name<$a, K, V> {}
+    };
+}
+
+alive_entries_iterator!(Keys<'a>, &'a K, |entry| &entry.inner.as_ref().unwrap().0);
+alive_entries_iterator!(Values<'a>, &'a V, |entry| &entry.inner.as_ref().unwrap().1);
+alive_entries_iterator!(ValuesMut<'a>, &'a mut V, |entry| {
+    &mut entry.inner.as_mut().unwrap().1
+});
+alive_entries_iterator!(Iter<'a>, (&'a K, &'a V), |entry| {
+    let (ref key, ref value) = *entry.inner.as_ref().unwrap();
+    (key, value)
+});
+alive_entries_iterator!(IterMut<'a>, (&'a K, &'a mut V), |entry| {
+    let (ref key, ref mut value) = *entry.inner.as_mut().unwrap();
+    (key, value)
+});
+
+impl<'a, K: Copy, V> Iterator for PairsMut<'a, K, V> {
+    type Item = (K, &'a mut V);
+
+    fn next(&mut self) -> Option<(K, &'a mut V)> {
+        self.pairs.next().map(|(key, value)| (*key, value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.pairs.size_hint()
+    }
+}
+
+impl<'a, K: Copy, V> DoubleEndedIterator for PairsMut<'a, K, V> {
+    fn next_back(&mut self) -> Option<(K, &'a mut V)> {
+        self.pairs.next_back().map(|(key, value)| (*key, value))
+    }
+}
+
+impl<'a, K: Copy, V> ExactSizeIterator for PairsMut<'a, K, V> {}
+
+impl<'a, K: Copy, V> FusedIterator for PairsMut<'a, K, V> {}
+
+impl<'a, K, V> Clone for Keys<'a, K, V> {
+    fn clone(&self) -> Self {
+        Keys {
+            entries: self.entries.clone(),
+            remaining: self.remaining,
+        }
+    }
+}
+
+impl<'a, K, V> Clone for Values<'a, K, V> {
+    fn clone(&self) -> Self {
+        Values {
+            entries: self.entries.clone(),
+            remaining: self.remaining,
+        }
+    }
+}
+
+impl<'a, K, V> Clone for Iter<'a, K, V> {
+    fn clone(&self) -> Self {
+        Iter {
+            entries: self.entries.clone(),
+            remaining: self.remaining,
+        }
+    }
+}
+
+impl<'a, K: Compact + Eq + Hash, V: Compact, A: Allocator, H: BuildHasher + Default> IntoIterator
+    for &'a OpenAddressingMap<K, V, A, H>
+{
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
+
+impl<'a, K: Compact + Eq + Hash, V: Compact, A: Allocator, H: BuildHasher + Default> IntoIterator
+    for &'a mut OpenAddressingMap<K, V, A, H>
+{
+    type Item = (&'a K, &'a mut V);
+    type IntoIter = IterMut<'a, K, V>;
+
+    fn into_iter(self) -> IterMut<'a, K, V> {
+        self.iter_mut()
+    }
+}
+
+impl<K: Compact + Eq + Hash, V: Compact, A: Allocator, H: BuildHasher + Default + Clone>
+    OpenAddressingMap<K, V, A, H>
+{
+    /// The capacity `shrink_to_fit` would rehash into, if compacting in `CapacityMode::Trim`
+    /// and that would shrink the map
+    fn trimmed_capacity(&self) -> Option<usize> {
+        if capacity_mode() == CapacityMode::Trim && self.trims_in_shrink_to_fit() {
+            Some(Self::round_capacity(
+                (self.number_alive as usize * 2).max(1),
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// The live entries in the order of their slots after a rehash into `capacity`
+    /// (which has to be big enough for them), `None` for free slots
+    fn placed_in(&self, capacity: usize) -> Vec<Option<&Entry<K, V>>> {
+        let mut placed = vec![None; capacity];
+        for entry in self.entries.iter().filter(|e| e.alive()) {
+            let slot = first_free_slot(entry.hash, capacity, |i| placed[i].is_some());
+            placed[slot] = Some(entry);
+        }
+        placed
+    }
+
+    /// `dynamic_size_bytes` of the entries after a rehash into `capacity`,
+    /// without rehashing: their dynamic parts follow each other in slot order,
+    /// each padded to the same alignment, so only the entry that ends up in the last slot
+    /// (which isn't followed by padding) has to be found by placing them
+    fn placed_dynamic_size_bytes(&self, capacity: usize) -> usize {
+        let base_size = capacity * ::std::mem::size_of::<Entry<K, V>>();
+        if !::std::mem::needs_drop::<Entry<K, V>>() {
+            return base_size;
+        }
+        let align = Entry::<K, V>::dynamic_align();
+        let mut size = align_up(base_size, align);
+        let mut unaligned = false;
+        for entry in self.entries.iter().filter(|e| e.alive()) {
+            let entry_size = entry.dynamic_size_bytes();
+            size += align_up(entry_size, align);
+            unaligned |= entry_size % align != 0;
+        }
+        if unaligned {
+            if let Some(last) = self.placed_in(capacity)[capacity - 1] {
+                let last_size = last.dynamic_size_bytes();
+                size -= align_up(last_size, align) - last_size;
+            }
+        }
+        size
+    }
+}
+
+impl<K: Compact + Eq + Hash, V: Compact, A: Allocator, H: BuildHasher + Default + Copy> Compact
+    for OpenAddressingMap<K, V, A, H>
+{
     fn is_still_compact(&self) -> bool {
         self.entries.is_still_compact()
     }
 
     fn dynamic_size_bytes(&self) -> usize {
-        self.entries.dynamic_size_bytes()
+        match self.trimmed_capacity() {
+            Some(capacity) => self.placed_dynamic_size_bytes(capacity),
+            None => self.entries.dynamic_size_bytes(),
+        }
+    }
+
+    fn dynamic_align() -> usize {
+        CompactVec::<Entry<K, V>, A>::dynamic_align()
+    }
+
+    fn collect_stats(&self, path: &str, stats: &mut CompactStats) {
+        self.entries.collect_stats(&format!("{}.entries", path), stats);
+    }
+
+    fn repair_lossy(&mut self, path: &str, dropped: &mut Vec<String>) {
+        self.entries.repair_lossy(&format!("{}.entries", path), dropped);
+        if self.entries.is_empty() {
+            *self = Self::new();
+        }
+    }
+
+    unsafe fn recompact_dirty_into(&mut self, spare: &mut &mut [u8]) -> Result<(), NeedBytes> {
+        self.entries.recompact_dirty_into(spare)
+    }
+
+    fn clear_dirty(&mut self) {
+        self.entries.clear_dirty();
     }
 
     unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        // the source is moved from anyway, so it can be trimmed in place
+        if capacity_mode() == CapacityMode::Trim {
+            (*source).shrink_to_fit();
+        }
         (*dest).number_alive = (*source).number_alive;
-        (*dest).number_used = (*source).number_used;
+        (*dest).hasher = (*source).hasher;
         Compact::compact(
             &mut (*source).entries,
             &mut (*dest).entries,
@@ -495,120 +1662,321 @@ impl<K: Copy + Eq + Hash, V: Compact, A: Allocator> Compact for OpenAddressingMa
         );
     }
 
-    unsafe fn decompact(source: *const Self) -> OpenAddressingMap<K, V, A> {
+    unsafe fn compact_static_part(&self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        (*dest).number_alive = self.number_alive;
+        (*dest).hasher = self.hasher;
+        match self.trimmed_capacity() {
+            Some(capacity) => CompactVec::compact_static_part_of_len(
+                &mut (*dest).entries,
+                capacity,
+                new_dynamic_part,
+            ),
+            None => self
+                .entries
+                .compact_static_part(&mut (*dest).entries, new_dynamic_part),
+        }
+    }
+
+    unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+        self.entries.shift_compact_pointers(shift);
+    }
+
+    fn write_dynamic_part<W: ::std::io::Write>(&self, writer: &mut W) -> ::std::io::Result<()> {
+        match self.trimmed_capacity() {
+            // the entries are only placed like `shrink_to_fit` would while writing them
+            Some(capacity) => {
+                let free = Entry::default();
+                let placed = self.placed_in(capacity);
+                let entries = placed.iter().map(|entry| entry.unwrap_or(&free));
+                CompactVec::<Entry<K, V>, A>::write_compacted_elements(entries, capacity, writer)
+            }
+            None => self.entries.write_dynamic_part(writer),
+        }
+    }
+
+    unsafe fn decompact(source: *const Self) -> OpenAddressingMap<K, V, A, H> {
         OpenAddressingMap {
             entries: Compact::decompact(&(*source).entries),
             number_alive: (*source).number_alive,
-            number_used: (*source).number_used,
+            hasher: (*source).hasher,
         }
     }
+
+    unsafe fn read_dynamic_part<R: ::std::io::Read>(
+        source: *const Self,
+        reader: &mut CompactReader<R>,
+    ) -> Result<OpenAddressingMap<K, V, A, H>, FromReaderError> {
+        let entries: CompactVec<Entry<K, V>, A> = reader.read_field(&(*source).entries)?;
+        if (*source).number_alive as usize > entries.len() {
+            return Err(reader.invalid("map has more entries than slots"));
+        }
+        Ok(OpenAddressingMap {
+            entries,
+            number_alive: (*source).number_alive,
+            hasher: (*source).hasher,
+        })
+    }
 }
 
-impl<K: Copy, V: Compact + Clone, A: Allocator> Clone for OpenAddressingMap<K, V, A> {
+impl<K: Compact, V: Compact + Clone, A: Allocator, H: Clone> Clone
+    for OpenAddressingMap<K, V, A, H>
+{
     fn clone(&self) -> Self {
         OpenAddressingMap {
             entries: self.entries.clone(),
             number_alive: self.number_alive,
-            number_used: self.number_used,
+            hasher: self.hasher.clone(),
         }
     }
 }
 
-impl<K: Copy + Eq + Hash, V: Compact, A: Allocator> Default for OpenAddressingMap<K, V, A> {
+impl<K: Compact + Eq + Hash, V: Compact, A: Allocator, H: BuildHasher + Default> Default
+    for OpenAddressingMap<K, V, A, H>
+{
     fn default() -> Self {
         OpenAddressingMap::with_capacity(5)
     }
 }
 
-impl<K: Copy + Eq + Hash, V: Compact + Clone, A: Allocator> ::std::iter::FromIterator<(K, V)>
-    for OpenAddressingMap<K, V, A>
+impl<K, V, A, H> ::std::iter::FromIterator<(K, V)> for OpenAddressingMap<K, V, A, H>
+where
+    K: Compact + Eq + Hash,
+    V: Compact + Clone,
+    A: Allocator,
+    H: BuildHasher + Default,
 {
     /// Construct a compact dictionary from an interator over key-value pairs
     fn from_iter<T: IntoIterator<Item = (K, V)>>(iter_to_be: T) -> Self {
+        let mut map = Self::new();
+        map.extend(iter_to_be);
+        map
+    }
+}
+
+impl<K, V, A, H> ::std::iter::Extend<(K, V)> for OpenAddressingMap<K, V, A, H>
+where
+    K: Compact + Eq + Hash,
+    V: Compact + Clone,
+    A: Allocator,
+    H: BuildHasher + Default,
+{
+    /// Extend a compact dictionary from an iterator over key-value pairs,
+    /// reserving room for the whole batch up front
+    fn extend<T: IntoIterator<Item = (K, V)>>(&mut self, iter_to_be: T) {
         let iter = iter_to_be.into_iter();
-        let mut map = Self::with_capacity(iter.size_hint().0);
+        self.reserve(iter.size_hint().0);
         for (key, value) in iter {
-            map.insert(key, value);
+            self.insert(key, value);
         }
-        map
+    }
+}
+
+impl<'a, K, V, A, H> ::std::iter::Extend<(&'a K, &'a V)> for OpenAddressingMap<K, V, A, H>
+where
+    K: Compact + Eq + Hash + 'a,
+    V: Compact + Clone + 'a,
+    A: Allocator,
+    H: BuildHasher + Default,
+{
+    /// Extend a compact dictionary from an iterator over borrowed key-value pairs, cloning them
+    fn extend<T: IntoIterator<Item = (&'a K, &'a V)>>(&mut self, iter: T) {
+        self.extend(
+            iter.into_iter()
+                .map(|(key, value)| (key.clone(), value.clone())),
+        );
+    }
+}
+
+impl<K, Q, V, A, H> ::std::ops::Index<&Q> for OpenAddressingMap<K, V, A, H>
+where
+    K: Compact + Eq + Hash + Borrow<Q>,
+    Q: Hash + Eq + ?Sized,
+    V: Compact,
+    A: Allocator,
+    H: BuildHasher + Default,
+{
+    type Output = V;
+
+    /// The value for key `query`, panics if it doesn't exist
+    fn index(&self, query: &Q) -> &V {
+        self.find_used(query)
+            .and_then(|e| e.value_option())
+            .expect("key not found")
+    }
+}
+
+impl<K, Q, V, A, H> ::std::ops::IndexMut<&Q> for OpenAddressingMap<K, V, A, H>
+where
+    K: Compact + Eq + Hash + Borrow<Q>,
+    Q: Hash + Eq + ?Sized,
+    V: Compact,
+    A: Allocator,
+    H: BuildHasher + Default,
+{
+    /// The value for key `query` mutably, panics if it doesn't exist
+    fn index_mut(&mut self, query: &Q) -> &mut V {
+        self.find_used_mut(query)
+            .and_then(|e| e.mut_value_option())
+            .expect("key not found")
     }
 }
 
 impl<
-        K: Copy + Eq + Hash + ::std::fmt::Debug,
+        K: Compact + Eq + Hash + ::std::fmt::Debug,
         V: Compact + Clone + ::std::fmt::Debug,
         A: Allocator,
-    > ::std::fmt::Debug for OpenAddressingMap<K, V, A>
+        H: BuildHasher + Default,
+    > ::std::fmt::Debug for OpenAddressingMap<K, V, A, H>
 {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
         f.debug_map().entries(self.pairs()).finish()
     }
 }
 
-impl<K: Hash + Eq + Copy, I: Compact, A1: Allocator, A2: Allocator>
-    OpenAddressingMap<K, CompactVec<I, A1>, A2>
+impl<K, V, A, B, H, G> PartialEq<OpenAddressingMap<K, V, B, G>> for OpenAddressingMap<K, V, A, H>
+where
+    K: Compact + Eq + Hash,
+    V: Compact + PartialEq,
+    A: Allocator,
+    B: Allocator,
+    H: BuildHasher + Default,
+    G: BuildHasher + Default,
+{
+    /// Maps are equal if they contain the same key-value pairs
+    fn eq(&self, other: &OpenAddressingMap<K, V, B, G>) -> bool {
+        self.len() == other.len()
+            && self
+                .pairs()
+                .all(|(key, value)| other.get(key) == Some(value))
+    }
+}
+
+impl<K: Compact + Eq + Hash, V: Compact + Eq, A: Allocator, H: BuildHasher + Default> Eq
+    for OpenAddressingMap<K, V, A, H>
+{
+}
+
+impl<K, V, A, H, S> PartialEq<HashMap<K, V, S>> for OpenAddressingMap<K, V, A, H>
+where
+    K: Compact + Eq + Hash,
+    V: Compact + PartialEq,
+    A: Allocator,
+    H: BuildHasher + Default,
+    S: BuildHasher,
+{
+    fn eq(&self, other: &HashMap<K, V, S>) -> bool {
+        self.len() == other.len()
+            && self
+                .pairs()
+                .all(|(key, value)| other.get(key) == Some(value))
+    }
+}
+
+impl<K, V, A, H, S> PartialEq<OpenAddressingMap<K, V, A, H>> for HashMap<K, V, S>
+where
+    K: Compact + Eq + Hash,
+    V: Compact + PartialEq,
+    A: Allocator,
+    H: BuildHasher + Default,
+    S: BuildHasher,
+{
+    fn eq(&self, other: &OpenAddressingMap<K, V, A, H>) -> bool {
+        other == self
+    }
+}
+
+impl<K: Compact + Eq + Hash, V: Compact + Hash, A: Allocator, H: BuildHasher + Default> Hash
+    for OpenAddressingMap<K, V, A, H>
+{
+    /// Hashes independently of the order of entries, so equal maps hash equally
+    fn hash<S: Hasher>(&self, state: &mut S) {
+        state.write_usize(self.len());
+        state.write_u64(unordered_hash(self.pairs()));
+    }
+}
+
+impl<K, I, A1, A2, H> OpenAddressingMap<K, CompactVec<I, A1>, A2, H>
+where
+    K: Hash + Eq + Compact,
+    I: Compact,
+    A1: Allocator,
+    A2: Allocator,
+    H: BuildHasher + Default,
 {
-    /// Push a value onto the `CompactVec` at the key `query`
+    /// Push a value onto the `CompactVec` at the key `query`,
+    /// starting a new one if the key doesn't exist yet
     pub fn push_at(&mut self, query: K, item: I) {
-        if self.push_at_inner(query, item) {
-            self.number_alive += 1;
-            self.number_used += 1;
-        }
+        self.get_or_insert_with(query, CompactVec::new).push(item)
     }
 
-    /// return true if new value pushed
-    fn push_at_inner(&mut self, query: K, item: I) -> bool {
-        self.ensure_capacity();
-        let hash = Self::hash(query);
-        for entry in self.quadratic_iterator_mut(hash) {
-            if entry.is_this(&query) {
-                entry.mut_value().push(item);
-                return false;
-            } else if !entry.used() {
-                let mut val = CompactVec::new();
-                val.push(item);
-                entry.make_used(hash, query, val);
-                return true;
-            }
+    /// Build a map of groups from key-item pairs, in two passes over `iter`:
+    /// the first counts the group sizes, so that each group's `CompactVec`
+    /// is allocated exactly once, the second fills them in.
+    ///
+    /// Items keep their relative order within a group.
+    pub fn from_grouped_iter<T>(iter: T) -> Self
+    where
+        T: IntoIterator<Item = (K, I)>,
+        T::IntoIter: Clone,
+    {
+        let iter = iter.into_iter();
+        let mut group_sizes: OpenAddressingMap<K, u32> = OpenAddressingMap::new();
+        for (key, _) in iter.clone() {
+            let size = group_sizes.get(&key).cloned().unwrap_or(0);
+            group_sizes.insert(key, size + 1);
+        }
+
+        // twice the groups, so that inserting them never triggers a rehash
+        let mut map = Self::with_capacity(group_sizes.len() * 2);
+        for (key, size) in group_sizes.pairs() {
+            map.insert(key.clone(), CompactVec::with_capacity(*size as usize));
         }
-        println!("{:?}", self.display());
-        panic!("should always have place");
+        for (key, item) in iter {
+            map.find_used_mut(&key)
+                .expect("group should have been counted")
+                .mut_value()
+                .push(item);
+        }
+        map
     }
 
     /// Iterator over the `CompactVec` at the key `query`
-    pub fn get_iter<'a>(&'a self, query: K) -> impl Iterator<Item = &'a I> + 'a {
+    pub fn get_iter<'a, Q: Hash + Eq + ?Sized>(
+        &'a self,
+        query: &Q,
+    ) -> impl Iterator<Item = &'a I> + 'a
+    where
+        K: Borrow<Q>,
+    {
         self.get(query)
             .into_iter()
             .flat_map(|vec_in_option| vec_in_option.iter())
     }
 
     /// Remove the `CompactVec` at the key `query` and iterate over its elements (if it existed)
-    pub fn remove_iter<'a>(&'a mut self, query: K) -> impl Iterator<Item = I> + 'a {
+    pub fn remove_iter<'a, Q: Hash + Eq + ?Sized>(
+        &'a mut self,
+        query: &Q,
+    ) -> impl Iterator<Item = I> + 'a
+    where
+        K: Borrow<Q>,
+    {
         self.remove(query)
             .into_iter()
             .flat_map(|vec_in_option| vec_in_option.into_iter())
     }
 }
 
-impl<T: Hash> Hash for CompactVec<T> {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        for elem in self {
-            elem.hash(state);
-        }
-    }
-}
-
 #[cfg(feature = "serde-serialization")]
 use serde::ser::SerializeMap;
-#[cfg(feature = "serde-serialization")]
-use std::marker::PhantomData;
 
 #[cfg(feature = "serde-serialization")]
-impl<K, V, A> ::serde::Serialize for OpenAddressingMap<K, V, A>
+impl<K, V, A, H> ::serde::Serialize for OpenAddressingMap<K, V, A, H>
 where
-    K: Copy + Eq + Hash + ::serde::Serialize,
+    K: Compact + Eq + Hash + ::serde::Serialize,
     V: Compact + ::serde::Serialize,
     A: Allocator,
+    H: BuildHasher + Default,
 {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -623,27 +1991,29 @@ where
 }
 
 #[cfg(feature = "serde-serialization")]
-struct OpenAddressingMapVisitor<K, V, A: Allocator> {
-    marker: PhantomData<fn() -> OpenAddressingMap<K, V, A>>,
+#[allow(clippy::type_complexity)]
+struct OpenAddressingMapVisitor<K, V, A: Allocator, H> {
+    marker: ::std::marker::PhantomData<fn() -> OpenAddressingMap<K, V, A, H>>,
 }
 
 #[cfg(feature = "serde-serialization")]
-impl<K, V, A: Allocator> OpenAddressingMapVisitor<K, V, A> {
+impl<K, V, A: Allocator, H> OpenAddressingMapVisitor<K, V, A, H> {
     fn new() -> Self {
         OpenAddressingMapVisitor {
-            marker: PhantomData,
+            marker: ::std::marker::PhantomData,
         }
     }
 }
 
 #[cfg(feature = "serde-serialization")]
-impl<'de, K, V, A> ::serde::de::Visitor<'de> for OpenAddressingMapVisitor<K, V, A>
+impl<'de, K, V, A, H> ::serde::de::Visitor<'de> for OpenAddressingMapVisitor<K, V, A, H>
 where
-    K: Copy + Eq + Hash + ::serde::de::Deserialize<'de>,
+    K: Compact + Eq + Hash + ::serde::de::Deserialize<'de>,
     V: Compact + ::serde::de::Deserialize<'de>,
     A: Allocator,
+    H: BuildHasher + Default,
 {
-    type Value = OpenAddressingMap<K, V, A>;
+    type Value = OpenAddressingMap<K, V, A, H>;
 
     fn expecting(&self, formatter: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
         formatter.write_str("A Compact Hash Map")
@@ -664,11 +2034,12 @@ where
 }
 
 #[cfg(feature = "serde-serialization")]
-impl<'de, K, V, A> ::serde::de::Deserialize<'de> for OpenAddressingMap<K, V, A>
+impl<'de, K, V, A, H> ::serde::de::Deserialize<'de> for OpenAddressingMap<K, V, A, H>
 where
-    K: Copy + Eq + Hash + ::serde::de::Deserialize<'de>,
+    K: Compact + Eq + Hash + ::serde::de::Deserialize<'de>,
     V: Compact + ::serde::de::Deserialize<'de>,
     A: Allocator,
+    H: BuildHasher + Default,
 {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -678,6 +2049,27 @@ where
     }
 }
 
+#[cfg(feature = "arbitrary")]
+impl<'a, K, V, A, H> ::arbitrary::Arbitrary<'a> for OpenAddressingMap<K, V, A, H>
+where
+    K: Compact + Eq + Hash + ::arbitrary::Arbitrary<'a>,
+    V: Compact + Clone + ::arbitrary::Arbitrary<'a>,
+    A: Allocator + 'a,
+    H: BuildHasher + Default + 'a,
+{
+    fn arbitrary(u: &mut ::arbitrary::Unstructured<'a>) -> ::arbitrary::Result<Self> {
+        u.arbitrary_iter()?.collect()
+    }
+
+    fn arbitrary_take_rest(u: ::arbitrary::Unstructured<'a>) -> ::arbitrary::Result<Self> {
+        u.arbitrary_take_rest_iter()?.collect()
+    }
+
+    fn size_hint(depth: usize) -> (usize, Option<usize>) {
+        <Vec<(K, V)> as ::arbitrary::Arbitrary>::size_hint(depth)
+    }
+}
+
 #[cfg(test)]
 fn elem(n: usize) -> usize {
     (n * n) as usize
@@ -687,9 +2079,9 @@ fn elem(n: usize) -> usize {
 fn very_basic1() {
     let mut map: OpenAddressingMap<u32, u32> = OpenAddressingMap::with_capacity(2);
     map.insert(0, 54);
-    assert!(*map.get(0).unwrap() == 54);
+    assert!(*map.get(&0).unwrap() == 54);
     map.insert(1, 48);
-    assert!(*map.get(1).unwrap() == 48);
+    assert!(*map.get(&1).unwrap() == 48);
 }
 
 #[test]
@@ -697,8 +2089,8 @@ fn very_basic2() {
     let mut map: OpenAddressingMap<u32, u32> = OpenAddressingMap::with_capacity(3);
     map.insert(0, 54);
     map.insert(1, 48);
-    assert!(*map.get(0).unwrap() == 54);
-    assert!(*map.get(1).unwrap() == 48);
+    assert!(*map.get(&0).unwrap() == 54);
+    assert!(*map.get(&1).unwrap() == 48);
 }
 
 #[test]
@@ -712,17 +2104,17 @@ fn basic() {
     }
     assert!(map.is_empty() == false);
     for i in 0..n {
-        let test = map.get(i).unwrap();
+        let test = map.get(&i).unwrap();
         let exp = elem(i);
         assert!(*test == exp, " failed exp {:?}  was {:?}", exp, test);
     }
     assert!(map.len() == n as usize);
-    assert!(*map.get(n - 1).unwrap() == elem(n - 1));
-    assert!(*map.get(n - 100).unwrap() == elem(n - 100));
-    assert!(map.contains_key(n - 300) == true);
-    assert!(map.contains_key(n + 1) == false);
-    assert!(map.remove(500) == Some(elem(500)));
-    assert!(map.get(500).is_none());
+    assert!(*map.get(&(n - 1)).unwrap() == elem(n - 1));
+    assert!(*map.get(&(n - 100)).unwrap() == elem(n - 100));
+    assert!(map.contains_key(&(n - 300)) == true);
+    assert!(map.contains_key(&(n + 1)) == false);
+    assert!(map.remove(&500) == Some(elem(500)));
+    assert!(map.get(&500).is_none());
 }
 
 #[test]
@@ -768,7 +2160,7 @@ fn values_mut() {
         }
     }
     for i in 0..100 {
-        assert!(*map.get(i).unwrap() == i * i + 1);
+        assert!(*map.get(&i).unwrap() == i * i + 1);
     }
 }
 
@@ -782,6 +2174,18 @@ fn pairs() {
     for (key, value) in map.pairs() {
         assert!(elem(*key) == *value);
     }
+    let mut pairs = map.pairs_mut();
+    assert_eq!(100, pairs.len());
+    for (key, value) in pairs.by_ref().take(10) {
+        *value += key + 1;
+    }
+    assert_eq!(90, pairs.len());
+    assert_eq!(
+        90,
+        map.pairs()
+            .filter(|&(key, value)| elem(*key) == *value)
+            .count()
+    );
 }
 
 #[test]
@@ -795,13 +2199,66 @@ fn push_at() {
 
     for n in 0..10000 {
         println!("n {:?}", n);
-        let mut iter = map.get_iter(n);
+        let mut iter = map.get_iter(&n);
         assert!(iter.find(|&i| *i == elem(n)).is_some());
-        let mut iter2 = map.get_iter(n);
+        let mut iter2 = map.get_iter(&n);
         assert!(iter2.find(|&i| *i == elem(n) + 1).is_some());
     }
 }
 
+#[test]
+fn merge_and_union() {
+    let a: OpenAddressingMap<u32, CompactVec<u32>> = (0..6).map(|n| (n, vec![n].into())).collect();
+    let b: OpenAddressingMap<u32, CompactVec<u32>> =
+        (4..10).map(|n| (n, vec![n * 10].into())).collect();
+
+    let union = a.union_with(&b, |_, x, y| x.iter().chain(y.iter()).cloned().collect());
+    assert_eq!(10, union.len());
+    assert_eq!(Some(&vec![3].into()), union.get(&3));
+    assert_eq!(Some(&vec![4, 40].into()), union.get(&4));
+    assert_eq!(Some(&vec![90].into()), union.get(&9));
+
+    let mut merged = a.clone();
+    merged.merge(b, |key, mut x, y| {
+        x.push(*key);
+        x.extend(y);
+        x
+    });
+    assert_eq!(Some(&vec![5, 5, 50].into()), merged.get(&5));
+    assert_eq!(Some(&vec![3].into()), merged.get(&3));
+    assert_eq!(10, merged.len());
+}
+
+#[test]
+fn extend() {
+    let mut map: OpenAddressingMap<u32, u32> = (0..10).map(|n| (n, n)).collect();
+    map.extend((5..1000).map(|n| (n, n * 2)));
+    assert_eq!(1000, map.len());
+    assert_eq!(Some(&4), map.get(&4));
+    assert_eq!(Some(&10), map.get(&5));
+    // room for the whole batch was reserved up front
+    assert!(map.capacity() >= 2 * map.len());
+
+    let other: OpenAddressingMap<u32, u32> = vec![(1, 100), (2000, 1)].into_iter().collect();
+    map.extend(other.pairs());
+    assert_eq!(Some(&100), map.get(&1));
+    assert_eq!(Some(&1), map.get(&2000));
+}
+
+#[test]
+fn from_grouped_iter() {
+    let pairs: Vec<(u32, u32)> = (0..100).map(|n| (n % 7, n)).collect();
+    let map: OpenAddressingMap<u32, CompactVec<u32>> =
+        OpenAddressingMap::from_grouped_iter(pairs.iter().cloned());
+    assert_eq!(7, map.len());
+    for group in 0..7 {
+        let vec = map.get(&group).unwrap();
+        let expected: Vec<u32> = (0..100).filter(|n| n % 7 == group).collect();
+        assert_eq!(*vec, expected);
+        assert_eq!(vec.len(), vec.capacity());
+    }
+}
+
 #[test]
 fn remove_iter() {
     let mut map: OpenAddressingMap<usize, CompactVec<usize>> = OpenAddressingMap::new();
@@ -811,7 +2268,7 @@ fn remove_iter() {
         map.push_at(n, elem(n) + 1);
     }
     let target = 500;
-    let mut iter = map.remove_iter(target);
+    let mut iter = map.remove_iter(&target);
     assert!(iter.find(|i| *i == elem(target)).is_some());
     assert!(iter.find(|i| *i == elem(target) + 1).is_some());
 }
@@ -828,33 +2285,417 @@ fn ensure_capacity_works() {
 }
 
 #[test]
-fn insert_after_remove_works_same_hash() {
-    // get 2 elems with the same hash
-    let mut hash_to_usize: HashMap<u32, usize> = HashMap::new();
-    let mut bad_pair_opt = None;
-    for i in 0..<usize>::max_value() {
-        if i % 10000 == 0 {
-            println!("i {}", i);
+fn power_of_two_capacities() {
+    type Map = OpenAddressingMap<u32, u32>;
+    assert_eq!(1, Map::with_capacity(0).capacity());
+    assert_eq!(64, Map::with_capacity(33).capacity());
+    // no longer limited by a sieve of primes
+    assert_eq!(1 << 20, Map::round_capacity(1_000_001));
+
+    let mut map: Map = Map::with_capacity(1);
+    for n in 0..1000 {
+        map.insert(n, n);
+    }
+    assert!(map.capacity().is_power_of_two());
+    assert!((0..1000).all(|n| map.get(&n) == Some(&n)));
+}
+
+#[test]
+fn shrink_to() {
+    let mut map: OpenAddressingMap<u32, u32> = (0..1000).map(|n| (n, n)).collect();
+    for n in 100..1000 {
+        map.remove(&n);
+    }
+    let big_capacity = map.capacity();
+
+    map.shrink_to(0.5);
+    assert_eq!(256, map.capacity());
+    map.shrink_to(0.9);
+    assert_eq!(128, map.capacity());
+    assert!(map.capacity() < big_capacity);
+    assert!((0..100).all(|n| map.get(&n) == Some(&n)));
+    assert_eq!(None, map.get(&100));
+}
+
+#[test]
+fn shrink_to_keeps_free_entries() {
+    let mut map: OpenAddressingMap<u32, u32> = (0..128).map(|n| (n, n)).collect();
+    // a load factor of 1 would leave no free entry to stop probing for missing keys
+    map.shrink_to(1.0);
+    assert_eq!(256, map.capacity());
+    assert_eq!(None, map.get(&1000));
+    assert!((0..128).all(|n| map.get(&n) == Some(&n)));
+}
+
+#[test]
+fn shrink_to_fit() {
+    let mut map: OpenAddressingMap<u32, u32> = OpenAddressingMap::new();
+    map.reserve(1000);
+    let reserved = map.capacity();
+    assert!(reserved >= 2000);
+    map.extend((0..1000).map(|n| (n, n)));
+    assert_eq!(reserved, map.capacity());
+
+    for n in 100..1000 {
+        map.remove(&n);
+    }
+    map.shrink_to_fit();
+    assert_eq!(256, map.capacity());
+    map.shrink_to_fit();
+    assert_eq!(256, map.capacity());
+    assert!((0..100).all(|n| map.get(&n) == Some(&n)));
+}
+
+#[test]
+fn rehash_allocates_once() {
+    use super::compacted_box::CompactedBox;
+    use super::tracking_allocator::{allocation_stats, reset_allocation_stats, TrackingAllocator};
+    type Map = OpenAddressingMap<u32, CompactVec<u32>, TrackingAllocator>;
+    let map: Map = (0..100).map(|n| (n, vec![n].into())).collect();
+    let mut boxed = CompactedBox::new(map);
+
+    reset_allocation_stats();
+    let capacity = boxed.capacity();
+    // grows out of compact storage, so there is nothing to deallocate
+    boxed.with_mut(|map| map.reserve(capacity));
+    assert_eq!(1, allocation_stats().allocations);
+    assert_eq!(0, allocation_stats().deallocations);
+    assert!((0..100).all(|n| boxed.get(&n) == Some(&vec![n].into())));
+
+    reset_allocation_stats();
+    boxed.with_mut(|map| map.shrink_to_fit());
+    assert_eq!(1, allocation_stats().allocations);
+    assert_eq!(1, allocation_stats().deallocations);
+    assert!((0..100).all(|n| boxed.get(&n) == Some(&vec![n].into())));
+}
+
+#[test]
+fn compact_trimmed() {
+    use super::compact::with_capacity_mode;
+    use super::compacted_box::CompactedBox;
+    let mut map: OpenAddressingMap<u32, CompactVec<u32>> = OpenAddressingMap::new();
+    map.extend((0..1000).map(|n| (n, vec![n; 3].into())));
+    for n in 3..1000 {
+        map.remove(&n);
+    }
+    let preserved = map.total_size_bytes();
+
+    let bytes = map.compact_to_bytes();
+    assert!(bytes.len() < preserved / 100);
+    let read =
+        unsafe { OpenAddressingMap::<u32, CompactVec<u32>>::from_compact_bytes(&bytes) }.unwrap();
+    assert_eq!(8, read.capacity());
+    assert_eq!(map, read);
+
+    let boxed = with_capacity_mode(CapacityMode::Trim, || CompactedBox::new(map.clone()));
+    assert_eq!(bytes.len(), boxed.as_bytes().len());
+    assert_eq!(8, boxed.capacity());
+    assert_eq!(Some(&vec![2; 3].into()), boxed.get(&2));
+    assert_eq!(2048, map.capacity());
+}
+
+#[test]
+fn stream_trimmed() {
+    use super::compact::with_capacity_mode;
+    use super::compact_str::CompactString;
+    type Map = OpenAddressingMap<CompactVec<u64>, CompactString>;
+    // the dynamic parts of the entries end unaligned, after the strings
+    let key = |n: u64| -> CompactVec<u64> { vec![n; 1 + n as usize % 3].into() };
+    let mut map = Map::new();
+    map.extend((0..100).map(|n| (key(n), "x".repeat(n as usize).into())));
+    for n in 7..100 {
+        map.remove(&key(n));
+    }
+    assert_eq!(7, map.len());
+
+    let bytes = map.compact_to_bytes();
+    let mut streamed = Vec::new();
+    map.compact_to_writer(&mut streamed).unwrap();
+    assert_eq!(bytes.len(), streamed.len());
+    assert_eq!(
+        map,
+        unsafe { Map::decompact_from_reader(&mut &streamed[..]) }.unwrap()
+    );
+
+    let read = unsafe { Map::from_compact_bytes(&bytes) }.unwrap();
+    assert_eq!(16, read.capacity());
+    assert_eq!(map, read);
+    assert_eq!(
+        bytes.len(),
+        with_capacity_mode(CapacityMode::Trim, || read.total_size_bytes())
+    );
+}
+
+#[test]
+fn insert_many() {
+    type Map = OpenAddressingMap<u32, u32>;
+    let mut map: Map = (0..10).map(|n| (n, n)).collect();
+    map.insert_many((5..1000).map(|n| (n, n * 2)));
+    assert_eq!(1000, map.len());
+    assert_eq!(Some(&4), map.get(&4));
+    assert_eq!(Some(&10), map.get(&5));
+    assert_eq!(2048, map.capacity());
+
+    // counts pairs of unknown amount first, then grows right to the needed capacity
+    let mut map = Map::new();
+    map.insert_many((0..5000).filter(|n| n % 5 == 0).map(|n| (n, n)));
+    assert_eq!(1000, map.len());
+    assert_eq!(2048, map.capacity());
+}
+
+#[test]
+fn get_or_insert_with() {
+    let mut counts: OpenAddressingMap<u32, CompactVec<u32>> = OpenAddressingMap::new();
+    for n in 0..100 {
+        counts.get_or_insert_with(n % 10, CompactVec::new).push(n);
+    }
+    assert_eq!(10, counts.len());
+    assert_eq!(10, counts.get(&3).unwrap().len());
+
+    *counts.get_or_insert_default(42) = vec![1].into();
+    assert_eq!(Some(&vec![1].into()), counts.get(&42));
+    assert_eq!(11, counts.len());
+}
+
+#[test]
+fn rehash_reuses_stored_hashes() {
+    use std::cell::Cell;
+    thread_local! {
+        static HASHED: Cell<usize> = const { Cell::new(0) };
+    }
+
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    struct CountingKey(u32);
+    impl Hash for CountingKey {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            HASHED.with(|hashed| hashed.set(hashed.get() + 1));
+            self.0.hash(state);
+        }
+    }
+
+    let mut map: OpenAddressingMap<CountingKey, u32> = OpenAddressingMap::with_capacity(1000);
+    for n in 0..100 {
+        map.insert(CountingKey(n), n);
+    }
+    let hashed = HASHED.with(Cell::get);
+    map.shrink_to(0.5);
+    map.reserve(1000);
+    assert_eq!(hashed, HASHED.with(Cell::get));
+    assert!((0..100).all(|n| map.get(&CountingKey(n)) == Some(&n)));
+}
+
+#[test]
+fn fast_hasher() {
+    use super::fx_hash::FxHasher;
+    let mut map: FastOpenAddressingMap<u32, CompactVec<u32>> = FastOpenAddressingMap::new();
+    for n in 0..1000 {
+        map.push_at(n % 100, n);
+    }
+    assert_eq!(100, map.len());
+    assert_eq!(10, map.get(&42).unwrap().len());
+    let mut hasher = FxHasher::default();
+    42u32.hash(&mut hasher);
+    assert_eq!(
+        hasher.finish(),
+        FastOpenAddressingMap::<u32, u32>::new().hash_key(&42)
+    );
+}
+
+#[test]
+fn with_hasher() {
+    use super::compacted_box::CompactedBox;
+    type Map = OpenAddressingMap<u32, u32, DefaultHeap, SeededHashBuilder>;
+    let old: Map = (0..100).map(|n| (n, n)).collect();
+    let mut new = Map::with_capacity_and_hasher(8, SeededHashBuilder::random());
+    new.extend((50..150).map(|n| (n, n)));
+    *new.get_mut(&60).unwrap() = 0;
+
+    // maps with differently seeded hashers hash keys again to look them up in each other
+    let diff = old.diff(&new);
+    assert_eq!(
+        (50, 50, 1),
+        (diff.added.len(), diff.removed.len(), diff.changed.len())
+    );
+    assert_eq!(150, old.union_with(&new, |_, a, b| a + b).len());
+    let mut merged = old.clone();
+    merged.merge(new.clone(), |_, a, _| a);
+    assert_eq!(Some(&60), merged.get(&60));
+    assert_eq!(150, merged.len());
+
+    // the hasher is compacted along with the map
+    let boxed = CompactedBox::new(new.clone());
+    assert_eq!(new, *boxed);
+    assert_eq!(new.hasher(), boxed.hasher());
+    assert_eq!(Some(&149), boxed.get(&149));
+    let union = boxed.union_with(&old, |_, a, _| *a);
+    assert_eq!(new.hasher(), union.hasher());
+
+    // the same seed always hashes keys the same way
+    let fixed = || Map::with_hasher(SeededHashBuilder::new(7));
+    assert_eq!(fixed().hash_key(&42), fixed().hash_key(&42));
+    assert!(fixed().hash_key(&42) != old.hash_key(&42));
+}
+
+#[test]
+fn raw_entry() {
+    type Map = OpenAddressingMap<u32, CompactVec<u32>>;
+    let mut routes: Map = OpenAddressingMap::new();
+    let hash = |id: u32| routes.hasher().hash_one(id);
+    let hashes: Vec<u64> = (0..100).map(hash).collect();
+    for id in 0..100u32 {
+        match routes
+            .raw_entry_mut()
+            .from_hash(hashes[id as usize], |key| *key == id)
+        {
+            RawEntryMut::Vacant(vacant) => vacant.insert(id, vec![id].into()).1.push(id * 10),
+            RawEntryMut::Occupied(_) => unreachable!(),
+        }
+    }
+    assert_eq!(100, routes.len());
+    assert_eq!(Some(&vec![7, 70].into()), routes.get(&7));
+    let (key, value) = routes
+        .raw_entry()
+        .from_hash(hashes[42], |key| *key == 42)
+        .unwrap();
+    assert_eq!((42, 420), (*key, value[1]));
+    assert!(routes
+        .raw_entry()
+        .from_hash(hashes[42], |key| *key == 43)
+        .is_none());
+
+    match routes.raw_entry_mut().from_hash(hashes[3], |key| *key == 3) {
+        RawEntryMut::Occupied(mut occupied) => {
+            occupied.get_mut().push(300);
+            assert_eq!(vec![3, 30, 300], **occupied.get());
+            assert_eq!((3, vec![3, 30, 300].into()), occupied.remove_entry());
         }
-        let hash = OpenAddressingMap::<usize, usize>::hash(i);
-        if hash_to_usize.contains_key(&hash) {
-            let p: usize = *hash_to_usize.get(&hash).unwrap();
-            bad_pair_opt = Some((i, p));
-            break;
+        RawEntryMut::Vacant(_) => unreachable!(),
+    }
+    assert_eq!(99, routes.len());
+    assert_eq!(None, routes.get(&3));
+    assert!((4..100).all(|id| routes.get(&id).is_some()));
+}
+
+#[test]
+fn key_set_algebra() {
+    let a: OpenAddressingMap<u32, u32> = (0..6).map(|n| (n, n)).collect();
+    let b: OpenAddressingMap<u32, CompactVec<u32>> =
+        (4..8).map(|n| (n, vec![n].into())).collect();
+
+    let sorted = |keys: Vec<&u32>| {
+        let mut keys: Vec<u32> = keys.into_iter().cloned().collect();
+        keys.sort();
+        keys
+    };
+    let (a_keys, b_keys) = (a.keys_set(), b.keys_set());
+    assert!(a_keys.contains(&5));
+    assert!(!b_keys.contains(&3));
+    assert_eq!(sorted(a_keys.intersection(b_keys).collect()), vec![4, 5]);
+    assert_eq!(sorted(a_keys.difference(b_keys).collect()), vec![0, 1, 2, 3]);
+    assert_eq!(sorted(b_keys.difference(a_keys).collect()), vec![6, 7]);
+    assert_eq!(
+        sorted(a_keys.symmetric_difference(b_keys).collect()),
+        vec![0, 1, 2, 3, 6, 7]
+    );
+}
+
+#[test]
+fn diff() {
+    let old: OpenAddressingMap<u32, CompactVec<u32>> =
+        (0..6).map(|n| (n, vec![n].into())).collect();
+    let mut new = old.clone();
+    new.remove(&1);
+    new.insert(3, vec![30].into());
+    new.insert(7, vec![7].into());
+
+    let diff = old.diff(&new);
+    assert_eq!(diff.added, vec![7]);
+    assert_eq!(diff.removed, vec![1]);
+    assert_eq!(diff.changed, vec![3]);
+    assert!(old.diff(&old.clone()).is_empty());
+
+    let reverse = new.diff(&old);
+    assert_eq!((reverse.added, reverse.removed), (diff.removed, diff.added));
+}
+
+#[test]
+fn compares_keys_only_for_matching_hashes() {
+    use std::cell::Cell;
+    thread_local!(static COMPARISONS: Cell<usize> = const { Cell::new(0) });
+    #[derive(Clone, Copy, Debug)]
+    struct Counted(u32);
+    impl Hash for Counted {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            self.0.hash(state)
+        }
+    }
+    impl PartialEq for Counted {
+        fn eq(&self, other: &Counted) -> bool {
+            COMPARISONS.with(|c| c.set(c.get() + 1));
+            self.0 == other.0
         }
-        hash_to_usize.insert(hash, i);
     }
+    impl Eq for Counted {}
+    let comparisons = || COMPARISONS.with(|c| c.replace(0));
 
-    type NestedType = OpenAddressingMap<usize, usize>;
+    let mut map: OpenAddressingMap<Counted, u32> = OpenAddressingMap::new();
+    for n in 0..1000 {
+        map.insert(Counted(n), n);
+    }
+    assert_eq!(0, comparisons());
+    assert!((0..1000).all(|n| map.get(&Counted(n)) == Some(&n)));
+    assert_eq!(1000, comparisons());
+    assert!((1000..2000).all(|n| !map.contains_key(&Counted(n))));
+    assert_eq!(0, comparisons());
+    map.insert(Counted(7), 70);
+    *map.get_or_insert_with(Counted(8), || 0) += 1;
+    assert_eq!(Some(9), map.remove(&Counted(8)));
+    assert_eq!(3, comparisons());
+}
+
+#[test]
+fn insert_grows_when_probing_finds_no_place() {
+    let mut map: OpenAddressingMap<usize, usize> = OpenAddressingMap {
+        entries: vec![Entry::default(); 1].into(),
+        number_alive: 0,
+        hasher: Default::default(),
+    };
+    // bypass ensure_capacity, so the second insert can't find a free entry
+    assert_eq!(None, map.insert_inner(1, 10));
+    assert_eq!(None, map.insert_inner(2, 20));
+    assert_eq!(2, map.len());
+    assert!(map.capacity() > 1);
+    assert_eq!(Some(&10), map.get(&1));
+    assert_eq!(Some(&20), map.get(&2));
+}
+
+#[test]
+fn insert_after_remove_works_same_hash() {
+    // 64-bit hashes practically never collide, so use a hasher that always does
+    #[derive(Default)]
+    struct CollidingHasher;
+    impl Hasher for CollidingHasher {
+        fn write(&mut self, _bytes: &[u8]) {}
+        fn finish(&self) -> u64 {
+            7
+        }
+    }
+    type NestedType = OpenAddressingMap<
+        usize,
+        usize,
+        DefaultHeap,
+        ::std::hash::BuildHasherDefault<CollidingHasher>,
+    >;
+
+    let bad_pair = (1, 2);
     let mut map: NestedType = OpenAddressingMap::new();
 
-    let bad_pair = bad_pair_opt.unwrap();
     println!("bad pair {:?}", bad_pair);
     map.insert(bad_pair.0, 1);
     println!("map {}", map.display());
     map.insert(bad_pair.1, 2);
     println!("map {}", map.display());
-    map.remove(bad_pair.0);
+    map.remove(&bad_pair.0);
     println!("map {}", map.display());
     map.insert(bad_pair.1, 3);
     println!("map {}", map.display());
@@ -875,7 +2716,7 @@ fn compact_notcopy() {
     let mut map: NestedType = OpenAddressingMap::new();
     let assert_fun = |map: &NestedType, t: usize| {
         assert!(map
-            .get(t)
+            .get(&t)
             .unwrap()
             .into_iter()
             .find(|i| **i == elem(t))
@@ -904,7 +2745,7 @@ fn compact_copy() {
     type NestedType = OpenAddressingMap<usize, usize>;
 
     let mut map: NestedType = OpenAddressingMap::new();
-    let assert_fun = |map: &NestedType, t: usize| assert!(map.get(t).is_some());
+    let assert_fun = |map: &NestedType, t: usize| assert!(map.get(&t).is_some());
 
     for n in 0..1000 {
         map.insert(n, elem(n));
@@ -930,49 +2771,293 @@ fn map_len_is_the_amount_of_inserted_and_not_removed_items() {
         map.insert(n, elem(n));
     }
     for n in 0..10 {
-        map.remove(n);
+        map.remove(&n);
     }
     assert_eq!(990, map.len());
 }
 
 #[test]
-fn when_there_are_lots_of_dead_tombstoned_entries_capacity_is_not_doubled() {
+fn removing_frees_entries_without_tombstones() {
     type Map = OpenAddressingMap<usize, usize>;
     let mut map: Map = OpenAddressingMap::new();
     for n in 0..1000 {
         map.insert(n, elem(n));
     }
+    let capacity = map.capacity();
     for n in 0..600 {
-        map.remove(n);
+        map.remove(&n);
     }
-    println!("self {}", map.capacity());
     assert_eq!(400, map.len());
-    assert_eq!(1000, map.len_used());
-    assert_eq!(3203, map.capacity());
-    for n in 0..1000 {
+    assert!((600..1000).all(|n| map.get(&n) == Some(&elem(n))));
+    for n in 0..600 {
         map.insert(10000 + n, elem(n));
     }
-    assert_eq!(1400, map.len());
-    assert_eq!(3203, map.capacity());
+    assert_eq!(1000, map.len());
+    assert_eq!(capacity, map.capacity());
 }
 
 #[test]
-fn when_there_are_lots_of_few_tombstoned_entries_capacity_is_doubled() {
+fn churn_keeps_probing_runs_short() {
     type Map = OpenAddressingMap<usize, usize>;
-    let mut map: Map = OpenAddressingMap::new();
-    for n in 0..1000 {
-        map.insert(n, elem(n));
+    let mut map: Map = OpenAddressingMap::with_capacity(100);
+    for round in 0..1000 {
+        for n in 0..40 {
+            map.insert(round * 40 + n, n);
+        }
+        for n in 0..40 {
+            assert_eq!(Some(n), map.remove(&(round * 40 + n)));
+        }
     }
-    for n in 0..60 {
-        map.remove(n);
+    assert!(map.is_empty());
+    assert!(map.entries.iter().all(|entry| entry.free()));
+    assert_eq!(128, map.capacity());
+}
+
+#[test]
+fn misses_stop_at_free_entries() {
+    type Map = OpenAddressingMap<usize, usize>;
+    let mut map: Map = (0..1000).map(|n| (n, n)).collect();
+    for n in 0..900 {
+        map.remove(&n);
+    }
+    let longest = map.max_probe_length();
+    assert!((1..16).contains(&longest));
+    let capacity = map.capacity();
+    for missing in 1000..2000 {
+        let visited = LinearProbingIterator::for_map(&map, map.hash_key(&missing)).count();
+        assert!(visited <= longest + 1 && visited < capacity);
+    }
+    assert_eq!(0, Map::new().max_probe_length());
+}
+
+#[cfg(test)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct TestName(CompactVec<u8>);
+
+#[cfg(test)]
+impl TestName {
+    fn new(name: &str) -> Self {
+        TestName(name.as_bytes().to_vec().into())
     }
-    println!("self {}", map.capacity());
-    assert_eq!(940, map.len());
-    assert_eq!(1000, map.len_used());
-    assert_eq!(3203, map.capacity());
-    for n in 0..1000 {
-        map.insert(10000 + n, elem(n));
+}
+
+#[cfg(test)]
+impl Compact for TestName {
+    fn is_still_compact(&self) -> bool {
+        self.0.is_still_compact()
     }
-    assert_eq!(1940, map.len());
-    assert_eq!(6421, map.capacity());
+
+    fn dynamic_size_bytes(&self) -> usize {
+        self.0.dynamic_size_bytes()
+    }
+
+    fn dynamic_align() -> usize {
+        CompactVec::<u8>::dynamic_align()
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        Compact::compact(&mut (*source).0, &mut (*dest).0, new_dynamic_part)
+    }
+
+    unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+        self.0.shift_compact_pointers(shift);
+    }
+
+    unsafe fn decompact(source: *const Self) -> Self {
+        TestName(Compact::decompact(&(*source).0))
+    }
+}
+
+#[test]
+fn compact_notcopy_keys() {
+    type NestedType = OpenAddressingMap<TestName, CompactVec<u8>>;
+
+    let mut map: NestedType = OpenAddressingMap::new();
+    let name = |n: usize| TestName::new(&format!("actor-{}", n));
+    let assert_fun = |map: &NestedType, t: usize| {
+        assert!(map.get(&name(t)).unwrap().contains(&(t as u8)));
+        assert!(map.get(&name(t + 1000)).is_none());
+    };
+
+    for n in 0..200 {
+        map.push_at(name(n), n as u8);
+        map.push_at(name(n), n as u8 + 1);
+    }
+    map.remove(&name(7));
+    assert!(map.insert(name(8), vec![8].into()).is_some());
+    assert_eq!(199, map.len());
+    assert_fun(&map, 100);
+
+    let bytes = map.total_size_bytes();
+    let storage = DefaultHeap::allocate(bytes);
+    unsafe {
+        Compact::compact_behind(&mut map, storage as *mut NestedType);
+        ::std::mem::forget(map);
+        assert!((*(storage as *mut NestedType)).is_still_compact());
+        assert_fun(&(*(storage as *mut NestedType)), 149);
+        assert!((*(storage as *mut NestedType)).get(&name(7)).is_none());
+        let decompacted = Compact::decompact(storage as *mut NestedType);
+        assert_fun(&decompacted, 149);
+        assert_eq!(199, decompacted.keys().count());
+        DefaultHeap::deallocate(storage, bytes);
+    }
+}
+
+#[test]
+fn remove_entry() {
+    use super::compact_str::CompactString;
+    use super::compacted_box::CompactedBox;
+    let map: OpenAddressingMap<CompactString, CompactVec<u32>> = (0..20)
+        .map(|n| (format!("id-{}", n).into(), vec![n].into()))
+        .collect();
+    // keys and values are moved out of compact storage
+    let mut boxed = CompactedBox::new(map);
+    let (key, value) = boxed.with_mut(|map| map.remove_entry("id-7")).unwrap();
+    assert_eq!("id-7", key.as_str());
+    assert_eq!(vec![7], value);
+    assert_eq!(None, boxed.with_mut(|map| map.remove_entry("id-7")));
+    assert_eq!(19, boxed.len());
+    assert_eq!(Some(&vec![8].into()), boxed.get("id-8"));
+}
+
+#[test]
+fn index() {
+    use super::compacted_box::CompactedBox;
+    let mut map: OpenAddressingMap<u32, CompactVec<u32>> =
+        (0..20).map(|n| (n, vec![n].into())).collect();
+    map[&3].push(30);
+    assert_eq!(vec![3, 30], map[&3]);
+    let mut boxed = CompactedBox::new(map);
+    boxed.with_mut(|map| map[&5].push(50));
+    assert_eq!(vec![5, 50], boxed[&5]);
+    assert_eq!(vec![19], boxed[&19]);
+}
+
+#[test]
+fn get_many_mut() {
+    let mut ledgers: OpenAddressingMap<u32, CompactVec<i32>> =
+        (0..10).map(|actor| (actor, vec![100].into())).collect();
+    {
+        let [from, to] = ledgers.get_many_mut([&3, &7]).unwrap();
+        from.push(-30);
+        to.push(30);
+    }
+    assert_eq!(Some(&vec![100, -30].into()), ledgers.get(&3));
+    assert_eq!(Some(&vec![100, 30].into()), ledgers.get(&7));
+    assert!(ledgers.get_many_mut([&3, &3]).is_none());
+    assert!(ledgers.get_many_mut([&3, &10]).is_none());
+    assert_eq!(3, ledgers.get_many_mut([&1, &2, &4]).unwrap().len());
+}
+
+#[test]
+#[should_panic(expected = "key not found")]
+fn index_missing_key() {
+    let map: OpenAddressingMap<u32, u32> = (0..20).map(|n| (n, n)).collect();
+    let _ = map[&20];
+}
+
+#[test]
+fn equality() {
+    type Map = OpenAddressingMap<usize, CompactVec<usize>>;
+    let mut map: Map = OpenAddressingMap::new();
+    let mut other: Map = OpenAddressingMap::with_capacity(100);
+    let mut std_map = HashMap::new();
+    for n in 0..50 {
+        map.push_at(n, elem(n));
+        other.push_at(49 - n, elem(49 - n));
+        std_map.insert(n, CompactVec::from(vec![elem(n)]));
+    }
+    assert_eq!(map, other);
+    assert_eq!(map, std_map);
+    assert_eq!(std_map, map);
+    other.push_at(3, 0);
+    assert_ne!(map, other);
+    other.remove(&3);
+    assert_ne!(map, other);
+}
+
+#[test]
+fn hash_is_order_independent() {
+    use std::collections::hash_map::DefaultHasher;
+    type Map = OpenAddressingMap<usize, CompactVec<usize>>;
+    let hash_of = |map: &Map| {
+        let mut hasher = DefaultHasher::new();
+        map.hash(&mut hasher);
+        hasher.finish()
+    };
+    let mut map: Map = OpenAddressingMap::new();
+    let mut other: Map = OpenAddressingMap::with_capacity(100);
+    for n in 0..50 {
+        map.push_at(n, elem(n));
+        other.push_at(49 - n, elem(49 - n));
+    }
+    assert_eq!(hash_of(&map), hash_of(&other));
+    other.push_at(3, 0);
+    assert!(hash_of(&map) != hash_of(&other));
+
+    let mut maps: HashMap<Map, usize> = HashMap::new();
+    maps.insert(map, 1);
+    assert!(!maps.contains_key(&other));
+    other.remove(&3);
+    other.push_at(3, elem(3));
+    assert_eq!(Some(&1), maps.get(&other));
+}
+
+#[cfg(feature = "arbitrary")]
+#[test]
+fn arbitrary_nested() {
+    use super::compact_option::CompactOption;
+    use super::compact_str::CompactString;
+    use arbitrary::{Arbitrary, Unstructured};
+    type Nested = OpenAddressingMap<u32, CompactVec<CompactOption<CompactString>>>;
+
+    let bytes: Vec<u8> = (0..4096u32).map(|i| (i * 7919 % 251) as u8).collect();
+    let mut u = Unstructured::new(&bytes);
+    let mut non_empty = 0;
+    while !u.is_empty() {
+        let map = Nested::arbitrary(&mut u).unwrap();
+        non_empty += !map.is_empty() as usize;
+        assert_eq!(
+            map,
+            unsafe { Nested::from_compact_bytes(&map.compact_to_bytes()) }.unwrap()
+        );
+    }
+    assert!(non_empty > 0);
+}
+
+#[test]
+fn named_iterators() {
+    let mut map: OpenAddressingMap<u32, u32> = (0..10).map(|n| (n, n * n)).collect();
+    map.remove(&3);
+
+    struct Cursor<'a> {
+        keys: Keys<'a, u32, u32>,
+    }
+    let cursor = Cursor { keys: map.keys() };
+    assert_eq!(9, cursor.keys.len());
+    let mut keys: Vec<u32> = cursor.keys.clone().cloned().collect();
+    keys.sort();
+    assert_eq!(vec![0, 1, 2, 4, 5, 6, 7, 8, 9], keys);
+    let mut reversed: Vec<u32> = cursor.keys.rev().cloned().collect();
+    reversed.reverse();
+    assert_eq!(map.keys().cloned().collect::<Vec<_>>(), reversed);
+
+    let mut values = map.values();
+    values.next();
+    assert_eq!(8, values.len());
+    assert_eq!((8, Some(8)), values.size_hint());
+
+    for (key, value) in map.iter_mut() {
+        *value += key;
+    }
+    for value in map.values_mut() {
+        *value *= 2;
+    }
+    for (key, value) in &map {
+        assert_eq!((key * key + key) * 2, *value);
+    }
+    let mut iter = map.iter();
+    while iter.next().is_some() {}
+    assert_eq!(0, iter.len());
+    assert!(iter.next().is_none());
 }
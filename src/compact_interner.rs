@@ -0,0 +1,335 @@
+use super::compact::{Compact, CompactReader, CompactStats, DynamicLayout, FromReaderError};
+use super::compact_vec::CompactVec;
+use super::fx_hash::FxHasher;
+use std::hash::Hasher;
+use std::io::Read;
+
+/// Marks an empty slot of the lookup table
+const EMPTY: u32 = u32::MAX;
+
+/// A small id for a string interned in a `CompactInterner`.
+///
+/// Ids are handed out in order, starting at 0. Being `Copy`, they can be stored
+/// in compact containers like any other plain value.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Symbol(u32);
+
+impl Symbol {
+    /// The index of the symbol in its interner
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// Stores unique strings (like names or tags) once and refers to them by `Symbol`.
+///
+/// All strings are stored back to back in one `CompactVec` of bytes,
+/// with another one of the ends of each string and an open addressing table of
+/// symbols (hashed with `FxHasher`) to look strings up, so all of it compacts.
+pub struct CompactInterner {
+    bytes: CompactVec<u8>,
+    ends: CompactVec<u32>,
+    table: CompactVec<u32>,
+}
+
+fn hash(string: &str) -> u64 {
+    let mut hasher = FxHasher::default();
+    hasher.write(string.as_bytes());
+    hasher.finish()
+}
+
+impl CompactInterner {
+    /// Create a new, empty interner
+    pub fn new() -> Self {
+        CompactInterner {
+            bytes: CompactVec::new(),
+            ends: CompactVec::new(),
+            table: CompactVec::new(),
+        }
+    }
+
+    /// Amount of interned strings
+    pub fn len(&self) -> usize {
+        self.ends.len()
+    }
+
+    /// Are there no interned strings?
+    pub fn is_empty(&self) -> bool {
+        self.ends.is_empty()
+    }
+
+    /// The string for `symbol`, if it was handed out by this interner
+    pub fn resolve(&self, symbol: Symbol) -> Option<&str> {
+        let end = *self.ends.get(symbol.index())? as usize;
+        let start = match symbol.index() {
+            0 => 0,
+            i => self.ends[i - 1] as usize,
+        };
+        // only valid UTF-8 is interned (or read, see `read_dynamic_part`)
+        Some(unsafe { ::std::str::from_utf8_unchecked(&self.bytes[start..end]) })
+    }
+
+    /// The table slot of `string`, or the empty slot where it would go
+    fn slot(&self, string: &str) -> usize {
+        let mask = self.table.len() - 1;
+        // the high bits of the hash depend on all bytes
+        let mut slot = (hash(string) >> (64 - self.table.len().trailing_zeros())) as usize;
+        loop {
+            match self.table[slot] {
+                EMPTY => return slot,
+                symbol if self.resolve(Symbol(symbol)) == Some(string) => return slot,
+                _ => slot = (slot + 1) & mask,
+            }
+        }
+    }
+
+    /// The symbol for `string`, if it is interned
+    pub fn get(&self, string: &str) -> Option<Symbol> {
+        if self.table.is_empty() {
+            return None;
+        }
+        match self.table[self.slot(string)] {
+            EMPTY => None,
+            symbol => Some(Symbol(symbol)),
+        }
+    }
+
+    /// The symbol for `string`, interning it first if needed
+    pub fn get_or_intern(&mut self, string: &str) -> Symbol {
+        if let Some(symbol) = self.get(string) {
+            return symbol;
+        }
+        // keep the table at most half full
+        if (self.len() + 1) * 2 > self.table.len() {
+            self.grow_table();
+        }
+        let symbol = self.len() as u32;
+        self.bytes.extend_from_copy_slice(string.as_bytes());
+        self.ends.push(self.bytes.len() as u32);
+        let slot = self.slot(string);
+        self.table[slot] = symbol;
+        Symbol(symbol)
+    }
+
+    fn grow_table(&mut self) {
+        let cap = (self.table.len() * 2).max(16);
+        self.table = vec![EMPTY; cap].into();
+        for symbol in 0..self.len() as u32 {
+            let slot = self.slot(self.resolve(Symbol(symbol)).unwrap());
+            self.table[slot] = symbol;
+        }
+    }
+
+    /// Iterator over all symbols and their strings, in the order they were interned
+    pub fn iter(&self) -> impl Iterator<Item = (Symbol, &str)> + '_ {
+        (0..self.len() as u32).map(move |symbol| {
+            let symbol = Symbol(symbol);
+            (symbol, self.resolve(symbol).unwrap())
+        })
+    }
+
+    /// Why the parts of the interner don't fit together, if they don't
+    fn invalid_reason(&self) -> Option<&'static str> {
+        let mut start = 0;
+        for &end in self.ends.iter() {
+            if end < start || end as usize > self.bytes.len() {
+                return Some("interned strings point outside of the pool");
+            }
+            if ::std::str::from_utf8(&self.bytes[start as usize..end as usize]).is_err() {
+                return Some("interned string contains invalid UTF-8");
+            }
+            start = end;
+        }
+        let used = self.table.iter().filter(|&&symbol| symbol != EMPTY).count();
+        // lookups need a power of two of slots, with at least one of them empty
+        let table_valid = if self.table.is_empty() {
+            self.is_empty()
+        } else {
+            self.table.len().is_power_of_two() && self.table.len() > 1 && used < self.table.len()
+        };
+        if !table_valid
+            || used > self.len()
+            || self
+                .table
+                .iter()
+                .any(|&symbol| symbol != EMPTY && symbol as usize >= self.len())
+        {
+            return Some("interner lookup table doesn't match its strings");
+        }
+        None
+    }
+}
+
+impl Compact for CompactInterner {
+    fn is_still_compact(&self) -> bool {
+        self.bytes.is_still_compact()
+            && self.ends.is_still_compact()
+            && self.table.is_still_compact()
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        let mut layout = DynamicLayout::new();
+        layout.field(&self.bytes);
+        layout.field(&self.ends);
+        layout.field(&self.table);
+        layout.size()
+    }
+
+    fn dynamic_align() -> usize {
+        CompactVec::<u8>::dynamic_align().max(CompactVec::<u32>::dynamic_align())
+    }
+
+    fn collect_stats(&self, path: &str, stats: &mut CompactStats) {
+        self.bytes.collect_stats(&format!("{}.bytes", path), stats);
+        self.ends.collect_stats(&format!("{}.ends", path), stats);
+        self.table.collect_stats(&format!("{}.table", path), stats);
+    }
+
+    fn repair_lossy(&mut self, path: &str, dropped: &mut Vec<String>) {
+        self.bytes.repair_lossy(&format!("{}.bytes", path), dropped);
+        self.ends.repair_lossy(&format!("{}.ends", path), dropped);
+        self.table.repair_lossy(&format!("{}.table", path), dropped);
+        if self.invalid_reason().is_some() {
+            *self = CompactInterner::new();
+            dropped.push(path.to_owned());
+        }
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        let mut layout = DynamicLayout::new();
+        let bytes_offset = layout.field(&(*source).bytes);
+        let ends_offset = layout.field(&(*source).ends);
+        let table_offset = layout.field(&(*source).table);
+        Compact::compact(
+            &mut (*source).bytes,
+            &mut (*dest).bytes,
+            new_dynamic_part.add(bytes_offset),
+        );
+        Compact::compact(
+            &mut (*source).ends,
+            &mut (*dest).ends,
+            new_dynamic_part.add(ends_offset),
+        );
+        Compact::compact(
+            &mut (*source).table,
+            &mut (*dest).table,
+            new_dynamic_part.add(table_offset),
+        );
+    }
+
+    unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+        self.bytes.shift_compact_pointers(shift);
+        self.ends.shift_compact_pointers(shift);
+        self.table.shift_compact_pointers(shift);
+    }
+
+    unsafe fn decompact(source: *const Self) -> Self {
+        // `from_compact_bytes` checks for invalid interners while reading, see `read_dynamic_part`
+        CompactInterner {
+            bytes: Compact::decompact(&(*source).bytes),
+            ends: Compact::decompact(&(*source).ends),
+            table: Compact::decompact(&(*source).table),
+        }
+    }
+
+    unsafe fn read_dynamic_part<R: Read>(
+        source: *const Self,
+        reader: &mut CompactReader<R>,
+    ) -> Result<Self, FromReaderError> {
+        let interner = CompactInterner {
+            bytes: reader.read_field(&(*source).bytes)?,
+            ends: reader.read_field(&(*source).ends)?,
+            table: reader.read_field(&(*source).table)?,
+        };
+        match interner.invalid_reason() {
+            Some(reason) => Err(reader.invalid(reason)),
+            None => Ok(interner),
+        }
+    }
+}
+
+impl Clone for CompactInterner {
+    fn clone(&self) -> Self {
+        CompactInterner {
+            bytes: self.bytes.clone(),
+            ends: self.ends.clone(),
+            table: self.table.clone(),
+        }
+    }
+}
+
+impl Default for CompactInterner {
+    fn default() -> Self {
+        CompactInterner::new()
+    }
+}
+
+impl ::std::fmt::Debug for CompactInterner {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_map()
+            .entries(self.iter().map(|(symbol, string)| (symbol.0, string)))
+            .finish()
+    }
+}
+
+impl<'a> ::std::iter::FromIterator<&'a str> for CompactInterner {
+    /// Intern all strings of an iterator
+    fn from_iter<T: IntoIterator<Item = &'a str>>(iter: T) -> Self {
+        let mut interner = CompactInterner::new();
+        for string in iter {
+            interner.get_or_intern(string);
+        }
+        interner
+    }
+}
+
+#[test]
+fn get_or_intern_and_resolve() {
+    let mut interner = CompactInterner::new();
+    let names: Vec<String> = (0..100).map(|i| format!("actor {}", i % 40)).collect();
+    let symbols: Vec<Symbol> = names
+        .iter()
+        .map(|name| interner.get_or_intern(name))
+        .collect();
+    assert_eq!(40, interner.len());
+    assert_eq!(symbols[3], symbols[43]);
+    assert_eq!(Symbol(39), symbols[39]);
+    for (name, symbol) in names.iter().zip(&symbols) {
+        assert_eq!(Some(name.as_str()), interner.resolve(*symbol));
+    }
+    assert_eq!(Some(Symbol(0)), interner.get("actor 0"));
+    assert_eq!(None, interner.get("actor 40"));
+    assert_eq!(None, interner.resolve(Symbol(40)));
+    assert_eq!(Symbol(40), interner.get_or_intern(""));
+    assert_eq!(Some(""), interner.resolve(Symbol(40)));
+}
+
+#[test]
+fn compact_interner() {
+    use super::compacted_box::CompactedBox;
+    let interner: CompactInterner = ["tree", "rock", "tree", "bush"].iter().cloned().collect();
+    let mut boxed = CompactedBox::new(interner.clone());
+    assert!(boxed.is_still_compact());
+    assert_eq!(Some(Symbol(1)), boxed.get("rock"));
+    assert_eq!(Some("bush"), boxed.resolve(Symbol(2)));
+    assert_eq!(
+        Symbol(3),
+        boxed.with_mut(|interner| interner.get_or_intern("moss"))
+    );
+    assert_eq!(Some("moss"), boxed.resolve(Symbol(3)));
+
+    let decompacted =
+        unsafe { CompactInterner::from_compact_bytes(&interner.compact_to_bytes()) }.unwrap();
+    assert_eq!(Some(Symbol(0)), decompacted.get("tree"));
+    let mut stream = Vec::new();
+    interner.compact_to_writer(&mut stream).unwrap();
+    // corrupt the last string end
+    let position = stream.len() - 16 * 4 - 4;
+    stream[position] = 200;
+    match unsafe { CompactInterner::decompact_from_reader(&mut &stream[..]) } {
+        Err(FromReaderError::Invalid { reason, .. }) => {
+            assert_eq!("interned strings point outside of the pool", reason)
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+}
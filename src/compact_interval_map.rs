@@ -0,0 +1,317 @@
+use super::compact::{Compact, CompactReader, CompactStats, DynamicLayout, FromReaderError};
+use super::compact_vec::CompactVec;
+use super::simple_allocator_trait::{Allocator, DefaultHeap};
+use std::io::Read;
+use std::ops::Range;
+
+/// A stored interval, together with the largest end of it and all intervals before it
+#[derive(Copy, Clone, Debug)]
+struct Interval<K> {
+    start: K,
+    end: K,
+    max_end: K,
+}
+
+/// A map from half-open ranges of keys (like tick ranges) to values,
+/// answering which ranges contain a point or overlap another range.
+///
+/// Ranges may overlap each other and are kept sorted by their start in a `CompactVec`,
+/// so inserts and removals have to shift all later ranges.
+pub struct CompactIntervalMap<K: Copy, V: Compact + Clone, A: Allocator = DefaultHeap> {
+    intervals: CompactVec<Interval<K>, A>,
+    values: CompactVec<V, A>,
+}
+
+impl<K: Ord + Copy, V: Compact + Clone, A: Allocator> CompactIntervalMap<K, V, A> {
+    /// Create a new, empty interval map
+    pub fn new() -> Self {
+        CompactIntervalMap {
+            intervals: CompactVec::new(),
+            values: CompactVec::new(),
+        }
+    }
+
+    /// Create a new, empty interval map with a given capacity
+    pub fn with_capacity(cap: usize) -> Self {
+        CompactIntervalMap {
+            intervals: CompactVec::with_capacity(cap),
+            values: CompactVec::with_capacity(cap),
+        }
+    }
+
+    /// Amount of ranges in the map
+    pub fn len(&self) -> usize {
+        self.intervals.len()
+    }
+
+    /// Is the map empty?
+    pub fn is_empty(&self) -> bool {
+        self.intervals.is_empty()
+    }
+
+    /// Insert `value` for `range`, after all ranges with the same start.
+    /// Empty ranges are kept, but never contain or overlap anything.
+    pub fn insert(&mut self, range: Range<K>, value: V) {
+        let i = self
+            .intervals
+            .partition_point(|interval| interval.start <= range.start);
+        self.intervals.insert(
+            i,
+            Interval {
+                start: range.start,
+                end: range.end,
+                max_end: range.end,
+            },
+        );
+        self.values.insert(i, value);
+        self.update_max_ends(i);
+    }
+
+    /// Remove the first range equal to `range` and return its value, if it existed
+    pub fn remove(&mut self, range: Range<K>) -> Option<V> {
+        let first = self
+            .intervals
+            .partition_point(|interval| interval.start < range.start);
+        let i = first
+            + self.intervals[first..]
+                .iter()
+                .take_while(|interval| interval.start == range.start)
+                .position(|interval| interval.end == range.end)?;
+        self.intervals.remove(i);
+        let value = self.values.remove(i);
+        self.update_max_ends(i);
+        Some(value)
+    }
+
+    /// Recompute the largest ends from index `from` on
+    fn update_max_ends(&mut self, from: usize) {
+        let mut max_end = match from.checked_sub(1) {
+            Some(previous) => Some(self.intervals[previous].max_end),
+            None => None,
+        };
+        for interval in &mut self.intervals[from..] {
+            let end = max_end.map_or(interval.end, |max_end| max_end.max(interval.end));
+            interval.max_end = end;
+            max_end = Some(end);
+        }
+    }
+
+    /// The ranges starting before `end` that might end after `start`
+    fn candidates(&self, start: &K, end: impl Fn(&K) -> bool) -> Range<usize> {
+        let first = self
+            .intervals
+            .partition_point(|interval| interval.max_end <= *start);
+        let last = self
+            .intervals
+            .partition_point(|interval| end(&interval.start));
+        first..last.max(first)
+    }
+
+    /// Iterator over all ranges containing `point` and their values, in ascending order of starts
+    pub fn containing<'a>(&'a self, point: K) -> impl Iterator<Item = (Range<K>, &'a V)> + 'a {
+        let candidates = self.candidates(&point, |start| *start <= point);
+        self.pairs_in(candidates)
+            .filter(move |(range, _)| range.contains(&point))
+    }
+
+    /// Iterator over all ranges overlapping `range` and their values, in ascending order of starts
+    pub fn overlapping<'a>(
+        &'a self,
+        range: Range<K>,
+    ) -> impl Iterator<Item = (Range<K>, &'a V)> + 'a {
+        let candidates = self.candidates(&range.start, |start| *start < range.end);
+        // also rules out empty ranges on either side
+        self.pairs_in(candidates)
+            .filter(move |(other, _)| other.start.max(range.start) < other.end.min(range.end))
+    }
+
+    /// Iterator over all ranges and their values, in ascending order of starts
+    pub fn pairs<'a>(&'a self) -> impl Iterator<Item = (Range<K>, &'a V)> + 'a {
+        self.pairs_in(0..self.len())
+    }
+
+    fn pairs_in<'a>(
+        &'a self,
+        indices: Range<usize>,
+    ) -> impl Iterator<Item = (Range<K>, &'a V)> + 'a {
+        self.intervals[indices.clone()]
+            .iter()
+            .map(|interval| interval.start..interval.end)
+            .zip(self.values[indices].iter())
+    }
+
+    /// Iterator over mutable references to all values, in ascending order of their starts
+    pub fn values_mut(&mut self) -> ::std::slice::IterMut<'_, V> {
+        self.values.iter_mut()
+    }
+}
+
+impl<K: Copy, V: Compact + Clone, A: Allocator> Compact for CompactIntervalMap<K, V, A> {
+    fn is_still_compact(&self) -> bool {
+        self.intervals.is_still_compact() && self.values.is_still_compact()
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        let mut layout = DynamicLayout::new();
+        layout.field(&self.intervals);
+        layout.field(&self.values);
+        layout.size()
+    }
+
+    fn dynamic_align() -> usize {
+        CompactVec::<Interval<K>, A>::dynamic_align().max(CompactVec::<V, A>::dynamic_align())
+    }
+
+    fn collect_stats(&self, path: &str, stats: &mut CompactStats) {
+        self.intervals
+            .collect_stats(&format!("{}.intervals", path), stats);
+        self.values
+            .collect_stats(&format!("{}.values", path), stats);
+    }
+
+    fn repair_lossy(&mut self, path: &str, dropped: &mut Vec<String>) {
+        self.intervals
+            .repair_lossy(&format!("{}.intervals", path), dropped);
+        self.values
+            .repair_lossy(&format!("{}.values", path), dropped);
+        // only keep ranges that still have a value,
+        // the largest ends of the remaining ones stay valid
+        let len = self.intervals.len().min(self.values.len());
+        self.intervals.truncate(len);
+        self.values.truncate(len);
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        let mut layout = DynamicLayout::new();
+        let intervals_offset = layout.field(&(*source).intervals);
+        let values_offset = layout.field(&(*source).values);
+        Compact::compact(
+            &mut (*source).intervals,
+            &mut (*dest).intervals,
+            new_dynamic_part.add(intervals_offset),
+        );
+        Compact::compact(
+            &mut (*source).values,
+            &mut (*dest).values,
+            new_dynamic_part.add(values_offset),
+        );
+    }
+
+    unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+        self.intervals.shift_compact_pointers(shift);
+        self.values.shift_compact_pointers(shift);
+    }
+
+    unsafe fn decompact(source: *const Self) -> Self {
+        CompactIntervalMap {
+            intervals: Compact::decompact(&(*source).intervals),
+            values: Compact::decompact(&(*source).values),
+        }
+    }
+
+    unsafe fn read_dynamic_part<R: Read>(
+        source: *const Self,
+        reader: &mut CompactReader<R>,
+    ) -> Result<Self, FromReaderError> {
+        let map = CompactIntervalMap {
+            intervals: reader.read_field(&(*source).intervals)?,
+            values: reader.read_field(&(*source).values)?,
+        };
+        if map.intervals.len() == map.values.len() {
+            Ok(map)
+        } else {
+            Err(reader.invalid("interval map has a different amount of ranges and values"))
+        }
+    }
+}
+
+impl<K: Copy, V: Compact + Clone, A: Allocator> Clone for CompactIntervalMap<K, V, A> {
+    fn clone(&self) -> Self {
+        CompactIntervalMap {
+            intervals: self.intervals.clone(),
+            values: self.values.clone(),
+        }
+    }
+}
+
+impl<K: Ord + Copy, V: Compact + Clone, A: Allocator> Default for CompactIntervalMap<K, V, A> {
+    fn default() -> Self {
+        CompactIntervalMap::new()
+    }
+}
+
+impl<K: Ord + Copy + ::std::fmt::Debug, V: Compact + Clone + ::std::fmt::Debug, A: Allocator>
+    ::std::fmt::Debug for CompactIntervalMap<K, V, A>
+{
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_map().entries(self.pairs()).finish()
+    }
+}
+
+impl<K: Ord + Copy, V: Compact + Clone, A: Allocator> ::std::iter::FromIterator<(Range<K>, V)>
+    for CompactIntervalMap<K, V, A>
+{
+    /// Construct an interval map from an iterator over ranges and their values
+    fn from_iter<T: IntoIterator<Item = (Range<K>, V)>>(iter: T) -> Self {
+        let mut map = Self::new();
+        for (range, value) in iter {
+            map.insert(range, value);
+        }
+        map
+    }
+}
+
+#[test]
+fn containing_and_overlapping() {
+    let mut map: CompactIntervalMap<u32, char> = CompactIntervalMap::new();
+    map.insert(10..20, 'a');
+    map.insert(0..100, 'b');
+    map.insert(15..16, 'c');
+    map.insert(30..40, 'd');
+    map.insert(10..12, 'e');
+    map.insert(50..50, 'f');
+
+    type Map = CompactIntervalMap<u32, char>;
+    let containing = |map: &Map, point| map.containing(point).map(|(_, v)| *v).collect::<String>();
+    assert_eq!("b", containing(&map, 0));
+    assert_eq!("bae", containing(&map, 10));
+    assert_eq!("bac", containing(&map, 15));
+    assert_eq!("b", containing(&map, 20));
+    assert_eq!("b", containing(&map, 50));
+    assert_eq!("", containing(&map, 100));
+
+    let overlapping =
+        |map: &Map, range| map.overlapping(range).map(|(_, v)| *v).collect::<String>();
+    assert_eq!("baec", overlapping(&map, 5..16));
+    assert_eq!("bd", overlapping(&map, 20..31));
+    assert_eq!("bd", overlapping(&map, 39..45));
+    assert_eq!("", overlapping(&map, 100..200));
+    assert_eq!("", overlapping(&map, 15..15));
+
+    assert_eq!(Some('b'), map.remove(0..100));
+    assert_eq!(None, map.remove(0..100));
+    assert_eq!("", containing(&map, 0));
+    assert_eq!("ac", overlapping(&map, 12..30));
+    assert_eq!(5, map.len());
+}
+
+#[test]
+fn compact_interval_map() {
+    use super::compact_str::CompactString;
+    use super::compacted_box::CompactedBox;
+    let map: CompactIntervalMap<i64, CompactString> = (0..50)
+        .map(|i| (i * 10..i * 10 + 25, format!("event {}", i).into()))
+        .collect();
+    let boxed = CompactedBox::new(map.clone());
+    assert!(boxed.is_still_compact());
+    let events = boxed
+        .containing(245)
+        .map(|(range, name)| format!("{:?} {}", range, name.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(vec!["230..255 event 23", "240..265 event 24"], events);
+    assert_eq!(4, boxed.overlapping(100..115).count());
+
+    let decompacted: CompactIntervalMap<i64, CompactString> =
+        unsafe { Compact::from_compact_bytes(&map.compact_to_bytes()) }.unwrap();
+    assert!(decompacted.pairs().eq(map.pairs()));
+}
@@ -32,6 +32,10 @@ impl Compact for Opaque {
         Compact::compact(&mut (*source).data, &mut (*dest).data, new_dynamic_part)
     }
 
+    unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+        self.data.shift_compact_pointers(shift);
+    }
+
     unsafe fn decompact(source: *const Self) -> Self {
         Opaque {
             data: Compact::decompact(&(*source).data),
@@ -1,11 +1,90 @@
-use super::compact::Compact;
+use super::compact::{Compact, CompactReader, CompactStats, FromReaderError};
+use std::io::Read;
 
 /// A wrapper to make an `Option` of a nontrivial `Compact` possible.
 /// Unfortunately, we can't blanket-`impl` that, since that overlaps
 /// (for the compiler) with the `impl` for trivial `Copy` types...
-#[derive(Clone, Default, Debug)]
+#[derive(Clone, Default, Debug, PartialEq, Eq, Hash)]
 pub struct CompactOption<T: Compact + Clone>(pub Option<T>);
 
+impl<T: Compact + Clone> CompactOption<T> {
+    /// Reference to the contained value, if any
+    pub fn as_ref(&self) -> Option<&T> {
+        self.0.as_ref()
+    }
+
+    /// Mutable reference to the contained value, if any
+    pub fn as_mut(&mut self) -> Option<&mut T> {
+        self.0.as_mut()
+    }
+
+    /// Map the contained value, if any
+    pub fn map<U, F: FnOnce(T) -> U>(self, f: F) -> Option<U> {
+        self.0.map(f)
+    }
+
+    /// `None` if there is no value, otherwise calls `f` with the value and returns the result
+    pub fn and_then<U, F: FnOnce(T) -> Option<U>>(self, f: F) -> Option<U> {
+        self.0.and_then(f)
+    }
+
+    /// The contained value or a default
+    pub fn unwrap_or_default(self) -> T
+    where
+        T: Default,
+    {
+        self.0.unwrap_or_default()
+    }
+
+    /// Take the value out, leaving `None` in its place.
+    /// Unlike `Option::take`, this also works if the value is stored compactly.
+    pub fn take(&mut self) -> Option<T> {
+        let taken = self
+            .0
+            .as_ref()
+            .map(|value| unsafe { Compact::decompact(value) });
+        if taken.is_some() {
+            // the value should be decompacted (not dropped), else internal relative pointers get messed up!
+            unsafe { ::std::ptr::write(&mut self.0, None) };
+        }
+        taken
+    }
+
+    /// Put `value` in place and return the previous value, if any.
+    /// Unlike `Option::replace`, this also works if the previous value is stored compactly.
+    pub fn replace(&mut self, value: T) -> Option<T> {
+        let old = self.take();
+        self.0 = Some(value);
+        old
+    }
+
+    /// Insert the value computed by `f` if there is none, then return a reference to the value
+    pub fn get_or_insert_with<F: FnOnce() -> T>(&mut self, f: F) -> &mut T {
+        if self.0.is_none() {
+            self.0 = Some(f());
+        }
+        self.0.as_mut().unwrap()
+    }
+}
+
+impl<T: Compact + Clone> From<Option<T>> for CompactOption<T> {
+    fn from(option: Option<T>) -> Self {
+        CompactOption(option)
+    }
+}
+
+impl<T: Compact + Clone + PartialEq> PartialEq<Option<T>> for CompactOption<T> {
+    fn eq(&self, other: &Option<T>) -> bool {
+        self.0 == *other
+    }
+}
+
+impl<T: Compact + Clone + PartialEq> PartialEq<CompactOption<T>> for Option<T> {
+    fn eq(&self, other: &CompactOption<T>) -> bool {
+        *self == other.0
+    }
+}
+
 impl<T: Compact + Clone> ::std::ops::Deref for CompactOption<T> {
     type Target = Option<T>;
 
@@ -32,6 +111,28 @@ impl<T: Clone + Compact> Compact for CompactOption<T> {
         self.0.as_ref().map(|t| t.dynamic_size_bytes()).unwrap_or(0)
     }
 
+    fn dynamic_align() -> usize {
+        T::dynamic_align()
+    }
+
+    fn collect_stats(&self, path: &str, stats: &mut CompactStats) {
+        if let Some(ref value) = self.0 {
+            value.collect_stats(path, stats);
+        }
+    }
+
+    fn repair_lossy(&mut self, path: &str, dropped: &mut Vec<String>) {
+        if let Some(ref mut value) = self.0 {
+            value.repair_lossy(path, dropped);
+        }
+    }
+
+    fn clear_dirty(&mut self) {
+        if let Some(ref mut value) = self.0 {
+            value.clear_dirty();
+        }
+    }
+
     unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
         if let CompactOption(Some(ref mut s)) = *source {
             ::std::ptr::copy_nonoverlapping(source, dest, 1);
@@ -45,6 +146,12 @@ impl<T: Clone + Compact> Compact for CompactOption<T> {
         }
     }
 
+    unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+        if let CompactOption(Some(ref mut value)) = *self {
+            value.shift_compact_pointers(shift);
+        }
+    }
+
     unsafe fn decompact(source: *const Self) -> Self {
         if let CompactOption(Some(ref s)) = *source {
             CompactOption(Some(Compact::decompact(s)))
@@ -52,6 +159,17 @@ impl<T: Clone + Compact> Compact for CompactOption<T> {
             CompactOption(None)
         }
     }
+
+    unsafe fn read_dynamic_part<R: Read>(
+        source: *const Self,
+        reader: &mut CompactReader<R>,
+    ) -> Result<Self, FromReaderError> {
+        if let CompactOption(Some(ref s)) = *source {
+            Ok(CompactOption(Some(reader.read_field(s)?)))
+        } else {
+            Ok(CompactOption(None))
+        }
+    }
 }
 
 #[cfg(feature = "serde-serialization")]
@@ -121,6 +239,19 @@ impl<'de, T: Compact + ::serde::de::Deserialize<'de>> ::serde::de::Deserialize<'
     }
 }
 
+#[cfg(feature = "arbitrary")]
+impl<'a, T: Compact + Clone + ::arbitrary::Arbitrary<'a>> ::arbitrary::Arbitrary<'a>
+    for CompactOption<T>
+{
+    fn arbitrary(u: &mut ::arbitrary::Unstructured<'a>) -> ::arbitrary::Result<Self> {
+        Ok(CompactOption(::arbitrary::Arbitrary::arbitrary(u)?))
+    }
+
+    fn size_hint(depth: usize) -> (usize, Option<usize>) {
+        <Option<T> as ::arbitrary::Arbitrary>::size_hint(depth)
+    }
+}
+
 #[test]
 fn basic_option() {
     use super::compact_vec::CompactVec;
@@ -157,3 +288,72 @@ fn basic_option() {
         DefaultHeap::deallocate(storage, bytes);
     }
 }
+
+#[test]
+fn equality() {
+    use super::compact_vec::CompactVec;
+    let option: CompactOption<CompactVec<u32>> = CompactOption(Some(vec![1, 2].into()));
+    assert_eq!(option, CompactOption(Some(vec![1, 2].into())));
+    assert_ne!(option, CompactOption(None));
+    assert_eq!(option, Some(vec![1, 2].into()));
+    assert_eq!(None, CompactOption::<CompactVec<u32>>(None));
+}
+
+#[test]
+fn combinators() {
+    use super::compact_vec::CompactVec;
+    let mut option: CompactOption<CompactVec<u32>> = None.into();
+    assert_eq!(None, option.as_ref());
+    option.get_or_insert_with(CompactVec::new).push(1);
+    option.get_or_insert_with(|| unreachable!()).push(2);
+    assert_eq!(Some(2), option.as_ref().map(|list| list.len()));
+    option.as_mut().unwrap().push(3);
+
+    assert_eq!(Some(3), option.clone().map(|list| list.len()));
+    assert_eq!(None, option.clone().and_then(|list| list.get(5).cloned()));
+    assert_eq!(vec![1, 2, 3], option.clone().unwrap_or_default());
+    assert_eq!(0, CompactOption::<CompactVec<u32>>(None).unwrap_or_default().len());
+
+    assert_eq!(Some(vec![1, 2, 3].into()), option.replace(vec![4].into()));
+    assert_eq!(Some(vec![4].into()), option.take());
+    assert_eq!(None, option.take());
+}
+
+#[test]
+fn take_from_compact_storage() {
+    use super::compact_vec::CompactVec;
+    use super::simple_allocator_trait::{Allocator, DefaultHeap};
+    type Nested = CompactOption<CompactVec<CompactVec<u32>>>;
+    let mut option: Nested = CompactOption(Some(vec![vec![1, 2].into(); 3].into()));
+
+    let bytes = option.total_size_bytes();
+    let storage = DefaultHeap::allocate(bytes) as *mut Nested;
+    unsafe {
+        Compact::compact_behind(&mut option, storage);
+        ::std::mem::forget(option);
+        let taken = (*storage).take().unwrap();
+        assert_eq!(None, (*storage).as_ref());
+        // the taken value has to stay valid after the compact storage is gone
+        ::std::ptr::write_bytes(storage as *mut u8, 0, bytes);
+        DefaultHeap::deallocate(storage as *mut u8, bytes);
+        assert_eq!(3, taken.len());
+        assert!(taken.iter().all(|list| list == &[1, 2]));
+    }
+}
+
+#[cfg(feature = "serde-serialization")]
+#[test]
+fn serde_roundtrip() {
+    use super::compact_vec::CompactVec;
+    let some: CompactOption<CompactVec<u32>> = CompactOption(Some(vec![1, 2].into()));
+    let json = ::serde_json::to_string(&some).unwrap();
+    assert_eq!(json, "[1,2]");
+    let deserialized: CompactOption<CompactVec<u32>> = ::serde_json::from_str(&json).unwrap();
+    assert_eq!(deserialized, some);
+
+    let none: CompactOption<CompactVec<u32>> = CompactOption(None);
+    let json = ::serde_json::to_string(&none).unwrap();
+    assert_eq!(json, "null");
+    let deserialized: CompactOption<CompactVec<u32>> = ::serde_json::from_str(&json).unwrap();
+    assert_eq!(deserialized, none);
+}
@@ -0,0 +1,214 @@
+use super::compact::{Compact, CompactReader, CompactStats, FromReaderError};
+use std::io::Read;
+
+/// A wrapper to make a `Result` of nontrivial `Compact`s possible,
+/// like `CompactOption` does for `Option`.
+/// Only the active variant contributes to the dynamic size.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CompactResult<T: Compact + Clone, E: Compact + Clone>(pub Result<T, E>);
+
+impl<T: Compact + Clone, E: Compact + Clone> CompactResult<T, E> {
+    /// Is the result `Ok`?
+    pub fn is_ok(&self) -> bool {
+        self.0.is_ok()
+    }
+
+    /// Is the result `Err`?
+    pub fn is_err(&self) -> bool {
+        self.0.is_err()
+    }
+
+    /// Convert into a `std::result::Result`
+    pub fn into_result(self) -> Result<T, E> {
+        self.0
+    }
+}
+
+impl<T: Compact + Clone, E: Compact + Clone> From<Result<T, E>> for CompactResult<T, E> {
+    fn from(result: Result<T, E>) -> Self {
+        CompactResult(result)
+    }
+}
+
+impl<T: Compact + Clone, E: Compact + Clone> From<CompactResult<T, E>> for Result<T, E> {
+    fn from(result: CompactResult<T, E>) -> Self {
+        result.0
+    }
+}
+
+impl<T: Compact + Clone + PartialEq, E: Compact + Clone + PartialEq> PartialEq<Result<T, E>>
+    for CompactResult<T, E>
+{
+    fn eq(&self, other: &Result<T, E>) -> bool {
+        self.0 == *other
+    }
+}
+
+impl<T: Compact + Clone, E: Compact + Clone> ::std::ops::Deref for CompactResult<T, E> {
+    type Target = Result<T, E>;
+
+    fn deref(&self) -> &Result<T, E> {
+        &self.0
+    }
+}
+
+impl<T: Compact + Clone, E: Compact + Clone> ::std::ops::DerefMut for CompactResult<T, E> {
+    fn deref_mut(&mut self) -> &mut Result<T, E> {
+        &mut self.0
+    }
+}
+
+impl<T: Compact + Clone, E: Compact + Clone> Compact for CompactResult<T, E> {
+    fn is_still_compact(&self) -> bool {
+        match self.0 {
+            Ok(ref value) => value.is_still_compact(),
+            Err(ref error) => error.is_still_compact(),
+        }
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        match self.0 {
+            Ok(ref value) => value.dynamic_size_bytes(),
+            Err(ref error) => error.dynamic_size_bytes(),
+        }
+    }
+
+    fn dynamic_align() -> usize {
+        T::dynamic_align().max(E::dynamic_align())
+    }
+
+    fn collect_stats(&self, path: &str, stats: &mut CompactStats) {
+        match self.0 {
+            Ok(ref value) => value.collect_stats(&format!("{}.ok", path), stats),
+            Err(ref error) => error.collect_stats(&format!("{}.err", path), stats),
+        }
+    }
+
+    fn repair_lossy(&mut self, path: &str, dropped: &mut Vec<String>) {
+        match self.0 {
+            Ok(ref mut value) => value.repair_lossy(&format!("{}.ok", path), dropped),
+            Err(ref mut error) => error.repair_lossy(&format!("{}.err", path), dropped),
+        }
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        ::std::ptr::copy_nonoverlapping(source, dest, 1);
+        match ((*source).0.as_mut(), (*dest).0.as_mut()) {
+            (Ok(s), Ok(d)) => Compact::compact(s, d, new_dynamic_part),
+            (Err(s), Err(d)) => Compact::compact(s, d, new_dynamic_part),
+            _ => unreachable!(),
+        }
+    }
+
+    unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+        match self.0 {
+            Ok(ref mut value) => value.shift_compact_pointers(shift),
+            Err(ref mut error) => error.shift_compact_pointers(shift),
+        }
+    }
+
+    unsafe fn decompact(source: *const Self) -> Self {
+        match (*source).0 {
+            Ok(ref value) => CompactResult(Ok(Compact::decompact(value))),
+            Err(ref error) => CompactResult(Err(Compact::decompact(error))),
+        }
+    }
+
+    unsafe fn read_dynamic_part<R: Read>(
+        source: *const Self,
+        reader: &mut CompactReader<R>,
+    ) -> Result<Self, FromReaderError> {
+        Ok(match (*source).0 {
+            Ok(ref value) => CompactResult(Ok(reader.read_field(value)?)),
+            Err(ref error) => CompactResult(Err(reader.read_field(error)?)),
+        })
+    }
+}
+
+#[cfg(feature = "serde-serialization")]
+impl<T, E> ::serde::ser::Serialize for CompactResult<T, E>
+where
+    T: Compact + Clone + ::serde::ser::Serialize,
+    E: Compact + Clone + ::serde::ser::Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: ::serde::ser::Serializer,
+    {
+        self.0.serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde-serialization")]
+impl<'de, T, E> ::serde::de::Deserialize<'de> for CompactResult<T, E>
+where
+    T: Compact + Clone + ::serde::de::Deserialize<'de>,
+    E: Compact + Clone + ::serde::de::Deserialize<'de>,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: ::serde::de::Deserializer<'de>,
+    {
+        Result::deserialize(deserializer).map(CompactResult)
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a, T, E> ::arbitrary::Arbitrary<'a> for CompactResult<T, E>
+where
+    T: Compact + Clone + ::arbitrary::Arbitrary<'a>,
+    E: Compact + Clone + ::arbitrary::Arbitrary<'a>,
+{
+    fn arbitrary(u: &mut ::arbitrary::Unstructured<'a>) -> ::arbitrary::Result<Self> {
+        Ok(CompactResult(::arbitrary::Arbitrary::arbitrary(u)?))
+    }
+
+    fn size_hint(depth: usize) -> (usize, Option<usize>) {
+        <Result<T, E> as ::arbitrary::Arbitrary>::size_hint(depth)
+    }
+}
+
+#[test]
+fn compact_result() {
+    use super::compact_str::CompactString;
+    use super::compact_vec::CompactVec;
+    use super::simple_allocator_trait::{Allocator, DefaultHeap};
+    type Message = CompactResult<CompactVec<u32>, CompactString>;
+
+    for original in [
+        Message::from(Ok(vec![1, 2, 3].into())),
+        Message::from(Err("Something went wrong".into())),
+    ] {
+        let mut result = original.clone();
+        let bytes = result.total_size_bytes();
+        let storage = DefaultHeap::allocate(bytes) as *mut Message;
+        unsafe {
+            Compact::compact_behind(&mut result, storage);
+            ::std::mem::forget(result);
+            assert!((*storage).is_still_compact());
+            assert_eq!(original, *storage);
+            let decompacted = Compact::decompact(storage);
+            DefaultHeap::deallocate(storage as *mut u8, bytes);
+            assert_eq!(original, decompacted);
+        }
+    }
+
+    let ok: Message = Ok(vec![1].into()).into();
+    let err: Message = Err("error".into()).into();
+    assert!(ok.is_ok() && err.is_err());
+    assert_eq!(4, ok.dynamic_size_bytes());
+    assert_eq!(5, err.dynamic_size_bytes());
+    assert_eq!(Err("error".into()), Result::from(err));
+}
+
+#[cfg(feature = "serde-serialization")]
+#[test]
+fn serde_roundtrip() {
+    use super::compact_vec::CompactVec;
+    let ok: CompactResult<CompactVec<u32>, CompactVec<u8>> = CompactResult(Ok(vec![1, 2].into()));
+    let json = ::serde_json::to_string(&ok).unwrap();
+    assert_eq!(json, "{\"Ok\":[1,2]}");
+    let deserialized: CompactResult<CompactVec<u32>, CompactVec<u8>> =
+        ::serde_json::from_str(&json).unwrap();
+    assert_eq!(deserialized, ok);
+}
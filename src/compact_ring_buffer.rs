@@ -0,0 +1,357 @@
+use super::compact::{Compact, CompactReader, CompactStats, DynamicLayout, FromReaderError};
+use std::io::Read;
+use std::mem::MaybeUninit;
+use std::ptr;
+
+/// A queue of the last `N` values pushed to it (like rolling samples),
+/// where pushing to a full buffer overwrites the oldest value.
+///
+/// All `N` slots are stored inline in the static part, so the buffer never allocates
+/// and its dynamic part only consists of the dynamic parts of its elements.
+pub struct CompactRingBuffer<T, const N: usize> {
+    slots: [MaybeUninit<T>; N],
+    /// Slot of the oldest element
+    start: u32,
+    len: u32,
+}
+
+impl<T: Compact, const N: usize> CompactRingBuffer<T, N> {
+    /// Create a new, empty ring buffer
+    pub fn new() -> Self {
+        CompactRingBuffer {
+            // an array of `MaybeUninit`s doesn't need to be initialized
+            slots: unsafe { MaybeUninit::uninit().assume_init() },
+            start: 0,
+            len: 0,
+        }
+    }
+
+    /// Amount of elements in the buffer
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    /// Is the buffer empty?
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Does the next push overwrite the oldest element?
+    pub fn is_full(&self) -> bool {
+        self.len() == N
+    }
+
+    /// Maximum amount of elements in the buffer, `N`
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    fn slot(&self, index: usize) -> usize {
+        (self.start as usize + index) % N
+    }
+
+    /// Append `value` as the newest element, removing and returning the oldest one
+    /// if the buffer was full
+    pub fn push(&mut self, value: T) -> Option<T> {
+        if N == 0 {
+            return Some(value);
+        }
+        if self.is_full() {
+            let slot = self.slots[self.start as usize].as_mut_ptr();
+            self.start = self.slot(1) as u32;
+            // decompact, since the element might be stored compactly with its dynamic part
+            unsafe {
+                let oldest = Compact::decompact(slot);
+                ptr::write(slot, value);
+                Some(oldest)
+            }
+        } else {
+            let slot = self.slot(self.len());
+            self.slots[slot] = MaybeUninit::new(value);
+            self.len += 1;
+            None
+        }
+    }
+
+    /// Remove and return the oldest element, if any
+    pub fn pop_front(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        let slot = self.slots[self.start as usize].as_ptr();
+        self.start = self.slot(1) as u32;
+        self.len -= 1;
+        unsafe { Some(Compact::decompact(slot)) }
+    }
+
+    /// Remove all elements
+    pub fn clear(&mut self) {
+        while self.pop_front().is_some() {}
+    }
+
+    /// The elements in the order they were pushed,
+    /// as the slices before and after wrapping around
+    pub fn as_slices(&self) -> (&[T], &[T]) {
+        let start = self.start as usize;
+        let first_len = self.len().min(N - start.min(N));
+        unsafe {
+            let slots = self.slots.as_ptr() as *const T;
+            (
+                ::std::slice::from_raw_parts(slots.add(start), first_len),
+                ::std::slice::from_raw_parts(slots, self.len() - first_len),
+            )
+        }
+    }
+
+    /// The elements in the order they were pushed, mutably,
+    /// as the slices before and after wrapping around
+    pub fn as_mut_slices(&mut self) -> (&mut [T], &mut [T]) {
+        let start = self.start as usize;
+        let first_len = self.len().min(N - start.min(N));
+        unsafe {
+            let slots = self.slots.as_mut_ptr() as *mut T;
+            (
+                ::std::slice::from_raw_parts_mut(slots.add(start), first_len),
+                ::std::slice::from_raw_parts_mut(slots, self.len() - first_len),
+            )
+        }
+    }
+
+    /// The element pushed `index` pushes after the oldest one, if any
+    pub fn get(&self, index: usize) -> Option<&T> {
+        if index < self.len() {
+            unsafe { Some(&*self.slots[self.slot(index)].as_ptr()) }
+        } else {
+            None
+        }
+    }
+
+    /// The oldest element, if any
+    pub fn front(&self) -> Option<&T> {
+        self.get(0)
+    }
+
+    /// The newest element, if any
+    pub fn back(&self) -> Option<&T> {
+        self.get(self.len().wrapping_sub(1))
+    }
+
+    /// Iterator over all elements, from the oldest to the newest
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &T> {
+        let (first, second) = self.as_slices();
+        first.iter().chain(second.iter())
+    }
+
+    /// Iterator over mutable references to all elements, from the oldest to the newest
+    pub fn iter_mut(&mut self) -> impl DoubleEndedIterator<Item = &mut T> {
+        let (first, second) = self.as_mut_slices();
+        first.iter_mut().chain(second.iter_mut())
+    }
+}
+
+impl<T, const N: usize> Drop for CompactRingBuffer<T, N> {
+    fn drop(&mut self) {
+        for i in 0..self.len as usize {
+            unsafe {
+                ptr::drop_in_place(self.slots[(self.start as usize + i) % N].as_mut_ptr());
+            }
+        }
+    }
+}
+
+impl<T: Compact, const N: usize> Compact for CompactRingBuffer<T, N> {
+    fn is_still_compact(&self) -> bool {
+        self.iter().all(|elem| elem.is_still_compact())
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        let mut layout = DynamicLayout::new();
+        for elem in self.iter() {
+            layout.field(elem);
+        }
+        layout.size()
+    }
+
+    fn dynamic_align() -> usize {
+        T::dynamic_align()
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        (*dest).start = (*source).start;
+        (*dest).len = (*source).len;
+        let mut layout = DynamicLayout::new();
+        for i in 0..(*source).len() {
+            let slot = (*source).slot(i);
+            let item = (*source).slots[slot].as_mut_ptr();
+            let offset = layout.field(&*item);
+            Compact::compact(
+                item,
+                (*dest).slots[slot].as_mut_ptr(),
+                new_dynamic_part.add(offset),
+            );
+        }
+    }
+
+    unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+        for i in 0..self.len() {
+            let slot = self.slot(i);
+            (*self.slots[slot].as_mut_ptr()).shift_compact_pointers(shift);
+        }
+    }
+
+    unsafe fn decompact(source: *const Self) -> Self {
+        let mut decompacted = Self::new();
+        for item in (*source).iter() {
+            decompacted.push(Compact::decompact(item));
+        }
+        decompacted
+    }
+
+    unsafe fn read_dynamic_part<R: Read>(
+        source: *const Self,
+        reader: &mut CompactReader<R>,
+    ) -> Result<Self, FromReaderError> {
+        if (*source).len() > N || ((*source).start as usize) >= N.max(1) {
+            return Err(reader.invalid("ring buffer points outside of its slots"));
+        }
+        let mut buffer = Self::new();
+        for i in 0..(*source).len() {
+            buffer.push(reader.read_field((*source).slots[(*source).slot(i)].as_ptr())?);
+        }
+        Ok(buffer)
+    }
+
+    fn collect_stats(&self, path: &str, stats: &mut CompactStats) {
+        for (i, item) in self.iter().enumerate() {
+            item.collect_stats(&format!("{}[{}]", path, i), stats);
+        }
+    }
+
+    fn repair_lossy(&mut self, path: &str, dropped: &mut Vec<String>) {
+        if self.len() > N || (self.start as usize) >= N.max(1) {
+            // the elements can't be dropped safely, leak them instead
+            self.start = 0;
+            self.len = 0;
+            dropped.push(path.to_owned());
+        } else {
+            for i in 0..self.len() {
+                let slot = self.slot(i);
+                unsafe { &mut *self.slots[slot].as_mut_ptr() }
+                    .repair_lossy(&format!("{}[{}]", path, i), dropped);
+            }
+        }
+    }
+}
+
+impl<T: Compact, const N: usize> Clone for CompactRingBuffer<T, N> {
+    fn clone(&self) -> Self {
+        self.iter().cloned().collect()
+    }
+}
+
+impl<T: Compact, const N: usize> Default for CompactRingBuffer<T, N> {
+    fn default() -> Self {
+        CompactRingBuffer::new()
+    }
+}
+
+impl<T: Compact + ::std::fmt::Debug, const N: usize> ::std::fmt::Debug for CompactRingBuffer<T, N> {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<T: Compact + PartialEq, const N: usize> PartialEq for CompactRingBuffer<T, N> {
+    fn eq(&self, other: &Self) -> bool {
+        self.iter().eq(other.iter())
+    }
+}
+
+impl<T: Compact, const N: usize> ::std::iter::FromIterator<T> for CompactRingBuffer<T, N> {
+    /// Construct a ring buffer from the last `N` elements of an iterator
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut buffer = Self::new();
+        buffer.extend(iter);
+        buffer
+    }
+}
+
+impl<T: Compact, const N: usize> ::std::iter::Extend<T> for CompactRingBuffer<T, N> {
+    /// Push all elements of an iterator, overwriting the oldest ones when full
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for item in iter {
+            self.push(item);
+        }
+    }
+}
+
+#[test]
+fn push_overwrites_oldest() {
+    let mut samples: CompactRingBuffer<u32, 3> = CompactRingBuffer::new();
+    assert_eq!(None, samples.push(1));
+    assert_eq!(None, samples.push(2));
+    assert_eq!(None, samples.push(3));
+    assert!(samples.is_full());
+    assert_eq!(Some(1), samples.push(4));
+    assert_eq!(Some(2), samples.push(5));
+    assert_eq!(vec![3, 4, 5], samples.iter().cloned().collect::<Vec<_>>());
+    assert_eq!(
+        vec![5, 4, 3],
+        samples.iter().rev().cloned().collect::<Vec<_>>()
+    );
+    assert_eq!((&[3][..], &[4, 5][..]), samples.as_slices());
+    assert_eq!(Some(&3), samples.front());
+    assert_eq!(Some(&5), samples.back());
+    assert_eq!(Some(3), samples.pop_front());
+    assert_eq!(2, samples.len());
+
+    let mut nothing: CompactRingBuffer<u32, 0> = CompactRingBuffer::new();
+    assert_eq!(Some(1), nothing.push(1));
+    assert!(nothing.is_empty());
+}
+
+#[test]
+fn compact_ring_buffer() {
+    use super::compact_vec::CompactVec;
+    use super::compacted_box::CompactedBox;
+    use super::drop_tracker::DropCounter;
+    let counter = DropCounter::new();
+    let buffer: CompactRingBuffer<_, 4> = (0..6u32)
+        .map(|n| counter.track(CompactVec::<u32>::from(vec![n; n as usize])))
+        .collect();
+    assert_eq!(4, counter.alive());
+
+    let mut boxed = CompactedBox::new(buffer.clone());
+    assert!(boxed.is_still_compact());
+    assert_eq!(buffer, *boxed);
+    assert_eq!(
+        vec![2, 2],
+        **boxed.with_mut(|buffer| buffer.pop_front()).unwrap()
+    );
+    assert_eq!(
+        None,
+        boxed.with_mut(|buffer| buffer.push(counter.track(vec![6].into())))
+    );
+    assert_eq!(
+        vec![3, 3, 3],
+        **boxed
+            .with_mut(|buffer| buffer.push(counter.track(vec![7].into())))
+            .unwrap()
+    );
+    let lens: Vec<_> = boxed.iter().map(|samples| samples.len()).collect();
+    assert_eq!(vec![4, 5, 1, 1], lens);
+
+    let decompacted: CompactRingBuffer<_, 4> =
+        unsafe { Compact::from_compact_bytes(&buffer.compact_to_bytes()) }.unwrap();
+    assert_eq!(buffer, decompacted);
+    drop((buffer, boxed, decompacted));
+    assert_eq!(0, counter.alive());
+
+    let wrapped: CompactRingBuffer<CompactVec<u32>, 2> =
+        (0..3).map(|n| vec![n; 3].into()).collect();
+    let mut stream = Vec::new();
+    wrapped.compact_to_writer(&mut stream).unwrap();
+    let read: CompactRingBuffer<_, 2> =
+        unsafe { Compact::decompact_from_reader(&mut &stream[..]) }.unwrap();
+    assert_eq!(wrapped, read);
+}
@@ -0,0 +1,136 @@
+use super::compact::Compact;
+use super::compact_vec::CompactVec;
+use std::mem;
+use std::ops::Deref;
+
+/// A typed, read-only view of a compacted `CompactVec<T>` living inside a foreign
+/// byte buffer (such as a received network packet), for `T` without a dynamic part.
+///
+/// Accessing the elements never allocates or copies.
+#[derive(Clone, Copy, Debug)]
+pub struct CompactSlice<'a, T: 'a> {
+    elements: &'a [T],
+}
+
+impl<'a, T: Copy + 'a> CompactSlice<'a, T> {
+    /// View the compacted `CompactVec<T>` at the start of `bytes`.
+    ///
+    /// Returns `None` if `bytes` is misaligned, the vector is not stored compactly
+    /// or its elements don't lie completely within `bytes`.
+    ///
+    /// # Safety
+    ///
+    /// `bytes` has to start with the static part of a `CompactVec<T>`, as written by
+    /// `Compact::compact_behind` (with the dynamic part following it in `bytes`).
+    pub unsafe fn from_bytes(bytes: &'a [u8]) -> Option<CompactSlice<'a, T>> {
+        let start = bytes.as_ptr() as usize;
+        if bytes.len() < mem::size_of::<CompactVec<T>>()
+            || !start.is_multiple_of(mem::align_of::<CompactVec<T>>())
+        {
+            return None;
+        }
+
+        let vec = &*(bytes.as_ptr() as *const CompactVec<T>);
+        if !vec.is_still_compact() {
+            return None;
+        }
+
+        if vec.is_empty() {
+            return Some(CompactSlice { elements: &[] });
+        }
+
+        let elements = vec.elements_ptr();
+        let elements_start = elements as usize;
+        let elements_end = vec
+            .len()
+            .checked_mul(mem::size_of::<T>())
+            .and_then(|size| elements_start.checked_add(size))?;
+        if elements.is_null()
+            || elements_start < start
+            || elements_end > start + bytes.len()
+            || !elements_start.is_multiple_of(mem::align_of::<T>())
+        {
+            return None;
+        }
+
+        Some(CompactSlice {
+            elements: ::std::slice::from_raw_parts(elements, vec.len()),
+        })
+    }
+
+    /// Get the viewed elements as a slice, with the lifetime of the underlying buffer
+    pub fn as_slice(&self) -> &'a [T] {
+        self.elements
+    }
+}
+
+impl<'a, T: 'a> Deref for CompactSlice<'a, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        self.elements
+    }
+}
+
+impl<'a, T: 'a> IntoIterator for CompactSlice<'a, T> {
+    type Item = &'a T;
+    type IntoIter = ::std::slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.elements.iter()
+    }
+}
+
+impl<'a, T: 'a> IntoIterator for &CompactSlice<'a, T> {
+    type Item = &'a T;
+    type IntoIter = ::std::slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.elements.iter()
+    }
+}
+
+#[cfg(test)]
+fn compacted_words(list: &mut CompactVec<u32>) -> Vec<u64> {
+    let bytes = list.total_size_bytes();
+    let mut words = vec![0u64; bytes.div_ceil(8)];
+    unsafe {
+        Compact::compact_behind(list, words.as_mut_ptr() as *mut CompactVec<u32>);
+    }
+    words
+}
+
+#[cfg(test)]
+fn as_bytes(words: &[u64]) -> &[u8] {
+    unsafe { ::std::slice::from_raw_parts(words.as_ptr() as *const u8, words.len() * 8) }
+}
+
+#[test]
+fn view_compacted_bytes() {
+    let mut list: CompactVec<u32> = vec![1, 2, 3].into();
+    let words = compacted_words(&mut list);
+    ::std::mem::forget(list);
+
+    let slice = unsafe { CompactSlice::<u32>::from_bytes(as_bytes(&words)) }.unwrap();
+    assert_eq!(3, slice.len());
+    assert_eq!(2, slice[1]);
+    assert_eq!(vec![1, 2, 3], slice.into_iter().cloned().collect::<Vec<_>>());
+
+    let mut empty: CompactVec<u32> = CompactVec::new();
+    let words = compacted_words(&mut empty);
+    let slice = unsafe { CompactSlice::<u32>::from_bytes(as_bytes(&words)) }.unwrap();
+    assert!(slice.is_empty());
+}
+
+#[test]
+fn rejects_truncated_bytes() {
+    let mut list: CompactVec<u32> = vec![1, 2, 3].into();
+    let words = compacted_words(&mut list);
+    ::std::mem::forget(list);
+    let bytes = as_bytes(&words);
+
+    let header_size = mem::size_of::<CompactVec<u32>>();
+    assert!(unsafe { CompactSlice::<u32>::from_bytes(&bytes[..header_size + 8]) }.is_none());
+    assert!(unsafe { CompactSlice::<u32>::from_bytes(&bytes[..4]) }.is_none());
+    assert!(unsafe { CompactSlice::<u32>::from_bytes(&bytes[4..]) }.is_none());
+}
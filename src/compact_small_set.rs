@@ -0,0 +1,332 @@
+use super::compact::{Compact, CompactReader, CompactStats, FromReaderError};
+use super::compact_vec::CompactVec;
+use super::simple_allocator_trait::{Allocator, DefaultHeap};
+use std::cmp::Ordering;
+use std::io::{self, Read, Write};
+
+/// A set for few elements (like IDs), implemented as a sorted `CompactVec`
+/// and looked up using binary search.
+///
+/// Compared to the key set of a `CHashMap`, it has no hashing or probing overhead,
+/// but inserts and removals have to shift all later elements.
+pub struct CompactSmallSet<T, A: Allocator = DefaultHeap> {
+    elements: CompactVec<T, A>,
+}
+
+impl<T: Compact + Clone + Ord, A: Allocator> CompactSmallSet<T, A> {
+    /// Create a new, empty set
+    pub fn new() -> Self {
+        CompactSmallSet {
+            elements: CompactVec::new(),
+        }
+    }
+
+    /// Create a new, empty set with a given capacity
+    pub fn with_capacity(cap: usize) -> Self {
+        CompactSmallSet {
+            elements: CompactVec::with_capacity(cap),
+        }
+    }
+
+    /// Amount of elements in the set
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    /// Is the set empty?
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    /// Is `element` in the set?
+    pub fn contains(&self, element: &T) -> bool {
+        self.elements.binary_search(element).is_ok()
+    }
+
+    /// Insert `element`, returning whether it wasn't in the set yet
+    pub fn insert(&mut self, element: T) -> bool {
+        match self.elements.binary_search(&element) {
+            Ok(_) => false,
+            Err(i) => {
+                self.elements.insert(i, element);
+                true
+            }
+        }
+    }
+
+    /// Remove `element`, returning whether it was in the set
+    pub fn remove(&mut self, element: &T) -> bool {
+        match self.elements.binary_search(element) {
+            Ok(i) => {
+                self.elements.remove(i);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Remove all elements
+    pub fn clear(&mut self) {
+        self.elements.clear();
+    }
+
+    /// Iterator over all elements, in ascending order
+    pub fn iter(&self) -> ::std::slice::Iter<'_, T> {
+        self.elements.iter()
+    }
+
+    /// The elements as a sorted slice
+    pub fn as_slice(&self) -> &[T] {
+        &self.elements
+    }
+
+    /// Iterator over the elements that are in either set, in ascending order
+    pub fn union<'a, B: Allocator>(
+        &'a self,
+        other: &'a CompactSmallSet<T, B>,
+    ) -> impl Iterator<Item = &'a T> + 'a {
+        merge(&self.elements, &other.elements, true, true, true)
+    }
+
+    /// Iterator over the elements that are in both sets, in ascending order
+    pub fn intersection<'a, B: Allocator>(
+        &'a self,
+        other: &'a CompactSmallSet<T, B>,
+    ) -> impl Iterator<Item = &'a T> + 'a {
+        merge(&self.elements, &other.elements, false, true, false)
+    }
+
+    /// Iterator over the elements that are in this set, but not in `other`, in ascending order
+    pub fn difference<'a, B: Allocator>(
+        &'a self,
+        other: &'a CompactSmallSet<T, B>,
+    ) -> impl Iterator<Item = &'a T> + 'a {
+        merge(&self.elements, &other.elements, true, false, false)
+    }
+
+    /// Iterator over the elements that are in exactly one of the two sets, in ascending order
+    pub fn symmetric_difference<'a, B: Allocator>(
+        &'a self,
+        other: &'a CompactSmallSet<T, B>,
+    ) -> impl Iterator<Item = &'a T> + 'a {
+        merge(&self.elements, &other.elements, true, false, true)
+    }
+
+    /// Are all elements of this set also in `other`?
+    pub fn is_subset<B: Allocator>(&self, other: &CompactSmallSet<T, B>) -> bool {
+        self.difference(other).next().is_none()
+    }
+
+    /// Do the two sets have no elements in common?
+    pub fn is_disjoint<B: Allocator>(&self, other: &CompactSmallSet<T, B>) -> bool {
+        self.intersection(other).next().is_none()
+    }
+}
+
+/// Walk two sorted slices in step, yielding the elements only in `a`, in both or only in `b`
+fn merge<'a, T: Ord>(
+    a: &'a [T],
+    b: &'a [T],
+    only_a: bool,
+    both: bool,
+    only_b: bool,
+) -> impl Iterator<Item = &'a T> + 'a {
+    let mut a = a.iter().peekable();
+    let mut b = b.iter().peekable();
+    ::std::iter::from_fn(move || loop {
+        let (element, keep) = match (a.peek(), b.peek()) {
+            (Some(x), Some(y)) => match x.cmp(y) {
+                Ordering::Less => (a.next(), only_a),
+                Ordering::Greater => (b.next(), only_b),
+                Ordering::Equal => {
+                    b.next();
+                    (a.next(), both)
+                }
+            },
+            (Some(_), None) => (a.next(), only_a),
+            (None, Some(_)) => (b.next(), only_b),
+            (None, None) => return None,
+        };
+        if keep {
+            return element;
+        }
+    })
+}
+
+impl<T: Compact + Clone, A: Allocator> Compact for CompactSmallSet<T, A> {
+    fn is_still_compact(&self) -> bool {
+        self.elements.is_still_compact()
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        self.elements.dynamic_size_bytes()
+    }
+
+    fn dynamic_align() -> usize {
+        CompactVec::<T, A>::dynamic_align()
+    }
+
+    fn collect_stats(&self, path: &str, stats: &mut CompactStats) {
+        self.elements.collect_stats(path, stats);
+    }
+
+    fn repair_lossy(&mut self, path: &str, dropped: &mut Vec<String>) {
+        self.elements.repair_lossy(path, dropped);
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        Compact::compact(
+            &mut (*source).elements,
+            &mut (*dest).elements,
+            new_dynamic_part,
+        )
+    }
+
+    unsafe fn compact_static_part(&self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        self.elements
+            .compact_static_part(&mut (*dest).elements, new_dynamic_part)
+    }
+
+    fn write_dynamic_part<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.elements.write_dynamic_part(writer)
+    }
+
+    unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+        self.elements.shift_compact_pointers(shift);
+    }
+
+    unsafe fn decompact(source: *const Self) -> Self {
+        CompactSmallSet {
+            elements: Compact::decompact(&(*source).elements),
+        }
+    }
+
+    unsafe fn read_dynamic_part<R: Read>(
+        source: *const Self,
+        reader: &mut CompactReader<R>,
+    ) -> Result<Self, FromReaderError> {
+        Ok(CompactSmallSet {
+            elements: reader.read_field(&(*source).elements)?,
+        })
+    }
+}
+
+impl<T: Compact + Clone, A: Allocator> Clone for CompactSmallSet<T, A> {
+    fn clone(&self) -> Self {
+        CompactSmallSet {
+            elements: self.elements.clone(),
+        }
+    }
+}
+
+impl<T: Compact + Clone + Ord, A: Allocator> Default for CompactSmallSet<T, A> {
+    fn default() -> Self {
+        CompactSmallSet::new()
+    }
+}
+
+impl<T: Compact + ::std::fmt::Debug, A: Allocator> ::std::fmt::Debug for CompactSmallSet<T, A> {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_set().entries(self.elements.iter()).finish()
+    }
+}
+
+impl<T: PartialEq, A: Allocator, B: Allocator> PartialEq<CompactSmallSet<T, B>>
+    for CompactSmallSet<T, A>
+{
+    fn eq(&self, other: &CompactSmallSet<T, B>) -> bool {
+        self.elements == other.elements
+    }
+}
+
+impl<T: Eq, A: Allocator> Eq for CompactSmallSet<T, A> {}
+
+impl<T: ::std::hash::Hash, A: Allocator> ::std::hash::Hash for CompactSmallSet<T, A> {
+    fn hash<H: ::std::hash::Hasher>(&self, state: &mut H) {
+        self.elements.hash(state);
+    }
+}
+
+impl<T: Compact + Clone + Ord, A: Allocator> ::std::iter::FromIterator<T>
+    for CompactSmallSet<T, A>
+{
+    /// Construct a set from an iterator over elements, dropping duplicates
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut elements: Vec<T> = iter.into_iter().collect();
+        elements.sort();
+        elements.dedup();
+        CompactSmallSet {
+            elements: elements.into_iter().collect(),
+        }
+    }
+}
+
+impl<T: Compact + Clone + Ord, A: Allocator> ::std::iter::Extend<T> for CompactSmallSet<T, A> {
+    /// Insert all elements from an iterator
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for element in iter {
+            self.insert(element);
+        }
+    }
+}
+
+impl<'a, T, A: Allocator> IntoIterator for &'a CompactSmallSet<T, A> {
+    type Item = &'a T;
+    type IntoIter = ::std::slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.elements.iter()
+    }
+}
+
+#[test]
+fn insert_contains_remove() {
+    let mut set: CompactSmallSet<u32> = CompactSmallSet::new();
+    for id in &[7, 3, 11, 3, 1, 7] {
+        set.insert(*id);
+    }
+    assert_eq!(&[1, 3, 7, 11], set.as_slice());
+    assert!(set.contains(&7));
+    assert!(!set.contains(&8));
+    assert!(!set.insert(11));
+    assert!(set.remove(&3));
+    assert!(!set.remove(&3));
+    assert_eq!(vec![1, 7, 11], set.iter().cloned().collect::<Vec<_>>());
+}
+
+#[test]
+fn set_algebra() {
+    let a: CompactSmallSet<u32> = vec![1, 2, 3, 5, 8].into_iter().collect();
+    let b: CompactSmallSet<u32> = vec![2, 4, 8, 16].into_iter().collect();
+    let collect = |iter: &mut dyn Iterator<Item = &u32>| iter.cloned().collect::<Vec<_>>();
+    assert_eq!(vec![1, 2, 3, 4, 5, 8, 16], collect(&mut a.union(&b)));
+    assert_eq!(vec![2, 8], collect(&mut a.intersection(&b)));
+    assert_eq!(vec![1, 3, 5], collect(&mut a.difference(&b)));
+    assert_eq!(
+        vec![1, 3, 4, 5, 16],
+        collect(&mut a.symmetric_difference(&b))
+    );
+    assert!(!a.is_subset(&b));
+    assert!(a
+        .intersection(&b)
+        .cloned()
+        .collect::<CompactSmallSet<u32>>()
+        .is_subset(&b));
+    let c: CompactSmallSet<u32> = vec![4, 6].into_iter().collect();
+    assert!(a.is_disjoint(&c));
+}
+
+#[test]
+fn compact_small_set() {
+    use super::compact_str::CompactString;
+    use super::compacted_box::CompactedBox;
+    let set: CompactSmallSet<CompactString> = ["tree", "rock", "bush", "rock"]
+        .iter()
+        .map(|name| CompactString::from(*name))
+        .collect();
+    let boxed = CompactedBox::new(set.clone());
+    assert!(boxed.is_still_compact());
+    assert_eq!(set, *boxed);
+    assert!(boxed.contains(&"bush".into()));
+    assert_eq!(3, boxed.len());
+}
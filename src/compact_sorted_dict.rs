@@ -0,0 +1,341 @@
+use super::compact::{Compact, CompactReader, CompactStats, DynamicLayout, FromReaderError};
+use super::compact_vec::CompactVec;
+use super::simple_allocator_trait::{Allocator, DefaultHeap};
+use std::io::Read;
+use std::ops::{Bound, RangeBounds};
+use std::ptr;
+
+/// A key-value dictionary that keeps its keys sorted and looks them up using binary search,
+/// implemented using two `CompactVec`'s, one for keys, one for values.
+///
+/// Compared to `CompactDict`, lookups stay fast for many entries,
+/// while inserts and removals have to shift all later entries.
+pub struct CompactSortedDict<K: Copy, V: Compact + Clone, A: Allocator = DefaultHeap> {
+    keys: CompactVec<K, A>,
+    values: CompactVec<V, A>,
+}
+
+impl<K: Ord + Copy, V: Compact + Clone, A: Allocator> CompactSortedDict<K, V, A> {
+    /// Create new, empty dictionary
+    pub fn new() -> Self {
+        CompactSortedDict {
+            keys: CompactVec::new(),
+            values: CompactVec::new(),
+        }
+    }
+
+    /// Create new, empty dictionary with a given capactity
+    pub fn with_capacity(cap: usize) -> Self {
+        CompactSortedDict {
+            keys: CompactVec::with_capacity(cap),
+            values: CompactVec::with_capacity(cap),
+        }
+    }
+
+    /// Amount of entries in the dictionary
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Is the dictionary empty?
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Look up the value for key `query`, if it exists
+    pub fn get(&self, query: K) -> Option<&V> {
+        match self.keys.binary_search(&query) {
+            Ok(i) => Some(&self.values[i]),
+            Err(_) => None,
+        }
+    }
+
+    /// Look up the value for key `query` mutably, if it exists
+    pub fn get_mut(&mut self, query: K) -> Option<&mut V> {
+        match self.keys.binary_search(&query) {
+            Ok(i) => Some(&mut self.values[i]),
+            Err(_) => None,
+        }
+    }
+
+    /// Does the dictionary contain a value for `query`?
+    pub fn contains_key(&self, query: K) -> bool {
+        self.keys.binary_search(&query).is_ok()
+    }
+
+    /// Insert new value at key `query` and return the previous value at that key, if any existed
+    pub fn insert(&mut self, query: K, new_value: V) -> Option<V> {
+        match self.keys.binary_search(&query) {
+            Ok(i) => {
+                let slot: *mut V = &mut self.values[i];
+                // decompact, since the value might be stored compactly with its dynamic part
+                unsafe {
+                    let old_val = Compact::decompact(slot);
+                    ptr::write(slot, new_value);
+                    Some(old_val)
+                }
+            }
+            Err(i) => {
+                self.keys.insert(i, query);
+                self.values.insert(i, new_value);
+                None
+            }
+        }
+    }
+
+    /// Remove value at key `query` and return it, if it existed
+    pub fn remove(&mut self, query: K) -> Option<V> {
+        match self.keys.binary_search(&query) {
+            Ok(i) => {
+                self.keys.remove(i);
+                Some(self.values.remove(i))
+            }
+            Err(_) => None,
+        }
+    }
+
+    /// Iterator over all keys in the dictionary, in ascending order
+    pub fn keys(&self) -> ::std::slice::Iter<'_, K> {
+        self.keys.iter()
+    }
+
+    /// Iterator over all values in the dictionary, in ascending order of their keys
+    pub fn values(&self) -> ::std::slice::Iter<'_, V> {
+        self.values.iter()
+    }
+
+    /// Iterator over mutable references to all values in the dictionary
+    pub fn values_mut(&mut self) -> ::std::slice::IterMut<'_, V> {
+        self.values.iter_mut()
+    }
+
+    /// Iterator over all key-value pairs in the dictionary, in ascending order of keys
+    pub fn pairs<'a>(&'a self) -> impl Iterator<Item = (&'a K, &'a V)> + Clone + 'a {
+        self.keys().zip(self.values())
+    }
+
+    /// Iterator over the key-value pairs with keys in `range`, in ascending order of keys
+    pub fn range<'a, R: RangeBounds<K>>(
+        &'a self,
+        range: R,
+    ) -> impl Iterator<Item = (&'a K, &'a V)> + Clone + 'a {
+        let start = match range.start_bound() {
+            Bound::Included(start) => self.keys.partition_point(|key| key < start),
+            Bound::Excluded(start) => self.keys.partition_point(|key| key <= start),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(end) => self.keys.partition_point(|key| key <= end),
+            Bound::Excluded(end) => self.keys.partition_point(|key| key < end),
+            Bound::Unbounded => self.len(),
+        };
+        let end = end.max(start);
+        self.keys[start..end]
+            .iter()
+            .zip(self.values[start..end].iter())
+    }
+}
+
+impl<K: Copy, V: Compact + Clone, A: Allocator> Compact for CompactSortedDict<K, V, A> {
+    fn is_still_compact(&self) -> bool {
+        self.keys.is_still_compact() && self.values.is_still_compact()
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        let mut layout = DynamicLayout::new();
+        layout.field(&self.keys);
+        layout.field(&self.values);
+        layout.size()
+    }
+
+    fn dynamic_align() -> usize {
+        CompactVec::<K, A>::dynamic_align().max(CompactVec::<V, A>::dynamic_align())
+    }
+
+    fn collect_stats(&self, path: &str, stats: &mut CompactStats) {
+        self.keys.collect_stats(&format!("{}.keys", path), stats);
+        self.values.collect_stats(&format!("{}.values", path), stats);
+    }
+
+    fn repair_lossy(&mut self, path: &str, dropped: &mut Vec<String>) {
+        self.keys.repair_lossy(&format!("{}.keys", path), dropped);
+        self.values
+            .repair_lossy(&format!("{}.values", path), dropped);
+        // only keep pairs that still have both a key and a value
+        let len = self.keys.len().min(self.values.len());
+        self.keys.truncate(len);
+        self.values.truncate(len);
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        let mut layout = DynamicLayout::new();
+        let keys_offset = layout.field(&(*source).keys);
+        let values_offset = layout.field(&(*source).values);
+        Compact::compact(
+            &mut (*source).keys,
+            &mut (*dest).keys,
+            new_dynamic_part.add(keys_offset),
+        );
+        Compact::compact(
+            &mut (*source).values,
+            &mut (*dest).values,
+            new_dynamic_part.add(values_offset),
+        );
+    }
+
+    unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+        self.keys.shift_compact_pointers(shift);
+        self.values.shift_compact_pointers(shift);
+    }
+
+    unsafe fn decompact(source: *const Self) -> CompactSortedDict<K, V, A> {
+        CompactSortedDict {
+            keys: Compact::decompact(&(*source).keys),
+            values: Compact::decompact(&(*source).values),
+        }
+    }
+
+    unsafe fn read_dynamic_part<R: Read>(
+        source: *const Self,
+        reader: &mut CompactReader<R>,
+    ) -> Result<Self, FromReaderError> {
+        Ok(CompactSortedDict {
+            keys: reader.read_field(&(*source).keys)?,
+            values: reader.read_field(&(*source).values)?,
+        })
+    }
+}
+
+impl<K: Copy, V: Compact + Clone, A: Allocator> Clone for CompactSortedDict<K, V, A> {
+    fn clone(&self) -> Self {
+        CompactSortedDict {
+            keys: self.keys.clone(),
+            values: self.values.clone(),
+        }
+    }
+}
+
+impl<K: Ord + Copy, V: Compact + Clone, A: Allocator> Default for CompactSortedDict<K, V, A> {
+    fn default() -> Self {
+        CompactSortedDict::new()
+    }
+}
+
+impl<K: Ord + Copy, V: Compact + Clone, A: Allocator> ::std::iter::FromIterator<(K, V)>
+    for CompactSortedDict<K, V, A>
+{
+    /// Construct a sorted dictionary from an iterator over key-value pairs.
+    /// For duplicate keys, the last value wins.
+    fn from_iter<T: IntoIterator<Item = (K, V)>>(iter: T) -> Self {
+        let mut pairs: Vec<(K, V)> = iter.into_iter().collect();
+        // stable, so the last of several equal keys stays last
+        pairs.sort_by_key(|pair| pair.0);
+        let mut dict = Self::with_capacity(pairs.len());
+        for (key, value) in pairs {
+            if dict.keys.last() == Some(&key) {
+                dict.keys.pop();
+                dict.values.pop();
+            }
+            dict.keys.push(key);
+            dict.values.push(value);
+        }
+        dict
+    }
+}
+
+impl<K: Ord + Copy, V: Compact + Clone, A: Allocator> ::std::iter::Extend<(K, V)>
+    for CompactSortedDict<K, V, A>
+{
+    /// Extend a sorted dictionary from an iterator over key-value pairs
+    fn extend<T: IntoIterator<Item = (K, V)>>(&mut self, iter: T) {
+        for (key, value) in iter {
+            self.insert(key, value);
+        }
+    }
+}
+
+impl<K, V, A> ::std::fmt::Debug for CompactSortedDict<K, V, A>
+where
+    K: Ord + Copy + ::std::fmt::Debug,
+    V: Compact + ::std::fmt::Debug,
+    A: Allocator,
+{
+    fn fmt(&self, fmt: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        fmt.debug_map().entries(self.pairs()).finish()
+    }
+}
+
+impl<K, V, A, B> PartialEq<CompactSortedDict<K, V, B>> for CompactSortedDict<K, V, A>
+where
+    K: Ord + Copy,
+    V: Compact + PartialEq,
+    A: Allocator,
+    B: Allocator,
+{
+    fn eq(&self, other: &CompactSortedDict<K, V, B>) -> bool {
+        self.keys == other.keys && self.values == other.values
+    }
+}
+
+impl<K: Ord + Copy, V: Compact + Eq, A: Allocator> Eq for CompactSortedDict<K, V, A> {}
+
+#[cfg(feature = "arbitrary")]
+impl<'a, K, V, A> ::arbitrary::Arbitrary<'a> for CompactSortedDict<K, V, A>
+where
+    K: Ord + Copy + ::arbitrary::Arbitrary<'a>,
+    V: Compact + Clone + ::arbitrary::Arbitrary<'a>,
+    A: Allocator + 'a,
+{
+    fn arbitrary(u: &mut ::arbitrary::Unstructured<'a>) -> ::arbitrary::Result<Self> {
+        u.arbitrary_iter()?.collect()
+    }
+
+    fn arbitrary_take_rest(u: ::arbitrary::Unstructured<'a>) -> ::arbitrary::Result<Self> {
+        u.arbitrary_take_rest_iter()?.collect()
+    }
+
+    fn size_hint(depth: usize) -> (usize, Option<usize>) {
+        <Vec<(K, V)> as ::arbitrary::Arbitrary>::size_hint(depth)
+    }
+}
+
+#[test]
+fn sorted_lookups() {
+    let mut dict: CompactSortedDict<u32, u32> = (0..1000).rev().map(|n| (n * 2, n)).collect();
+    assert_eq!(1000, dict.len());
+    assert!(dict.keys().zip(dict.keys().skip(1)).all(|(a, b)| a < b));
+    assert_eq!(Some(&500), dict.get(1000));
+    assert_eq!(None, dict.get(1001));
+
+    assert_eq!(None, dict.insert(1001, 7));
+    assert_eq!(Some(7), dict.insert(1001, 8));
+    assert_eq!(Some(&8), dict.get(1001));
+    assert_eq!(Some(500), dict.remove(1000));
+    assert!(!dict.contains_key(1000));
+
+    let range: Vec<_> = dict.range(998..=1002).map(|(k, v)| (*k, *v)).collect();
+    assert_eq!(vec![(998, 499), (1001, 8), (1002, 501)], range);
+    assert_eq!(0, dict.range(5000..).count());
+}
+
+#[test]
+fn compact_sorted_dict() {
+    type Dict = CompactSortedDict<u32, CompactVec<u32>>;
+    let mut dict: Dict = (0..20).map(|n| (20 - n, vec![n; 3].into())).collect();
+
+    let bytes = dict.total_size_bytes();
+    let storage = DefaultHeap::allocate(bytes);
+    unsafe {
+        Compact::compact_behind(&mut dict, storage as *mut Dict);
+        ::std::mem::forget(dict);
+        assert!((*(storage as *mut Dict)).is_still_compact());
+        assert_eq!(
+            Some(vec![15; 3].into()),
+            (*(storage as *mut Dict)).insert(5, vec![1].into())
+        );
+        let decompacted = Compact::decompact(storage as *mut Dict);
+        assert_eq!(Some(&vec![1].into()), decompacted.get(5));
+        assert_eq!(Some(&vec![0; 3].into()), decompacted.get(20));
+        DefaultHeap::deallocate(storage, bytes);
+    }
+}
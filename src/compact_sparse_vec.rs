@@ -0,0 +1,341 @@
+use super::compact::{Compact, CompactReader, CompactStats, DynamicLayout, FromReaderError};
+use super::compact_vec::CompactVec;
+use super::simple_allocator_trait::{Allocator, DefaultHeap};
+use std::io::Read;
+use std::ptr;
+
+const PAGE_SIZE: usize = 64;
+
+/// Which of 64 consecutive indices are occupied, and where the value of the first one is
+#[derive(Copy, Clone, Debug)]
+struct Page {
+    occupied: u64,
+    /// Amount of values of all previous pages
+    first_value: u32,
+}
+
+/// A vector where most indices (like entity ids) are empty, storing only the occupied ones.
+///
+/// Values are stored densely in order of their indices, while pages of 64 indices
+/// only store a bitmask of the occupied ones, so lookups are still constant time
+/// and an empty index costs just two bits.
+/// Setting or removing values has to shift all values at higher indices.
+pub struct CompactSparseVec<T, A: Allocator = DefaultHeap> {
+    pages: CompactVec<Page, A>,
+    values: CompactVec<T, A>,
+}
+
+impl<T: Compact + Clone, A: Allocator> CompactSparseVec<T, A> {
+    /// Create a new, empty sparse vector
+    pub fn new() -> Self {
+        CompactSparseVec {
+            pages: CompactVec::new(),
+            values: CompactVec::new(),
+        }
+    }
+
+    /// Amount of occupied indices
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Are all indices empty?
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// The position in `values` of `index`, and whether it is occupied
+    fn position(&self, index: usize) -> (usize, bool) {
+        match self.pages.get(index / PAGE_SIZE) {
+            Some(page) => {
+                let bit = 1 << (index % PAGE_SIZE);
+                let before = (page.occupied & (bit - 1)).count_ones();
+                (
+                    (page.first_value + before) as usize,
+                    page.occupied & bit != 0,
+                )
+            }
+            None => (self.values.len(), false),
+        }
+    }
+
+    /// The value at `index`, if it is occupied
+    pub fn get(&self, index: usize) -> Option<&T> {
+        match self.position(index) {
+            (position, true) => Some(&self.values[position]),
+            _ => None,
+        }
+    }
+
+    /// The value at `index` mutably, if it is occupied
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        match self.position(index) {
+            (position, true) => Some(&mut self.values[position]),
+            _ => None,
+        }
+    }
+
+    /// Is `index` occupied?
+    pub fn contains(&self, index: usize) -> bool {
+        self.position(index).1
+    }
+
+    /// Set the value at `index` and return the previous one, if it was occupied
+    pub fn set(&mut self, index: usize, value: T) -> Option<T> {
+        match self.position(index) {
+            (position, true) => {
+                let slot: *mut T = &mut self.values[position];
+                // decompact, since the value might be stored compactly with its dynamic part
+                unsafe {
+                    let old_value = Compact::decompact(slot);
+                    ptr::write(slot, value);
+                    Some(old_value)
+                }
+            }
+            (position, false) => {
+                while self.pages.len() <= index / PAGE_SIZE {
+                    self.pages.push(Page {
+                        occupied: 0,
+                        first_value: self.values.len() as u32,
+                    });
+                }
+                self.values.insert(position, value);
+                self.pages[index / PAGE_SIZE].occupied |= 1 << (index % PAGE_SIZE);
+                for page in &mut self.pages[index / PAGE_SIZE + 1..] {
+                    page.first_value += 1;
+                }
+                None
+            }
+        }
+    }
+
+    /// Remove the value at `index` and return it, if it was occupied
+    pub fn remove(&mut self, index: usize) -> Option<T> {
+        let position = match self.position(index) {
+            (position, true) => position,
+            _ => return None,
+        };
+        let value = self.values.remove(position);
+        self.pages[index / PAGE_SIZE].occupied &= !(1 << (index % PAGE_SIZE));
+        for page in &mut self.pages[index / PAGE_SIZE + 1..] {
+            page.first_value -= 1;
+        }
+        while self.pages.last().is_some_and(|page| page.occupied == 0) {
+            self.pages.pop();
+        }
+        Some(value)
+    }
+
+    /// Iterator over all occupied indices, in ascending order
+    pub fn indices(&self) -> impl Iterator<Item = usize> + '_ {
+        occupied_indices(&self.pages)
+    }
+
+    /// Iterator over all values, in ascending order of their indices
+    pub fn values(&self) -> ::std::slice::Iter<'_, T> {
+        self.values.iter()
+    }
+
+    /// Iterator over mutable references to all values, in ascending order of their indices
+    pub fn values_mut(&mut self) -> ::std::slice::IterMut<'_, T> {
+        self.values.iter_mut()
+    }
+
+    /// Iterator over all occupied indices and their values, in ascending order of indices
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &T)> + '_ {
+        self.indices().zip(self.values.iter())
+    }
+
+    /// Iterator over all occupied indices and mutable references to their values,
+    /// in ascending order of indices
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (usize, &mut T)> + '_ {
+        occupied_indices(&self.pages).zip(self.values.iter_mut())
+    }
+}
+
+fn occupied_indices(pages: &[Page]) -> impl Iterator<Item = usize> + '_ {
+    pages.iter().enumerate().flat_map(|(p, page)| {
+        let mut occupied = page.occupied;
+        ::std::iter::from_fn(move || {
+            if occupied == 0 {
+                return None;
+            }
+            let bit = occupied.trailing_zeros() as usize;
+            // clear the lowest set bit
+            occupied &= occupied - 1;
+            Some(p * PAGE_SIZE + bit)
+        })
+    })
+}
+
+impl<T: Compact + Clone, A: Allocator> Compact for CompactSparseVec<T, A> {
+    fn is_still_compact(&self) -> bool {
+        self.pages.is_still_compact() && self.values.is_still_compact()
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        let mut layout = DynamicLayout::new();
+        layout.field(&self.pages);
+        layout.field(&self.values);
+        layout.size()
+    }
+
+    fn dynamic_align() -> usize {
+        CompactVec::<Page, A>::dynamic_align().max(CompactVec::<T, A>::dynamic_align())
+    }
+
+    fn collect_stats(&self, path: &str, stats: &mut CompactStats) {
+        self.pages.collect_stats(&format!("{}.pages", path), stats);
+        self.values
+            .collect_stats(&format!("{}.values", path), stats);
+    }
+
+    fn repair_lossy(&mut self, path: &str, dropped: &mut Vec<String>) {
+        self.pages.repair_lossy(&format!("{}.pages", path), dropped);
+        self.values
+            .repair_lossy(&format!("{}.values", path), dropped);
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        let mut layout = DynamicLayout::new();
+        let pages_offset = layout.field(&(*source).pages);
+        let values_offset = layout.field(&(*source).values);
+        Compact::compact(
+            &mut (*source).pages,
+            &mut (*dest).pages,
+            new_dynamic_part.add(pages_offset),
+        );
+        Compact::compact(
+            &mut (*source).values,
+            &mut (*dest).values,
+            new_dynamic_part.add(values_offset),
+        );
+    }
+
+    unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+        self.pages.shift_compact_pointers(shift);
+        self.values.shift_compact_pointers(shift);
+    }
+
+    unsafe fn decompact(source: *const Self) -> Self {
+        CompactSparseVec {
+            pages: Compact::decompact(&(*source).pages),
+            values: Compact::decompact(&(*source).values),
+        }
+    }
+
+    unsafe fn read_dynamic_part<R: Read>(
+        source: *const Self,
+        reader: &mut CompactReader<R>,
+    ) -> Result<Self, FromReaderError> {
+        let sparse = CompactSparseVec {
+            pages: reader.read_field(&(*source).pages)?,
+            values: reader.read_field(&(*source).values)?,
+        };
+        let mut count = 0;
+        for page in sparse.pages.iter() {
+            if page.first_value != count {
+                break;
+            }
+            count += page.occupied.count_ones();
+        }
+        if count as usize == sparse.values.len() {
+            Ok(sparse)
+        } else {
+            Err(reader.invalid("sparse vector pages don't match its values"))
+        }
+    }
+}
+
+impl<T: Compact + Clone, A: Allocator> Clone for CompactSparseVec<T, A> {
+    fn clone(&self) -> Self {
+        CompactSparseVec {
+            pages: self.pages.clone(),
+            values: self.values.clone(),
+        }
+    }
+}
+
+impl<T: Compact + Clone, A: Allocator> Default for CompactSparseVec<T, A> {
+    fn default() -> Self {
+        CompactSparseVec::new()
+    }
+}
+
+impl<T: Compact + Clone + ::std::fmt::Debug, A: Allocator> ::std::fmt::Debug
+    for CompactSparseVec<T, A>
+{
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<T: Compact + Clone + PartialEq, A: Allocator> PartialEq for CompactSparseVec<T, A> {
+    fn eq(&self, other: &Self) -> bool {
+        self.iter().eq(other.iter())
+    }
+}
+
+impl<T: Compact + Clone, A: Allocator> ::std::iter::FromIterator<(usize, T)>
+    for CompactSparseVec<T, A>
+{
+    /// Construct a sparse vector from an iterator over indices and values.
+    /// For duplicate indices, the last value wins.
+    fn from_iter<I: IntoIterator<Item = (usize, T)>>(iter: I) -> Self {
+        let mut sparse = Self::new();
+        for (index, value) in iter {
+            sparse.set(index, value);
+        }
+        sparse
+    }
+}
+
+#[test]
+fn set_get_remove() {
+    let mut sparse: CompactSparseVec<u32> = CompactSparseVec::new();
+    for &index in &[1000, 3, 64, 63, 129] {
+        assert_eq!(None, sparse.set(index, index as u32 * 10));
+    }
+    assert_eq!(Some(30), sparse.set(3, 33));
+    assert_eq!(5, sparse.len());
+    assert_eq!(Some(&630), sparse.get(63));
+    assert_eq!(Some(&10000), sparse.get(1000));
+    assert_eq!(None, sparse.get(4));
+    assert_eq!(None, sparse.get(5000));
+    assert_eq!(
+        vec![(3, 33), (63, 630), (64, 640), (129, 1290), (1000, 10000)],
+        sparse.iter().map(|(i, v)| (i, *v)).collect::<Vec<_>>()
+    );
+
+    assert_eq!(Some(10000), sparse.remove(1000));
+    assert_eq!(None, sparse.remove(1000));
+    assert_eq!(3, sparse.pages.len());
+    assert_eq!(Some(630), sparse.remove(63));
+    for (_, value) in sparse.iter_mut() {
+        *value += 1;
+    }
+    assert_eq!(vec![3, 64, 129], sparse.indices().collect::<Vec<_>>());
+    assert_eq!(
+        vec![34, 641, 1291],
+        sparse.values().cloned().collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn compact_sparse_vec() {
+    use super::compact_str::CompactString;
+    use super::compacted_box::CompactedBox;
+    let sparse: CompactSparseVec<CompactString> = (0..20)
+        .map(|i| (i * i * 7, format!("entity {}", i).into()))
+        .collect();
+    let boxed = CompactedBox::new(sparse.clone());
+    assert!(boxed.is_still_compact());
+    assert_eq!(sparse, *boxed);
+    assert_eq!("entity 12", boxed.get(12 * 12 * 7).unwrap().as_str());
+
+    let mut stream = Vec::new();
+    sparse.compact_to_writer(&mut stream).unwrap();
+    let read: CompactSparseVec<CompactString> =
+        unsafe { Compact::decompact_from_reader(&mut &stream[..]) }.unwrap();
+    assert_eq!(sparse, read);
+}
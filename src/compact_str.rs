@@ -1,9 +1,16 @@
-use super::compact::Compact;
+use super::compact::{Compact, CompactReader, CompactStats, FromReaderError};
+use super::compact_bytes::CompactBytes;
 use super::compact_vec::CompactVec;
+use std::io::{self, Read, Write};
+use std::slice::SliceIndex;
+use std::str::{CharIndices, Chars, Utf8Error};
 
 /// A compact storage for a `String`. So far doesn't support direct mutable operations,
 /// Only conversion from and to `String`/`&str`
-#[derive(Clone, Default)]
+///
+/// Always contains valid UTF-8, also after decompaction.
+/// Use `CBytes` to store arbitrary bytes.
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
 pub struct CompactString {
     chars: CompactVec<u8>,
 }
@@ -14,12 +21,97 @@ impl CompactString {
         Default::default()
     }
 
+    /// Convert bytes into a `CString`, if they are valid UTF-8.
+    /// Otherwise, the error gives the bytes back.
+    pub fn from_utf8(bytes: CompactBytes) -> Result<Self, FromUtf8Error> {
+        match ::std::str::from_utf8(&bytes) {
+            Ok(_) => Ok(CompactString {
+                chars: bytes.into_vec(),
+            }),
+            Err(error) => Err(FromUtf8Error { bytes, error }),
+        }
+    }
+
+    /// Convert bytes into a `CString` without checking that they are valid UTF-8.
+    ///
+    /// # Safety
+    ///
+    /// `bytes` have to be valid UTF-8.
+    pub unsafe fn from_utf8_unchecked(bytes: CompactBytes) -> Self {
+        CompactString {
+            chars: bytes.into_vec(),
+        }
+    }
+
     /// Appends a given string slice onto the end of this `CString`.
     pub fn push_str(&mut self, string: &str) {
         self.chars.extend_from_copy_slice(string.as_bytes());
     }
+
+    /// The contents as a string slice
+    pub fn as_str(&self) -> &str {
+        self
+    }
+
+    /// The contents as a byte slice
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.chars
+    }
+
+    /// Convert into the underlying bytes
+    pub fn into_bytes(self) -> CompactBytes {
+        self.chars.into()
+    }
+
+    /// Iterator over the `char`s of the string
+    pub fn chars(&self) -> Chars<'_> {
+        self.as_str().chars()
+    }
+
+    /// Iterator over the `char`s of the string and their byte positions
+    pub fn char_indices(&self) -> CharIndices<'_> {
+        self.as_str().char_indices()
+    }
+
+    /// Is `index` the start of a `char` (or the end of the string)?
+    pub fn is_char_boundary(&self, index: usize) -> bool {
+        self.as_str().is_char_boundary(index)
+    }
+
+    /// Checked slicing by byte positions: `None` if the range is out of bounds
+    /// or doesn't start and end at `char` boundaries
+    pub fn get<I: SliceIndex<str>>(&self, index: I) -> Option<&I::Output> {
+        self.as_str().get(index)
+    }
 }
 
+/// Why bytes couldn't be converted into a `CString`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FromUtf8Error {
+    bytes: CompactBytes,
+    error: Utf8Error,
+}
+
+impl FromUtf8Error {
+    /// The bytes that were attempted to convert
+    pub fn into_bytes(self) -> CompactBytes {
+        self.bytes
+    }
+
+    /// Details about where the bytes aren't valid UTF-8
+    pub fn utf8_error(&self) -> Utf8Error {
+        self.error
+    }
+}
+
+impl ::std::fmt::Display for FromUtf8Error {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl ::std::error::Error for FromUtf8Error {}
+
 impl ::std::ops::Deref for CompactString {
     type Target = str;
 
@@ -36,6 +128,90 @@ impl ::std::convert::From<String> for CompactString {
     }
 }
 
+impl<'a> ::std::convert::From<&'a str> for CompactString {
+    fn from(string: &'a str) -> CompactString {
+        let mut compact_string = CompactString {
+            chars: CompactVec::with_capacity(string.len()),
+        };
+        compact_string.push_str(string);
+        compact_string
+    }
+}
+
+impl AsRef<str> for CompactString {
+    fn as_ref(&self) -> &str {
+        self
+    }
+}
+
+impl AsRef<[u8]> for CompactString {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl ::std::fmt::Display for CompactString {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        ::std::fmt::Display::fmt(self.as_str(), f)
+    }
+}
+
+impl ::std::fmt::Debug for CompactString {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        ::std::fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl ::std::hash::Hash for CompactString {
+    /// Hashes like a `str` (and thus like a `String`) with the same contents
+    fn hash<H: ::std::hash::Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl ::std::borrow::Borrow<str> for CompactString {
+    /// Lets maps with `CompactString` keys be queried with a `&str`
+    fn borrow(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl PartialEq<str> for CompactString {
+    fn eq(&self, other: &str) -> bool {
+        &**self == other
+    }
+}
+
+impl<'a> PartialEq<&'a str> for CompactString {
+    fn eq(&self, other: &&'a str) -> bool {
+        &**self == *other
+    }
+}
+
+impl PartialEq<String> for CompactString {
+    fn eq(&self, other: &String) -> bool {
+        **self == **other
+    }
+}
+
+impl PartialEq<CompactString> for str {
+    fn eq(&self, other: &CompactString) -> bool {
+        self == &**other
+    }
+}
+
+impl PartialEq<CompactString> for &str {
+    fn eq(&self, other: &CompactString) -> bool {
+        *self == &**other
+    }
+}
+
+impl PartialEq<CompactString> for String {
+    fn eq(&self, other: &CompactString) -> bool {
+        **self == **other
+    }
+}
+
 impl Compact for CompactString {
     fn is_still_compact(&self) -> bool {
         self.chars.is_still_compact()
@@ -45,15 +221,56 @@ impl Compact for CompactString {
         self.chars.dynamic_size_bytes()
     }
 
+    fn dynamic_align() -> usize {
+        CompactVec::<u8>::dynamic_align()
+    }
+
+    fn collect_stats(&self, path: &str, stats: &mut CompactStats) {
+        self.chars.collect_stats(path, stats);
+    }
+
+    fn repair_lossy(&mut self, path: &str, dropped: &mut Vec<String>) {
+        self.chars.repair_lossy(path, dropped);
+        if ::std::str::from_utf8(&self.chars).is_err() {
+            self.chars = CompactVec::new();
+            dropped.push(path.to_owned());
+        }
+    }
+
     unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
         Compact::compact(&mut (*source).chars, &mut (*dest).chars, new_dynamic_part)
     }
 
+    unsafe fn compact_static_part(&self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        self.chars
+            .compact_static_part(&mut (*dest).chars, new_dynamic_part)
+    }
+
+    fn write_dynamic_part<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.chars.write_dynamic_part(writer)
+    }
+
+    unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+        self.chars.shift_compact_pointers(shift);
+    }
+
     unsafe fn decompact(source: *const Self) -> Self {
+        // `from_compact_bytes` checks for invalid UTF-8 while reading, see `read_dynamic_part`
         CompactString {
             chars: Compact::decompact(&(*source).chars),
         }
     }
+
+    unsafe fn read_dynamic_part<R: Read>(
+        source: *const Self,
+        reader: &mut CompactReader<R>,
+    ) -> Result<Self, FromReaderError> {
+        let chars: CompactVec<u8> = reader.read_field(&(*source).chars)?;
+        if ::std::str::from_utf8(&chars).is_err() {
+            return Err(reader.invalid("CString contains invalid UTF-8"));
+        }
+        Ok(CompactString { chars })
+    }
 }
 
 #[cfg(feature = "serde-serialization")]
@@ -65,7 +282,7 @@ impl ::serde::ser::Serialize for CompactString{
     where
         S: ::serde::ser::Serializer,
     {
-        serializer.serialize_str(&self)
+        serializer.serialize_str(self)
     }
 }
 
@@ -96,7 +313,15 @@ impl<'de> ::serde::de::Visitor<'de> for CompactStringVisitor
     where
         E: ::serde::de::Error,
     {
-        Ok(s.to_owned().into())
+        Ok(s.into())
+    }
+
+    /// Copies the borrowed input straight into the storage of the string
+    fn visit_borrowed_str<E>(self, s: &'de str) -> Result<Self::Value, E>
+    where
+        E: ::serde::de::Error,
+    {
+        Ok(s.into())
     }
 
     fn visit_string<E>(self, s: String) -> Result<Self::Value, E>
@@ -115,6 +340,212 @@ impl<'de> ::serde::de::Deserialize<'de> for CompactString
     where
         D: ::serde::de::Deserializer<'de>,
     {
-        deserializer.deserialize_string(CompactStringVisitor::new())
+        // hints that no owned `String` is needed, so formats can lend out their input
+        deserializer.deserialize_str(CompactStringVisitor::new())
+    }
+}
+
+#[cfg(feature = "serde-serialization")]
+use super::compact_deserialize::{input_changed, CompactDeserialize, Placer, Planner};
+
+#[cfg(feature = "serde-serialization")]
+struct PlanVisitor<'a> {
+    planner: &'a mut Planner,
+}
+
+#[cfg(feature = "serde-serialization")]
+impl<'a, 'de> ::serde::de::Visitor<'de> for PlanVisitor<'a> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        formatter.write_str("A string")
     }
-}
\ No newline at end of file
+
+    fn visit_str<E>(self, s: &str) -> Result<(), E>
+    where
+        E: ::serde::de::Error,
+    {
+        let part = self.planner.begin();
+        self.planner
+            .end(part, s.len(), s.len(), CompactVec::<u8>::dynamic_align());
+        Ok(())
+    }
+}
+
+#[cfg(feature = "serde-serialization")]
+struct PlaceVisitor<'a> {
+    placer: &'a mut Placer,
+    dest: *mut CompactString,
+}
+
+#[cfg(feature = "serde-serialization")]
+impl<'a, 'de> ::serde::de::Visitor<'de> for PlaceVisitor<'a> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        formatter.write_str("A string")
+    }
+
+    fn visit_str<E>(self, s: &str) -> Result<(), E>
+    where
+        E: ::serde::de::Error,
+    {
+        match self.placer.take() {
+            Some((len, bytes)) if len == s.len() => unsafe {
+                ::std::ptr::copy_nonoverlapping(s.as_ptr(), bytes, len);
+                let chars = ::std::ptr::addr_of_mut!((*self.dest).chars);
+                CompactVec::init_placed(chars, len, bytes);
+                Ok(())
+            },
+            _ => Err(input_changed()),
+        }
+    }
+}
+
+#[cfg(feature = "serde-serialization")]
+impl<'de> CompactDeserialize<'de> for CompactString {
+    fn plan<D>(deserializer: D, planner: &mut Planner) -> Result<(), D::Error>
+    where
+        D: ::serde::de::Deserializer<'de>,
+    {
+        deserializer.deserialize_str(PlanVisitor { planner })
+    }
+
+    unsafe fn place<D>(deserializer: D, dest: *mut Self, placer: &mut Placer) -> Result<(), D::Error>
+    where
+        D: ::serde::de::Deserializer<'de>,
+    {
+        deserializer.deserialize_str(PlaceVisitor { placer, dest })
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> ::arbitrary::Arbitrary<'a> for CompactString {
+    fn arbitrary(u: &mut ::arbitrary::Unstructured<'a>) -> ::arbitrary::Result<Self> {
+        <&str as ::arbitrary::Arbitrary>::arbitrary(u).map(CompactString::from)
+    }
+
+    fn arbitrary_take_rest(u: ::arbitrary::Unstructured<'a>) -> ::arbitrary::Result<Self> {
+        <&str as ::arbitrary::Arbitrary>::arbitrary_take_rest(u).map(CompactString::from)
+    }
+
+    fn size_hint(depth: usize) -> (usize, Option<usize>) {
+        <&str as ::arbitrary::Arbitrary>::size_hint(depth)
+    }
+}
+
+#[test]
+fn equality() {
+    let mut string = CompactString::new();
+    string.push_str("Hello");
+    let other: CompactString = String::from("Hello").into();
+    assert!(string == other);
+    assert!(string == "Hello");
+    assert!("Hello" == string);
+    let std_string = String::from("Hello");
+    assert!(string == std_string);
+    assert!(std_string == string);
+    string.push_str(" World");
+    assert!(string != other);
+    assert!(string == *"Hello World");
+}
+
+#[test]
+fn hash_like_str() {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+    fn hash_of<T: Hash + ?Sized>(value: &T) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+    let string: CompactString = String::from("Hello").into();
+    assert_eq!(hash_of(&string), hash_of("Hello"));
+    assert_eq!(hash_of(&string), hash_of(&String::from("Hello")));
+}
+
+#[test]
+fn ordering() {
+    let names = ["Zoe", "anna", "Bob", "Björn", ""];
+    let mut strings: Vec<CompactString> =
+        names.iter().map(|name| name.to_string().into()).collect();
+    strings.sort();
+    let mut expected = names.to_vec();
+    expected.sort();
+    assert!(strings.iter().zip(expected.iter()).all(|(a, b)| a == b));
+
+    let set: ::std::collections::BTreeSet<CompactString> = strings.into_iter().collect();
+    assert!(set.iter().next().unwrap() == "");
+}
+
+#[test]
+fn str_conversions() {
+    fn takes_str(string: &str) -> usize {
+        string.len()
+    }
+    fn takes_as_ref<S: AsRef<str>>(string: S) -> String {
+        string.as_ref().to_uppercase()
+    }
+    let string = CompactString::from("Grüße");
+    let from_string: CompactString = String::from("Grüße").into();
+    assert_eq!(from_string, string);
+    assert_eq!(7, takes_str(&string));
+    assert_eq!("GRÜSSE", takes_as_ref(&string));
+    assert_eq!("<Grüße>", format!("<{}>", string));
+    assert_eq!("\"Grüße\"", format!("{:?}", string));
+    assert_eq!("Grüße  ", format!("{:7}", string));
+}
+
+#[test]
+fn utf8_checked() {
+    let string = CompactString::from_utf8(b"Gr\xc3\xbc\xc3\x9fe".to_vec().into()).unwrap();
+    assert!(string == "Grüße");
+    assert_eq!(vec!['G', 'r', 'ü', 'ß', 'e'], string.chars().collect::<Vec<_>>());
+    assert_eq!(Some((4, 'ß')), string.char_indices().nth(3));
+    assert!(string.is_char_boundary(2) && !string.is_char_boundary(3));
+    assert_eq!(Some("rü"), string.get(1..4));
+    assert_eq!(None, string.get(1..3));
+    assert_eq!(None, string.get(1..10));
+
+    let invalid: CompactBytes = vec![b'a', 0xff].into();
+    let error = match CompactString::from_utf8(invalid.clone()) {
+        Err(error) => error,
+        Ok(_) => panic!("Should reject invalid UTF-8"),
+    };
+    assert_eq!(1, error.utf8_error().valid_up_to());
+    assert_eq!(invalid, error.into_bytes());
+}
+
+#[test]
+fn from_compact_bytes_rejects_invalid_utf8() {
+    use super::compact::FromBytesError;
+    let bytes: CompactBytes = vec![b'a', 0xff].into();
+    assert_eq!(
+        Err(FromBytesError::Invalid),
+        unsafe { CompactString::from_compact_bytes(&bytes.compact_to_bytes()) }.map(|_| ())
+    );
+}
+
+#[cfg(feature = "serde-serialization")]
+#[test]
+fn serde_roundtrip() {
+    let string: CompactString = String::from("Hello").into();
+    let json = ::serde_json::to_string(&string).unwrap();
+    assert_eq!(json, "\"Hello\"");
+    let deserialized: CompactString = ::serde_json::from_str(&json).unwrap();
+    assert!(deserialized == string);
+}
+
+#[cfg(feature = "serde-serialization")]
+#[test]
+fn deserialize_borrowed() {
+    use serde::de::value::{BorrowedStrDeserializer, Error};
+    use serde::Deserialize;
+    let borrowed = BorrowedStrDeserializer::<Error>::new("Main Avenue");
+    let string = CompactString::deserialize(borrowed).unwrap();
+    assert_eq!("Main Avenue", string.as_str());
+    assert_eq!(string.len(), string.chars.capacity());
+
+    let escaped: CompactString = ::serde_json::from_str("\"Main\\tAvenue\"").unwrap();
+    assert_eq!("Main\tAvenue", escaped.as_str());
+}
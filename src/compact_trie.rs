@@ -0,0 +1,426 @@
+use super::compact::{Compact, CompactReader, CompactStats, DynamicLayout, FromReaderError};
+use super::compact_vec::CompactVec;
+use super::simple_allocator_trait::{Allocator, DefaultHeap};
+use std::io::Read;
+use std::ptr;
+
+/// Marks a missing child, sibling or value
+const NONE: u32 = u32::MAX;
+
+/// A node of the radix tree: the bytes of its label are a range of `CompactTrie::labels`,
+/// the other fields are indices into `CompactTrie::nodes` and `CompactTrie::values`
+#[derive(Copy, Clone, Debug)]
+struct TrieNode {
+    label_start: u32,
+    label_len: u32,
+    first_child: u32,
+    /// Siblings are sorted by the first byte of their label
+    next_sibling: u32,
+    value: u32,
+}
+
+/// A map from byte strings (like names) to values, for lookups by prefix,
+/// stored as a radix tree whose nodes, labels and values live in `CompactVec`s
+/// and refer to each other by index.
+pub struct CompactTrie<V, A: Allocator = DefaultHeap> {
+    labels: CompactVec<u8, A>,
+    nodes: CompactVec<TrieNode, A>,
+    values: CompactVec<V, A>,
+}
+
+impl<V: Compact + Clone, A: Allocator> CompactTrie<V, A> {
+    /// Create a new, empty trie
+    pub fn new() -> Self {
+        let mut nodes = CompactVec::new();
+        nodes.push(TrieNode {
+            label_start: 0,
+            label_len: 0,
+            first_child: NONE,
+            next_sibling: NONE,
+            value: NONE,
+        });
+        CompactTrie {
+            labels: CompactVec::new(),
+            nodes,
+            values: CompactVec::new(),
+        }
+    }
+
+    /// Amount of keys in the trie
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Is the trie empty?
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    fn label(&self, node: u32) -> &[u8] {
+        let node = &self.nodes[node as usize];
+        &self.labels[node.label_start as usize..(node.label_start + node.label_len) as usize]
+    }
+
+    /// The child of `node` whose label starts with `byte`, if any
+    fn child(&self, node: u32, byte: u8) -> Option<u32> {
+        let mut child = self.nodes[node as usize].first_child;
+        while child != NONE {
+            match self.label(child)[0] {
+                first if first == byte => return Some(child),
+                first if first > byte => return None,
+                _ => child = self.nodes[child as usize].next_sibling,
+            }
+        }
+        None
+    }
+
+    /// The node whose path is exactly `key`, if any
+    fn find(&self, key: &[u8]) -> Option<u32> {
+        let mut node = 0;
+        let mut rest = key;
+        while !rest.is_empty() {
+            node = self.child(node, rest[0])?;
+            let label = self.label(node);
+            if !rest.starts_with(label) {
+                return None;
+            }
+            rest = &rest[label.len()..];
+        }
+        Some(node)
+    }
+
+    /// Look up the value for `key`, if it exists
+    pub fn get(&self, key: &[u8]) -> Option<&V> {
+        let value = self.nodes[self.find(key)? as usize].value;
+        self.values.get(value as usize)
+    }
+
+    /// Look up the value for `key` mutably, if it exists
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut V> {
+        let value = self.nodes[self.find(key)? as usize].value;
+        self.values.get_mut(value as usize)
+    }
+
+    /// Does the trie contain a value for `key`?
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Insert `value` at `key` and return the previous value at that key, if any existed
+    pub fn insert(&mut self, key: &[u8], value: V) -> Option<V> {
+        let mut node = 0;
+        let mut rest = key;
+        while !rest.is_empty() {
+            let child = match self.child(node, rest[0]) {
+                Some(child) => child,
+                None => {
+                    let leaf = self.add_leaf(rest);
+                    self.link_child(node, leaf);
+                    node = leaf;
+                    break;
+                }
+            };
+            let common = self
+                .label(child)
+                .iter()
+                .zip(rest)
+                .take_while(|(a, b)| a == b)
+                .count();
+            if common < self.label(child).len() {
+                self.split(child, common);
+            }
+            node = child;
+            rest = &rest[common..];
+        }
+
+        let slot = self.nodes[node as usize].value;
+        if slot == NONE {
+            self.nodes[node as usize].value = self.values.len() as u32;
+            self.values.push(value);
+            None
+        } else {
+            let slot: *mut V = &mut self.values[slot as usize];
+            // decompact, since the value might be stored compactly with its dynamic part
+            unsafe {
+                let old_value = Compact::decompact(slot);
+                ptr::write(slot, value);
+                Some(old_value)
+            }
+        }
+    }
+
+    /// Add an unlinked node without value labelled `label`
+    fn add_leaf(&mut self, label: &[u8]) -> u32 {
+        let label_start = self.labels.len() as u32;
+        self.labels.extend_from_copy_slice(label);
+        self.nodes.push(TrieNode {
+            label_start,
+            label_len: label.len() as u32,
+            first_child: NONE,
+            next_sibling: NONE,
+            value: NONE,
+        });
+        self.nodes.len() as u32 - 1
+    }
+
+    /// Insert `child` among the children of `parent`, keeping them sorted
+    fn link_child(&mut self, parent: u32, child: u32) {
+        let byte = self.label(child)[0];
+        let mut previous = NONE;
+        let mut next = self.nodes[parent as usize].first_child;
+        while next != NONE && self.label(next)[0] < byte {
+            previous = next;
+            next = self.nodes[next as usize].next_sibling;
+        }
+        self.nodes[child as usize].next_sibling = next;
+        if previous == NONE {
+            self.nodes[parent as usize].first_child = child;
+        } else {
+            self.nodes[previous as usize].next_sibling = child;
+        }
+    }
+
+    /// Shorten the label of `node` to `at` bytes, moving the rest of it,
+    /// its children and value to a new child
+    fn split(&mut self, node: u32, at: usize) {
+        let old = self.nodes[node as usize];
+        let split_off = self.nodes.len() as u32;
+        self.nodes.push(TrieNode {
+            label_start: old.label_start + at as u32,
+            label_len: old.label_len - at as u32,
+            first_child: old.first_child,
+            next_sibling: NONE,
+            value: old.value,
+        });
+        let rest = &mut self.nodes[node as usize];
+        rest.label_len = at as u32;
+        rest.value = NONE;
+        rest.first_child = split_off;
+    }
+
+    /// Iterator over all keys starting with `prefix` and their values,
+    /// in lexicographic order of the keys
+    pub fn iter_prefix<'a>(&'a self, prefix: &[u8]) -> impl Iterator<Item = (Vec<u8>, &'a V)> + 'a {
+        // find the topmost node whose path starts with `prefix`, and the length of its parent's path
+        let mut start = Some((0, 0));
+        let mut depth = 0;
+        while let Some((node, _)) = start.filter(|_| depth < prefix.len()) {
+            let rest = &prefix[depth..];
+            start = self
+                .child(node, rest[0])
+                .filter(|&child| {
+                    let label = self.label(child);
+                    label.starts_with(&rest[..rest.len().min(label.len())])
+                })
+                .map(|child| (child, depth));
+            depth += start.map_or(0, |(child, _)| self.label(child).len());
+        }
+        let mut key = Vec::new();
+        let mut stack = Vec::new();
+        if let Some((start, parent_depth)) = start {
+            key.extend_from_slice(&prefix[..parent_depth]);
+            stack.push((start, parent_depth, false));
+        }
+
+        // depth-first, visiting siblings of the start node only below it
+        ::std::iter::from_fn(move || {
+            while let Some((node, depth, with_siblings)) = stack.pop() {
+                let links = self.nodes[node as usize];
+                key.truncate(depth);
+                key.extend_from_slice(self.label(node));
+                if with_siblings && links.next_sibling != NONE {
+                    stack.push((links.next_sibling, depth, true));
+                }
+                if links.first_child != NONE {
+                    stack.push((links.first_child, key.len(), true));
+                }
+                if links.value != NONE {
+                    return Some((key.clone(), &self.values[links.value as usize]));
+                }
+            }
+            None
+        })
+    }
+
+    /// Iterator over all keys and their values, in lexicographic order of the keys
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = (Vec<u8>, &'a V)> + 'a {
+        self.iter_prefix(&[])
+    }
+}
+
+impl<V: Compact + Clone, A: Allocator> Default for CompactTrie<V, A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V: Compact + Clone, A: Allocator> Clone for CompactTrie<V, A> {
+    fn clone(&self) -> Self {
+        CompactTrie {
+            labels: self.labels.clone(),
+            nodes: self.nodes.clone(),
+            values: self.values.clone(),
+        }
+    }
+}
+
+impl<V: Compact + Clone + ::std::fmt::Debug, A: Allocator> ::std::fmt::Debug for CompactTrie<V, A> {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_map()
+            .entries(
+                self.iter()
+                    .map(|(key, value)| (String::from_utf8_lossy(&key).into_owned(), value)),
+            )
+            .finish()
+    }
+}
+
+impl<V: Compact + Clone, A: Allocator> Compact for CompactTrie<V, A> {
+    fn is_still_compact(&self) -> bool {
+        self.labels.is_still_compact()
+            && self.nodes.is_still_compact()
+            && self.values.is_still_compact()
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        let mut layout = DynamicLayout::new();
+        layout.field(&self.labels);
+        layout.field(&self.nodes);
+        layout.field(&self.values);
+        layout.size()
+    }
+
+    fn dynamic_align() -> usize {
+        CompactVec::<u8, A>::dynamic_align()
+            .max(CompactVec::<TrieNode, A>::dynamic_align())
+            .max(CompactVec::<V, A>::dynamic_align())
+    }
+
+    fn collect_stats(&self, path: &str, stats: &mut CompactStats) {
+        self.labels
+            .collect_stats(&format!("{}.labels", path), stats);
+        self.nodes.collect_stats(&format!("{}.nodes", path), stats);
+        self.values
+            .collect_stats(&format!("{}.values", path), stats);
+    }
+
+    fn repair_lossy(&mut self, path: &str, dropped: &mut Vec<String>) {
+        self.values
+            .repair_lossy(&format!("{}.values", path), dropped);
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        let mut layout = DynamicLayout::new();
+        let labels_offset = layout.field(&(*source).labels);
+        let nodes_offset = layout.field(&(*source).nodes);
+        let values_offset = layout.field(&(*source).values);
+        Compact::compact(
+            &mut (*source).labels,
+            &mut (*dest).labels,
+            new_dynamic_part.add(labels_offset),
+        );
+        Compact::compact(
+            &mut (*source).nodes,
+            &mut (*dest).nodes,
+            new_dynamic_part.add(nodes_offset),
+        );
+        Compact::compact(
+            &mut (*source).values,
+            &mut (*dest).values,
+            new_dynamic_part.add(values_offset),
+        );
+    }
+
+    unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+        self.labels.shift_compact_pointers(shift);
+        self.nodes.shift_compact_pointers(shift);
+        self.values.shift_compact_pointers(shift);
+    }
+
+    unsafe fn decompact(source: *const Self) -> Self {
+        CompactTrie {
+            labels: Compact::decompact(&(*source).labels),
+            nodes: Compact::decompact(&(*source).nodes),
+            values: Compact::decompact(&(*source).values),
+        }
+    }
+
+    unsafe fn read_dynamic_part<R: Read>(
+        source: *const Self,
+        reader: &mut CompactReader<R>,
+    ) -> Result<Self, FromReaderError> {
+        let trie = CompactTrie {
+            labels: reader.read_field(&(*source).labels)?,
+            nodes: reader.read_field(&(*source).nodes)?,
+            values: reader.read_field(&(*source).values)?,
+        };
+        let index_valid = |index: u32, len: usize| index == NONE || (index as usize) < len;
+        let valid = !trie.nodes.is_empty()
+            && trie.nodes.iter().all(|node| {
+                (node.label_start as usize + node.label_len as usize) <= trie.labels.len()
+                    && index_valid(node.first_child, trie.nodes.len())
+                    && index_valid(node.next_sibling, trie.nodes.len())
+                    && index_valid(node.value, trie.values.len())
+            });
+        if valid {
+            Ok(trie)
+        } else {
+            Err(reader.invalid("trie nodes point outside of the trie"))
+        }
+    }
+}
+
+#[test]
+fn insert_and_get() {
+    let mut trie: CompactTrie<u32> = CompactTrie::new();
+    let words = ["main", "mainz", "maine", "man", "m", "market", "", "zoo"];
+    for (i, word) in words.iter().enumerate() {
+        assert_eq!(None, trie.insert(word.as_bytes(), i as u32));
+    }
+    assert_eq!(Some(3), trie.insert(b"man", 33));
+    assert_eq!(words.len(), trie.len());
+
+    for (i, word) in words.iter().enumerate() {
+        let expected = if *word == "man" { 33 } else { i as u32 };
+        assert_eq!(Some(&expected), trie.get(word.as_bytes()), "{}", word);
+    }
+    assert_eq!(None, trie.get(b"ma"));
+    assert_eq!(None, trie.get(b"mains"));
+    assert!(!trie.contains_key(b"z"));
+    *trie.get_mut(b"zoo").unwrap() += 1;
+    assert_eq!(Some(&8), trie.get(b"zoo"));
+}
+
+#[test]
+fn iter_prefix() {
+    use super::compact_str::CompactString;
+    use super::compacted_box::CompactedBox;
+    let mut trie: CompactTrie<CompactString> = CompactTrie::new();
+    for street in &[
+        "Main Street",
+        "Maine Avenue",
+        "Market Square",
+        "Mill Lane",
+        "Elm Road",
+    ] {
+        trie.insert(street.as_bytes(), street.to_uppercase().into());
+    }
+    let boxed = CompactedBox::new(trie);
+    let keys = |prefix: &str| {
+        boxed
+            .iter_prefix(prefix.as_bytes())
+            .map(|(key, _)| String::from_utf8(key).unwrap())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(vec!["Main Street", "Maine Avenue"], keys("Mai"));
+    assert_eq!(
+        vec!["Main Street", "Maine Avenue", "Market Square"],
+        keys("Ma")
+    );
+    assert_eq!(vec!["Maine Avenue"], keys("Maine"));
+    assert_eq!(vec!["Maine Avenue"], keys("Maine Av"));
+    assert_eq!(vec!["Market Square"], keys("Mark"));
+    assert_eq!(Vec::<String>::new(), keys("Mx"));
+    assert_eq!(5, keys("").len());
+    assert_eq!("ELM ROAD", boxed.get(b"Elm Road").unwrap().as_str());
+}
@@ -1,10 +1,20 @@
-use super::compact::Compact;
+use super::compact::{
+    capacity_mode, recompact_whole_into, with_scratch, write_zeros, CapacityMode, Compact,
+    CompactReader, CompactStats, DynamicLayout, FromReaderError, NeedBytes,
+};
 use super::pointer_to_maybe_compact::PointerToMaybeCompact;
 use super::simple_allocator_trait::{Allocator, DefaultHeap};
-use std::iter::FromIterator;
+use std::io::{self, Read, Write};
+use std::iter::{FromIterator, FusedIterator};
 use std::marker::PhantomData;
-use std::ops::{Deref, DerefMut};
+use std::mem::MaybeUninit;
+use std::ops::{Bound, Deref, DerefMut, Range, RangeBounds};
 use std::ptr;
+use std::slice;
+
+/// Vectors of plain elements read from a stream start out with storage of at most this size,
+/// growing as more elements are actually read
+const READ_CHUNK_BYTES: usize = 64 * 1024;
 
 /// A dynamically-sized vector that can be stored in compact sequential storage and
 /// automatically spills over into free heap storage using `Allocator`.
@@ -18,6 +28,12 @@ pub struct CompactVec<T, A: Allocator = DefaultHeap> {
     _alloc: PhantomData<*const A>,
 }
 
+// The elements are uniquely owned, like those of a `Vec`: whether they live on the heap
+// or compactly next to the vector, they move (and are shared) along with it.
+// Allocators only have associated functions, so they are not shared state.
+unsafe impl<T: Send, A: Allocator> Send for CompactVec<T, A> {}
+unsafe impl<T: Sync, A: Allocator> Sync for CompactVec<T, A> {}
+
 impl<T: Compact + Clone, A: Allocator> CompactVec<T, A> {
     /// Get the number of elements in the vector
     pub fn len(&self) -> usize {
@@ -53,6 +69,9 @@ impl<T: Compact + Clone, A: Allocator> CompactVec<T, A> {
     }
 
     /// Create a new vector from raw parts
+    ///
+    /// # Safety
+    ///
     /// Assumes that `ptr` has been allocated by the same Allocator that is `A`
     pub unsafe fn from_raw_parts(ptr: *mut T, len: usize, cap: usize) -> CompactVec<T, A> {
         CompactVec {
@@ -63,19 +82,55 @@ impl<T: Compact + Clone, A: Allocator> CompactVec<T, A> {
         }
     }
 
+    /// Set the length of the vector without dropping or initializing elements
+    ///
+    /// # Safety
+    ///
+    /// `new_len` has to be at most the capacity and all elements up to it have to be initialized
+    pub unsafe fn set_len(&mut self, new_len: usize) {
+        self.len = new_len as u32;
+    }
+
     /// current capacity
     pub fn capacity(&self) -> usize {
         self.cap as usize
     }
 
+    /// Pointer to the elements (null if never allocated), without forming a slice
+    pub(crate) fn elements_ptr(&self) -> *const T {
+        unsafe { self.ptr.ptr() }
+    }
+
+    /// Capacity of the vector once compacted, depending on the current `CapacityMode`
+    fn compact_cap(&self) -> u32 {
+        match capacity_mode() {
+            CapacityMode::Preserve => self.cap,
+            CapacityMode::Trim => self.len,
+        }
+    }
+
     /// Double the capacity of the vector by spilling onto the heap
     fn double_buf(&mut self) {
         let new_cap = if self.cap == 0 { 1 } else { self.cap * 2 };
+        self.spill_with_capacity(new_cap);
+    }
+
+    /// Move the elements into new heap storage with capacity `new_cap`
+    fn spill_with_capacity(&mut self, new_cap: u32) {
+        #[cfg(feature = "metrics")]
+        super::metrics::VEC.record_spill(new_cap as usize * ::std::mem::size_of::<T>());
+        #[cfg(feature = "log")]
+        ::log::trace!(
+            "CVec<{}> spills to the heap with capacity {} ({} bytes)",
+            ::std::any::type_name::<T>(),
+            new_cap,
+            new_cap as usize * ::std::mem::size_of::<T>()
+        );
         let new_ptr = A::allocate::<T>(new_cap as usize);
 
         // items should be decompacted, else internal relative pointers get messed up!
         for (i, item) in self.iter().enumerate() {
-            unsafe { ptr::write(new_ptr.offset(i as isize), Compact::decompact(item)) };
+            unsafe { ptr::write(new_ptr.add(i), Compact::decompact(item)) };
         }
 
         // items shouldn't be dropped here, they live on in the new backing store!
@@ -92,23 +147,15 @@ impl<T: Compact + Clone, A: Allocator> CompactVec<T, A> {
         }
 
         unsafe {
-            let end = self.as_mut_ptr().offset(self.len as isize);
+            let end = self.as_mut_ptr().add(self.len as usize);
             ptr::write(end, value);
             self.len += 1;
         }
     }
 
-    /// push at position
-    pub fn push_at(&mut self, _: usize, value: T) {
-        if self.len == self.cap {
-            self.double_buf();
-        }
-
-        unsafe {
-            let end = self.as_mut_ptr().offset(self.len as isize);
-            ptr::write(end, value);
-            self.len += 1;
-        }
+    /// Insert a value at `index`, same as `insert`
+    pub fn push_at(&mut self, index: usize, value: T) {
+        self.insert(index, value);
     }
 
     /// Extend from a copyable slice
@@ -125,6 +172,28 @@ impl<T: Compact + Clone, A: Allocator> CompactVec<T, A> {
         self[old_len..].copy_from_slice(other);
     }
 
+    /// Move all elements of `other` to the end of the vector, leaving `other` empty.
+    /// Grows the vector at most once.
+    pub fn append(&mut self, other: &mut CompactVec<T, A>) {
+        let new_len = self.len + other.len;
+        if new_len > self.cap {
+            self.spill_with_capacity(new_len.max(self.cap * 2));
+        }
+
+        unsafe {
+            let end = self.as_mut_ptr().add(self.len as usize);
+            // items should be decompacted, else internal relative pointers get messed up!
+            for (i, item) in other.iter().enumerate() {
+                ptr::write(end.add(i), Compact::decompact(item));
+            }
+            self.len = new_len;
+
+            // items shouldn't be dropped here, they live on in self!
+            other.ptr.deallocate_if_free::<A>(other.cap as usize);
+            ptr::write(other, CompactVec::new());
+        }
+    }
+
     /// Pop and return the last element, if the vector wasn't empty
     pub fn pop(&mut self) -> Option<T> {
         if self.len == 0 {
@@ -132,13 +201,14 @@ impl<T: Compact + Clone, A: Allocator> CompactVec<T, A> {
         } else {
             unsafe {
                 self.len -= 1;
-                Some(Compact::decompact(self.get_unchecked(self.len as usize)))
+                Some(Compact::decompact(self.as_ptr().add(self.len as usize)))
             }
         }
     }
 
     /// Insert a value at `index`, copying the elements after `index` upwards
     pub fn insert(&mut self, index: usize, value: T) {
+        assert!(index <= self.len as usize, "insertion index out of bounds");
         if self.len == self.cap {
             self.double_buf();
         }
@@ -146,11 +216,11 @@ impl<T: Compact + Clone, A: Allocator> CompactVec<T, A> {
         unsafe {
             // infallible
             {
-                let ptr = self.as_mut_ptr().offset(index as isize);
+                let ptr = self.as_mut_ptr().add(index);
                 // elements should be decompacted, else internal relative pointers get messed up!
                 for i in (0..self.len as usize - index).rev() {
                     ptr::write(
-                        ptr.offset((i + 1) as isize),
+                        ptr.add(i + 1),
                         Compact::decompact(&self[index + i]),
                     );
                 }
@@ -169,7 +239,7 @@ impl<T: Compact + Clone, A: Allocator> CompactVec<T, A> {
             let ret;
             {
                 // the place we are taking from.
-                let ptr = self.as_mut_ptr().offset(index as isize);
+                let ptr = self.as_mut_ptr().add(index);
                 // copy it out, unsafely having a copy of the value on
                 // the stack and in the vector at the same time.
                 ret = Compact::decompact(ptr);
@@ -178,7 +248,7 @@ impl<T: Compact + Clone, A: Allocator> CompactVec<T, A> {
                 // elements should be decompacted, else internal relative pointers get messed up!
                 for i in 0..(len as usize) - index - 1 {
                     ptr::write(
-                        ptr.offset(i as isize),
+                        ptr.add(i),
                         Compact::decompact(&self[index + i + 1]),
                     )
                 }
@@ -194,12 +264,16 @@ impl<T: Compact + Clone, A: Allocator> CompactVec<T, A> {
     ///
     /// This does not preserve ordering, but is O(1).
     pub fn swap_remove(&mut self, index: usize) -> T {
+        // the last element doesn't move, so decompacting it once is enough
+        if index + 1 == self.len() {
+            return self.pop().expect("vector shouldn't be empty");
+        }
         unsafe {
             let ret = Compact::decompact(&self[index]);
             let len = self.len;
 
             ptr::write(
-                self.as_mut_ptr().offset(index as isize),
+                self.as_mut_ptr().add(index),
                 Compact::decompact(&self[len as usize - 1]),
             );
 
@@ -211,23 +285,164 @@ impl<T: Compact + Clone, A: Allocator> CompactVec<T, A> {
     /// Take a function which returns whether an element should be kept,
     /// and mutably removes all elements from the vector which are not kept
     pub fn retain<F: FnMut(&T) -> bool>(&mut self, mut keep: F) {
-        let mut del = 0;
-        let len = self.len as usize;
-        {
-            let v = &mut **self;
+        self.retain_mut(|elem| keep(elem))
+    }
 
+    /// Like `retain`, but gives mutable access to the elements while deciding
+    pub fn retain_mut<F: FnMut(&mut T) -> bool>(&mut self, mut keep: F) {
+        let len = self.len as usize;
+        let ptr = self.as_mut_ptr();
+        // leak instead of double-dropping elements if `keep` panics
+        self.len = 0;
+        let mut kept = 0;
+        unsafe {
             for i in 0..len {
-                if !keep(&v[i]) {
-                    del += 1;
+                if keep(&mut *ptr.add(i)) {
+                    if i != kept {
+                        // elements should be decompacted, else internal relative pointers get messed up!
+                        ptr::write(ptr.add(kept), Compact::decompact(ptr.add(i)));
+                    }
+                    kept += 1;
+                } else {
+                    ptr::drop_in_place(ptr.add(i));
+                }
+            }
+        }
+        self.len = kept as u32;
+    }
+
+    /// Remove consecutive repeated elements, like `Vec::dedup`
+    pub fn dedup(&mut self)
+    where
+        T: PartialEq,
+    {
+        self.dedup_by(|a, b| a == b)
+    }
+
+    /// Remove consecutive elements that map to the same key, like `Vec::dedup_by_key`
+    pub fn dedup_by_key<K: PartialEq, F: FnMut(&mut T) -> K>(&mut self, mut key: F) {
+        self.dedup_by(|a, b| key(a) == key(b))
+    }
+
+    /// Remove consecutive elements for which `same_bucket(element, previous)` is true,
+    /// like `Vec::dedup_by`
+    pub fn dedup_by<F: FnMut(&mut T, &mut T) -> bool>(&mut self, mut same_bucket: F) {
+        let len = self.len as usize;
+        if len <= 1 {
+            return;
+        }
+
+        // leak instead of double-dropping elements if `same_bucket` panics
+        self.len = 0;
+        let mut kept = 1;
+        unsafe {
+            let ptr = self.as_mut_ptr();
+            for i in 1..len {
+                if same_bucket(&mut *ptr.add(i), &mut *ptr.add(kept - 1)) {
+                    ptr::drop_in_place(ptr.add(i));
                 } else {
-                    v.swap(i - del, i);
+                    if i != kept {
+                        // elements should be decompacted, else internal relative pointers get messed up!
+                        ptr::write(ptr.add(kept), Compact::decompact(ptr.add(i)));
+                    }
+                    kept += 1;
                 }
             }
         }
+        self.len = kept as u32;
+    }
+
+    /// Move the dynamic parts of all elements out of compact storage,
+    /// so the elements can be moved around bit-for-bit
+    fn decompact_elements(&mut self) {
+        let len = self.len();
+        self.decompact_range(0..len)
+    }
+
+    /// Like `decompact_elements`, only for the elements in `range`
+    fn decompact_range(&mut self, range: Range<usize>) {
+        // elements of free storage were already decompacted when spilling
+        if self.ptr.is_compact() {
+            for element in self[range].iter_mut() {
+                let element: *mut T = element;
+                unsafe { ptr::write(element, Compact::decompact(element)) };
+            }
+        }
+    }
+
+    /// The elements as a mutable slice in which they can be moved around
+    /// (swapped, rotated, sorted...), decompacting them first if the vector is compact.
+    ///
+    /// Moving elements through the slice of `DerefMut` instead would move them
+    /// away from their compact dynamic parts, breaking their relative pointers.
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        self.decompact_elements();
+        self
+    }
+
+    /// Swap two elements, like `slice::swap`, decompacting only these two
+    pub fn swap(&mut self, a: usize, b: usize) {
+        if a != b {
+            self.decompact_range(a..a + 1);
+            self.decompact_range(b..b + 1);
+        }
+        (**self).swap(a, b)
+    }
+
+    /// Reverse the order of elements, decompacting all but the middle one
+    pub fn reverse(&mut self) {
+        let (len, half) = (self.len(), self.len() / 2);
+        self.decompact_range(0..half);
+        self.decompact_range(len - half..len);
+        (**self).reverse()
+    }
+
+    /// Rotate the elements so that element `mid` becomes the first, see `as_mut_slice`
+    pub fn rotate_left(&mut self, mid: usize) {
+        if mid != 0 && mid != self.len() {
+            self.decompact_elements();
+        }
+        (**self).rotate_left(mid)
+    }
 
-        if del > 0 {
-            self.truncate(len - del);
+    /// Rotate the elements so that the last `k` elements come first, see `as_mut_slice`
+    pub fn rotate_right(&mut self, k: usize) {
+        if k != 0 && k != self.len() {
+            self.decompact_elements();
         }
+        (**self).rotate_right(k)
+    }
+
+    /// Sort the vector, like `slice::sort`, see `as_mut_slice`
+    pub fn sort(&mut self)
+    where
+        T: Ord,
+    {
+        self.sort_by(Ord::cmp)
+    }
+
+    /// Sort the vector with a comparator function, see `sort`
+    pub fn sort_by<F: FnMut(&T, &T) -> ::std::cmp::Ordering>(&mut self, compare: F) {
+        self.as_mut_slice().sort_by(compare)
+    }
+
+    /// Sort the vector with a key extraction function, see `sort`
+    pub fn sort_by_key<K: Ord, F: FnMut(&T) -> K>(&mut self, key: F) {
+        self.as_mut_slice().sort_by_key(key)
+    }
+
+    /// Sort the vector without preserving the order of equal elements, see `sort`
+    pub fn sort_unstable(&mut self)
+    where
+        T: Ord,
+    {
+        self.sort_unstable_by(Ord::cmp)
+    }
+
+    /// Sort the vector with a comparator function without preserving
+    /// the order of equal elements, see `sort`
+    pub fn sort_unstable_by<F: FnMut(&T, &T) -> ::std::cmp::Ordering>(&mut self, compare: F) {
+        self.as_mut_slice().sort_unstable_by(compare)
     }
 
     /// Truncate the vector to the given length
@@ -236,23 +451,193 @@ impl<T: Compact + Clone, A: Allocator> CompactVec<T, A> {
             while desired_len < self.len as usize {
                 self.len -= 1;
                 let len = self.len;
-                ptr::drop_in_place(self.get_unchecked_mut(len as usize));
+                ptr::drop_in_place(self.as_mut_ptr().add(len as usize));
             }
         }
     }
 
+    /// Resize the vector to `new_len`, either truncating it
+    /// or filling it up with clones of `value`
+    pub fn resize(&mut self, new_len: usize, value: T) {
+        self.resize_with(new_len, || value.clone());
+    }
+
+    /// Resize the vector to `new_len`, either truncating it
+    /// or filling it up with values returned by `f`
+    pub fn resize_with<F: FnMut() -> T>(&mut self, new_len: usize, mut f: F) {
+        if new_len > self.cap as usize {
+            self.spill_with_capacity((new_len as u32).max(self.cap * 2));
+        }
+
+        while (self.len as usize) < new_len {
+            self.push(f());
+        }
+        self.truncate(new_len);
+    }
+
     /// Clear the vector
     pub fn clear(&mut self) {
         self.truncate(0);
     }
 
-    /// Drain (empty & iterate over) the vector
-    pub fn drain(&mut self) -> IntoIter<T, A> {
+    /// Remove the elements in `range` and iterate over them,
+    /// copying the elements after `range` downwards
+    pub fn drain<R: RangeBounds<usize>>(&mut self, range: R) -> Drain<T, A> {
+        Drain {
+            iter: self.extract_range(range).into_iter(),
+        }
+    }
+
+    /// Move the elements in `range` into a new vector,
+    /// copying the elements after `range` downwards
+    pub fn extract_range<R: RangeBounds<usize>>(&mut self, range: R) -> CompactVec<T, A> {
+        let (start, end) = self.resolve_range(range);
+        self.replace_range(start, end, Vec::new())
+    }
+
+    /// Replace the elements in `range` with the items of `replace_with`
+    /// and iterate over the removed elements
+    pub fn splice<R: RangeBounds<usize>, I: IntoIterator<Item = T>>(
+        &mut self,
+        range: R,
+        replace_with: I,
+    ) -> Drain<T, A> {
+        let (start, end) = self.resolve_range(range);
+        Drain {
+            iter: self
+                .replace_range(start, end, replace_with.into_iter().collect())
+                .into_iter(),
+        }
+    }
+
+    fn resolve_range<R: RangeBounds<usize>>(&self, range: R) -> (usize, usize) {
+        let start = match range.start_bound() {
+            Bound::Included(&start) => start,
+            Bound::Excluded(&start) => start + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&end) => end + 1,
+            Bound::Excluded(&end) => end,
+            Bound::Unbounded => self.len as usize,
+        };
+        assert!(start <= end && end <= self.len as usize, "range out of bounds");
+        (start, end)
+    }
+
+    /// Move the elements in `start..end` into a new vector and put `replacement` in their place,
+    /// shifting the elements after them only once
+    fn replace_range(&mut self, start: usize, end: usize, replacement: Vec<T>) -> CompactVec<T, A> {
+        let len = self.len as usize;
+        let new_len = len - (end - start) + replacement.len();
+        // grow while all elements are still in place
+        if new_len > self.cap as usize {
+            self.spill_with_capacity((new_len as u32).max(self.cap * 2));
+        }
+
+        let mut extracted = CompactVec::with_capacity(end - start);
         unsafe {
-            let decompacted = Compact::decompact(self);
-            ::std::ptr::write(self, CompactVec::new());
-            decompacted.into_iter()
+            // elements should be decompacted, else internal relative pointers get messed up!
+            for item in &self[start..end] {
+                extracted.push(Compact::decompact(item));
+            }
+
+            let ptr = self.as_mut_ptr();
+            let new_end = start + replacement.len();
+            if new_end < end {
+                for i in end..len {
+                    ptr::write(ptr.add(i - (end - new_end)), Compact::decompact(ptr.add(i)));
+                }
+            } else if new_end > end {
+                for i in (end..len).rev() {
+                    ptr::write(ptr.add(i + (new_end - end)), Compact::decompact(ptr.add(i)));
+                }
+            }
+
+            for (i, item) in replacement.into_iter().enumerate() {
+                ptr::write(ptr.add(start + i), item);
+            }
+        }
+        self.len = new_len as u32;
+        extracted
+    }
+
+    /// Iterate over mutable references to all elements, each together with its index
+    /// and a `Rest` accessor that gives access to all other elements of the vector.
+    ///
+    /// Useful for pairwise interactions (like collisions between element `i` and `j`).
+    /// Since the yielded items borrow from the iterator, use it with `while let`.
+    pub fn iter_with_rest_mut(&mut self) -> IterWithRestMut<'_, T> {
+        IterWithRestMut {
+            slice: self,
+            index: 0,
+        }
+    }
+
+    /// Like `compact_static_part`, for a vector of `len` elements without unused capacity
+    /// that are written separately (see `write_compacted_elements`)
+    pub(crate) unsafe fn compact_static_part_of_len(
+        dest: *mut Self,
+        len: usize,
+        new_dynamic_part: *mut u8,
+    ) {
+        (*dest).len = len as u32;
+        (*dest).cap = len as u32;
+        (*dest).ptr.set_to_compact(new_dynamic_part as *mut T);
+    }
+
+    /// Like `write_dynamic_part`, for a vector of capacity `cap` holding `elements`,
+    /// which don't have to be stored in a vector (in this order) yet
+    pub(crate) fn write_compacted_elements<'a, I, W>(
+        elements: I,
+        cap: usize,
+        writer: &mut W,
+    ) -> io::Result<()>
+    where
+        T: 'a,
+        I: Iterator<Item = &'a T> + Clone,
+        W: Write,
+    {
+        let size = ::std::mem::size_of::<T>();
+        let base_size = cap * size;
+
+        // first the static parts of all elements, pointing to their dynamic parts behind them
+        let mut layout = DynamicLayout::after(base_size);
+        let mut len = 0;
+        with_scratch::<T, _, _>(size, |slot| -> io::Result<()> {
+            for item in elements.clone() {
+                let offset = layout.field(item);
+                unsafe {
+                    if std::mem::needs_drop::<T>() {
+                        ptr::write_bytes(slot, 0, size);
+                        item.compact_static_part(
+                            slot as *mut T,
+                            slot.wrapping_add(offset - len * size),
+                        );
+                        writer.write_all(slice::from_raw_parts(slot, size))?;
+                    } else {
+                        let bytes = slice::from_raw_parts(item as *const T as *const u8, size);
+                        writer.write_all(bytes)?;
+                    }
+                }
+                len += 1;
+            }
+            Ok(())
+        })?;
+        write_zeros(writer, base_size - len * size)?;
+        if !std::mem::needs_drop::<T>() {
+            return Ok(());
+        }
+
+        let mut layout = DynamicLayout::after(base_size);
+        let mut written = base_size;
+        for item in elements {
+            let offset = layout.field(item);
+            write_zeros(writer, offset - written)?;
+            item.write_dynamic_part(writer)?;
+            written = offset + item.dynamic_size_bytes();
         }
+        Ok(())
     }
 
     /// debug printing
@@ -261,10 +646,31 @@ impl<T: Compact + Clone, A: Allocator> CompactVec<T, A> {
     }
 }
 
-impl<T: Compact + Clone, A: Allocator> From<Vec<T>> for CompactVec<T, A> {
-    /// Create a `CompactVec` from a normal `Vec`,
-    /// directly using the backing storage as free heap storage
+/// Allocators that a `CompactVec` can be created from a `Vec` for (see `From<Vec<T>>`)
+pub trait FromVecAllocator: Allocator {
+    /// Does this allocator free memory allocated by `Vec` (the global allocator)?
+    /// Then the storage of a `Vec` is taken over as is, instead of moving its elements.
+    const SHARES_VEC_STORAGE: bool = false;
+}
+
+impl FromVecAllocator for DefaultHeap {
+    const SHARES_VEC_STORAGE: bool = true;
+}
+
+impl<T: Compact + Clone, A: FromVecAllocator> From<Vec<T>> for CompactVec<T, A> {
+    /// Create a `CompactVec` from a normal `Vec`, directly using the backing storage
+    /// as free heap storage if `A` shares it with `Vec` (like `DefaultHeap`),
+    /// or moving the elements into storage allocated by `A` otherwise
     fn from(mut vec: Vec<T>) -> Self {
+        if !A::SHARES_VEC_STORAGE {
+            let mut cvec = Self::with_capacity(vec.len());
+            unsafe {
+                ptr::copy_nonoverlapping(vec.as_ptr(), cvec.as_mut_ptr(), vec.len());
+                cvec.set_len(vec.len());
+                vec.set_len(0);
+            }
+            return cvec;
+        }
         let cvec = unsafe { Self::from_raw_parts(vec.as_mut_ptr(), vec.len(), vec.capacity()) };
         ::std::mem::forget(vec);
         cvec
@@ -284,7 +690,7 @@ impl<T, A: Allocator> Deref for CompactVec<T, A> {
 
     fn deref(&self) -> &[T] {
         if unsafe { self.ptr.ptr().is_null() } {
-            unsafe { ::std::slice::from_raw_parts(0x1 as *const T, 0) }
+            unsafe { ::std::slice::from_raw_parts(ptr::NonNull::dangling().as_ptr(), 0) }
         } else {
             unsafe { ::std::slice::from_raw_parts(self.ptr.ptr(), self.len as usize) }
         }
@@ -292,9 +698,13 @@ impl<T, A: Allocator> Deref for CompactVec<T, A> {
 }
 
 impl<T, A: Allocator> DerefMut for CompactVec<T, A> {
+    /// Mutable access to the elements in place.
+    ///
+    /// Don't move elements around through this slice (with `mem::swap`, `copy_within`...)
+    /// if the vector might be compact, use the methods of the vector or `as_mut_slice` instead
     fn deref_mut(&mut self) -> &mut [T] {
         if unsafe { self.ptr.ptr().is_null() } {
-            unsafe { ::std::slice::from_raw_parts_mut(0x1 as *mut T, 0) }
+            unsafe { ::std::slice::from_raw_parts_mut(ptr::NonNull::dangling().as_ptr(), 0) }
         } else {
             unsafe { ::std::slice::from_raw_parts_mut(self.ptr.mut_ptr(), self.len as usize) }
         }
@@ -309,30 +719,69 @@ pub struct IntoIter<T, A: Allocator> {
     _alloc: PhantomData<*const A>,
 }
 
+unsafe impl<T: Send, A: Allocator> Send for IntoIter<T, A> {}
+unsafe impl<T: Sync, A: Allocator> Sync for IntoIter<T, A> {}
+
 impl<T, A: Allocator> Iterator for IntoIter<T, A> {
     type Item = T;
 
     fn next(&mut self) -> Option<T> {
         if self.index < self.len {
-            let item = unsafe { ptr::read(self.ptr.ptr().offset(self.index as isize)) };
+            let item = unsafe { ptr::read(self.ptr.ptr().add(self.index)) };
             self.index += 1;
             Some(item)
         } else {
             None
         }
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.len - self.index;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<T, A: Allocator> DoubleEndedIterator for IntoIter<T, A> {
+    fn next_back(&mut self) -> Option<T> {
+        if self.index < self.len {
+            // the element is taken out by shrinking the range that is dropped
+            self.len -= 1;
+            Some(unsafe { ptr::read(self.ptr.ptr().add(self.len)) })
+        } else {
+            None
+        }
+    }
 }
 
+impl<T, A: Allocator> ExactSizeIterator for IntoIter<T, A> {}
+
+impl<T, A: Allocator> FusedIterator for IntoIter<T, A> {}
+
 impl<T, A: Allocator> Drop for IntoIter<T, A> {
     fn drop(&mut self) {
         // drop all remaining elements
-        unsafe {
-            ptr::drop_in_place(&mut ::std::slice::from_raw_parts(
-                self.ptr.ptr().offset(self.index as isize),
-                self.len,
-            ))
-        };
-        self.ptr.deallocate_if_free::<A>(self.cap as usize);
+        if self.index < self.len {
+            unsafe {
+                ptr::drop_in_place(ptr::slice_from_raw_parts_mut(
+                    self.ptr.mut_ptr().add(self.index),
+                    self.len - self.index,
+                ))
+            };
+        }
+        self.ptr.deallocate_if_free::<A>(self.cap);
+    }
+}
+
+/// Iterator over the elements removed by `CompactVec::drain`
+pub struct Drain<T, A: Allocator = DefaultHeap> {
+    iter: IntoIter<T, A>,
+}
+
+impl<T, A: Allocator> Iterator for Drain<T, A> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.iter.next()
     }
 }
 
@@ -370,6 +819,82 @@ impl<'a, T, A: Allocator> IntoIterator for &'a mut CompactVec<T, A> {
         self.iter_mut()
     }
 }
+/// Iterator-like helper returned by `CompactVec::iter_with_rest_mut`
+pub struct IterWithRestMut<'a, T: 'a> {
+    slice: &'a mut [T],
+    index: usize,
+}
+
+impl<'a, T: 'a> IterWithRestMut<'a, T> {
+    /// Get the next element together with its index and access to all other elements
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<(usize, &mut T, Rest<'_, T>)> {
+        if self.index >= self.slice.len() {
+            return None;
+        }
+        let index = self.index;
+        self.index += 1;
+        let (before, elem_and_after) = self.slice.split_at_mut(index);
+        let (elem, after) = elem_and_after.split_first_mut().unwrap();
+        Some((
+            index,
+            elem,
+            Rest {
+                before,
+                after,
+                index,
+            },
+        ))
+    }
+}
+
+/// Access to all elements of a `CompactVec` except the one at `index`
+pub struct Rest<'a, T: 'a> {
+    before: &'a mut [T],
+    after: &'a mut [T],
+    index: usize,
+}
+
+impl<'a, T: 'a> Rest<'a, T> {
+    /// The index of the excluded element
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Get the element at `index`, if it exists and isn't the excluded one
+    pub fn get(&self, index: usize) -> Option<&T> {
+        if index < self.index {
+            self.before.get(index)
+        } else if index > self.index {
+            self.after.get(index - self.index - 1)
+        } else {
+            None
+        }
+    }
+
+    /// Get the element at `index` mutably, if it exists and isn't the excluded one
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        if index < self.index {
+            self.before.get_mut(index)
+        } else if index > self.index {
+            self.after.get_mut(index - self.index - 1)
+        } else {
+            None
+        }
+    }
+
+    /// Iterate over all other elements, together with their indices
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &T)> + '_ {
+        let after_start = self.index + 1;
+        self.before.iter().enumerate().chain(
+            self.after
+                .iter()
+                .enumerate()
+                .map(move |(i, elem)| (after_start + i, elem)),
+        )
+    }
+}
+
 impl<T: Compact + Clone, A: Allocator> Compact for CompactVec<T, A> {
     fn is_still_compact(&self) -> bool {
         if std::mem::needs_drop::<T>() {
@@ -380,39 +905,46 @@ impl<T: Compact + Clone, A: Allocator> Compact for CompactVec<T, A> {
     }
 
     fn dynamic_size_bytes(&self) -> usize {
-        let base_size = self.cap as usize * ::std::mem::size_of::<T>();
+        let base_size = self.compact_cap() as usize * ::std::mem::size_of::<T>();
 
         if std::mem::needs_drop::<T>() {
-            base_size
-                + self
-                    .iter()
-                    .map(|elem| elem.dynamic_size_bytes())
-                    .sum::<usize>()
+            let mut layout = DynamicLayout::after(base_size);
+            for elem in self.iter() {
+                layout.field(elem);
+            }
+            layout.size()
         } else {
             base_size
         }
     }
 
+    fn dynamic_align() -> usize {
+        ::std::mem::align_of::<T>().max(T::dynamic_align())
+    }
+
     unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        let compact_cap = (*source).compact_cap();
+        #[cfg(feature = "metrics")]
+        super::metrics::VEC.record_compaction(compact_cap as usize * ::std::mem::size_of::<T>());
         (*dest).len = (*source).len;
-        (*dest).cap = (*source).cap;
+        (*dest).cap = compact_cap;
         (*dest).ptr.set_to_compact(new_dynamic_part as *mut T);
 
         if std::mem::needs_drop::<T>() {
-            let mut offset = (*source).cap as usize * ::std::mem::size_of::<T>();
+            let mut layout =
+                DynamicLayout::after(compact_cap as usize * ::std::mem::size_of::<T>());
 
             for (i, item) in (*source).iter_mut().enumerate() {
-                let size_of_this_item = item.dynamic_size_bytes();
+                let offset = layout.field(item);
                 Compact::compact(
                     item,
                     &mut (&mut *dest)[i],
-                    new_dynamic_part.offset(offset as isize),
+                    new_dynamic_part.add(offset),
                 );
-                offset += size_of_this_item;
             }
         } else {
             ptr::copy_nonoverlapping(
-                (*source).ptr.ptr(),
+                (*source).as_ptr(),
                 new_dynamic_part as *mut T,
                 (*source).len(),
             );
@@ -423,6 +955,125 @@ impl<T: Compact + Clone, A: Allocator> Compact for CompactVec<T, A> {
             .deallocate_if_free::<A>((*source).cap as usize);
     }
 
+    unsafe fn compact_static_part(&self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        (*dest).len = self.len;
+        (*dest).cap = self.compact_cap();
+        (*dest).ptr.set_to_compact(new_dynamic_part as *mut T);
+    }
+
+    fn write_dynamic_part<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        if !std::mem::needs_drop::<T>() {
+            let size = ::std::mem::size_of::<T>();
+            let elements =
+                unsafe { slice::from_raw_parts(self.as_ptr() as *const u8, self.len() * size) };
+            writer.write_all(elements)?;
+            return write_zeros(writer, (self.compact_cap() as usize - self.len()) * size);
+        }
+        Self::write_compacted_elements(self.iter(), self.compact_cap() as usize, writer)
+    }
+
+    unsafe fn read_dynamic_part<R: Read>(
+        source: *const Self,
+        reader: &mut CompactReader<R>,
+    ) -> Result<Self, FromReaderError> {
+        let (len, cap) = ((*source).len as usize, (*source).cap as usize);
+        if !(*source).ptr.is_compact() {
+            return Err(reader.invalid("vector points outside of the stream"));
+        }
+        if len > cap {
+            return Err(reader.invalid("vector is longer than its capacity"));
+        }
+        let size = ::std::mem::size_of::<T>();
+
+        if !std::mem::needs_drop::<T>() {
+            // grow along with the bytes actually read, so a corrupt length can't allocate a lot
+            let mut vec = Self::with_capacity(len.min(READ_CHUNK_BYTES / size.max(1)));
+            while vec.len() < len {
+                if vec.len() == vec.capacity() {
+                    vec.spill_with_capacity((vec.cap * 2).clamp(1, len as u32));
+                }
+                let count = vec.capacity() - vec.len();
+                let end = vec.as_mut_ptr().add(vec.len()) as *mut u8;
+                ptr::write_bytes(end, 0, count * size);
+                reader.read_exact(slice::from_raw_parts_mut(end, count * size))?;
+                vec.len += count as u32;
+            }
+            reader.skip((cap - len) * size)?;
+            return Ok(vec);
+        }
+
+        // the static parts of all elements come before their dynamic parts
+        let mut statics = Vec::new();
+        for _ in 0..len {
+            let mut item = MaybeUninit::<T>::zeroed();
+            reader.read_exact(slice::from_raw_parts_mut(
+                item.as_mut_ptr() as *mut u8,
+                size,
+            ))?;
+            statics.push(item);
+        }
+        reader.skip((cap - len) * size)?;
+
+        let mut vec = Self::with_capacity(len);
+        for item in &statics {
+            vec.push(reader.read_field(item.as_ptr())?);
+        }
+        Ok(vec)
+    }
+
+    fn collect_stats(&self, path: &str, stats: &mut CompactStats) {
+        if self.cap > 0 {
+            stats.record_container(
+                path,
+                self.ptr.is_compact(),
+                self.cap as usize * ::std::mem::size_of::<T>(),
+                self.elements_ptr() as *const u8,
+            );
+        }
+        if std::mem::needs_drop::<T>() {
+            for (i, item) in self.iter().enumerate() {
+                item.collect_stats(&format!("{}[{}]", path, i), stats);
+            }
+        }
+    }
+
+    fn repair_lossy(&mut self, path: &str, dropped: &mut Vec<String>) {
+        if self.len > self.cap || (self.len > 0 && self.elements_ptr().is_null()) {
+            // the elements can't be dropped safely, leak them instead
+            ::std::mem::forget(::std::mem::take(self));
+            dropped.push(path.to_owned());
+        } else if std::mem::needs_drop::<T>() {
+            for (i, item) in self.iter_mut().enumerate() {
+                item.repair_lossy(&format!("{}[{}]", path, i), dropped);
+            }
+        }
+    }
+
+    fn clear_dirty(&mut self) {
+        // even elements without a dynamic part might be `CompactCell`s
+        for item in self.iter_mut() {
+            item.clear_dirty();
+        }
+    }
+
+    unsafe fn recompact_dirty_into(&mut self, spare: &mut &mut [u8]) -> Result<(), NeedBytes> {
+        if !self.ptr.is_compact() {
+            recompact_whole_into(self, spare)
+        } else if std::mem::needs_drop::<T>() {
+            // the elements stay in place, only their spilled parts move
+            for item in self.iter_mut() {
+                item.recompact_dirty_into(spare)?;
+            }
+            Ok(())
+        } else {
+            Ok(())
+        }
+    }
+
+    unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+        self.ptr.shift_compact(shift);
+    }
+
     unsafe fn decompact(source: *const Self) -> Self {
         if (*source).ptr.is_compact() {
             if std::mem::needs_drop::<T>() {
@@ -431,12 +1082,15 @@ impl<T: Compact + Clone, A: Allocator> Compact for CompactVec<T, A> {
                     .map(|item| Compact::decompact(item))
                     .collect()
             } else {
-                CompactVec {
-                    ptr: ptr::read(&(*source).ptr as *const PointerToMaybeCompact<T>),
-                    len: (*source).len,
-                    cap: (*source).cap,
-                    _alloc: (*source)._alloc,
-                }
+                // elements can be copied bitwise, but the relative pointer to them can't!
+                let mut decompacted = Self::with_capacity((*source).cap as usize);
+                ptr::copy_nonoverlapping(
+                    (*source).as_ptr(),
+                    decompacted.as_mut_ptr(),
+                    (*source).len(),
+                );
+                decompacted.len = (*source).len;
+                decompacted
             }
         } else {
             CompactVec {
@@ -452,13 +1106,13 @@ impl<T: Compact + Clone, A: Allocator> Compact for CompactVec<T, A> {
 impl<T: Compact + Clone, A: Allocator> Clone for CompactVec<T, A> {
     fn clone(&self) -> CompactVec<T, A> {
         if std::mem::needs_drop::<T>() {
-            self.iter().cloned().collect::<Vec<_>>().into()
+            self.iter().cloned().collect()
         } else {
             let mut new_vec = Self::with_capacity(self.cap as usize);
             unsafe {
                 ptr::copy_nonoverlapping(
-                    self.ptr.ptr(),
-                    new_vec.ptr.mut_ptr(),
+                    self.as_ptr(),
+                    new_vec.as_mut_ptr(),
                     self.len as usize,
                 );
             }
@@ -499,6 +1153,67 @@ impl<T: Compact + ::std::fmt::Debug, A: Allocator> ::std::fmt::Debug for Compact
     }
 }
 
+impl<T, U, A: Allocator, B: Allocator> PartialEq<CompactVec<U, B>> for CompactVec<T, A>
+where
+    T: PartialEq<U>,
+{
+    fn eq(&self, other: &CompactVec<U, B>) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl<T: Eq, A: Allocator> Eq for CompactVec<T, A> {}
+
+impl<T: PartialOrd, A: Allocator> PartialOrd for CompactVec<T, A> {
+    /// Compares lexicographically, like `Vec`
+    fn partial_cmp(&self, other: &Self) -> Option<::std::cmp::Ordering> {
+        (**self).partial_cmp(&**other)
+    }
+}
+
+impl<T: Ord, A: Allocator> Ord for CompactVec<T, A> {
+    fn cmp(&self, other: &Self) -> ::std::cmp::Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+impl<T: ::std::hash::Hash, A: Allocator> ::std::hash::Hash for CompactVec<T, A> {
+    /// Hashes like a slice (and thus like a `Vec`) with the same elements
+    fn hash<H: ::std::hash::Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl<T: PartialEq<U>, U, A: Allocator> PartialEq<Vec<U>> for CompactVec<T, A> {
+    fn eq(&self, other: &Vec<U>) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl<T: PartialEq<U>, U, A: Allocator> PartialEq<CompactVec<U, A>> for Vec<T> {
+    fn eq(&self, other: &CompactVec<U, A>) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl<T: PartialEq<U>, U, A: Allocator> PartialEq<[U]> for CompactVec<T, A> {
+    fn eq(&self, other: &[U]) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl<'a, T: PartialEq<U>, U, A: Allocator> PartialEq<&'a [U]> for CompactVec<T, A> {
+    fn eq(&self, other: &&'a [U]) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl<T: PartialEq<U>, U, A: Allocator, const N: usize> PartialEq<[U; N]> for CompactVec<T, A> {
+    fn eq(&self, other: &[U; N]) -> bool {
+        self[..] == other[..]
+    }
+}
+
 #[cfg(feature = "serde-serialization")]
 use serde::ser::SerializeSeq;
 
@@ -570,7 +1285,144 @@ where
     where
         D: ::serde::de::Deserializer<'de>,
     {
-        deserializer.deserialize_map(CompactVecVisitor::new())
+        deserializer.deserialize_seq(CompactVecVisitor::new())
+    }
+}
+
+#[cfg(feature = "serde-serialization")]
+use super::compact_deserialize::{input_changed, CompactDeserialize, Placer, Planner};
+
+#[cfg(feature = "serde-serialization")]
+impl<T: Compact + Clone, A: Allocator> CompactVec<T, A> {
+    /// Point the uninitialized vector at `dest` to `len` elements at `elements`,
+    /// placed in its compact storage
+    pub(crate) unsafe fn init_placed(dest: *mut Self, len: usize, elements: *mut T) {
+        (*dest).len = len as u32;
+        (*dest).cap = len as u32;
+        (*dest).ptr.set_to_compact(elements);
+    }
+}
+
+#[cfg(feature = "serde-serialization")]
+struct PlanVisitor<'a, T, A: Allocator> {
+    planner: &'a mut Planner,
+    marker: PhantomData<fn() -> CompactVec<T, A>>,
+}
+
+#[cfg(feature = "serde-serialization")]
+impl<'a, 'de, T, A> ::serde::de::Visitor<'de> for PlanVisitor<'a, T, A>
+where
+    T: CompactDeserialize<'de> + Clone,
+    A: Allocator,
+{
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        formatter.write_str("A Compact Vector")
+    }
+
+    fn visit_seq<S>(self, mut access: S) -> Result<(), S::Error>
+    where
+        S: ::serde::de::SeqAccess<'de>,
+    {
+        // the elements are placed before their own dynamic parts
+        let part = self.planner.begin();
+        let mut len = 0;
+        while access.next_element_seed(self.planner.seed::<T>())?.is_some() {
+            len += 1;
+        }
+        self.planner.end(
+            part,
+            len,
+            len * ::std::mem::size_of::<T>(),
+            CompactVec::<T, A>::dynamic_align(),
+        );
+        Ok(())
+    }
+}
+
+#[cfg(feature = "serde-serialization")]
+struct PlaceVisitor<'a, T, A: Allocator> {
+    placer: &'a mut Placer,
+    dest: *mut CompactVec<T, A>,
+}
+
+#[cfg(feature = "serde-serialization")]
+impl<'a, 'de, T, A> ::serde::de::Visitor<'de> for PlaceVisitor<'a, T, A>
+where
+    T: CompactDeserialize<'de> + Clone,
+    A: Allocator,
+{
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        formatter.write_str("A Compact Vector")
+    }
+
+    fn visit_seq<S>(self, mut access: S) -> Result<(), S::Error>
+    where
+        S: ::serde::de::SeqAccess<'de>,
+    {
+        let (len, elements) = self.placer.take().ok_or_else(input_changed)?;
+        let elements = elements as *mut T;
+        unsafe {
+            CompactVec::init_placed(self.dest, len, elements);
+            for i in 0..len {
+                if access
+                    .next_element_seed(self.placer.seed(elements.add(i)))?
+                    .is_none()
+                {
+                    return Err(input_changed());
+                }
+            }
+        }
+        match access.next_element::<::serde::de::IgnoredAny>()? {
+            Some(_) => Err(input_changed()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(feature = "serde-serialization")]
+impl<'de, T, A> CompactDeserialize<'de> for CompactVec<T, A>
+where
+    T: CompactDeserialize<'de> + Clone,
+    A: Allocator,
+{
+    fn plan<D>(deserializer: D, planner: &mut Planner) -> Result<(), D::Error>
+    where
+        D: ::serde::de::Deserializer<'de>,
+    {
+        deserializer.deserialize_seq(PlanVisitor::<T, A> {
+            planner,
+            marker: PhantomData,
+        })
+    }
+
+    unsafe fn place<D>(deserializer: D, dest: *mut Self, placer: &mut Placer) -> Result<(), D::Error>
+    where
+        D: ::serde::de::Deserializer<'de>,
+    {
+        deserializer.deserialize_seq(PlaceVisitor { placer, dest })
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a, T, A> ::arbitrary::Arbitrary<'a> for CompactVec<T, A>
+where
+    T: Compact + Clone + ::arbitrary::Arbitrary<'a>,
+    A: Allocator + 'a,
+{
+    fn arbitrary(u: &mut ::arbitrary::Unstructured<'a>) -> ::arbitrary::Result<Self> {
+        u.arbitrary_iter()?.collect()
+    }
+
+    fn arbitrary_take_rest(u: ::arbitrary::Unstructured<'a>) -> ::arbitrary::Result<Self> {
+        u.arbitrary_take_rest_iter()?.collect()
+    }
+
+    fn size_hint(depth: usize) -> (usize, Option<usize>) {
+        <Vec<T> as ::arbitrary::Arbitrary>::size_hint(depth)
     }
 }
 
@@ -616,8 +1468,8 @@ fn nested_vector() {
     unsafe {
         Compact::compact_behind(&mut list_of_lists, storage as *mut NestedType);
         ::std::mem::forget(list_of_lists);
-        assert_eq!(&[1, 2, 3], &*(*(storage as *mut NestedType))[0]);
-        assert_eq!(&[4, 5, 6, 7, 8, 9], &*(*(storage as *mut NestedType))[1]);
+        assert_eq!(&[1, 2, 3], &*(&*(storage as *mut NestedType))[0]);
+        assert_eq!(&[4, 5, 6, 7, 8, 9], &*(&*(storage as *mut NestedType))[1]);
         println!("before decompact!");
         let decompacted = Compact::decompact(storage as *mut NestedType);
         println!("after decompact!");
@@ -626,3 +1478,470 @@ fn nested_vector() {
         DefaultHeap::deallocate(storage, bytes);
     }
 }
+
+#[test]
+fn trimmed_capacity() {
+    use super::compact::{with_capacity_mode, CapacityMode};
+    type NestedType = CompactVec<CompactVec<u32>>;
+    let mut list_of_lists: NestedType = CompactVec::with_capacity(8);
+    let mut inner = CompactVec::with_capacity(16);
+    inner.push(1);
+    list_of_lists.push(inner);
+    list_of_lists.push(vec![2, 3].into());
+
+    let preserved_bytes = list_of_lists.total_size_bytes();
+    with_capacity_mode(CapacityMode::Trim, move || {
+        let bytes = list_of_lists.total_size_bytes();
+        assert!(bytes < preserved_bytes);
+        let inner_size = ::std::mem::size_of::<CompactVec<u32>>();
+        assert_eq!(::std::mem::size_of::<NestedType>() + 2 * inner_size + 3 * 4, bytes);
+        let storage = DefaultHeap::allocate(bytes);
+
+        unsafe {
+            Compact::compact_behind(&mut list_of_lists, storage as *mut NestedType);
+            ::std::mem::forget(list_of_lists);
+            let compacted = &mut *(storage as *mut NestedType);
+            assert_eq!(2, compacted.capacity());
+            assert_eq!(1, compacted[0].capacity());
+            assert_eq!(&[1], &*compacted[0]);
+            assert_eq!(&[2, 3], &*compacted[1]);
+
+            // growing spills onto the heap
+            compacted[1].push(4);
+            assert!(!compacted.is_still_compact());
+            let decompacted = Compact::decompact(storage as *mut NestedType);
+            assert_eq!(&[2, 3, 4], &*decompacted[1]);
+            DefaultHeap::deallocate(storage, bytes);
+        }
+    });
+}
+
+#[test]
+fn swap_remove_compact_elements() {
+    use super::compact_str::CompactString;
+    use super::compacted_box::CompactedBox;
+    use super::tracking_allocator::{allocation_stats, reset_allocation_stats, TrackingAllocator};
+    type Names = CompactVec<CompactString, TrackingAllocator>;
+    let names = |names: &Names| {
+        names
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>()
+    };
+    let lists: CompactVec<Names> = (1..5)
+        .map(|n| (0..n).map(|i| i.to_string().into()).collect())
+        .collect();
+    let mut boxed = CompactedBox::new(lists);
+
+    // only the removed element is decompacted, once
+    reset_allocation_stats();
+    assert_eq!(
+        vec!["0", "1", "2", "3"],
+        names(&boxed.with_mut(|lists| lists.swap_remove(3)))
+    );
+    assert_eq!(1, allocation_stats().allocations);
+
+    // the last element moves into the gap
+    reset_allocation_stats();
+    assert_eq!(
+        vec!["0"],
+        names(&boxed.with_mut(|lists| lists.swap_remove(0)))
+    );
+    assert_eq!(2, allocation_stats().allocations);
+    assert_eq!(vec!["0", "1", "2"], names(&boxed[0]));
+    assert_eq!(vec!["0", "1"], names(&boxed[1]));
+}
+
+#[test]
+fn drops_each_element_once() {
+    use super::drop_tracker::{DropCounter, DropTracker};
+    type Tracked = DropTracker<CompactVec<u32>>;
+    let counter = DropCounter::new();
+    let mut list: CompactVec<Tracked> = (0..8).map(|n| counter.track(vec![n].into())).collect();
+
+    assert_eq!(vec![7], **list.pop().unwrap());
+    assert_eq!(vec![1], **list.remove(1));
+    assert_eq!(vec![0], **list.swap_remove(0));
+    assert_eq!(2, list.drain(1..3).count());
+    // dropping a partially consumed drain drops the rest
+    assert_eq!(1, list.drain(..2).take(1).count());
+    assert_eq!(1, list.len());
+    assert_eq!(1, counter.alive());
+
+    // decompacting moves the elements out of compact storage
+    let mut list: CompactVec<Tracked> = (0..4).map(|n| counter.track(vec![n].into())).collect();
+    let bytes = list.total_size_bytes();
+    let storage = DefaultHeap::allocate(bytes);
+    unsafe {
+        Compact::compact_behind(&mut list, storage);
+        ::std::mem::forget(list);
+        let decompacted: CompactVec<Tracked> = Compact::decompact(storage);
+        DefaultHeap::deallocate(storage, bytes);
+        assert_eq!(5, counter.alive());
+        drop(decompacted);
+    }
+    assert_eq!(1, counter.alive());
+}
+
+#[test]
+fn into_iter_from_both_ends() {
+    use super::drop_tracker::DropCounter;
+    let counter = DropCounter::new();
+    let list: CompactVec<_> = (0..6)
+        .map(|n| counter.track(CompactVec::<u32>::from(vec![n])))
+        .collect();
+
+    let mut iter = list.into_iter();
+    assert_eq!(6, iter.len());
+    assert_eq!(vec![5], **iter.next_back().unwrap());
+    assert_eq!(vec![0], **iter.next().unwrap());
+    assert_eq!((4, Some(4)), iter.size_hint());
+    assert_eq!(vec![4], **iter.next_back().unwrap());
+    // the rest is dropped with the iterator
+    assert_eq!(3, counter.alive());
+    drop(iter);
+    assert_eq!(0, counter.alive());
+
+    let list: CompactVec<u32> = vec![1, 2, 3].into();
+    let reversed: Vec<u32> = list.into_iter().rev().collect();
+    assert_eq!(vec![3, 2, 1], reversed);
+}
+
+#[test]
+fn retain_mut() {
+    use super::drop_tracker::DropCounter;
+    let counter = DropCounter::new();
+    let mut entries: CompactVec<_> = (1..6)
+        .map(|ttl| counter.track(CompactVec::<u32>::from(vec![ttl])))
+        .collect();
+    entries.retain_mut(|entry| {
+        entry[0] -= 1;
+        entry[0] > 2
+    });
+    assert_eq!(vec![3, 4], entries.iter().map(|e| e[0]).collect::<Vec<_>>());
+    assert_eq!(2, counter.alive());
+
+    // in compact storage
+    let bytes = entries.total_size_bytes();
+    let storage = DefaultHeap::allocate(bytes);
+    unsafe {
+        Compact::compact_behind(&mut entries, storage);
+        ::std::mem::forget(entries);
+        (*storage).retain(|entry| entry[0] == 4);
+        let decompacted: CompactVec<_> = Compact::decompact(storage);
+        DefaultHeap::deallocate(storage, bytes);
+        assert_eq!(vec![4], decompacted.iter().map(|e| e[0]).collect::<Vec<_>>());
+    }
+    assert_eq!(0, counter.alive());
+}
+
+#[test]
+fn sort_compact_elements() {
+    use super::compacted_box::CompactedBox;
+    let lists: CompactVec<CompactVec<u32>> = vec![
+        vec![3, 30, 300].into(),
+        vec![1].into(),
+        vec![2, 20].into(),
+        vec![0; 0].into(),
+    ]
+    .into();
+    let mut boxed = CompactedBox::new(lists);
+    assert!(boxed.is_still_compact());
+    boxed.with_mut(|lists| lists.sort());
+    let sorted: Vec<Vec<u32>> = boxed.iter().map(|list| list.to_vec()).collect();
+    assert_eq!(vec![vec![], vec![1], vec![2, 20], vec![3, 30, 300]], sorted);
+
+    boxed.recompact();
+    boxed.with_mut(|lists| lists.sort_by_key(|list| ::std::cmp::Reverse(list.len())));
+    assert_eq!(vec![3, 30, 300], boxed[0].to_vec());
+    boxed.with_mut(|lists| lists.sort_unstable_by(|a, b| a.first().cmp(&b.first())));
+    assert_eq!(vec![0; 0], boxed[0].to_vec());
+    assert_eq!(vec![2, 20], boxed[2].to_vec());
+}
+
+#[test]
+fn move_compact_elements() {
+    use super::compact_str::CompactString;
+    use super::compacted_box::CompactedBox;
+    let names: CompactVec<CompactString> =
+        vec!["Ada".into(), "Barbara".into(), "Grace".into()].into();
+    let mut boxed = CompactedBox::new(names);
+    let names = |boxed: &CompactedBox<CompactVec<CompactString>>| {
+        boxed
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>()
+    };
+    let in_box = |boxed: &CompactedBox<CompactVec<CompactString>>, i: usize| {
+        boxed.as_bytes().as_ptr_range().contains(&boxed[i].as_ptr())
+    };
+    boxed.with_mut(|names| names.swap(0, 2));
+    assert_eq!(vec!["Grace", "Barbara", "Ada"], names(&boxed));
+    // only the swapped elements were decompacted
+    assert!(!in_box(&boxed, 0) && in_box(&boxed, 1) && !in_box(&boxed, 2));
+    boxed.recompact();
+    boxed.with_mut(|names| names.rotate_left(1));
+    assert_eq!(vec!["Barbara", "Ada", "Grace"], names(&boxed));
+    boxed.recompact();
+    boxed.with_mut(|names| {
+        names.reverse();
+        names.as_mut_slice()[..2].rotate_right(1);
+    });
+    assert_eq!(vec!["Ada", "Grace", "Barbara"], names(&boxed));
+}
+
+#[test]
+fn dedup() {
+    use super::drop_tracker::DropCounter;
+    let counter = DropCounter::new();
+    let mut events: CompactVec<_> = vec![1, 1, 2, 3, 3, 3, 1]
+        .into_iter()
+        .map(|n| counter.track(CompactVec::<u32>::from(vec![n])))
+        .collect();
+    events.dedup();
+    assert_eq!(vec![1, 2, 3, 1], events.iter().map(|e| e[0]).collect::<Vec<_>>());
+    assert_eq!(4, counter.alive());
+
+    events.dedup_by_key(|e| e[0] % 2);
+    assert_eq!(vec![1, 2, 3], events.iter().map(|e| e[0]).collect::<Vec<_>>());
+
+    events.dedup_by(|_, _| true);
+    assert_eq!(1, events.len());
+    assert_eq!(1, counter.alive());
+}
+
+#[test]
+fn resize() {
+    let mut grid: CompactVec<CompactVec<u32>> = vec![vec![1].into()].into();
+    grid.resize(3, vec![0, 0].into());
+    assert_eq!(grid, vec![vec![1], vec![0, 0], vec![0, 0]]);
+    assert_eq!(3, grid.capacity());
+    grid.resize(1, CompactVec::new());
+    assert_eq!(grid, vec![vec![1]]);
+
+    let mut n = 0;
+    grid.resize_with(3, || {
+        n += 1;
+        vec![n].into()
+    });
+    assert_eq!(grid, vec![vec![1], vec![1], vec![2]]);
+}
+
+#[test]
+fn drain_range() {
+    let mut list: CompactVec<CompactVec<u32>> =
+        vec![vec![1].into(), vec![2].into(), vec![3].into(), vec![4].into()].into();
+    let first_two: Vec<_> = list.drain(..2).collect();
+    assert_eq!(first_two, vec![vec![1], vec![2]]);
+    assert_eq!(list, vec![vec![3], vec![4]]);
+
+    // draining from compact storage
+    let bytes = list.total_size_bytes();
+    let storage = DefaultHeap::allocate(bytes);
+    unsafe {
+        Compact::compact_behind(&mut list, storage);
+        ::std::mem::forget(list);
+        let last: Vec<_> = (*storage).drain(1..=1).collect();
+        assert_eq!(last, vec![vec![4]]);
+        assert_eq!(*storage, vec![vec![3]]);
+        let decompacted = Compact::decompact(storage);
+        DefaultHeap::deallocate(storage, bytes);
+        assert_eq!(decompacted, vec![vec![3]]);
+    }
+}
+
+#[test]
+fn push_at_inserts_at_position() {
+    let mut list: CompactVec<CompactVec<u32>> = vec![vec![1].into(), vec![4].into()].into();
+    list.push_at(1, vec![3].into());
+    list.push_at(1, vec![2].into());
+    list.push_at(0, vec![0].into());
+    list.push_at(5, vec![5].into());
+    assert_eq!(list, vec![vec![0], vec![1], vec![2], vec![3], vec![4], vec![5]]);
+}
+
+#[test]
+#[should_panic(expected = "insertion index out of bounds")]
+fn push_at_past_the_end() {
+    let mut list: CompactVec<u32> = vec![1].into();
+    list.push_at(2, 2);
+}
+
+#[test]
+fn extract_range() {
+    let mut batch: CompactVec<CompactVec<u32>> =
+        vec![vec![1].into(), vec![2].into(), vec![3].into(), vec![4].into()].into();
+    let extracted = batch.extract_range(1..3);
+    assert_eq!(extracted, vec![vec![2], vec![3]]);
+    assert_eq!(2, extracted.capacity());
+    assert_eq!(batch, vec![vec![1], vec![4]]);
+    assert!(batch.extract_range(2..).is_empty());
+}
+
+#[test]
+fn splice() {
+    let mut path: CompactVec<CompactVec<u32>> =
+        vec![vec![1].into(), vec![2].into(), vec![3].into(), vec![4].into()].into();
+    let removed: Vec<_> = path
+        .splice(1..3, vec![vec![5].into(), vec![6].into(), vec![7].into()])
+        .collect();
+    assert_eq!(removed, vec![vec![2], vec![3]]);
+    assert_eq!(path, vec![vec![1], vec![5], vec![6], vec![7], vec![4]]);
+
+    // in compact storage, replacing with fewer elements
+    let bytes = path.total_size_bytes();
+    let storage = DefaultHeap::allocate(bytes);
+    unsafe {
+        Compact::compact_behind(&mut path, storage);
+        ::std::mem::forget(path);
+        let removed: Vec<_> = (*storage).splice(..4, Some(vec![8].into())).collect();
+        assert_eq!(removed, vec![vec![1], vec![5], vec![6], vec![7]]);
+        assert_eq!(*storage, vec![vec![8], vec![4]]);
+        let decompacted = Compact::decompact(storage);
+        DefaultHeap::deallocate(storage, bytes);
+        assert_eq!(decompacted, vec![vec![8], vec![4]]);
+    }
+}
+
+#[test]
+fn append() {
+    let mut list: CompactVec<CompactVec<u32>> = vec![vec![1].into()].into();
+    let mut other: CompactVec<CompactVec<u32>> = vec![vec![2, 3].into(), vec![4].into()].into();
+    list.append(&mut other);
+    assert!(other.is_empty());
+    assert_eq!(list, vec![vec![1], vec![2, 3], vec![4]]);
+    assert_eq!(3, list.capacity());
+
+    // appending from compact storage
+    let mut other: CompactVec<CompactVec<u32>> = vec![vec![5, 6].into()].into();
+    let bytes = other.total_size_bytes();
+    let storage = DefaultHeap::allocate(bytes);
+    unsafe {
+        Compact::compact_behind(&mut other, storage);
+        ::std::mem::forget(other);
+        list.append(&mut *storage);
+        assert!((*storage).is_empty());
+        DefaultHeap::deallocate(storage, bytes);
+    }
+    assert_eq!(list, vec![vec![1], vec![2, 3], vec![4], vec![5, 6]]);
+    assert_eq!(6, list.capacity());
+}
+
+#[test]
+fn compact_lossy() {
+    let mut list: CompactVec<CompactVec<u32>> =
+        vec![vec![1, 2].into(), vec![3, 4].into(), vec![5].into()].into();
+    // corrupt the length of one element
+    list[1].len = 100;
+
+    let (bytes, dropped) = list.compact_lossy();
+    assert_eq!(dropped, vec!["[1]".to_owned()]);
+    let restored = unsafe { CompactVec::<CompactVec<u32>>::from_compact_bytes(&bytes) }.unwrap();
+    assert_eq!(restored, vec![vec![1, 2], vec![], vec![5]]);
+}
+
+#[test]
+fn iter_with_rest_mut() {
+    let mut list: CompactVec<u32> = vec![1, 2, 3, 4].into();
+
+    {
+        let mut iter = list.iter_with_rest_mut();
+        while let Some((i, elem, mut rest)) = iter.next() {
+            assert_eq!(i, rest.index());
+            assert!(rest.get(i).is_none());
+            assert_eq!(3, rest.iter().count());
+            if let Some(next) = rest.get_mut(i + 1) {
+                *next += 10;
+            }
+            *elem *= 2;
+        }
+    }
+
+    assert_eq!(&[2, 24, 26, 28], &*list);
+}
+
+#[test]
+fn equality() {
+    let list: CompactVec<u32> = vec![1, 2, 3].into();
+    let mut other: CompactVec<u32> = CompactVec::new();
+    other.extend_from_copy_slice(&[1, 2]);
+    assert_ne!(list, other);
+    other.push(3);
+    assert_eq!(list, other);
+
+    assert_eq!(list, vec![1, 2, 3]);
+    assert_eq!(vec![1, 2, 3], list);
+    assert_eq!(list, [1, 2, 3]);
+    assert_eq!(list, &[1, 2, 3][..]);
+    assert_ne!(list, [1, 2, 4]);
+
+    let nested: CompactVec<CompactVec<u32>> = vec![list.clone(), CompactVec::new()].into();
+    assert_eq!(nested, vec![list, CompactVec::new()]);
+}
+
+#[test]
+fn send_and_sync() {
+    use super::compact_dict::CompactDict;
+    use super::compact_hash_map::OpenAddressingMap;
+    use super::compact_option::CompactOption;
+    use super::compact_str::CompactString;
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<CompactVec<CompactVec<u32>>>();
+    assert_send_sync::<IntoIter<CompactString, DefaultHeap>>();
+    assert_send_sync::<CompactString>();
+    assert_send_sync::<CompactOption<CompactString>>();
+    assert_send_sync::<CompactDict<u32, CompactVec<u8>>>();
+    assert_send_sync::<OpenAddressingMap<u32, CompactString>>();
+
+    let list: CompactVec<CompactString> = vec!["moved".into(), "across".into()].into();
+    let joined = ::std::thread::spawn(move || {
+        let words: Vec<&str> = list.iter().map(|s| s.as_str()).collect();
+        words.join(" ")
+    });
+    assert_eq!("moved across", joined.join().unwrap());
+}
+
+#[test]
+fn ordering() {
+    let a: CompactVec<u32> = vec![1, 2, 3].into();
+    let b: CompactVec<u32> = vec![1, 3].into();
+    let c: CompactVec<u32> = vec![1, 2].into();
+    assert!(a < b);
+    assert!(c < a);
+    assert_eq!(::std::cmp::Ordering::Equal, a.cmp(&a.clone()));
+
+    let mut lists = vec![b.clone(), a.clone(), c.clone()];
+    lists.sort();
+    assert_eq!(lists, vec![c, a, b]);
+}
+
+#[cfg(feature = "serde-serialization")]
+#[test]
+fn serde_roundtrip() {
+    let list: CompactVec<CompactVec<u32>> = vec![vec![1, 2].into(), vec![3].into()].into();
+    let json = ::serde_json::to_string(&list).unwrap();
+    assert_eq!(json, "[[1,2],[3]]");
+    let deserialized: CompactVec<CompactVec<u32>> = ::serde_json::from_str(&json).unwrap();
+    assert_eq!(deserialized, list);
+}
+
+#[test]
+fn decompact_from_reader_validates_lengths() {
+    use super::compact::FromReaderError;
+    let vec: CompactVec<u32> = vec![1, 2, 3].into();
+    let mut streamed = Vec::new();
+    vec.compact_to_writer(&mut streamed).unwrap();
+    assert_eq!(
+        vec,
+        unsafe { CompactVec::<u32>::decompact_from_reader(&mut &streamed[..]) }.unwrap()
+    );
+
+    let len_offset = &vec.len as *const u32 as usize - &vec as *const CompactVec<u32> as usize;
+    streamed[len_offset..len_offset + 4].copy_from_slice(&4u32.to_ne_bytes());
+    match unsafe { CompactVec::<u32>::decompact_from_reader(&mut &streamed[..]) } {
+        Err(FromReaderError::Invalid { reason, .. }) => {
+            assert_eq!("vector is longer than its capacity", reason)
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+}
@@ -0,0 +1,615 @@
+use super::compact::{AlignedBuffer, Compact, CompactStats, FromBytesError};
+use std::marker::PhantomData;
+use std::mem;
+use std::ptr;
+use std::sync::Arc;
+
+/// A value stored compactly in a buffer that it owns,
+/// wrapping the unsafe dance of allocating `total_size_bytes`, `compact_behind`,
+/// `decompact` and deallocating.
+///
+/// The value can be accessed and even mutated in place with `with_mut`
+/// (parts of it might spill to the heap then).
+/// With spare space at the end of the buffer, `recompact_dirty` can recompact just the parts
+/// that spilled into it.
+pub struct CompactedBox<T: Compact> {
+    buffer: AlignedBuffer,
+    /// Used bytes at the start of the buffer
+    size: usize,
+    /// Bytes of the buffer
+    capacity: usize,
+    /// Spare bytes to allocate behind the value when compacting it into a new buffer
+    spare: usize,
+    spill_budget: Option<(usize, SpillAction)>,
+    marker: PhantomData<T>,
+}
+
+/// What `CompactedBox::enforce_spill_budget` does when too many bytes spilled to the heap
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpillAction {
+    /// Compact the whole value into a new buffer
+    Recompact,
+    /// Recompact only the spilled parts into the spare bytes, see `recompact_dirty`
+    RecompactDirty,
+    /// Panic, like to find code that spills too much in tests
+    Panic,
+}
+
+unsafe impl<T: Compact + Send> Send for CompactedBox<T> {}
+unsafe impl<T: Compact + Sync> Sync for CompactedBox<T> {}
+
+impl<T: Compact> CompactedBox<T> {
+    /// Compact `value` into a new buffer of exactly its total size
+    pub fn new(value: T) -> Self {
+        Self::with_spare_bytes(value, 0)
+    }
+
+    /// Compact `value` into a new buffer of its total size plus `spare` bytes,
+    /// for parts that spill later to be recompacted into by `recompact_dirty`
+    pub fn with_spare_bytes(mut value: T, spare: usize) -> Self {
+        let size = value.total_size_bytes();
+        #[cfg(feature = "log")]
+        ::log::trace!(
+            "compacting a {} into {} bytes (plus {} spare)",
+            ::std::any::type_name::<T>(),
+            size,
+            spare
+        );
+        let buffer = AlignedBuffer::new::<T>(size + spare);
+        unsafe {
+            Compact::compact_behind(&mut value, buffer.ptr as *mut T);
+            mem::forget(value);
+        }
+        CompactedBox {
+            buffer,
+            size,
+            capacity: size + spare,
+            spare,
+            spill_budget: None,
+            marker: PhantomData,
+        }
+    }
+
+    /// Take ownership of a buffer of `size` bytes that a value was already compacted into
+    #[cfg(feature = "serde-serialization")]
+    pub(crate) unsafe fn from_buffer(buffer: AlignedBuffer, size: usize) -> Self {
+        CompactedBox {
+            buffer,
+            size,
+            capacity: size,
+            spare: 0,
+            spill_budget: None,
+            marker: PhantomData,
+        }
+    }
+
+    /// The used part of the buffer, containing the static part of the value followed by
+    /// its dynamic part (and old parts left behind by `recompact_dirty`).
+    /// Parts of the value that were mutated after compaction might live outside of it,
+    /// so `recompact` (or `defragment` after `recompact_dirty`) before sending the bytes elsewhere.
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { ::std::slice::from_raw_parts(self.buffer.ptr, self.size) }
+    }
+
+    /// Recreate a compacted value from bytes produced by `as_bytes`
+    /// (or `Compact::compact_to_bytes`), checked like `Compact::from_compact_bytes`
+    ///
+    /// # Safety
+    ///
+    /// See `Compact::from_compact_bytes`
+    pub unsafe fn from_bytes(bytes: &[u8]) -> Result<Self, FromBytesError> {
+        T::from_compact_bytes(bytes).map(CompactedBox::new)
+    }
+
+    /// Take a compacted value from bytes produced by `as_bytes`
+    /// (or `Compact::compact_to_bytes`), only copying them into an aligned buffer
+    ///
+    /// # Safety
+    ///
+    /// `bytes` have to contain a compacted `T` that is still compact,
+    /// as produced by the same build of the same program.
+    pub unsafe fn from_bytes_unchecked(bytes: &[u8]) -> Self {
+        let buffer = AlignedBuffer::new::<T>(bytes.len());
+        ptr::copy_nonoverlapping(bytes.as_ptr(), buffer.ptr, bytes.len());
+        CompactedBox {
+            buffer,
+            size: bytes.len(),
+            capacity: bytes.len(),
+            spare: 0,
+            spill_budget: None,
+            marker: PhantomData,
+        }
+    }
+
+    /// Mutate the value in place. Parts of it might spill to the heap,
+    /// but the value itself stays in the buffer its compacted parts point into,
+    /// which is why there is no `DerefMut`.
+    pub fn with_mut<R, F: FnOnce(&mut T) -> R>(&mut self, f: F) -> R {
+        f(unsafe { &mut *(self.buffer.ptr as *mut T) })
+    }
+
+    /// Move the value out of the buffer and free the buffer
+    pub fn decompact(self) -> T {
+        #[cfg(feature = "log")]
+        ::log::trace!(
+            "decompacting a {} of {} bytes",
+            ::std::any::type_name::<T>(),
+            self.size
+        );
+        let value = unsafe { Compact::decompact(self.buffer.ptr as *const T) };
+        self.free_without_drop();
+        value
+    }
+
+    /// If parts of the value spilled to the heap, compact it again
+    /// into a new buffer of the right size (plus the same spare bytes).
+    /// Returns whether that was necessary.
+    pub fn recompact(&mut self) -> bool {
+        if self.is_still_compact() {
+            return false;
+        }
+        self.compact_anew();
+        true
+    }
+
+    /// Compact the value contiguously into a new buffer (plus the same spare bytes),
+    /// dropping the holes that `recompact_dirty` left behind.
+    /// Returns how many bytes of the buffer that reclaimed.
+    pub fn defragment(&mut self) -> usize {
+        let size = self.size;
+        if self.is_still_compact() && self.total_size_bytes() == size {
+            return 0;
+        }
+        self.compact_anew();
+        size.saturating_sub(self.size)
+    }
+
+    fn compact_anew(&mut self) {
+        let value = unsafe { Compact::decompact(self.buffer.ptr as *const T) };
+        let mut compacted = CompactedBox::with_spare_bytes(value, self.spare);
+        compacted.spill_budget = self.spill_budget;
+        mem::replace(self, compacted).free_without_drop();
+    }
+
+    /// Bytes of the own storage of all containers within the value that spilled to the heap
+    /// (see `CompactStats::spilled_bytes`)
+    pub fn spilled_bytes(&self) -> usize {
+        let mut stats = CompactStats::default();
+        (**self).collect_stats("", &mut stats);
+        stats.spilled_bytes
+    }
+
+    /// Allow at most `bytes` to spill to the heap before `enforce_spill_budget` does `action`
+    pub fn set_spill_budget(&mut self, bytes: usize, action: SpillAction) {
+        self.spill_budget = Some((bytes, action));
+    }
+
+    /// Remove the spill budget
+    pub fn clear_spill_budget(&mut self) {
+        self.spill_budget = None;
+    }
+
+    /// If more bytes than the spill budget spilled to the heap, do its `SpillAction`,
+    /// like after handling each message of an actor.
+    /// Returns whether the budget was exceeded.
+    pub fn enforce_spill_budget(&mut self) -> bool {
+        let (budget, action) = match self.spill_budget {
+            Some(spill_budget) => spill_budget,
+            None => return false,
+        };
+        let spilled = self.spilled_bytes();
+        if spilled <= budget {
+            return false;
+        }
+        match action {
+            SpillAction::Recompact => {
+                self.recompact();
+            }
+            SpillAction::RecompactDirty => {
+                self.recompact_dirty();
+            }
+            SpillAction::Panic => panic!(
+                "{} bytes spilled to the heap, but the budget is {}",
+                spilled, budget
+            ),
+        }
+        true
+    }
+
+    /// Bytes left at the end of the buffer for `recompact_dirty`
+    pub fn spare_bytes(&self) -> usize {
+        self.capacity - self.size
+    }
+
+    /// If parts of the value spilled to the heap, recompact only those parts
+    /// into the spare bytes of the buffer, leaving everything else where it is
+    /// (see `Compact::recompact_dirty_into`). Their old places in the buffer stay unused.
+    /// If the spare bytes run out, falls back to `recompact`.
+    /// Returns whether anything had to be recompacted.
+    pub fn recompact_dirty(&mut self) -> bool {
+        if self.is_still_compact() {
+            return false;
+        }
+        let result = unsafe {
+            let mut spare = ::std::slice::from_raw_parts_mut(
+                self.buffer.ptr.add(self.size),
+                self.capacity - self.size,
+            );
+            let result = (*(self.buffer.ptr as *mut T)).recompact_dirty_into(&mut spare);
+            self.size = self.capacity - spare.len();
+            result
+        };
+        if result.is_err() {
+            self.recompact();
+        }
+        true
+    }
+
+    /// Free the buffer, but don't drop the value, since it was moved out
+    fn free_without_drop(self) {
+        let buffer = unsafe { ptr::read(&self.buffer) };
+        mem::forget(self);
+        drop(buffer);
+    }
+}
+
+impl<T: Compact> ::std::ops::Deref for CompactedBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*(self.buffer.ptr as *const T) }
+    }
+}
+
+impl<T: Compact> AsRef<T> for CompactedBox<T> {
+    fn as_ref(&self) -> &T {
+        self
+    }
+}
+
+impl<T: Compact> Drop for CompactedBox<T> {
+    /// Drop the value in place, the buffer itself is freed afterwards
+    fn drop(&mut self) {
+        unsafe { ptr::drop_in_place(self.buffer.ptr as *mut T) }
+    }
+}
+
+impl<T: Compact> Clone for CompactedBox<T> {
+    fn clone(&self) -> Self {
+        let mut cloned = CompactedBox::with_spare_bytes((**self).clone(), self.spare);
+        cloned.spill_budget = self.spill_budget;
+        cloned
+    }
+}
+
+impl<T: Compact + ::std::fmt::Debug> ::std::fmt::Debug for CompactedBox<T> {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+/// A `CompactedBox` shared between clones, like for speculatively simulating
+/// copies of compacted state that are mostly only read.
+///
+/// Cloning only bumps a reference count. The first mutable access through `with_mut`
+/// (or `make_mut`) gives a clone its own `CompactedBox`,
+/// while the others keep sharing the original buffer.
+pub struct SharedCompactedBox<T: Compact> {
+    shared: Arc<CompactedBox<T>>,
+}
+
+impl<T: Compact> SharedCompactedBox<T> {
+    /// Compact `value` into a new buffer, not shared yet
+    pub fn new(value: T) -> Self {
+        CompactedBox::new(value).into()
+    }
+
+    /// Is the buffer shared with other clones?
+    pub fn is_shared(&self) -> bool {
+        Arc::strong_count(&self.shared) > 1
+    }
+
+    /// Access the `CompactedBox` mutably, like to `recompact` it,
+    /// compacting a private copy of it first if it is shared
+    pub fn make_mut(&mut self) -> &mut CompactedBox<T> {
+        Arc::make_mut(&mut self.shared)
+    }
+
+    /// Mutate the value in place (see `CompactedBox::with_mut`),
+    /// compacting a private copy of it first if it is shared
+    pub fn with_mut<R, F: FnOnce(&mut T) -> R>(&mut self, f: F) -> R {
+        self.make_mut().with_mut(f)
+    }
+
+    /// Take the `CompactedBox`, compacting a private copy of it if it is shared
+    pub fn into_unshared(self) -> CompactedBox<T> {
+        Arc::try_unwrap(self.shared).unwrap_or_else(|shared| (*shared).clone())
+    }
+}
+
+impl<T: Compact> From<CompactedBox<T>> for SharedCompactedBox<T> {
+    fn from(boxed: CompactedBox<T>) -> Self {
+        SharedCompactedBox {
+            shared: Arc::new(boxed),
+        }
+    }
+}
+
+impl<T: Compact> ::std::ops::Deref for SharedCompactedBox<T> {
+    type Target = CompactedBox<T>;
+
+    fn deref(&self) -> &CompactedBox<T> {
+        &self.shared
+    }
+}
+
+impl<T: Compact> Clone for SharedCompactedBox<T> {
+    /// Share the buffer with the clone
+    fn clone(&self) -> Self {
+        SharedCompactedBox {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T: Compact + ::std::fmt::Debug> ::std::fmt::Debug for SharedCompactedBox<T> {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        (**self.shared).fmt(f)
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a, T: Compact + ::arbitrary::Arbitrary<'a>> ::arbitrary::Arbitrary<'a> for CompactedBox<T> {
+    fn arbitrary(u: &mut ::arbitrary::Unstructured<'a>) -> ::arbitrary::Result<Self> {
+        T::arbitrary(u).map(CompactedBox::new)
+    }
+
+    fn arbitrary_take_rest(u: ::arbitrary::Unstructured<'a>) -> ::arbitrary::Result<Self> {
+        T::arbitrary_take_rest(u).map(CompactedBox::new)
+    }
+
+    fn size_hint(depth: usize) -> (usize, Option<usize>) {
+        T::size_hint(depth)
+    }
+}
+
+#[test]
+fn compacted_box() {
+    use super::compact_vec::CompactVec;
+    let list: CompactVec<CompactVec<u32>> = vec![vec![1, 2].into(), vec![3].into()].into();
+    let total_size = list.total_size_bytes();
+
+    let mut boxed = CompactedBox::new(list.clone());
+    assert!(boxed.is_still_compact());
+    assert_eq!(total_size, boxed.as_bytes().len());
+    assert_eq!(list, *boxed);
+
+    // spills to the heap
+    boxed.with_mut(|list| list[0].push(7));
+    assert!(!boxed.is_still_compact());
+    assert_eq!(vec![1, 2, 7], boxed.as_ref()[0]);
+    assert!(boxed.recompact());
+    assert!(!boxed.recompact());
+    assert_eq!(boxed.total_size_bytes(), boxed.as_bytes().len());
+
+    let cloned = boxed.clone();
+    assert!(cloned.is_still_compact());
+    let decompacted = boxed.decompact();
+    assert_eq!(*cloned, decompacted);
+}
+
+#[test]
+fn compacted_box_from_bytes() {
+    use super::compact_str::CompactString;
+    use super::compact_vec::CompactVec;
+    let names: CompactVec<CompactString> = vec!["Ada".into(), "Grace".into()].into();
+    let mut boxed = CompactedBox::new(names.clone());
+    boxed.with_mut(|names| names[0].push_str(" Lovelace"));
+    boxed.recompact();
+
+    // as if sent over the network
+    let received = boxed.as_bytes().to_vec();
+    let unchecked: CompactedBox<CompactVec<CompactString>> =
+        unsafe { CompactedBox::from_bytes_unchecked(&received) };
+    assert!(unchecked.is_still_compact());
+    assert_eq!(*boxed, *unchecked);
+    let checked =
+        unsafe { CompactedBox::<CompactVec<CompactString>>::from_bytes(&received) }.unwrap();
+    assert_eq!("Ada Lovelace", checked[0].as_str());
+    assert_eq!(
+        Err(FromBytesError::TooShort {
+            expected: received.len(),
+            actual: received.len() - 1,
+        }),
+        unsafe {
+            CompactedBox::<CompactVec<CompactString>>::from_bytes(&received[..received.len() - 1])
+        }
+        .map(|_| ())
+    );
+}
+
+#[test]
+fn recompact_dirty() {
+    use super::compact_vec::CompactVec;
+    use super::drop_tracker::DropCounter;
+    let counter = DropCounter::new();
+    let lists: CompactVec<_> = (0..100u32)
+        .map(|n| counter.track(CompactVec::<u32>::from(vec![n; 10])))
+        .collect();
+    let mut boxed = CompactedBox::with_spare_bytes(lists, 256);
+    assert_eq!(256, boxed.spare_bytes());
+    assert!(!boxed.recompact_dirty());
+
+    // only the spilled list moves into the spare bytes
+    let buffer = boxed.as_bytes().as_ptr();
+    let size = boxed.as_bytes().len();
+    boxed.with_mut(|lists| lists[42].push(7));
+    assert!(!boxed.is_still_compact());
+    assert!(boxed.recompact_dirty());
+    assert!(boxed.is_still_compact());
+    assert_eq!(buffer, boxed.as_bytes().as_ptr());
+    assert_eq!(size + boxed[42].capacity() * 4, boxed.as_bytes().len());
+    assert_eq!(Some(&7), boxed[42].last());
+    assert_eq!(vec![43; 10], **boxed[43]);
+
+    // falls back to recompacting everything into a new buffer
+    boxed.with_mut(|lists| lists[7].extend(0..100));
+    assert!(boxed.recompact_dirty());
+    assert!(boxed.is_still_compact());
+    assert_ne!(buffer, boxed.as_bytes().as_ptr());
+    assert_eq!(256, boxed.spare_bytes());
+    assert_eq!(110, boxed[7].len());
+
+    assert_eq!(100, counter.alive());
+    drop(boxed);
+    counter.assert_balanced();
+}
+
+#[test]
+fn defragment() {
+    use super::compact_str::CompactString;
+    use super::compact_vec::CompactVec;
+    let names: CompactVec<CompactString> = (0..10).map(|n| n.to_string().into()).collect();
+    let mut boxed = CompactedBox::with_spare_bytes(names, 256);
+    let size = boxed.as_bytes().len();
+    assert_eq!(0, boxed.defragment());
+
+    boxed.with_mut(|names| {
+        names[3].push_str("rd place of the hundred meter sprint");
+        names[5].push_str("th place of the hundred meter sprint");
+    });
+    assert!(boxed.recompact_dirty());
+    let grown = boxed.as_bytes().len();
+    let defragmented = boxed.total_size_bytes();
+    assert!(size < defragmented && defragmented < grown);
+    assert_eq!(grown - defragmented, boxed.defragment());
+    assert_eq!(defragmented, boxed.as_bytes().len());
+    assert_eq!(256, boxed.spare_bytes());
+    assert!(boxed[3].ends_with("sprint"));
+    assert_eq!(0, boxed.defragment());
+}
+
+#[test]
+fn spill_budget() {
+    use super::compact_vec::CompactVec;
+    let lists: CompactVec<CompactVec<u32>> = (0..4).map(|n| vec![n; 4].into()).collect();
+    let mut boxed = CompactedBox::new(lists);
+    boxed.set_spill_budget(64, SpillAction::Recompact);
+    assert_eq!(0, boxed.spilled_bytes());
+
+    boxed.with_mut(|lists| lists[0].push(1));
+    assert_eq!(8 * 4, boxed.spilled_bytes());
+    assert!(!boxed.enforce_spill_budget());
+    boxed.with_mut(|lists| {
+        lists[1].push(1);
+        lists[2].push(1);
+    });
+    assert_eq!(3 * 8 * 4, boxed.spilled_bytes());
+    assert!(boxed.enforce_spill_budget());
+    assert_eq!(0, boxed.spilled_bytes());
+
+    // the budget survives recompaction
+    boxed.set_spill_budget(0, SpillAction::Panic);
+    boxed.recompact();
+    boxed.with_mut(|lists| lists[3].push(1));
+    let result = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+        boxed.enforce_spill_budget()
+    }));
+    assert!(result.is_err());
+}
+
+#[cfg(feature = "log")]
+#[test]
+fn logs_storage_events() {
+    use super::compact_vec::CompactVec;
+    use std::sync::Mutex;
+    struct Capture(Mutex<Vec<String>>);
+
+    impl ::log::Log for Capture {
+        fn enabled(&self, _: &::log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &::log::Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    static CAPTURE: Capture = Capture(Mutex::new(Vec::new()));
+    ::log::set_logger(&CAPTURE).unwrap();
+    ::log::set_max_level(::log::LevelFilter::Trace);
+
+    let list = CompactVec::<i8>::from(vec![1, 2]);
+    let size = list.total_size_bytes();
+    let mut boxed = CompactedBox::new(list);
+    boxed.with_mut(|list| list.push(3));
+    boxed.decompact();
+    // other tests log concurrently
+    let logged = CAPTURE.0.lock().unwrap();
+    let type_name = ::std::any::type_name::<CompactVec<i8>>();
+    for expected in &[
+        format!("compacting a {} into {} bytes (plus 0 spare)", type_name, size),
+        "CVec<i8> spills to the heap with capacity 4 (4 bytes)".to_owned(),
+        format!("decompacting a {} of {} bytes", type_name, size),
+    ] {
+        assert!(logged.contains(expected), "{} wasn't logged", expected);
+    }
+}
+
+#[test]
+fn compacted_box_drops_value_once() {
+    use super::compact_vec::CompactVec;
+    use super::drop_tracker::{DropCounter, DropTracker};
+    type Tracked = DropTracker<CompactVec<u32>>;
+    let counter = DropCounter::new();
+    let list: CompactVec<Tracked> = (0..4).map(|n| counter.track(vec![n].into())).collect();
+    let boxed = CompactedBox::new(list);
+    assert_eq!(4, counter.alive());
+    drop(boxed);
+    assert_eq!(0, counter.alive());
+
+    let list: CompactVec<Tracked> = (0..4).map(|n| counter.track(vec![n].into())).collect();
+    let decompacted = CompactedBox::new(list).decompact();
+    assert_eq!(4, counter.alive());
+    drop(decompacted);
+    counter.assert_balanced();
+}
+
+#[test]
+fn shared_compacted_box() {
+    use super::compact_vec::CompactVec;
+    use super::drop_tracker::DropCounter;
+    let counter = DropCounter::new();
+    let lists: CompactVec<_> = (0..3u32)
+        .map(|n| counter.track(CompactVec::<u32>::from(vec![n; 4])))
+        .collect();
+    let original = SharedCompactedBox::new(lists);
+    assert!(!original.is_shared());
+
+    // speculative copies share the buffer until they mutate it
+    let mut speculative = original.clone();
+    let untouched = original.clone();
+    assert!(original.is_shared());
+    assert_eq!(original.as_bytes().as_ptr(), speculative.as_bytes().as_ptr());
+    speculative.with_mut(|lists| lists[1].push(10));
+    assert_ne!(original.as_bytes().as_ptr(), speculative.as_bytes().as_ptr());
+    assert_eq!(original.as_bytes().as_ptr(), untouched.as_bytes().as_ptr());
+    assert_eq!(vec![1; 4], **original[1]);
+    assert_eq!(Some(&10), speculative[1].last());
+    assert!(speculative.make_mut().recompact());
+
+    // unshared boxes are mutated in place
+    let buffer = speculative.as_bytes().as_ptr();
+    assert!(!speculative.is_shared());
+    speculative.with_mut(|lists| lists[2].clear());
+    assert_eq!(buffer, speculative.as_bytes().as_ptr());
+
+    drop(original);
+    let unshared = untouched.into_unshared();
+    assert_eq!(vec![0; 4], **unshared[0]);
+    assert_eq!(6, counter.alive());
+    drop((unshared, speculative));
+    counter.assert_balanced();
+}
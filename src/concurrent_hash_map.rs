@@ -0,0 +1,168 @@
+use super::compact::Compact;
+use super::compact_hash_map::{DefaultHashBuilder, OpenAddressingMap};
+use super::simple_allocator_trait::DefaultHeap;
+use std::borrow::Borrow;
+use std::hash::{BuildHasher, Hash};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+type Shard<K, V, H> = OpenAddressingMap<K, V, DefaultHeap, H>;
+
+/// A hash map that can be filled and read from several threads at once,
+/// by splitting it into shards, each an `OpenAddressingMap` behind its own lock.
+///
+/// Once building is done, `freeze` merges it into a single `OpenAddressingMap`
+/// that can be compacted.
+pub struct ConcurrentOpenAddressingMap<K, V, H = DefaultHashBuilder> {
+    shards: Vec<RwLock<Shard<K, V, H>>>,
+}
+
+impl<K: Compact + Eq + Hash, V: Compact, H: BuildHasher + Default>
+    ConcurrentOpenAddressingMap<K, V, H>
+{
+    /// Create an empty map with a few shards per available thread
+    pub fn new() -> Self {
+        let threads = ::std::thread::available_parallelism().map_or(1, |n| n.get());
+        Self::with_shards(threads * 4)
+    }
+
+    /// Create an empty map with (at least) `shards` shards
+    pub fn with_shards(shards: usize) -> Self {
+        ConcurrentOpenAddressingMap {
+            shards: (0..shards.max(1).next_power_of_two())
+                .map(|_| RwLock::new(OpenAddressingMap::new()))
+                .collect(),
+        }
+    }
+
+    fn shard<Q: Hash + ?Sized>(&self, key: &Q) -> usize {
+        H::default().hash_one(key) as usize & (self.shards.len() - 1)
+    }
+
+    fn read<Q: Hash + ?Sized>(&self, key: &Q) -> RwLockReadGuard<'_, Shard<K, V, H>> {
+        self.shards[self.shard(key)]
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn write<Q: Hash + ?Sized>(&self, key: &Q) -> RwLockWriteGuard<'_, Shard<K, V, H>> {
+        self.shards[self.shard(key)]
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Amount of entries, summed over all shards (which might change meanwhile)
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().unwrap_or_else(|p| p.into_inner()).len())
+            .sum()
+    }
+
+    /// Is the map empty?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Insert new value at key `query` and return the previous value at that key, if any existed.
+    pub fn insert(&self, query: K, value: V) -> Option<V> {
+        self.write(&query).insert(query, value)
+    }
+
+    /// Remove the value at key `query` and return it, if it existed
+    pub fn remove<Q: Hash + Eq + ?Sized>(&self, query: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+    {
+        self.write(query).remove(query)
+    }
+
+    /// A copy of the value for key `query`, if it exists
+    pub fn get<Q: Hash + Eq + ?Sized>(&self, query: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+    {
+        self.read(query).get(query).cloned()
+    }
+
+    /// Call `f` with the value for key `query`, if it exists, while its shard is locked
+    pub fn with<Q: Hash + Eq + ?Sized, R, F: FnOnce(&V) -> R>(&self, query: &Q, f: F) -> Option<R>
+    where
+        K: Borrow<Q>,
+    {
+        self.read(query).get(query).map(f)
+    }
+
+    /// Call `f` with the value for key `query`, inserting `default()` first if it
+    /// doesn't exist yet, while its shard is locked
+    pub fn update_or_insert_with<R, D: FnOnce() -> V, F: FnOnce(&mut V) -> R>(
+        &self,
+        query: K,
+        default: D,
+        f: F,
+    ) -> R {
+        f(self.write(&query).get_or_insert_with(query, default))
+    }
+
+    /// Does the map contain a value for `query`?
+    pub fn contains_key<Q: Hash + Eq + ?Sized>(&self, query: &Q) -> bool
+    where
+        K: Borrow<Q>,
+    {
+        self.read(query).contains_key(query)
+    }
+
+    /// Merge all shards into one regular map
+    pub fn freeze(self) -> Shard<K, V, H> {
+        let mut shards = self
+            .shards
+            .into_iter()
+            .map(|shard| shard.into_inner().unwrap_or_else(|p| p.into_inner()));
+        let mut frozen = shards.next().expect("at least one shard");
+        for shard in shards {
+            // keys of different shards are disjoint
+            frozen.merge(shard, |_, _, value| value);
+        }
+        frozen
+    }
+}
+
+impl<K: Compact + Eq + Hash, V: Compact, H: BuildHasher + Default> Default
+    for ConcurrentOpenAddressingMap<K, V, H>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn concurrent_inserts_then_freeze() {
+    type Map = ConcurrentOpenAddressingMap<u32, u32>;
+    let map = Map::with_shards(6);
+    assert_eq!(8, map.shards.len());
+
+    ::std::thread::scope(|scope| {
+        for worker in 0..4 {
+            let map = &map;
+            scope.spawn(move || {
+                for n in 0..1000 {
+                    map.update_or_insert_with(n % 100, || 0, |count| *count += 1);
+                    map.insert(1000 + worker * 1000 + n, n);
+                }
+            });
+        }
+    });
+    assert_eq!(100 + 4000, map.len());
+    assert_eq!(Some(40), map.get(&7));
+    assert_eq!(Some(10), map.with(&7, |count| count / 4));
+    assert_eq!(Some(5), map.remove(&2005));
+    assert!(!map.contains_key(&2005));
+
+    let frozen = map.freeze();
+    assert_eq!(100 + 3999, frozen.len());
+    assert_eq!(Some(&6), frozen.get(&2006));
+    assert_eq!(None, frozen.get(&2005));
+    let roundtripped =
+        unsafe { OpenAddressingMap::<u32, u32>::from_compact_bytes(&frozen.compact_to_bytes()) }
+            .unwrap();
+    assert_eq!(frozen, roundtripped);
+}
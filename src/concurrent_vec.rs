@@ -0,0 +1,194 @@
+use super::compact::Compact;
+use super::compact_vec::CompactVec;
+use super::simple_allocator_trait::{Allocator, DefaultHeap};
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+const FIRST_SEGMENT: usize = 16;
+/// Enough segments to hold `usize::MAX` elements
+const SEGMENTS: usize = (usize::BITS - FIRST_SEGMENT.trailing_zeros()) as usize;
+
+/// An append-only vector that several threads can push to at once.
+///
+/// Each push reserves an index by atomically incrementing the length, and writes
+/// its element into a chain of segments of doubling size, so elements never move.
+/// Afterwards, it can be turned into a `CompactVec` (to be compacted, for example into a message).
+pub struct ConcurrentCompactVec<T> {
+    len: AtomicUsize,
+    segments: [AtomicPtr<T>; SEGMENTS],
+}
+
+// Elements are only moved in by `push`, and only read once the vector is owned again
+unsafe impl<T: Send> Send for ConcurrentCompactVec<T> {}
+unsafe impl<T: Send> Sync for ConcurrentCompactVec<T> {}
+
+fn segment_capacity(segment: usize) -> usize {
+    FIRST_SEGMENT << segment
+}
+
+/// Segment and offset within it of the element at `index`
+fn locate(index: usize) -> (usize, usize) {
+    let segment = (usize::BITS - 1 - (index / FIRST_SEGMENT + 1).leading_zeros()) as usize;
+    (segment, index - (segment_capacity(segment) - FIRST_SEGMENT))
+}
+
+impl<T: Compact + Clone> ConcurrentCompactVec<T> {
+    /// Create a new, empty vector
+    pub fn new() -> Self {
+        ConcurrentCompactVec {
+            len: AtomicUsize::new(0),
+            segments: ::std::array::from_fn(|_| AtomicPtr::new(ptr::null_mut())),
+        }
+    }
+
+    /// Amount of elements pushed so far
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+
+    /// Is the vector empty?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Append `value` and return its index
+    pub fn push(&self, value: T) -> usize {
+        let index = self.len.fetch_add(1, Ordering::AcqRel);
+        let (segment, offset) = locate(index);
+        unsafe { ptr::write(self.segment(segment).add(offset), value) };
+        index
+    }
+
+    /// The storage of `segment`, allocated by whichever thread needs it first
+    fn segment(&self, segment: usize) -> *mut T {
+        let existing = self.segments[segment].load(Ordering::Acquire);
+        if !existing.is_null() {
+            return existing;
+        }
+        let new = DefaultHeap::allocate::<T>(segment_capacity(segment));
+        match self.segments[segment].compare_exchange(
+            ptr::null_mut(),
+            new,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => new,
+            Err(other) => {
+                unsafe { DefaultHeap::deallocate(new, segment_capacity(segment)) };
+                other
+            }
+        }
+    }
+
+    /// Move all elements, in index order, into a `CompactVec`
+    pub fn into_compact_vec(mut self) -> CompactVec<T> {
+        let len = *self.len.get_mut();
+        let mut vec = CompactVec::with_capacity(len);
+        let storage: *mut T = vec.as_mut_ptr();
+        let mut moved = 0;
+        for segment in 0..SEGMENTS {
+            if moved == len {
+                break;
+            }
+            let count = segment_capacity(segment).min(len - moved);
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    *self.segments[segment].get_mut(),
+                    storage.add(moved),
+                    count,
+                );
+            }
+            moved += count;
+        }
+        unsafe { vec.set_len(len) };
+        // the elements now belong to `vec`
+        *self.len.get_mut() = 0;
+        vec
+    }
+}
+
+impl<T: Compact + Clone> Default for ConcurrentCompactVec<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Compact + Clone> From<ConcurrentCompactVec<T>> for CompactVec<T> {
+    fn from(vec: ConcurrentCompactVec<T>) -> Self {
+        vec.into_compact_vec()
+    }
+}
+
+impl<T> Drop for ConcurrentCompactVec<T> {
+    /// Drop all pushed elements and deallocate all segments
+    fn drop(&mut self) {
+        let mut remaining = *self.len.get_mut();
+        for (segment, storage) in self.segments.iter_mut().enumerate() {
+            let storage = *storage.get_mut();
+            if storage.is_null() {
+                continue;
+            }
+            let count = segment_capacity(segment).min(remaining);
+            unsafe {
+                ptr::drop_in_place(ptr::slice_from_raw_parts_mut(storage, count));
+                DefaultHeap::deallocate(storage, segment_capacity(segment));
+            }
+            remaining -= count;
+        }
+    }
+}
+
+#[test]
+fn locate_in_segments() {
+    assert_eq!((0, 0), locate(0));
+    assert_eq!((0, 15), locate(15));
+    assert_eq!((1, 0), locate(16));
+    assert_eq!((1, 31), locate(47));
+    assert_eq!((2, 0), locate(48));
+    assert_eq!((3, 1), locate(113));
+}
+
+#[test]
+fn concurrent_pushes() {
+    let vec = ConcurrentCompactVec::new();
+    ::std::thread::scope(|scope| {
+        for worker in 0..4u32 {
+            let vec = &vec;
+            scope.spawn(move || {
+                for n in 0..1000 {
+                    vec.push(worker * 1000 + n);
+                }
+            });
+        }
+    });
+    assert_eq!(4000, vec.len());
+
+    let mut list: CompactVec<u32> = vec.into();
+    list.sort();
+    assert!(list.iter().cloned().eq(0..4000));
+    let roundtripped =
+        unsafe { CompactVec::<u32>::from_compact_bytes(&list.compact_to_bytes()) }.unwrap();
+    assert_eq!(list, roundtripped);
+}
+
+#[test]
+fn concurrent_vec_drops_elements_once() {
+    use super::drop_tracker::DropCounter;
+    let counter = DropCounter::new();
+
+    let converted = ConcurrentCompactVec::new();
+    for n in 0..100 {
+        assert_eq!(n, converted.push(counter.track(n)));
+    }
+    let list = converted.into_compact_vec();
+    assert_eq!(100, counter.alive());
+    assert!(list.iter().map(|tracked| **tracked).eq(0..100));
+    drop(list);
+
+    let unconverted = ConcurrentCompactVec::new();
+    for n in 0..20 {
+        unconverted.push(counter.track(n));
+    }
+    drop(unconverted);
+    counter.assert_balanced();
+}
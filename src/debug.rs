@@ -0,0 +1,153 @@
+//! Human-readable dumps of how compact values are laid out in memory,
+//! to help debugging corrupted compacted buffers.
+
+use super::compact::{Compact, ContainerStats};
+use super::compacted_box::CompactedBox;
+use std::fmt::Write;
+use std::mem;
+
+/// Describe where the static part of `value` and each dynamic region within it live:
+/// offset from the start of the static part (or heap address, if spilled),
+/// length and the path of the field that owns it.
+pub fn dump_layout<T: Compact>(value: &T) -> String {
+    let stats = value.compact_stats();
+    let start = value as *const T as usize;
+    let mut dump = String::new();
+    writeln!(
+        dump,
+        "{}: static part {} bytes, dynamic part {} bytes, {:.0}% spilled",
+        ::std::any::type_name::<T>(),
+        mem::size_of::<T>(),
+        stats.dynamic_bytes,
+        stats.fragmentation() * 100.0
+    )
+    .unwrap();
+    writeln!(
+        dump,
+        "  {:<24} offset {:>+8} len {:>8}",
+        "<static>",
+        0,
+        mem::size_of::<T>()
+    )
+    .unwrap();
+    for container in &stats.containers {
+        let location = if container.compact {
+            format!("offset {:>+8}", container.address as isize - start as isize)
+        } else {
+            format!("heap {:#x}", container.address)
+        };
+        writeln!(
+            dump,
+            "  {:<24} {} len {:>8}",
+            path_or_root(container),
+            location,
+            container.bytes
+        )
+        .unwrap();
+    }
+    dump
+}
+
+/// Hex dump of the buffer of `compacted`, annotating each line with
+/// the static part and the dynamic regions that start in it.
+/// Regions that spilled out of the buffer are listed at the end.
+pub fn dump_compacted<T: Compact>(compacted: &CompactedBox<T>) -> String {
+    let bytes = compacted.as_bytes();
+    let start = bytes.as_ptr() as usize;
+    // explicitly deref, `&CompactedBox<T>` itself is `Copy` and thus trivially `Compact`
+    let stats = (**compacted).compact_stats();
+    let in_buffer = |container: &&ContainerStats| {
+        container.address >= start && container.address < start + bytes.len()
+    };
+
+    let mut dump = String::new();
+    for (line, chunk) in bytes.chunks(16).enumerate() {
+        let offset = line * 16;
+        write!(dump, "{:06x} ", offset).unwrap();
+        for i in 0..16 {
+            match chunk.get(i) {
+                Some(byte) => write!(dump, " {:02x}", byte).unwrap(),
+                None => dump.push_str("   "),
+            }
+        }
+        dump.push_str("  |");
+        for &byte in chunk {
+            dump.push(if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            });
+        }
+        dump.push('|');
+
+        let mut labels = Vec::new();
+        if offset == 0 {
+            labels.push(format!("<static> ({} bytes)", mem::size_of::<T>()));
+        }
+        for container in stats.containers.iter().filter(&in_buffer) {
+            let region_offset = container.address - start;
+            if region_offset >= offset && region_offset < offset + 16 {
+                labels.push(format!(
+                    "{} at {:#x} ({} bytes)",
+                    path_or_root(container),
+                    region_offset,
+                    container.bytes
+                ));
+            }
+        }
+        if !labels.is_empty() {
+            write!(dump, " <- {}", labels.join(", ")).unwrap();
+        }
+        dump.push('\n');
+    }
+
+    for container in stats.containers.iter().filter(|c| !in_buffer(c)) {
+        writeln!(
+            dump,
+            "outside of buffer: {} at {:#x} ({} bytes)",
+            path_or_root(container),
+            container.address,
+            container.bytes
+        )
+        .unwrap();
+    }
+    dump
+}
+
+fn path_or_root(container: &ContainerStats) -> &str {
+    if container.path.is_empty() {
+        "<root>"
+    } else {
+        &container.path
+    }
+}
+
+#[test]
+fn dump_layout_and_buffer() {
+    use super::compact_dict::CompactDict;
+    use super::compact_str::CompactString;
+    type Dict = CompactDict<u32, CompactString>;
+    let dict: Dict = vec![(1, "one".into()), (2, "two".into())]
+        .into_iter()
+        .collect();
+
+    let mut boxed = CompactedBox::new(dict);
+    let layout = dump_layout(&*boxed);
+    assert!(layout.contains("static part 48 bytes"));
+    assert!(layout.contains("0% spilled"));
+    assert!(layout.contains(".keys"));
+    assert!(layout.contains(".values[1]"));
+    assert!(!layout.contains("heap"));
+
+    let hex = dump_compacted(&boxed);
+    assert!(hex.starts_with("000000 "));
+    assert!(hex.contains("<- <static> (48 bytes)"));
+    assert!(hex.contains("onetwo|"));
+    assert!(hex.contains(".keys at 0x30 (8 bytes)"));
+    assert!(hex.contains(".values[0] at 0x68 (3 bytes), .values[1] at 0x6b (3 bytes)"));
+    assert!(!hex.contains("outside of buffer"));
+
+    boxed.with_mut(|dict| dict.get_mut(2).unwrap().push_str(" and more"));
+    assert!(dump_layout(&*boxed).contains("heap"));
+    assert!(dump_compacted(&boxed).contains("outside of buffer: .values[1]"));
+}
@@ -0,0 +1,189 @@
+use super::compact::{Compact, CompactReader, CompactStats, FromReaderError};
+use std::cell::Cell;
+use std::fmt;
+use std::io::Read;
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+use std::rc::Rc;
+
+#[derive(Default)]
+struct Counts {
+    created: Cell<usize>,
+    dropped: Cell<usize>,
+}
+
+/// Counts how many `DropTracker`s it created and how many of them were dropped,
+/// asserting that both match when it goes out of scope.
+///
+/// Useful to check that `Compact` impls neither leak nor double-drop elements.
+#[derive(Default)]
+pub struct DropCounter {
+    counts: Rc<Counts>,
+}
+
+impl DropCounter {
+    /// Create a new counter, without any tracked values yet
+    pub fn new() -> DropCounter {
+        DropCounter::default()
+    }
+
+    /// Wrap `value` so that its construction and drop are counted
+    pub fn track<T>(&self, value: T) -> DropTracker<T> {
+        DropTracker::new(value, self.counts.clone())
+    }
+
+    /// Amount of tracked values created so far (including clones)
+    pub fn created(&self) -> usize {
+        self.counts.created.get()
+    }
+
+    /// Amount of tracked values dropped so far
+    pub fn dropped(&self) -> usize {
+        self.counts.dropped.get()
+    }
+
+    /// Amount of tracked values that are still alive
+    pub fn alive(&self) -> usize {
+        self.created() - self.dropped()
+    }
+
+    /// Assert that all tracked values have been dropped exactly once
+    pub fn assert_balanced(&self) {
+        assert_eq!(
+            self.created(),
+            self.dropped(),
+            "{} tracked values created, but {} dropped",
+            self.created(),
+            self.dropped()
+        );
+    }
+}
+
+impl Drop for DropCounter {
+    fn drop(&mut self) {
+        if !::std::thread::panicking() {
+            self.assert_balanced();
+        }
+    }
+}
+
+/// A value whose construction and drop are counted by the `DropCounter` that created it
+pub struct DropTracker<T> {
+    value: T,
+    counts: Rc<Counts>,
+}
+
+impl<T> DropTracker<T> {
+    fn new(value: T, counts: Rc<Counts>) -> DropTracker<T> {
+        counts.created.set(counts.created.get() + 1);
+        DropTracker { value, counts }
+    }
+}
+
+impl<T> Deref for DropTracker<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> DerefMut for DropTracker<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+impl<T> Drop for DropTracker<T> {
+    fn drop(&mut self) {
+        self.counts.dropped.set(self.counts.dropped.get() + 1);
+    }
+}
+
+impl<T: Clone> Clone for DropTracker<T> {
+    fn clone(&self) -> Self {
+        DropTracker::new(self.value.clone(), self.counts.clone())
+    }
+}
+
+impl<T: PartialEq> PartialEq for DropTracker<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.value == other.value
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for DropTracker<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.value.fmt(f)
+    }
+}
+
+/// Compacting and decompacting move the tracked value, so they aren't counted
+impl<T: Compact> Compact for DropTracker<T> {
+    fn is_still_compact(&self) -> bool {
+        self.value.is_still_compact()
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        self.value.dynamic_size_bytes()
+    }
+
+    fn dynamic_align() -> usize {
+        T::dynamic_align()
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        ptr::write(&mut (*dest).counts, ptr::read(&(*source).counts));
+        Compact::compact(&mut (*source).value, &mut (*dest).value, new_dynamic_part);
+    }
+
+    unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+        self.value.shift_compact_pointers(shift);
+    }
+
+    unsafe fn decompact(source: *const Self) -> Self {
+        DropTracker {
+            value: Compact::decompact(&(*source).value),
+            counts: ptr::read(&(*source).counts),
+        }
+    }
+
+    unsafe fn read_dynamic_part<R: Read>(
+        source: *const Self,
+        reader: &mut CompactReader<R>,
+    ) -> Result<Self, FromReaderError> {
+        Ok(DropTracker {
+            value: T::read_dynamic_part(&(*source).value, reader)?,
+            counts: ptr::read(&(*source).counts),
+        })
+    }
+
+    fn repair_lossy(&mut self, path: &str, dropped: &mut Vec<String>) {
+        self.value.repair_lossy(path, dropped);
+    }
+
+    fn collect_stats(&self, path: &str, stats: &mut CompactStats) {
+        self.value.collect_stats(path, stats);
+    }
+}
+
+#[test]
+fn counts_drops() {
+    let counter = DropCounter::new();
+    let tracked = counter.track(5);
+    let cloned = tracked.clone();
+    assert_eq!(2, counter.alive());
+    drop(tracked);
+    assert_eq!(1, counter.alive());
+    assert_eq!(5, *cloned);
+    drop(cloned);
+    counter.assert_balanced();
+}
+
+#[test]
+#[should_panic(expected = "1 tracked values created, but 0 dropped")]
+fn detects_leaks() {
+    let counter = DropCounter::new();
+    ::std::mem::forget(counter.track(5));
+    counter.assert_balanced();
+}
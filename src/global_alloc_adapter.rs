@@ -0,0 +1,170 @@
+use super::compact_vec::FromVecAllocator;
+use super::simple_allocator_trait::Allocator;
+use std::alloc::{handle_alloc_error, GlobalAlloc, Layout, System};
+use std::marker::PhantomData;
+use std::ptr::NonNull;
+
+/// Gives `GlobalAllocAdapter` access to a `GlobalAlloc` that lives for the whole program.
+///
+/// Since `Allocator` has no instances, implement this for a marker type
+/// that returns your allocator from a `static`:
+///
+/// ```
+/// # use compact::{CVec, GlobalAllocAdapter, StaticGlobalAlloc};
+/// use std::alloc::System;
+/// static MY_ALLOC: System = System;
+///
+/// struct MyAlloc;
+/// impl StaticGlobalAlloc for MyAlloc {
+///     type Alloc = System;
+///     fn get() -> &'static System {
+///         &MY_ALLOC
+///     }
+/// }
+///
+/// let list: CVec<u32, GlobalAllocAdapter<MyAlloc>> = vec![1, 2, 3].into();
+/// assert_eq!(&[1, 2, 3], &list[..]);
+/// ```
+pub trait StaticGlobalAlloc {
+    /// The allocator
+    type Alloc: GlobalAlloc + 'static;
+    /// The instance to allocate with
+    fn get() -> &'static Self::Alloc;
+}
+
+impl StaticGlobalAlloc for System {
+    type Alloc = System;
+    fn get() -> &'static System {
+        &System
+    }
+}
+
+/// Use a `GlobalAlloc` (like the system allocator or mimalloc) wherever an `Allocator` is expected
+pub struct GlobalAllocAdapter<G: StaticGlobalAlloc = System> {
+    _alloc: PhantomData<G>,
+}
+
+impl<G: StaticGlobalAlloc> Allocator for GlobalAllocAdapter<G> {
+    fn allocate<T>(capacity: usize) -> *mut T {
+        let layout = Layout::array::<T>(capacity).expect("capacity overflow");
+        if layout.size() == 0 {
+            return NonNull::dangling().as_ptr();
+        }
+        let ptr = unsafe { G::get().alloc(layout) };
+        if ptr.is_null() {
+            handle_alloc_error(layout);
+        }
+        ptr as *mut T
+    }
+
+    unsafe fn deallocate<T>(ptr: *mut T, capacity: usize) {
+        let layout = Layout::array::<T>(capacity).expect("capacity overflow");
+        if layout.size() != 0 {
+            G::get().dealloc(ptr as *mut u8, layout);
+        }
+    }
+}
+
+impl<G: StaticGlobalAlloc> FromVecAllocator for GlobalAllocAdapter<G> {}
+
+/// Gives `StdAllocatorAdapter` access to a `std::alloc::Allocator` that lives for the whole program,
+/// analogous to `StaticGlobalAlloc`
+#[cfg(feature = "allocator-api")]
+pub trait StaticStdAllocator {
+    /// The allocator
+    type Alloc: ::std::alloc::Allocator + 'static;
+    /// The instance to allocate with
+    fn get() -> &'static Self::Alloc;
+}
+
+/// Use a `std::alloc::Allocator` (nightly only) wherever an `Allocator` is expected
+#[cfg(feature = "allocator-api")]
+pub struct StdAllocatorAdapter<S: StaticStdAllocator> {
+    _alloc: PhantomData<S>,
+}
+
+#[cfg(feature = "allocator-api")]
+impl<S: StaticStdAllocator> Allocator for StdAllocatorAdapter<S> {
+    fn allocate<T>(capacity: usize) -> *mut T {
+        let layout = Layout::array::<T>(capacity).expect("capacity overflow");
+        match ::std::alloc::Allocator::allocate(S::get(), layout) {
+            Ok(ptr) => ptr.as_ptr() as *mut T,
+            Err(_) => handle_alloc_error(layout),
+        }
+    }
+
+    unsafe fn deallocate<T>(ptr: *mut T, capacity: usize) {
+        let layout = Layout::array::<T>(capacity).expect("capacity overflow");
+        if let Some(ptr) = NonNull::new(ptr as *mut u8) {
+            ::std::alloc::Allocator::deallocate(S::get(), ptr, layout);
+        }
+    }
+}
+
+#[cfg(feature = "allocator-api")]
+impl<S: StaticStdAllocator> FromVecAllocator for StdAllocatorAdapter<S> {}
+
+#[cfg(test)]
+struct CountingSystem(::std::sync::atomic::AtomicIsize);
+
+#[cfg(test)]
+unsafe impl GlobalAlloc for CountingSystem {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.0
+            .fetch_add(layout.size() as isize, ::std::sync::atomic::Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0
+            .fetch_sub(layout.size() as isize, ::std::sync::atomic::Ordering::SeqCst);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[test]
+fn global_alloc_adapter() {
+    use super::compact::Compact;
+    use super::compact_vec::CompactVec;
+    use std::sync::atomic::{AtomicIsize, Ordering};
+    static COUNTING: CountingSystem = CountingSystem(AtomicIsize::new(0));
+    struct Counting;
+    impl StaticGlobalAlloc for Counting {
+        type Alloc = CountingSystem;
+        fn get() -> &'static CountingSystem {
+            &COUNTING
+        }
+    }
+    type List = CompactVec<u64, GlobalAllocAdapter<Counting>>;
+
+    let mut list: List = vec![1, 2, 3].into();
+    assert_eq!(24, COUNTING.0.load(Ordering::SeqCst));
+    list.push(4);
+    assert_eq!(&[1, 2, 3, 4], &list[..]);
+    let roundtripped = unsafe { List::from_compact_bytes(&list.compact_to_bytes()) }.unwrap();
+    assert_eq!(list, roundtripped);
+    drop(list);
+    drop(roundtripped);
+    assert_eq!(0, COUNTING.0.load(Ordering::SeqCst));
+
+    let empty: List = CompactVec::with_capacity(0);
+    drop(empty);
+    assert_eq!(0, COUNTING.0.load(Ordering::SeqCst));
+}
+
+#[cfg(feature = "allocator-api")]
+#[test]
+fn std_allocator_adapter() {
+    use super::compact_vec::CompactVec;
+    struct Global;
+    impl StaticStdAllocator for Global {
+        type Alloc = ::std::alloc::Global;
+        fn get() -> &'static ::std::alloc::Global {
+            &::std::alloc::Global
+        }
+    }
+
+    let mut list: CompactVec<u64, StdAllocatorAdapter<Global>> = CompactVec::with_capacity(1);
+    list.extend(0..10);
+    assert_eq!(45, list.iter().sum::<u64>());
+}
@@ -0,0 +1,230 @@
+use super::compact::Compact;
+use std::mem;
+
+/// Where a field of a struct lives in its static part
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FieldLayout {
+    /// Name of the field
+    pub name: &'static str,
+    /// Offset of the field in bytes
+    pub offset: usize,
+    /// Size of the field's static part in bytes
+    pub size: usize,
+    /// Alignment of the field in bytes
+    pub align: usize,
+}
+
+/// Describes the fields of a `Compact` struct, usually implemented with `describe_layout!`
+pub trait DescribeLayout: Compact {
+    /// The layout of all fields
+    fn field_layouts() -> Vec<FieldLayout>;
+
+    /// Size of the dynamic part of each field, in the same order as `field_layouts`
+    fn field_dynamic_sizes(&self) -> Vec<usize>;
+}
+
+/// Implement `DescribeLayout` for a struct by listing all of its fields
+#[macro_export]
+macro_rules! describe_layout {
+    ($t:ty { $($field:ident),* $(,)* }) => {
+        impl $crate::DescribeLayout for $t {
+            fn field_layouts() -> Vec<$crate::FieldLayout> {
+                vec![$(
+                    $crate::FieldLayout {
+                        name: stringify!($field),
+                        offset: ::std::mem::offset_of!($t, $field),
+                        size: $crate::field_size_and_align(|value: &$t| &value.$field).0,
+                        align: $crate::field_size_and_align(|value: &$t| &value.$field).1,
+                    }
+                ),*]
+            }
+
+            fn field_dynamic_sizes(&self) -> Vec<usize> {
+                vec![$($crate::Compact::dynamic_size_bytes(&self.$field)),*]
+            }
+        }
+    };
+}
+
+/// Size and alignment of the field selected by `_field`, used by `describe_layout!`
+#[doc(hidden)]
+pub fn field_size_and_align<T, F, S: Fn(&T) -> &F>(_field: S) -> (usize, usize) {
+    (mem::size_of::<F>(), mem::align_of::<F>())
+}
+
+/// A suggestion for making compact values of a type smaller
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Advice {
+    /// Unused bytes between a field and the next one
+    PaddingHole {
+        /// The field before the hole
+        after: &'static str,
+        /// Size of the hole
+        bytes: usize,
+    },
+    /// Unused bytes after the last field
+    TrailingPadding {
+        /// Size of the padding
+        bytes: usize,
+    },
+    /// Ordering the fields by decreasing alignment (e.g. with `#[repr(C)]`) would save space
+    Reorder {
+        /// Suggested order of the fields
+        order: Vec<&'static str>,
+        /// Bytes saved in the static part
+        saved_bytes: usize,
+    },
+    /// The static part (header) of a field is larger than its dynamic part,
+    /// like a `CVec<u8>` holding just 3 bytes
+    HeaderDominates {
+        /// Name of the field
+        field: &'static str,
+        /// Size of the field's static part
+        header_bytes: usize,
+        /// Size of the field's dynamic part
+        payload_bytes: usize,
+    },
+}
+
+/// Report padding holes and field-reordering suggestions for the static part of `T`
+pub fn layout_advice<T: DescribeLayout>() -> Vec<Advice> {
+    let mut fields = T::field_layouts();
+    fields.sort_by_key(|field| field.offset);
+    let mut advice = Vec::new();
+
+    for pair in fields.windows(2) {
+        let end = pair[0].offset + pair[0].size;
+        if pair[1].offset > end {
+            advice.push(Advice::PaddingHole {
+                after: pair[0].name,
+                bytes: pair[1].offset - end,
+            });
+        }
+    }
+
+    let end = fields.last().map_or(0, |field| field.offset + field.size);
+    if mem::size_of::<T>() > end {
+        advice.push(Advice::TrailingPadding {
+            bytes: mem::size_of::<T>() - end,
+        });
+    }
+
+    // fields ordered by decreasing alignment only need trailing padding
+    fields.sort_by_key(|field| ::std::cmp::Reverse(field.align));
+    let unpadded: usize = fields.iter().map(|field| field.size).sum();
+    let reordered_size = unpadded.next_multiple_of(mem::align_of::<T>());
+    if reordered_size < mem::size_of::<T>() {
+        advice.push(Advice::Reorder {
+            order: fields.iter().map(|field| field.name).collect(),
+            saved_bytes: mem::size_of::<T>() - reordered_size,
+        });
+    }
+
+    advice
+}
+
+/// Like `layout_advice`, but also reports fields of `value` whose header dominates their payload
+pub fn layout_advice_for<T: DescribeLayout>(value: &T) -> Vec<Advice> {
+    let mut advice = layout_advice::<T>();
+    for (field, payload_bytes) in T::field_layouts()
+        .into_iter()
+        .zip(value.field_dynamic_sizes())
+    {
+        if payload_bytes > 0 && field.size > payload_bytes {
+            advice.push(Advice::HeaderDominates {
+                field: field.name,
+                header_bytes: field.size,
+                payload_bytes,
+            });
+        }
+    }
+    advice
+}
+
+#[cfg(test)]
+#[derive(Clone)]
+#[repr(C)]
+struct Padded {
+    flag: u8,
+    count: u64,
+    small: u16,
+    name: super::compact_vec::CompactVec<u8>,
+}
+
+#[cfg(test)]
+impl Compact for Padded {
+    fn is_still_compact(&self) -> bool {
+        self.name.is_still_compact()
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        self.name.dynamic_size_bytes()
+    }
+
+    fn dynamic_align() -> usize {
+        super::compact_vec::CompactVec::<u8>::dynamic_align()
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        (*dest).flag = (*source).flag;
+        (*dest).count = (*source).count;
+        (*dest).small = (*source).small;
+        Compact::compact(&mut (*source).name, &mut (*dest).name, new_dynamic_part);
+    }
+
+    unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+        self.name.shift_compact_pointers(shift);
+    }
+
+    unsafe fn decompact(source: *const Self) -> Self {
+        Padded {
+            flag: (*source).flag,
+            count: (*source).count,
+            small: (*source).small,
+            name: Compact::decompact(&(*source).name),
+        }
+    }
+}
+
+#[cfg(test)]
+describe_layout!(Padded { flag, count, small, name });
+
+#[test]
+fn reports_padding_and_headers() {
+    let advice = layout_advice::<Padded>();
+    assert_eq!(
+        advice[..2],
+        [
+            Advice::PaddingHole {
+                after: "flag",
+                bytes: 7
+            },
+            Advice::PaddingHole {
+                after: "small",
+                bytes: 6
+            },
+        ]
+    );
+    assert_eq!(
+        advice[2],
+        Advice::Reorder {
+            order: vec!["count", "name", "small", "flag"],
+            saved_bytes: 8
+        }
+    );
+
+    let value = Padded {
+        flag: 0,
+        count: 0,
+        small: 0,
+        name: vec![1, 2, 3].into(),
+    };
+    assert_eq!(
+        layout_advice_for(&value).last(),
+        Some(&Advice::HeaderDominates {
+            field: "name",
+            header_bytes: mem::size_of::<super::compact_vec::CompactVec<u8>>(),
+            payload_bytes: 3
+        })
+    );
+}
@@ -14,25 +14,142 @@
 //!     such as actors, threads and the network
 
 #![warn(missing_docs)]
+// the original tests are kept as they were written
+#![cfg_attr(
+    test,
+    allow(
+        clippy::assign_op_pattern,
+        clippy::bool_comparison,
+        clippy::legacy_numeric_constants,
+        clippy::unnecessary_cast
+    )
+)]
+#![cfg_attr(feature = "allocator-api", feature(allocator_api))]
 
 extern crate simple_allocator_trait;
 mod pointer_to_maybe_compact;
 mod compact;
 mod compact_option;
+mod compact_result;
+#[macro_use]
+mod compact_enum;
 mod compact_vec;
+mod concurrent_vec;
+mod compact_slice;
 mod compact_str;
+mod compact_bytes;
+mod compact_cow;
+mod compacted_box;
+mod sized_compact;
+mod compact_cell;
+#[cfg(feature = "serde-serialization")]
+mod compact_deserialize;
 mod compact_dict;
+mod compact_sorted_dict;
 mod compact_hash_map;
-
+mod concurrent_hash_map;
+mod fx_hash;
+mod compact_graph;
+mod compact_trie;
+mod compact_interval_map;
+mod compact_small_set;
+mod compact_ring_buffer;
+mod compact_interner;
+mod compact_grid;
+mod compact_sparse_vec;
 #[macro_use]
-extern crate lazy_static;
+mod compact_enum_map;
+mod compact_dedup_queue;
+mod compact_freezable_vec;
+mod lz;
+#[cfg(any(test, feature = "testing"))]
+mod drop_tracker;
+mod layout_advice;
+mod tracking_allocator;
+mod bump_allocator;
+mod global_alloc_adapter;
+#[cfg(feature = "metrics")]
+mod metrics;
+pub mod debug;
+#[cfg(feature = "proptest")]
+pub mod strategies;
 
 #[cfg(feature = "serde-serialization")]
 extern crate serde;
 
-pub use self::compact::Compact;
+#[cfg(feature = "arbitrary")]
+extern crate arbitrary;
+
+#[cfg(feature = "proptest")]
+extern crate proptest;
+
+#[cfg(feature = "log")]
+extern crate log;
+
+#[cfg(all(test, feature = "serde-serialization"))]
+extern crate serde_json;
+
+pub use self::compact::{
+    capacity_mode, release_scratch, reserve_scratch, scratch_size, with_capacity_mode,
+    CapacityMode, Compact, CompactReader, CompactStats, ContainerStats, DynamicLayout,
+    FromBytesError, FromReaderError, NeedBytes,
+};
 pub use self::compact_option::CompactOption as COption;
-pub use self::compact_vec::CompactVec as CVec;
-pub use self::compact_str::CompactString as CString;
-pub use self::compact_dict::CompactDict as CDict;
-pub use self::compact_hash_map::OpenAddressingMap as CHashMap;
+pub use self::compact_result::CompactResult as CResult;
+pub use self::compact_enum::VariantCompactor;
+pub use self::compact_vec::{CompactVec as CVec, FromVecAllocator};
+pub use self::concurrent_vec::ConcurrentCompactVec as ConcurrentCVec;
+pub use self::compact_slice::CompactSlice as CSlice;
+pub use self::compact_str::{CompactString as CString, FromUtf8Error};
+pub use self::compact_bytes::{CompactBytes as CBytes, FrozenBytes};
+pub use self::compact_cow::{CompactCow as CCow, CompactCowStr as CCowStr};
+pub use self::compacted_box::{CompactedBox, SharedCompactedBox, SpillAction};
+pub use self::sized_compact::SizedCompact;
+pub use self::compact_cell::CompactCell;
+#[cfg(feature = "serde-serialization")]
+pub use self::compact_deserialize::{
+    CompactDeserialize, DeserializePlan, PlaceSeed, PlanSeed, Placer, Planner,
+};
+pub use self::compact_dict::{
+    CompactDict as CDict, IntoIter as CDictIntoIter, Iter as CDictIter, IterMut as CDictIterMut,
+};
+pub use self::compact_sorted_dict::CompactSortedDict as CSortedDict;
+pub use self::compact_hash_map::{
+    DefaultHashBuilder, FastOpenAddressingMap as FastCHashMap, Iter as CHashMapIter,
+    IterMut as CHashMapIterMut, Keys as CHashMapKeys, MapDiff, OpenAddressingMap as CHashMap,
+    PairsMut as CHashMapPairsMut, RawEntryBuilder as CHashMapRawEntryBuilder,
+    RawEntryBuilderMut as CHashMapRawEntryBuilderMut, RawEntryMut as CHashMapRawEntryMut,
+    RawOccupiedEntryMut as CHashMapRawOccupiedEntryMut,
+    RawVacantEntryMut as CHashMapRawVacantEntryMut, SeededHashBuilder, Values as CHashMapValues,
+    ValuesMut as CHashMapValuesMut,
+};
+pub use self::concurrent_hash_map::ConcurrentOpenAddressingMap as ConcurrentCHashMap;
+pub use self::fx_hash::{FxBuildHasher, FxHasher};
+pub use self::compact_dedup_queue::CompactDedupQueue as CDedupQueue;
+pub use self::compact_freezable_vec::CompactFreezableVec as CFreezableVec;
+pub use self::compact_graph::{
+    CompactGraph as CGraph, Direction, EdgeIndex, Neighbors, NodeIndex,
+};
+pub use self::compact_trie::CompactTrie as CTrie;
+pub use self::compact_interval_map::CompactIntervalMap as CIntervalMap;
+pub use self::compact_small_set::CompactSmallSet as CSmallSet;
+pub use self::compact_ring_buffer::CompactRingBuffer as CRingBuffer;
+pub use self::compact_interner::{CompactInterner, Symbol};
+pub use self::compact_grid::CompactGrid as CGrid;
+pub use self::compact_sparse_vec::CompactSparseVec as CSparseVec;
+pub use self::compact_enum_map::{CompactEnumMap as CEnumMap, EnumArray};
+pub use self::tracking_allocator::{
+    allocation_stats, allocation_stats_for, reset_allocation_stats, AllocationStats,
+    TrackingAllocator,
+};
+pub use self::bump_allocator::BumpAllocator;
+pub use self::global_alloc_adapter::{GlobalAllocAdapter, StaticGlobalAlloc};
+#[cfg(feature = "metrics")]
+pub use self::metrics::{map_metrics, reset_metrics, vec_metrics, Metrics};
+#[cfg(feature = "allocator-api")]
+pub use self::global_alloc_adapter::{StaticStdAllocator, StdAllocatorAdapter};
+pub use self::layout_advice::{
+    field_size_and_align, layout_advice, layout_advice_for, Advice, DescribeLayout, FieldLayout,
+};
+#[cfg(feature = "testing")]
+pub use self::drop_tracker::{DropCounter, DropTracker};
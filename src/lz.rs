@@ -0,0 +1,107 @@
+//! A small LZ77-style byte compressor, used for freezing cold buffers.
+//!
+//! The output is a sequence of tokens, each starting with a control byte:
+//! `0xxxxxxx` is followed by `x + 1` literal bytes,
+//! `1xxxxxxx` is followed by a little-endian `u16` offset and copies `x + MIN_MATCH`
+//! bytes from that far back in the output.
+
+const MIN_MATCH: usize = 4;
+const MAX_MATCH: usize = MIN_MATCH + 0x7F;
+const MAX_LITERALS: usize = 0x80;
+const MAX_OFFSET: usize = 0xFFFF;
+const HASH_BITS: u32 = 12;
+
+fn hash(bytes: &[u8]) -> usize {
+    let word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    (word.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+fn push_literals(out: &mut Vec<u8>, literals: &[u8]) {
+    for chunk in literals.chunks(MAX_LITERALS) {
+        out.push((chunk.len() - 1) as u8);
+        out.extend_from_slice(chunk);
+    }
+}
+
+/// Compress `input` greedily, remembering the last position of each 4-byte sequence
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() / 2);
+    let mut last_positions = vec![usize::MAX; 1 << HASH_BITS];
+    let mut literals_start = 0;
+    let mut i = 0;
+
+    while i + MIN_MATCH <= input.len() {
+        let slot = &mut last_positions[hash(&input[i..])];
+        let candidate = *slot;
+        *slot = i;
+
+        if candidate != usize::MAX
+            && i - candidate <= MAX_OFFSET
+            && input[candidate..candidate + MIN_MATCH] == input[i..i + MIN_MATCH]
+        {
+            let mut len = MIN_MATCH;
+            while len < MAX_MATCH
+                && i + len < input.len()
+                && input[candidate + len] == input[i + len]
+            {
+                len += 1;
+            }
+
+            push_literals(&mut out, &input[literals_start..i]);
+            out.push(0x80 | (len - MIN_MATCH) as u8);
+            out.extend_from_slice(&((i - candidate) as u16).to_le_bytes());
+            i += len;
+            literals_start = i;
+        } else {
+            i += 1;
+        }
+    }
+
+    push_literals(&mut out, &input[literals_start..]);
+    out
+}
+
+/// Decompress the output of `compress`, which had `len` bytes originally
+pub fn decompress(input: &[u8], len: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(len);
+    let mut i = 0;
+
+    while i < input.len() {
+        let control = input[i] as usize;
+        i += 1;
+        if control < 0x80 {
+            out.extend_from_slice(&input[i..i + control + 1]);
+            i += control + 1;
+        } else {
+            let offset = u16::from_le_bytes([input[i], input[i + 1]]) as usize;
+            i += 2;
+            // copy byte by byte, since the match can overlap with what it produces
+            let start = out.len() - offset;
+            for j in 0..(control & 0x7F) + MIN_MATCH {
+                let byte = out[start + j];
+                out.push(byte);
+            }
+        }
+    }
+
+    debug_assert_eq!(len, out.len());
+    out
+}
+
+#[test]
+fn roundtrip() {
+    let repetitive: Vec<u8> = (0..10_000u32).flat_map(|n| (n % 7).to_le_bytes()).collect();
+    let compressed = compress(&repetitive);
+    assert!(compressed.len() < repetitive.len() / 10);
+    assert_eq!(repetitive, decompress(&compressed, repetitive.len()));
+
+    let noisy: Vec<u8> = (0..1000u32)
+        .map(|n| (n.wrapping_mul(2_654_435_761) >> 24) as u8)
+        .collect();
+    assert_eq!(noisy, decompress(&compress(&noisy), noisy.len()));
+
+    for len in 0..6 {
+        let short = vec![1u8; len];
+        assert_eq!(short, decompress(&compress(&short), len));
+    }
+}
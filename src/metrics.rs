@@ -0,0 +1,160 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Counters of one container type, shared by all threads, see `vec_metrics` and `map_metrics`
+pub(crate) struct ContainerMetrics {
+    spills: AtomicUsize,
+    spilled_bytes: AtomicUsize,
+    compactions: AtomicUsize,
+    compacted_bytes: AtomicUsize,
+    rehashes: AtomicUsize,
+    probes: AtomicUsize,
+    probed_slots: AtomicUsize,
+    max_probe_length: AtomicUsize,
+}
+
+/// A snapshot of the counters of one container type
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// Amount of times storage spilled to the heap
+    pub spills: usize,
+    /// Bytes allocated on the heap by spilling
+    pub spilled_bytes: usize,
+    /// Amount of times storage was compacted
+    pub compactions: usize,
+    /// Bytes of storage compacted
+    pub compacted_bytes: usize,
+    /// Amount of rehashes (maps only)
+    pub rehashes: usize,
+    /// Amount of probe sequences of inserts and removals (maps only)
+    pub probes: usize,
+    /// Slots visited by all those probe sequences (maps only)
+    pub probed_slots: usize,
+    /// Longest probe sequence (maps only)
+    pub max_probe_length: usize,
+}
+
+impl Metrics {
+    /// Average length of probe sequences, 0 if there were none
+    pub fn mean_probe_length(&self) -> f64 {
+        if self.probes == 0 {
+            0.0
+        } else {
+            self.probed_slots as f64 / self.probes as f64
+        }
+    }
+}
+
+impl ContainerMetrics {
+    const fn new() -> ContainerMetrics {
+        ContainerMetrics {
+            spills: AtomicUsize::new(0),
+            spilled_bytes: AtomicUsize::new(0),
+            compactions: AtomicUsize::new(0),
+            compacted_bytes: AtomicUsize::new(0),
+            rehashes: AtomicUsize::new(0),
+            probes: AtomicUsize::new(0),
+            probed_slots: AtomicUsize::new(0),
+            max_probe_length: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn record_spill(&self, bytes: usize) {
+        self.spills.fetch_add(1, Ordering::Relaxed);
+        self.spilled_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_compaction(&self, bytes: usize) {
+        self.compactions.fetch_add(1, Ordering::Relaxed);
+        self.compacted_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_rehash(&self) {
+        self.rehashes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_probe(&self, length: usize) {
+        self.probes.fetch_add(1, Ordering::Relaxed);
+        self.probed_slots.fetch_add(length, Ordering::Relaxed);
+        self.max_probe_length.fetch_max(length, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> Metrics {
+        Metrics {
+            spills: self.spills.load(Ordering::Relaxed),
+            spilled_bytes: self.spilled_bytes.load(Ordering::Relaxed),
+            compactions: self.compactions.load(Ordering::Relaxed),
+            compacted_bytes: self.compacted_bytes.load(Ordering::Relaxed),
+            rehashes: self.rehashes.load(Ordering::Relaxed),
+            probes: self.probes.load(Ordering::Relaxed),
+            probed_slots: self.probed_slots.load(Ordering::Relaxed),
+            max_probe_length: self.max_probe_length.load(Ordering::Relaxed),
+        }
+    }
+
+    fn reset(&self) {
+        for counter in &[
+            &self.spills,
+            &self.spilled_bytes,
+            &self.compactions,
+            &self.compacted_bytes,
+            &self.rehashes,
+            &self.probes,
+            &self.probed_slots,
+            &self.max_probe_length,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+pub(crate) static VEC: ContainerMetrics = ContainerMetrics::new();
+pub(crate) static MAP: ContainerMetrics = ContainerMetrics::new();
+
+/// Counters of all `CVec`s (and other containers storing their elements in one,
+/// like the entries of `CHashMap`s) since the last `reset_metrics`
+pub fn vec_metrics() -> Metrics {
+    VEC.snapshot()
+}
+
+/// Counters of all `CHashMap`s since the last `reset_metrics`
+pub fn map_metrics() -> Metrics {
+    MAP.snapshot()
+}
+
+/// Reset the counters of all container types
+pub fn reset_metrics() {
+    VEC.reset();
+    MAP.reset();
+}
+
+#[test]
+fn metrics() {
+    let metrics = ContainerMetrics::new();
+    metrics.record_spill(16);
+    metrics.record_spill(32);
+    metrics.record_probe(1);
+    metrics.record_probe(4);
+    let snapshot = metrics.snapshot();
+    assert_eq!(2, snapshot.spills);
+    assert_eq!(48, snapshot.spilled_bytes);
+    assert_eq!(4, snapshot.max_probe_length);
+    assert_eq!(2.5, snapshot.mean_probe_length());
+    metrics.reset();
+    assert_eq!(Metrics::default(), metrics.snapshot());
+}
+
+#[test]
+fn container_metrics() {
+    use super::compact_hash_map::OpenAddressingMap;
+    use super::compact_vec::CompactVec;
+    // other tests run concurrently, so only check that counters grew
+    let (vecs, maps) = (vec_metrics(), map_metrics());
+    let mut list: CompactVec<u32> = CompactVec::with_capacity(1);
+    list.extend(0..2);
+    let mut map: OpenAddressingMap<u32, u32> = OpenAddressingMap::with_capacity(2);
+    map.extend((0..4).map(|n| (n, n)));
+    assert!(vec_metrics().spills > vecs.spills);
+    assert!(vec_metrics().spilled_bytes >= vecs.spilled_bytes + 8);
+    assert!(map_metrics().rehashes > maps.rehashes);
+    assert!(map_metrics().probes >= maps.probes + 4);
+}
@@ -30,6 +30,16 @@ impl<T> std::fmt::Debug for PointerToMaybeCompact<T> {
     }
 }
 
+impl<T> std::fmt::Display for PointerToMaybeCompact<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.inner {
+            Inner::Free(p) => write!(f, "Free {:p}", p as *const T),
+            Inner::Compact(i) => write!(f, "Compact {:?}", i),
+            Inner::Uninitialized => write!(f, "uninitialized"),
+        }
+    }
+}
+
 impl<T> PointerToMaybeCompact<T> {
     /// Create a new pointer which is initialized to point on the heap
     pub fn new_free(ptr: *mut T) -> Self {
@@ -49,6 +59,13 @@ impl<T> PointerToMaybeCompact<T> {
         self.inner = Inner::Compact((ptr as isize - self as *const Self as isize) as i32);
     }
 
+    /// Move a compact pointer by `shift` bytes, as if what it points to was moved
+    pub fn shift_compact(&mut self, shift: isize) {
+        if let Inner::Compact(ref mut offset) = self.inner {
+            *offset = (*offset as isize + shift) as i32;
+        }
+    }
+
     /// Get a raw pointer to wherever it is pointing
     pub unsafe fn ptr(&self) -> *const T {
         match self.inner {
@@ -83,12 +100,4 @@ impl<T> PointerToMaybeCompact<T> {
             }
         }
     }
-
-    pub fn to_string(&self) -> String {
-        match self.inner {
-            Inner::Free(p) => format!("Free {:p}", p as *const T),
-            Inner::Compact(i) => format!("Compact {:?}", i),
-            Inner::Uninitialized => String::from("uninitialized"),
-        }
-    }
-}
\ No newline at end of file
+}
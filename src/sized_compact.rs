@@ -0,0 +1,177 @@
+use super::compact::{
+    capacity_mode, CapacityMode, Compact, CompactReader, CompactStats, FromReaderError,
+};
+use std::cell::Cell;
+use std::io::{self, Read, Write};
+
+/// Wraps a value and caches its `dynamic_size_bytes`, so repeated size queries
+/// (like when sending the same big message to many recipients) don't traverse it again.
+///
+/// Mutable access to the value invalidates the cached size.
+/// The cache remembers the `CapacityMode` it was computed in, since sizes depend on it.
+pub struct SizedCompact<T> {
+    value: T,
+    cached_size: Cell<Option<(CapacityMode, usize)>>,
+}
+
+impl<T: Compact> SizedCompact<T> {
+    /// Wrap `value`, its size is computed on the first query
+    pub fn new(value: T) -> Self {
+        SizedCompact {
+            value,
+            cached_size: Cell::new(None),
+        }
+    }
+
+    /// Unwrap the value
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+impl<T> ::std::ops::Deref for SizedCompact<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> ::std::ops::DerefMut for SizedCompact<T> {
+    /// Access the value mutably, invalidating the cached size
+    fn deref_mut(&mut self) -> &mut T {
+        self.cached_size.set(None);
+        &mut self.value
+    }
+}
+
+impl<T: Compact> Compact for SizedCompact<T> {
+    fn is_still_compact(&self) -> bool {
+        self.value.is_still_compact()
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        let mode = capacity_mode();
+        match self.cached_size.get() {
+            Some((cached_mode, size)) if cached_mode == mode => size,
+            _ => {
+                let size = self.value.dynamic_size_bytes();
+                self.cached_size.set(Some((mode, size)));
+                size
+            }
+        }
+    }
+
+    fn dynamic_align() -> usize {
+        T::dynamic_align()
+    }
+
+    fn collect_stats(&self, path: &str, stats: &mut CompactStats) {
+        self.value.collect_stats(path, stats);
+    }
+
+    fn repair_lossy(&mut self, path: &str, dropped: &mut Vec<String>) {
+        self.cached_size.set(None);
+        self.value.repair_lossy(path, dropped);
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        // compacting in the same mode keeps the size
+        let cached_size = (*source)
+            .cached_size
+            .get()
+            .filter(|&(mode, _)| mode == capacity_mode());
+        ::std::ptr::write(&mut (*dest).cached_size, Cell::new(cached_size));
+        Compact::compact(&mut (*source).value, &mut (*dest).value, new_dynamic_part)
+    }
+
+    unsafe fn compact_static_part(&self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        ::std::ptr::write(&mut (*dest).cached_size, Cell::new(None));
+        self.value
+            .compact_static_part(&mut (*dest).value, new_dynamic_part)
+    }
+
+    fn write_dynamic_part<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.value.write_dynamic_part(writer)
+    }
+
+    unsafe fn shift_compact_pointers(&mut self, shift: isize) {
+        self.value.shift_compact_pointers(shift);
+    }
+
+    unsafe fn decompact(source: *const Self) -> Self {
+        SizedCompact::new(Compact::decompact(&(*source).value))
+    }
+
+    unsafe fn read_dynamic_part<R: Read>(
+        source: *const Self,
+        reader: &mut CompactReader<R>,
+    ) -> Result<Self, FromReaderError> {
+        Ok(SizedCompact::new(reader.read_field(&(*source).value)?))
+    }
+}
+
+impl<T: Compact> Clone for SizedCompact<T> {
+    fn clone(&self) -> Self {
+        SizedCompact::new(self.value.clone())
+    }
+}
+
+impl<T: Compact + Default> Default for SizedCompact<T> {
+    fn default() -> Self {
+        SizedCompact::new(T::default())
+    }
+}
+
+impl<T: ::std::fmt::Debug> ::std::fmt::Debug for SizedCompact<T> {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        self.value.fmt(f)
+    }
+}
+
+impl<T: PartialEq> PartialEq for SizedCompact<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.value == other.value
+    }
+}
+
+#[test]
+fn caches_size_until_mutated() {
+    use super::compact::with_capacity_mode;
+    use super::compact_vec::CompactVec;
+    let mut lists: CompactVec<CompactVec<u32>> = CompactVec::with_capacity(4);
+    lists.push(vec![1, 2, 3].into());
+    let mut sized = SizedCompact::new(lists);
+    assert_eq!(None, sized.cached_size.get());
+    let size = sized.dynamic_size_bytes();
+    assert_eq!(sized.value.dynamic_size_bytes(), size);
+    assert!(sized.cached_size.get().is_some());
+    assert_eq!(size, sized.dynamic_size_bytes());
+
+    // the unused capacity for 3 more lists isn't counted when trimming
+    let trimmed = with_capacity_mode(CapacityMode::Trim, || sized.dynamic_size_bytes());
+    assert!(trimmed < sized.dynamic_size_bytes());
+
+    sized.push(vec![4; 100].into());
+    assert_eq!(None, sized.cached_size.get());
+    assert_eq!(sized.value.dynamic_size_bytes(), sized.dynamic_size_bytes());
+}
+
+#[test]
+fn compact_sized() {
+    use super::compact_str::CompactString;
+    use super::compacted_box::CompactedBox;
+    let name: SizedCompact<CompactString> = SizedCompact::new("Kay".into());
+    let size = name.total_size_bytes();
+    let boxed = CompactedBox::new(name.clone());
+    assert!(boxed.is_still_compact());
+    assert_eq!(size, boxed.as_bytes().len());
+    assert_eq!(Some((capacity_mode(), 3)), boxed.cached_size.get());
+    assert_eq!(name, *boxed);
+
+    let mut stream = Vec::new();
+    name.compact_to_writer(&mut stream).unwrap();
+    let read: SizedCompact<CompactString> =
+        unsafe { Compact::decompact_from_reader(&mut &stream[..]) }.unwrap();
+    assert_eq!(name, read);
+}
@@ -0,0 +1,146 @@
+//! `proptest` strategies generating compact containers, for property-based tests
+//! of types built from them (like compact/decompact round-trips):
+//!
+//! ```
+//! # extern crate compact;
+//! # extern crate proptest;
+//! use compact::strategies::{chashmap, cstring, cvec};
+//! use compact::{CHashMap, CString, CVec, Compact};
+//! use proptest::prelude::*;
+//!
+//! proptest! {
+//!     fn roundtrip(map in chashmap(any::<u32>(), cvec(cstring(".*"), 0..10), 0..20)) {
+//!         let bytes = map.compact_to_bytes();
+//!         let decompacted =
+//!             unsafe { CHashMap::<u32, CVec<CString>>::from_compact_bytes(&bytes) }.unwrap();
+//!         prop_assert_eq!(map, decompacted);
+//!     }
+//! }
+//! # fn main() { roundtrip(); }
+//! ```
+
+use super::compact::Compact;
+use super::compact_bytes::CompactBytes;
+use super::compact_dict::CompactDict;
+use super::compact_hash_map::OpenAddressingMap;
+use super::compact_option::CompactOption;
+use super::compact_result::CompactResult;
+use super::compact_sorted_dict::CompactSortedDict;
+use super::compact_str::CompactString;
+use super::compact_vec::CompactVec;
+use super::compacted_box::CompactedBox;
+use proptest::collection::{hash_map, vec, SizeRange};
+use proptest::prelude::*;
+use std::hash::Hash;
+
+/// `CVec`s with elements from `element` and a length in `size`
+pub fn cvec<S>(
+    element: S,
+    size: impl Into<SizeRange>,
+) -> impl Strategy<Value = CompactVec<S::Value>>
+where
+    S: Strategy,
+    S::Value: Compact + Clone,
+{
+    vec(element, size).prop_map(CompactVec::from)
+}
+
+/// `CString`s matching the regular expression `regex`
+pub fn cstring<'a>(regex: &'a str) -> impl Strategy<Value = CompactString> + 'a {
+    regex.prop_map(CompactString::from)
+}
+
+/// `CBytes` with a length in `size`
+pub fn cbytes(size: impl Into<SizeRange>) -> impl Strategy<Value = CompactBytes> {
+    vec(any::<u8>(), size).prop_map(CompactBytes::from)
+}
+
+/// `CHashMap`s with keys from `key`, values from `value` and a length in `size`
+pub fn chashmap<K, V>(
+    key: K,
+    value: V,
+    size: impl Into<SizeRange>,
+) -> impl Strategy<Value = OpenAddressingMap<K::Value, V::Value>>
+where
+    K: Strategy,
+    K::Value: Compact + Eq + Hash,
+    V: Strategy,
+    V::Value: Compact + Clone,
+{
+    hash_map(key, value, size).prop_map(|map| map.into_iter().collect())
+}
+
+/// `CDict`s with keys from `key`, values from `value` and a length in `size`
+pub fn cdict<K, V>(
+    key: K,
+    value: V,
+    size: impl Into<SizeRange>,
+) -> impl Strategy<Value = CompactDict<K::Value, V::Value>>
+where
+    K: Strategy,
+    K::Value: Copy + Eq + Hash,
+    V: Strategy,
+    V::Value: Compact + Clone,
+{
+    hash_map(key, value, size).prop_map(|map| map.into_iter().collect())
+}
+
+/// `CSortedDict`s with keys from `key`, values from `value` and a length in `size`
+pub fn csorted_dict<K, V>(
+    key: K,
+    value: V,
+    size: impl Into<SizeRange>,
+) -> impl Strategy<Value = CompactSortedDict<K::Value, V::Value>>
+where
+    K: Strategy,
+    K::Value: Ord + Copy + Hash,
+    V: Strategy,
+    V::Value: Compact + Clone,
+{
+    hash_map(key, value, size).prop_map(|map| map.into_iter().collect())
+}
+
+/// `COption`s that are `Some` with a value from `value` half of the time
+pub fn coption<S>(value: S) -> impl Strategy<Value = CompactOption<S::Value>>
+where
+    S: Strategy,
+    S::Value: Compact + Clone,
+{
+    proptest::option::of(value).prop_map(CompactOption)
+}
+
+/// `CResult`s that are `Ok` with a value from `ok` or `Err` with one from `err`
+pub fn cresult<S, E>(ok: S, err: E) -> impl Strategy<Value = CompactResult<S::Value, E::Value>>
+where
+    S: Strategy,
+    S::Value: Compact + Clone,
+    E: Strategy,
+    E::Value: Compact + Clone,
+{
+    proptest::result::maybe_ok(ok, err).prop_map(CompactResult)
+}
+
+/// `CompactedBox`es of values from `value`
+pub fn compacted_box<S>(value: S) -> impl Strategy<Value = CompactedBox<S::Value>>
+where
+    S: Strategy,
+    S::Value: Compact + Clone,
+{
+    value.prop_map(CompactedBox::new)
+}
+
+#[cfg(test)]
+proptest! {
+    #[test]
+    fn nested_roundtrip(
+        dict in cdict(any::<u16>(), cvec(coption(cstring("[a-z]{0,8}")), 0..8), 0..16),
+        results in cvec(cresult(cbytes(0..16), any::<u32>()), 0..8),
+    ) {
+        let decompacted: CompactDict<_, _> =
+            unsafe { Compact::from_compact_bytes(&dict.compact_to_bytes()) }.unwrap();
+        prop_assert_eq!(&dict, &decompacted);
+        let boxed = CompactedBox::new(results.clone());
+        prop_assert!(boxed.is_still_compact());
+        prop_assert_eq!(&results, &*boxed);
+    }
+}
@@ -0,0 +1,158 @@
+use super::compact_vec::FromVecAllocator;
+use super::simple_allocator_trait::{Allocator, DefaultHeap};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::mem;
+
+/// An `Allocator` that allocates using `A`, but counts allocations, allocated bytes
+/// and peak usage of the current thread, in total and per allocated element type.
+///
+/// Use it as the allocator of compact collections to check that code doesn't allocate:
+///
+/// ```
+/// # use compact::{CVec, TrackingAllocator, allocation_stats, reset_allocation_stats};
+/// let mut list: CVec<u32, TrackingAllocator> = CVec::with_capacity(4);
+/// reset_allocation_stats();
+/// list.push(1);
+/// assert_eq!(0, allocation_stats().allocations);
+/// ```
+pub struct TrackingAllocator<A: Allocator = DefaultHeap> {
+    _inner: PhantomData<A>,
+}
+
+/// Allocation counts of a `TrackingAllocator`, see `allocation_stats`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AllocationStats {
+    /// Amount of allocations
+    pub allocations: usize,
+    /// Amount of deallocations
+    pub deallocations: usize,
+    /// Bytes allocated in total
+    pub allocated_bytes: usize,
+    /// Bytes currently allocated
+    pub current_bytes: usize,
+    /// Maximum of bytes allocated at the same time
+    pub peak_bytes: usize,
+}
+
+impl AllocationStats {
+    fn record_allocation(&mut self, bytes: usize) {
+        self.allocations += 1;
+        self.allocated_bytes += bytes;
+        self.current_bytes += bytes;
+        self.peak_bytes = self.peak_bytes.max(self.current_bytes);
+    }
+
+    fn record_deallocation(&mut self, bytes: usize) {
+        self.deallocations += 1;
+        // storage might have been allocated before the last reset
+        self.current_bytes = self.current_bytes.saturating_sub(bytes);
+    }
+}
+
+#[derive(Default)]
+struct ThreadStats {
+    total: AllocationStats,
+    per_type: HashMap<&'static str, AllocationStats>,
+}
+
+thread_local! {
+    static THREAD_STATS: RefCell<ThreadStats> = RefCell::new(ThreadStats::default());
+}
+
+impl<A: Allocator> Allocator for TrackingAllocator<A> {
+    fn allocate<T>(capacity: usize) -> *mut T {
+        let bytes = capacity * mem::size_of::<T>();
+        THREAD_STATS.with(|stats| {
+            let mut stats = stats.borrow_mut();
+            stats.total.record_allocation(bytes);
+            stats
+                .per_type
+                .entry(::std::any::type_name::<T>())
+                .or_default()
+                .record_allocation(bytes);
+        });
+        A::allocate(capacity)
+    }
+
+    unsafe fn deallocate<T>(ptr: *mut T, capacity: usize) {
+        let bytes = capacity * mem::size_of::<T>();
+        THREAD_STATS.with(|stats| {
+            let mut stats = stats.borrow_mut();
+            stats.total.record_deallocation(bytes);
+            stats
+                .per_type
+                .entry(::std::any::type_name::<T>())
+                .or_default()
+                .record_deallocation(bytes);
+        });
+        A::deallocate(ptr, capacity)
+    }
+}
+
+impl<A: Allocator> FromVecAllocator for TrackingAllocator<A> {}
+
+/// Allocation counts of all `TrackingAllocator`s on the current thread
+pub fn allocation_stats() -> AllocationStats {
+    THREAD_STATS.with(|stats| stats.borrow().total)
+}
+
+/// Allocation counts of all `TrackingAllocator`s on the current thread,
+/// for storage of elements of type `T` only
+pub fn allocation_stats_for<T>() -> AllocationStats {
+    THREAD_STATS.with(|stats| {
+        stats
+            .borrow()
+            .per_type
+            .get(::std::any::type_name::<T>())
+            .cloned()
+            .unwrap_or_default()
+    })
+}
+
+/// Reset all allocation counts of the current thread, including currently allocated
+/// and peak bytes, which then only reflect allocations from now on
+pub fn reset_allocation_stats() {
+    THREAD_STATS.with(|stats| *stats.borrow_mut() = ThreadStats::default());
+}
+
+#[test]
+fn tracking_allocator() {
+    use super::compact::Compact;
+    use super::compacted_box::CompactedBox;
+    use super::compact_vec::CompactVec;
+    type List = CompactVec<u32, TrackingAllocator>;
+    type Lists = CompactVec<List, TrackingAllocator>;
+
+    reset_allocation_stats();
+    let mut lists: Lists = CompactVec::with_capacity(2);
+    lists.push(CompactVec::with_capacity(4));
+    lists.push(CompactVec::with_capacity(2));
+    let stats = allocation_stats();
+    assert_eq!(3, stats.allocations);
+    assert_eq!(2 * mem::size_of::<List>() + 6 * 4, stats.current_bytes);
+    assert_eq!(2, allocation_stats_for::<u32>().allocations);
+    assert_eq!(24, allocation_stats_for::<u32>().current_bytes);
+    assert_eq!(0, allocation_stats_for::<u64>().allocations);
+
+    // after compaction, working with the value doesn't allocate anymore
+    let mut boxed = CompactedBox::new(lists);
+    assert_eq!(3, allocation_stats().deallocations);
+    assert_eq!(0, allocation_stats().current_bytes);
+    reset_allocation_stats();
+    for tick in 0..10 {
+        boxed.with_mut(|lists| {
+            lists[0].clear();
+            lists[0].push(tick);
+        });
+        assert!(boxed.is_still_compact());
+    }
+    assert_eq!(AllocationStats::default(), allocation_stats());
+
+    // spilling does
+    boxed.with_mut(|lists| lists[1].extend(0..3));
+    let stats = allocation_stats();
+    assert_eq!(1, stats.allocations);
+    assert_eq!(stats.peak_bytes, stats.current_bytes);
+}
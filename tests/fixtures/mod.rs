@@ -0,0 +1,135 @@
+//! Infrastructure for checking that values compacted by previous versions of this crate
+//! can still be loaded.
+//!
+//! Fixtures live in `tests/data/<crate version>/<name>.bin` and contain the raw compacted
+//! representation (static part followed by the dynamic part) of a known value.
+//! Fixtures of the current, not yet released format live in `tests/data/unreleased`,
+//! which a release renames to the released version. To record them, run
+//! `COMPACT_WRITE_FIXTURES=1 cargo test --test persisted_format`.
+//!
+//! When the compacted layout changes on purpose, record new fixtures, raise
+//! `OLDEST_COMPATIBLE_VERSION` and delete the fixtures of older versions
+//! (only releases bump the crate version) - this keeps compatibility breaks explicit.
+
+extern crate compact;
+
+use self::compact::Compact;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+/// The directory of the fixtures of the current format, newer than all released versions
+pub const UNRELEASED: &str = "unreleased";
+
+/// Fixtures recorded by versions older than this are incompatible
+/// (the layout changed after 0.2.16 was released)
+pub const OLDEST_COMPATIBLE_VERSION: &str = UNRELEASED;
+
+fn data_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("data")
+}
+
+fn parse_version(version: &str) -> Vec<u32> {
+    if version == UNRELEASED {
+        return vec![u32::MAX];
+    }
+    version
+        .split('.')
+        .map(|part| part.parse().expect("Fixture directories should be named by version"))
+        .collect()
+}
+
+/// All versions that recorded fixtures, oldest first.
+/// Fixtures of incompatible versions would only be skipped, so they have to be deleted.
+pub fn compatible_versions() -> Vec<String> {
+    let oldest = parse_version(OLDEST_COMPATIBLE_VERSION);
+    let mut versions = fs::read_dir(data_dir())
+        .expect("tests/data should exist")
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    for version in &versions {
+        assert!(
+            parse_version(version) >= oldest,
+            "Fixtures of version {} are older than OLDEST_COMPATIBLE_VERSION, delete them",
+            version
+        );
+    }
+    versions.sort_by_key(|version| parse_version(version));
+    versions
+}
+
+fn read_fixture(version: &str, name: &str) -> Option<Vec<u8>> {
+    // the fixture might not have existed yet in that version
+    fs::read(data_dir().join(version).join(format!("{}.bin", name))).ok()
+}
+
+/// Compact `value` into a freshly allocated buffer and return the raw bytes
+pub fn compact_to_bytes<T: Compact>(mut value: T) -> Vec<u8> {
+    let size = value.total_size_bytes();
+    let mut buffer = vec![0u64; size.div_ceil(8)];
+    unsafe {
+        Compact::compact_behind(&mut value, buffer.as_mut_ptr() as *mut T);
+        mem::forget(value);
+        ::std::slice::from_raw_parts(buffer.as_ptr() as *const u8, size).to_vec()
+    }
+}
+
+/// Decompact a value from raw bytes previously produced by `compact_to_bytes`
+pub fn decompact_from_bytes<T: Compact>(bytes: &[u8]) -> T {
+    assert!(bytes.len() >= mem::size_of::<T>(), "Fixture is too short");
+    // copy into an 8-byte aligned buffer, so the static part can be read in place
+    let mut buffer = vec![0u64; bytes.len().div_ceil(8)];
+    unsafe {
+        ptr::copy_nonoverlapping(bytes.as_ptr(), buffer.as_mut_ptr() as *mut u8, bytes.len());
+        Compact::decompact(buffer.as_ptr() as *const T)
+    }
+}
+
+/// Check `value` against the fixture `name` of all compatible versions, using `equal`.
+///
+/// Also checks that the loaded value survives another compaction round-trip
+/// and (re)writes the fixture of the current version if requested.
+pub fn check_fixture<T, F>(name: &str, value: &T, equal: F)
+where
+    T: Compact,
+    F: Fn(&T, &T) -> bool,
+{
+    if ::std::env::var_os("COMPACT_WRITE_FIXTURES").is_some() {
+        let dir = data_dir().join(UNRELEASED);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(format!("{}.bin", name)), compact_to_bytes(value.clone())).unwrap();
+    }
+
+    // right after a release, the current format is recorded under the released version
+    let versions = compatible_versions();
+    let current_version = env!("CARGO_PKG_VERSION");
+    assert!(
+        versions
+            .iter()
+            .any(|version| version == UNRELEASED || version == current_version),
+        "No fixtures recorded for the current format"
+    );
+
+    for version in versions {
+        let bytes = match read_fixture(&version, name) {
+            Some(bytes) => bytes,
+            None => continue,
+        };
+        let loaded: T = decompact_from_bytes(&bytes);
+        assert!(
+            equal(&loaded, value),
+            "Fixture {} from version {} doesn't match",
+            name,
+            version
+        );
+        let round_tripped: T = decompact_from_bytes(&compact_to_bytes(loaded));
+        assert!(
+            equal(&round_tripped, value),
+            "Fixture {} from version {} doesn't survive a round-trip",
+            name,
+            version
+        );
+    }
+}
+
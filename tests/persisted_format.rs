@@ -0,0 +1,68 @@
+//! Compatibility of compacted values with fixtures recorded by previous versions.
+//! The fixtures are only valid for 64-bit little-endian targets.
+#![cfg(all(target_pointer_width = "64", target_endian = "little"))]
+
+extern crate compact;
+
+mod fixtures;
+
+use compact::{CDict, CHashMap, COption, CString, CVec};
+use fixtures::check_fixture;
+
+fn sample_nested_vec() -> CVec<CVec<u32>> {
+    vec![vec![1, 2, 3].into(), CVec::new(), vec![4, 5, 6, 7, 8, 9].into()].into()
+}
+
+#[test]
+fn vec_of_copy() {
+    let mut value: CVec<u32> = CVec::with_capacity(8);
+    value.extend_from_copy_slice(&[1, 2, 3, 5, 8]);
+    check_fixture("vec_of_copy", &value, |a, b| a == b);
+}
+
+#[test]
+fn nested_vec() {
+    check_fixture("nested_vec", &sample_nested_vec(), |a, b| a == b);
+}
+
+#[test]
+fn string() {
+    let value: CString = String::from("Grüße, compact world").into();
+    check_fixture("string", &value, |a, b| a == b);
+}
+
+#[test]
+fn option() {
+    let value = COption(Some(sample_nested_vec()));
+    check_fixture("option", &value, |a, b| a == b);
+}
+
+#[test]
+fn dict() {
+    let mut value: CDict<u32, CVec<u32>> = CDict::new();
+    for n in 0..20 {
+        value.push_at(n % 7, n * n);
+    }
+    check_fixture("dict", &value, |a, b| a == b);
+}
+
+#[test]
+fn hash_map() {
+    let mut value: CHashMap<u32, CVec<u32>> = CHashMap::new();
+    for n in 0..100 {
+        value.push_at(n % 37, n * n);
+    }
+    for n in 0..5 {
+        value.remove(&n);
+    }
+    check_fixture("hash_map", &value, |a, b| a == b);
+}
+
+#[test]
+fn padded_dict() {
+    let mut value: CDict<u8, CVec<u64>> = CDict::new();
+    for n in 0..9u8 {
+        value.push_at(n % 3, u64::from(n) << 40);
+    }
+    check_fixture("padded_dict", &value, |a, b| a == b);
+}